@@ -0,0 +1,12 @@
+#![no_main]
+//! Arbitrary topics & data through the log decoding path, see `tests/common/fuzz_input.rs` for the input format.
+//! Decoding errors are expected, panics & unbounded allocations aren't.
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../tests/common/fuzz_input.rs"]
+#[allow(dead_code)] // `bytes_from_log` is only used by the tests
+mod fuzz_input;
+
+fuzz_target!(|bytes: &[u8]| {
+    let _ = fuzz_input::decode(bytes);
+});
@@ -1,34 +1,294 @@
+use clap::Parser;
+use serde::Serialize;
 use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use tokio::fs;
+use uniswap_dai_usd_monitor::cassette::CassetteTransport;
+use uniswap_dai_usd_monitor::config::Config;
+use uniswap_dai_usd_monitor::events_handler::EventsHandler;
+use uniswap_dai_usd_monitor::events_source::Web3EventsSource;
 use uniswap_dai_usd_monitor::setup_web3;
-use web3::types::BlockHeader;
-use web3::Transport;
+use web3::types::{BlockHeader, Log, TransactionReceipt, H160, H256};
+use web3::{Transport, Web3};
+
+/// Captures block headers, pool swap logs & receipts from a node as test fixtures.
+/// Every request is also recorded into a cassette, so tests can replay them without a node.
+///
+/// The committed fixtures weren't generated with it, they were transcribed by hand (see Tests in the README)
+#[derive(Debug, Parser)]
+struct Args {
+    /// Blocks to capture, as numbers or inclusive ranges, e.g., `21836327-21836337,21904546`
+    #[arg(long, value_delimiter = ',', value_parser = parse_blocks, required_unless_present = "reorg")]
+    blocks: Vec<RangeInclusive<u64>>,
+
+    /// Also capture the Swap logs of this pool address, in `<out>/<block>/logs.json`
+    #[arg(long)]
+    pool: Option<H160>,
+
+    /// Also capture the receipts of the transactions which emitted the pool logs, in `<out>/<block>/receipts.json`
+    #[arg(long, requires = "pool")]
+    receipts: bool,
+
+    /// Hash of an orphaned block, it's captured with its canonical replacement in `<out>/reorg-<block>/`
+    #[arg(long)]
+    reorg: Vec<H256>,
+
+    /// Where the fixtures are written
+    #[arg(long, default_value = "tests/fixtures")]
+    out: PathBuf,
+
+    /// Where the recorded requests are written, `DEFAULT_CASSETTE` with `--blocks`.
+    /// Required without, so capturing a reorg doesn't replace the cassette of the blocks
+    #[arg(long, required_unless_present = "blocks")]
+    cassette: Option<PathBuf>,
+}
+
+const DEFAULT_CASSETTE: &str = "tests/fixtures/cassettes/mainnet.json";
+
+/// `N` or `FROM-TO`
+fn parse_blocks(value: &str) -> Result<RangeInclusive<u64>, String> {
+    let parse = |number: &str| {
+        number
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid block number {:?}: {}", number, e))
+    };
+    match value.split_once('-') {
+        Some((from, to)) => {
+            let (from, to) = (parse(from)?, parse(to)?);
+            if from > to {
+                return Err(format!("Empty blocks range {}", value));
+            }
+            Ok(from..=to)
+        }
+        None => parse(value).map(|number| number..=number),
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let web3 = setup_web3().await?;
+    dotenv::dotenv().ok();
+    let args = Args::parse();
 
-    let start_block = 21836327;
-    let end_block = start_block + 10;
-    println!("Blocks range: {} - {}", start_block, end_block);
+    // endpoints come from the env (`WEBSOCKET_ENDPOINT`)
+    let ws_web3 = setup_web3(&Config::load(None)?).await?;
+    let transport = CassetteTransport::record(ws_web3.transport().clone());
+    let web3 = Web3::new(transport.clone());
 
-    let mut headers = Vec::new();
-    for block_number in start_block..=end_block {
-        println!("Fetching block: {}", block_number);
-        let params = vec![json!(format!("0x{:x}", block_number)), json!(false)];
-        let response: Value = web3
-            .transport()
-            .execute("eth_getBlockByNumber", params)
-            .await?;
+    let block_numbers: BTreeSet<u64> = args.blocks.iter().cloned().flatten().collect();
+    if !block_numbers.is_empty() {
+        let mut headers = Vec::new();
+        for &block_number in &block_numbers {
+            println!("Fetching block: {}", block_number);
+            headers.push(fetch_header(&web3, block_number).await?);
+        }
+        write_json(&args.out.join("block_headers.json"), &headers).await?;
+        println!("✅ Generated {} block headers", headers.len());
 
-        let header: BlockHeader = serde_json::from_value(response)?;
-        headers.push(header);
+        if let Some(pool) = args.pool {
+            for header in &headers {
+                let dir = args.out.join(block_number(header)?.to_string());
+                // so the block's fixtures can be used on their own, e.g., with `FixtureEventsSource`
+                if capture_logs(&web3, &args, pool, header, &dir).await? {
+                    write_json(&dir.join("block_headers.json"), &vec![header]).await?;
+                }
+            }
+        }
     }
 
-    fs::create_dir_all("tests/fixtures").await?;
-    let json = serde_json::to_string_pretty(&headers)?;
-    fs::write("tests/fixtures/block_headers.json", json).await?;
+    for &orphaned_hash in &args.reorg {
+        capture_reorg(&web3, &args, orphaned_hash).await?;
+    }
 
-    println!("✅ Generated {} block headers", headers.len());
+    let cassette = args
+        .cassette
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CASSETTE));
+    transport.cassette().save(&cassette)?;
+    println!("✅ Recorded cassette {}", cassette.display());
     Ok(())
 }
+
+/// Writes the orphaned header (& logs) in `orphaned/` and the canonical ones at the same height in `canonical/`
+async fn capture_reorg<T>(
+    web3: &Web3<T>,
+    args: &Args,
+    orphaned_hash: H256,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: Transport + Send + Sync,
+    T::Out: Send,
+{
+    let orphaned = fetch_header_by_hash(web3, orphaned_hash).await?;
+    let number = block_number(&orphaned)?;
+    let canonical = fetch_header(web3, number).await?;
+    if canonical.hash == Some(orphaned_hash) {
+        return Err(format!("Block {:?} is canonical, not orphaned", orphaned_hash).into());
+    }
+
+    let dir = args.out.join(format!("reorg-{}", number));
+    for (name, header) in [("orphaned", &orphaned), ("canonical", &canonical)] {
+        write_json(&dir.join(name).join("block_headers.json"), &vec![header]).await?;
+        if let Some(pool) = args.pool {
+            capture_logs(web3, args, pool, header, &dir.join(name)).await?;
+        }
+    }
+
+    println!(
+        "✅ Captured reorg at block {}: {:?} replaced by {:?}",
+        number, orphaned_hash, canonical.hash
+    );
+    Ok(())
+}
+
+/// Logs are fetched by block hash, so they belong to `header` even if the chain reorgs meanwhile.
+/// They're fetched through `EventsHandler`, so the cassette records the requests the monitor sends.
+/// Returns whether the block has any Swap logs of `pool`.
+async fn capture_logs<T>(
+    web3: &Web3<T>,
+    args: &Args,
+    pool: H160,
+    header: &BlockHeader,
+    dir: &Path,
+) -> Result<bool, Box<dyn std::error::Error>>
+where
+    T: Transport + Send + Sync,
+    T::Out: Send,
+{
+    let block_hash = header.hash.ok_or("Block without hash")?;
+    let events_handler =
+        EventsHandler::new(Web3EventsSource { web3: web3.clone() })?.with_pool(pool);
+    let logs = events_handler.fetch_swap_logs(block_hash).await?;
+    if logs.is_empty() {
+        return Ok(false);
+    }
+
+    println!("Captured {} logs for block: {:?}", logs.len(), block_hash);
+    write_json(&dir.join("logs.json"), &logs).await?;
+    if args.receipts {
+        let receipts = fetch_receipts(web3, &logs).await?;
+        write_json(&dir.join("receipts.json"), &receipts).await?;
+    }
+    Ok(true)
+}
+
+async fn fetch_receipts<T: Transport>(
+    web3: &Web3<T>,
+    logs: &[Log],
+) -> Result<Vec<TransactionReceipt>, Box<dyn std::error::Error>> {
+    let transaction_hashes: BTreeSet<H256> =
+        logs.iter().filter_map(|log| log.transaction_hash).collect();
+
+    let mut receipts = Vec::new();
+    for transaction_hash in transaction_hashes {
+        let receipt = web3
+            .eth()
+            .transaction_receipt(transaction_hash)
+            .await?
+            .ok_or_else(|| format!("Receipt not found: {:?}", transaction_hash))?;
+        receipts.push(receipt);
+    }
+    Ok(receipts)
+}
+
+async fn fetch_header<T: Transport>(
+    web3: &Web3<T>,
+    block_number: u64,
+) -> Result<BlockHeader, Box<dyn std::error::Error>> {
+    let params = vec![json!(format!("0x{:x}", block_number)), json!(false)];
+    let response: Value = web3
+        .transport()
+        .execute("eth_getBlockByNumber", params)
+        .await?;
+    if response.is_null() {
+        return Err(format!("Block {} not found", block_number).into());
+    }
+
+    Ok(serde_json::from_value(response)?)
+}
+
+async fn fetch_header_by_hash<T: Transport>(
+    web3: &Web3<T>,
+    block_hash: H256,
+) -> Result<BlockHeader, Box<dyn std::error::Error>> {
+    let params = vec![json!(block_hash), json!(false)];
+    let response: Value = web3
+        .transport()
+        .execute("eth_getBlockByHash", params)
+        .await?;
+    if response.is_null() {
+        return Err(format!(
+            "Block {:?} not found, the node may have pruned it",
+            block_hash
+        )
+        .into());
+    }
+
+    Ok(serde_json::from_value(response)?)
+}
+
+fn block_number(header: &BlockHeader) -> Result<u64, Box<dyn std::error::Error>> {
+    Ok(header.number.ok_or("Pending block")?.as_u64())
+}
+
+async fn write_json(path: &Path, value: &impl Serialize) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    fs::write(path, serde_json::to_string_pretty(value)?).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_blocks() {
+        assert_eq!(parse_blocks("21836327"), Ok(21836327..=21836327));
+        assert_eq!(parse_blocks("21836327-21836337"), Ok(21836327..=21836337));
+        assert!(parse_blocks("21836337-21836327").is_err());
+        assert!(parse_blocks("latest").is_err());
+    }
+
+    #[test]
+    fn test_args() {
+        let args = Args::try_parse_from([
+            "generate_fixtures",
+            "--blocks",
+            "1-3,7",
+            "--blocks",
+            "10",
+            "--pool",
+            "0x5777d92f208679db4b9778590fa3cab3ac9e2168",
+            "--receipts",
+        ])
+        .unwrap();
+        assert_eq!(args.blocks, vec![1..=3, 7..=7, 10..=10]);
+        assert!(args.receipts);
+        assert_eq!(args.out, PathBuf::from("tests/fixtures"));
+        assert_eq!(args.cassette, None);
+
+        // receipts are the ones of the pool's logs
+        assert!(
+            Args::try_parse_from(["generate_fixtures", "--blocks", "1", "--receipts"]).is_err()
+        );
+        // nothing to capture
+        assert!(Args::try_parse_from(["generate_fixtures"]).is_err());
+        // a reorg on its own goes to a cassette of its own, rather than replacing the default one
+        let reorg = [
+            "generate_fixtures",
+            "--reorg",
+            "0x66bd54e8de15637a55f4d963c81309ba1ece5101e6f9c4db19ad70c7b4f5be21",
+        ];
+        assert!(Args::try_parse_from(reorg).is_err());
+        assert!(Args::try_parse_from(
+            reorg
+                .iter()
+                .chain(&["--cassette", "tests/fixtures/cassettes/reorg.json"])
+        )
+        .is_ok());
+    }
+}
@@ -0,0 +1,1001 @@
+use crate::{
+    blocks_handler::{BlockEvent, ConfirmedBlock, Reorg},
+    config::{AlertsConfig, PoolConfig},
+    sinks::{ProviderOutage, Sink},
+    swap_details::{SwapDetails, AMOUNT0_DECIMALS, AMOUNT1_DECIMALS},
+};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use log::{info, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+use web3::types::{H160, H256};
+
+/// Raised by a `Rule`, written to the sinks after the block which raised it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Alert {
+    /// The pool's price is off 1.0 by more than the threshold, over `window`
+    Depeg {
+        pool: H160,
+        block_number: u64,
+        price: Decimal,
+        /// First block the price was off at
+        since_block: u64,
+        window: DepegWindow,
+    },
+    /// The price of a depegged pool is within the threshold again
+    DepegRecovered {
+        pool: H160,
+        block_number: u64,
+        price: Decimal,
+    },
+    /// A swap of more than the configured amount of either token
+    LargeSwap {
+        pool: H160,
+        block_number: u64,
+        transaction_hash: Option<H256>,
+        sender: H160,
+        recipient: H160,
+        amount0: Decimal,
+        amount1: Decimal,
+    },
+    /// A swap sent or received by an address of the watchlist
+    Watchlist {
+        /// The watched address, the swap's sender or recipient
+        address: H160,
+        label: Option<String>,
+        pool: H160,
+        block_number: u64,
+        transaction_hash: Option<H256>,
+        sender: H160,
+        recipient: H160,
+        amount0: Decimal,
+        amount1: Decimal,
+    },
+    /// `swaps` swaps of one sender in blocks `from_block` - `block_number`
+    Burst {
+        sender: H160,
+        swaps: u64,
+        from_block: u64,
+        block_number: u64,
+    },
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Alert::Depeg {
+                pool,
+                block_number,
+                price,
+                since_block,
+                window,
+            } => write!(
+                f,
+                "depeg of pool {:?} at block {}: price {} ({}), off since block {}",
+                pool, block_number, price, window, since_block
+            ),
+            Alert::DepegRecovered {
+                pool,
+                block_number,
+                price,
+            } => write!(
+                f,
+                "pool {:?} recovered from a depeg at block {}: price {}",
+                pool, block_number, price
+            ),
+            Alert::LargeSwap {
+                pool,
+                block_number,
+                transaction_hash,
+                amount0,
+                amount1,
+                ..
+            } => write!(
+                f,
+                "large swap of pool {:?} at block {} tx {}: amount0 {} amount1 {}",
+                pool,
+                block_number,
+                transaction_hash.map_or("-".to_string(), |hash| format!("{:?}", hash)),
+                amount0,
+                amount1
+            ),
+            Alert::Watchlist {
+                address,
+                label,
+                pool,
+                block_number,
+                transaction_hash,
+                amount0,
+                amount1,
+                ..
+            } => write!(
+                f,
+                "watched address {:?}{} swapped on pool {:?} at block {} tx {}: amount0 {} amount1 {}",
+                address,
+                label.as_ref().map_or(String::new(), |label| format!(" ({})", label)),
+                pool,
+                block_number,
+                transaction_hash.map_or("-".to_string(), |hash| format!("{:?}", hash)),
+                amount0,
+                amount1
+            ),
+            Alert::Burst {
+                sender,
+                swaps,
+                from_block,
+                block_number,
+            } => write!(
+                f,
+                "burst of {} swaps by {:?} in blocks {} - {}",
+                swaps, sender, from_block, block_number
+            ),
+        }
+    }
+}
+
+/// Checks confirmed blocks for conditions worth an alert
+pub trait Rule: Send {
+    /// Alerts raised by `block`, blocks are checked in order
+    fn check(&mut self, block: &ConfirmedBlock) -> Vec<Alert>;
+}
+
+/// The rules `config` turns on, none by default. Fails when the watchlist can't be loaded
+pub fn rules(
+    config: &AlertsConfig,
+    pools: &[PoolConfig],
+) -> Result<Vec<Box<dyn Rule>>, anyhow::Error> {
+    let mut rules: Vec<Box<dyn Rule>> = vec![];
+    if let Some(threshold) = config.depeg_threshold {
+        rules.push(Box::new(DepegRule::new(
+            threshold,
+            config.depeg_price,
+            config.depeg_window(),
+            pools,
+        )));
+    }
+    if let Some(amount) = config.large_swap_amount {
+        rules.push(Box::new(LargeSwapRule::new(amount)));
+    }
+    if let Some(path) = &config.watchlist {
+        rules.push(Box::new(WatchlistRule::load(path)?));
+    }
+    if let (Some(swaps), Some(blocks)) = (config.burst_swaps, config.burst_blocks) {
+        rules.push(Box::new(BurstRule::new(swaps, blocks)));
+    }
+    Ok(rules)
+}
+
+/// Checks the rules against every confirmed block & writes their alerts to `inner`, right after the block
+pub struct AlertingSink<S> {
+    inner: S,
+    rules: Vec<Box<dyn Rule>>,
+}
+
+impl<S: Sink> AlertingSink<S> {
+    pub fn new(inner: S, rules: Vec<Box<dyn Rule>>) -> Self {
+        Self { inner, rules }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<S: Sink> Sink for AlertingSink<S> {
+    async fn write(&mut self, confirmed_block: &ConfirmedBlock) -> Result<(), anyhow::Error> {
+        self.inner.write(confirmed_block).await?;
+        let alerts: Vec<Alert> = self
+            .rules
+            .iter_mut()
+            .flat_map(|rule| rule.check(confirmed_block))
+            .collect();
+        for alert in &alerts {
+            warn!("🚨 {}", alert);
+            self.inner.write_alert(alert).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_reorg(&mut self, reorg: &Reorg) -> Result<(), anyhow::Error> {
+        self.inner.write_reorg(reorg).await
+    }
+
+    async fn write_outage(&mut self, outage: &ProviderOutage) -> Result<(), anyhow::Error> {
+        self.inner.write_outage(outage).await
+    }
+
+    async fn write_event(&mut self, event: &BlockEvent) -> Result<(), anyhow::Error> {
+        self.inner.write_event(event).await
+    }
+
+    async fn write_alert(&mut self, alert: &Alert) -> Result<(), anyhow::Error> {
+        self.inner.write_alert(alert).await
+    }
+
+    async fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.inner.flush().await
+    }
+}
+
+/// Which price of the swaps a `DepegRule` checks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DepegPrice {
+    /// What the swap paid, USDC per DAI
+    #[default]
+    Swap,
+    /// The pool's price after the swap, from `sqrtPriceX96`
+    Pool,
+}
+
+/// How long the price has to be off before a `DepegRule` alerts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DepegWindow {
+    /// The price of this many blocks in a row (the volume-weighted one of the block's swaps,
+    /// kept by the blocks without swaps)
+    Sustained { blocks: u64 },
+    /// The volume-weighted price of the swaps in the last `blocks` blocks
+    Vwap { blocks: u64 },
+}
+
+impl fmt::Display for DepegWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DepegWindow::Sustained { blocks } => write!(f, "{} blocks in a row", blocks),
+            DepegWindow::Vwap { blocks } => write!(f, "VWAP of {} blocks", blocks),
+        }
+    }
+}
+
+/// Alerts when a pool's price deviates from 1.0 by more than `threshold` over its window,
+/// & once more when the price is back within it
+pub struct DepegRule {
+    threshold: Decimal,
+    price: DepegPrice,
+    window: DepegWindow,
+    /// Token decimals of the pools, for their `sqrtPriceX96` prices
+    decimals: HashMap<H160, (u32, u32)>,
+    pools: BTreeMap<H160, DepegState>,
+}
+
+#[derive(Default)]
+struct DepegState {
+    /// `(block number, Σ price × volume, Σ volume)` of the blocks with swaps in the window
+    blocks: VecDeque<(u64, Decimal, Decimal)>,
+    /// First block of the current deviation
+    off_since: Option<u64>,
+    /// Alerted, until the price recovers
+    depegged: bool,
+}
+
+impl DepegRule {
+    pub fn new(
+        threshold: Decimal,
+        price: DepegPrice,
+        window: DepegWindow,
+        pools: &[PoolConfig],
+    ) -> Self {
+        let decimals = pools
+            .iter()
+            .map(|pool| (pool.address, (pool.token0.decimals, pool.token1.decimals)))
+            .collect();
+        Self {
+            threshold,
+            price,
+            window,
+            decimals,
+            pools: BTreeMap::new(),
+        }
+    }
+
+    /// Price of `swap` as configured, with its volume (DAI swapped) as the weight
+    fn weighted_price(&self, swap: &SwapDetails) -> Option<(Decimal, Decimal)> {
+        let price = match self.price {
+            DepegPrice::Swap => swap.price(),
+            DepegPrice::Pool => {
+                let (decimals0, decimals1) = self
+                    .decimals
+                    .get(&swap.metadata.pool)
+                    .copied()
+                    .unwrap_or((AMOUNT0_DECIMALS, AMOUNT1_DECIMALS));
+                swap.pool_price(decimals0, decimals1)
+            }
+        }?;
+        Some((price, swap.dai_and_usdc_amounts().0.abs()))
+    }
+
+    /// Adds the swaps of `block` to the windows of their pools
+    fn add_block(&mut self, block: &ConfirmedBlock) {
+        let mut sums: BTreeMap<H160, (Decimal, Decimal)> = BTreeMap::new();
+        for swap in &block.swaps {
+            let Some((price, volume)) = self.weighted_price(swap) else {
+                continue;
+            };
+            let (weighted, total) = sums.entry(swap.metadata.pool).or_default();
+            // e.g., the pool price of an illiquid pool is near `Decimal::MAX`
+            let sums = price
+                .checked_mul(volume)
+                .and_then(|price_volume| weighted.checked_add(price_volume))
+                .zip(total.checked_add(volume));
+            match sums {
+                Some((new_weighted, new_total)) => (*weighted, *total) = (new_weighted, new_total),
+                None => warn!(
+                    "dropping the price {} of a swap of pool {:?} at block {}, weighting it overflows",
+                    price, swap.metadata.pool, block.block_number
+                ),
+            }
+        }
+        for (pool, (weighted, volume)) in sums {
+            if volume.is_zero() {
+                continue;
+            }
+            let state = self.pools.entry(pool).or_default();
+            state
+                .blocks
+                .push_back((block.block_number, weighted, volume));
+        }
+    }
+}
+
+impl Rule for DepegRule {
+    fn check(&mut self, block: &ConfirmedBlock) -> Vec<Alert> {
+        self.add_block(block);
+
+        let mut alerts = vec![];
+        for (pool, state) in self.pools.iter_mut() {
+            match self.window {
+                DepegWindow::Sustained { .. } => {
+                    while state.blocks.len() > 1 {
+                        state.blocks.pop_front();
+                    }
+                }
+                DepegWindow::Vwap { blocks } => {
+                    while state
+                        .blocks
+                        .front()
+                        .is_some_and(|(number, ..)| number + blocks <= block.block_number)
+                    {
+                        state.blocks.pop_front();
+                    }
+                }
+            }
+            let (weighted, volume) = state.blocks.iter().fold(
+                (Decimal::ZERO, Decimal::ZERO),
+                |(weighted, volume), (number, block_weighted, block_volume)| {
+                    match weighted
+                        .checked_add(*block_weighted)
+                        .zip(volume.checked_add(*block_volume))
+                    {
+                        Some(sums) => sums,
+                        None => {
+                            warn!(
+                                "dropping the prices of pool {:?} at block {} from the window, summing them overflows",
+                                pool, number
+                            );
+                            (weighted, volume)
+                        }
+                    }
+                },
+            );
+            // nothing in the window, the last state stands
+            let Some(price) = weighted.checked_div(volume) else {
+                continue;
+            };
+            let price = price.round_dp(18).normalize();
+
+            if (price - Decimal::ONE).abs() <= self.threshold {
+                state.off_since = None;
+                if state.depegged {
+                    state.depegged = false;
+                    alerts.push(Alert::DepegRecovered {
+                        pool: *pool,
+                        block_number: block.block_number,
+                        price,
+                    });
+                }
+                continue;
+            }
+            let since_block = *state.off_since.get_or_insert(block.block_number);
+            let sustained = match self.window {
+                DepegWindow::Sustained { blocks } => block.block_number - since_block + 1 >= blocks,
+                DepegWindow::Vwap { .. } => true,
+            };
+            if sustained && !state.depegged {
+                state.depegged = true;
+                alerts.push(Alert::Depeg {
+                    pool: *pool,
+                    block_number: block.block_number,
+                    price,
+                    since_block,
+                    window: self.window,
+                });
+            }
+        }
+        alerts
+    }
+}
+
+/// Alerts on swaps of more than `amount` of either token
+pub struct LargeSwapRule {
+    amount: Decimal,
+}
+
+impl LargeSwapRule {
+    pub fn new(amount: Decimal) -> Self {
+        Self { amount }
+    }
+}
+
+impl Rule for LargeSwapRule {
+    fn check(&mut self, block: &ConfirmedBlock) -> Vec<Alert> {
+        block
+            .swaps
+            .iter()
+            .filter(|swap| {
+                swap.amount0_as_decimal_num.abs() > self.amount
+                    || swap.amount1_as_decimal_num.abs() > self.amount
+            })
+            .map(|swap| Alert::LargeSwap {
+                pool: swap.metadata.pool,
+                block_number: block.block_number,
+                transaction_hash: swap.metadata.transaction_hash,
+                sender: swap.sender,
+                recipient: swap.recipient,
+                amount0: swap.amount0_as_decimal_num,
+                amount1: swap.amount1_as_decimal_num,
+            })
+            .collect()
+    }
+}
+
+/// Alerts on swaps sent or received by the addresses of a watchlist file (treasuries, MEV bots, sanctioned addresses, ..).
+/// The file is read again once it changes, so the list is updated without a restart
+pub struct WatchlistRule {
+    path: PathBuf,
+    /// Modification time of the loaded file
+    modified: Option<SystemTime>,
+    /// Watched addresses, with their labels
+    addresses: HashMap<H160, Option<String>>,
+}
+
+impl WatchlistRule {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref().to_path_buf();
+        let modified = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let addresses = read_watchlist(&path)?;
+        info!(
+            "Watching {} addresses of {}",
+            addresses.len(),
+            path.display()
+        );
+        Ok(Self {
+            path,
+            modified,
+            addresses,
+        })
+    }
+
+    /// Reads the file again when its modification time changed, a file which fails to load leaves the list as it was
+    fn reload_if_changed(&mut self) {
+        let modified = match fs::metadata(&self.path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(err) => {
+                warn!(
+                    "Failed to check the watchlist {}: {}",
+                    self.path.display(),
+                    err
+                );
+                return;
+            }
+        };
+        if self.modified == Some(modified) {
+            return;
+        }
+        match read_watchlist(&self.path) {
+            Ok(addresses) => {
+                info!(
+                    "Reloaded the watchlist {}, watching {} addresses",
+                    self.path.display(),
+                    addresses.len()
+                );
+                self.addresses = addresses;
+                self.modified = Some(modified);
+            }
+            Err(err) => warn!("Keeping the previous watchlist: {:#}", err),
+        }
+    }
+}
+
+/// An address per line, optionally followed by a label. Blank lines & `#` comments are skipped
+fn read_watchlist(path: &Path) -> Result<HashMap<H160, Option<String>>, anyhow::Error> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read the watchlist {}: {}", path.display(), e))?;
+    let mut addresses = HashMap::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let Some(address) = parts.next().filter(|address| !address.is_empty()) else {
+            continue;
+        };
+        let label = parts
+            .next()
+            .map(str::trim)
+            .filter(|label| !label.is_empty())
+            .map(str::to_string);
+        let address = H160::from_str(address).map_err(|e| {
+            anyhow!(
+                "{} line {}: invalid address {:?}: {}",
+                path.display(),
+                index + 1,
+                address,
+                e
+            )
+        })?;
+        addresses.insert(address, label);
+    }
+    Ok(addresses)
+}
+
+impl Rule for WatchlistRule {
+    fn check(&mut self, block: &ConfirmedBlock) -> Vec<Alert> {
+        self.reload_if_changed();
+        let mut alerts = vec![];
+        for swap in &block.swaps {
+            let parties: BTreeSet<H160> = [swap.sender, swap.recipient].into();
+            for address in parties {
+                let Some(label) = self.addresses.get(&address) else {
+                    continue;
+                };
+                alerts.push(Alert::Watchlist {
+                    address,
+                    label: label.clone(),
+                    pool: swap.metadata.pool,
+                    block_number: block.block_number,
+                    transaction_hash: swap.metadata.transaction_hash,
+                    sender: swap.sender,
+                    recipient: swap.recipient,
+                    amount0: swap.amount0_as_decimal_num,
+                    amount1: swap.amount1_as_decimal_num,
+                });
+            }
+        }
+        alerts
+    }
+}
+
+/// Alerts when one sender makes `swaps` swaps (of any pool) within `blocks` blocks,
+/// once per burst: the sender alerts again after its swaps in the window dropped below `swaps`
+pub struct BurstRule {
+    swaps: u64,
+    blocks: u64,
+    /// Block numbers of the swaps in the window, by sender
+    senders: BTreeMap<H160, VecDeque<u64>>,
+    /// Senders alerted for, until their burst is over
+    bursting: BTreeSet<H160>,
+}
+
+impl BurstRule {
+    pub fn new(swaps: u64, blocks: u64) -> Self {
+        Self {
+            swaps,
+            blocks,
+            senders: BTreeMap::new(),
+            bursting: BTreeSet::new(),
+        }
+    }
+}
+
+impl Rule for BurstRule {
+    fn check(&mut self, block: &ConfirmedBlock) -> Vec<Alert> {
+        for swap in &block.swaps {
+            self.senders
+                .entry(swap.sender)
+                .or_default()
+                .push_back(block.block_number);
+        }
+
+        let mut alerts = vec![];
+        let blocks = self.blocks;
+        self.senders.retain(|sender, swaps| {
+            while swaps
+                .front()
+                .is_some_and(|number| number + blocks <= block.block_number)
+            {
+                swaps.pop_front();
+            }
+            if (swaps.len() as u64) < self.swaps {
+                self.bursting.remove(sender);
+            } else if self.bursting.insert(*sender) {
+                alerts.push(Alert::Burst {
+                    sender: *sender,
+                    swaps: swaps.len() as u64,
+                    from_block: swaps.front().copied().unwrap_or(block.block_number),
+                    block_number: block.block_number,
+                });
+            }
+            !swaps.is_empty()
+        });
+        alerts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blocks_handler::ConfirmedBlock,
+        swap_details::{LogMetadata, SwapDirection},
+    };
+    use web3::types::{H256, U256};
+
+    const POOL: H160 = H160::repeat_byte(0xaa);
+
+    /// A swap of `amount0` DAI at `price` USDC per DAI
+    fn swap(amount0: i64, price: Decimal) -> SwapDetails {
+        let amount0 = Decimal::from(amount0);
+        SwapDetails {
+            metadata: LogMetadata {
+                pool: POOL,
+                ..Default::default()
+            },
+            sender: H160::repeat_byte(1),
+            recipient: H160::repeat_byte(2),
+            amount0_raw: U256::zero(),
+            amount0_as_decimal_num: amount0,
+            amount1_raw: U256::zero(),
+            amount1_as_decimal_num: -amount0 * price,
+            direction: SwapDirection::DaiToUsdc,
+            sqrt_price_x96: U256::zero(),
+            dai_is_token1: false,
+        }
+    }
+
+    fn block(block_number: u64, swaps: Vec<SwapDetails>) -> ConfirmedBlock {
+        ConfirmedBlock {
+            block_number,
+            block_hash: H256::from_low_u64_be(block_number),
+            timestamp: block_number * 12,
+            swaps,
+        }
+    }
+
+    fn price(per_mille: i64) -> Decimal {
+        Decimal::new(per_mille, 3)
+    }
+
+    fn depeg_rule(window: DepegWindow) -> DepegRule {
+        DepegRule::new(price(5), DepegPrice::Swap, window, &[])
+    }
+
+    /// `(kind, block number)` of the alerts of each block, in order
+    fn check(rule: &mut DepegRule, blocks: Vec<ConfirmedBlock>) -> Vec<(&'static str, u64)> {
+        blocks
+            .iter()
+            .flat_map(|block| rule.check(block))
+            .map(|alert| match alert {
+                Alert::Depeg { block_number, .. } => ("depeg", block_number),
+                Alert::DepegRecovered { block_number, .. } => ("recovered", block_number),
+                alert => panic!("unexpected alert: {:?}", alert),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_depeg_has_to_be_sustained() {
+        let mut rule = depeg_rule(DepegWindow::Sustained { blocks: 3 });
+        let alerts = check(
+            &mut rule,
+            vec![
+                block(1, vec![swap(100, price(990))]),
+                // back within the threshold, the streak starts over
+                block(2, vec![swap(100, price(999))]),
+                block(3, vec![swap(100, price(990))]),
+                // no swaps, the price stays off
+                block(4, vec![]),
+                block(5, vec![swap(100, price(992)), swap(300, price(994))]),
+                block(6, vec![swap(100, price(989))]),
+                // a small swap doesn't outweigh the block's volume
+                block(7, vec![swap(1000, price(990)), swap(1, price(1000))]),
+                block(8, vec![swap(10, price(1001))]),
+            ],
+        );
+        assert_eq!(alerts, vec![("depeg", 5), ("recovered", 8)]);
+
+        let mut rule = depeg_rule(DepegWindow::Sustained { blocks: 1 });
+        let alerts = rule.check(&block(
+            1,
+            vec![swap(100, price(1010)), swap(100, price(1020))],
+        ));
+        assert_eq!(
+            alerts,
+            vec![Alert::Depeg {
+                pool: POOL,
+                block_number: 1,
+                price: Decimal::new(1015, 3),
+                since_block: 1,
+                window: DepegWindow::Sustained { blocks: 1 },
+            }]
+        );
+    }
+
+    #[test]
+    fn test_depeg_over_a_volume_weighted_window() {
+        let mut rule = depeg_rule(DepegWindow::Vwap { blocks: 3 });
+        let alerts = check(
+            &mut rule,
+            vec![
+                block(1, vec![swap(1000, price(1000))]),
+                // VWAP (1000 * 1.0 + 100 * 0.95) / 1100 = 0.9954
+                block(2, vec![swap(100, price(950))]),
+                // (1000 + 95 + 100 * 0.95) / 1200 = 0.9916
+                block(3, vec![swap(100, price(950))]),
+                // block 1 left the window, 0.95
+                block(4, vec![]),
+                block(5, vec![]),
+                // blocks 2 & 3 left the window
+                block(6, vec![swap(10000, price(1000))]),
+            ],
+        );
+        assert_eq!(alerts, vec![("depeg", 3), ("recovered", 6)]);
+        // nothing left in the window, nothing changes
+        assert!(rule.check(&block(20, vec![])).is_empty());
+    }
+
+    #[test]
+    fn test_depeg_of_the_pool_price() {
+        let mut depegged = swap(100, price(1000));
+        // DAI/USDC at 0.98: sqrt(0.98e-12) * 2^96
+        depegged.sqrt_price_x96 = U256::from_dec_str("78431879364700595886224").unwrap();
+        let mut rule = DepegRule::new(
+            price(5),
+            DepegPrice::Pool,
+            DepegWindow::Sustained { blocks: 1 },
+            &crate::networks::Network::Mainnet.pools(),
+        );
+        // unknown pool prices are skipped
+        assert!(rule
+            .check(&block(1, vec![swap(100, price(900))]))
+            .is_empty());
+        let alerts = rule.check(&block(2, vec![depegged]));
+        let [Alert::Depeg {
+            price: pool_price, ..
+        }] = &alerts[..]
+        else {
+            panic!("unexpected alerts: {:?}", alerts);
+        };
+        assert_eq!(pool_price.round_dp(9), price(980));
+    }
+
+    #[test]
+    fn test_overflowing_prices_are_dropped() {
+        let mut rule = DepegRule::new(
+            price(5),
+            DepegPrice::Pool,
+            DepegWindow::Vwap { blocks: 3 },
+            &crate::networks::Network::Mainnet.pools(),
+        );
+        // a pool price of 10^28 (sqrt(10^28 * 10^-12) * 2^96) times 100 DAI doesn't fit
+        let mut extreme = swap(100, price(1000));
+        extreme.sqrt_price_x96 = (U256::one() << 96) * U256::exp10(8);
+        // DAI/USDC at 1.0: sqrt(10^-12) * 2^96
+        let mut pegged = swap(100, price(1000));
+        pegged.sqrt_price_x96 = U256::from_dec_str("79228162514264337593543").unwrap();
+        assert!(rule.check(&block(1, vec![extreme, pegged])).is_empty());
+
+        // nor does the sum of prices that fit on their own
+        let mut rule = DepegRule::new(
+            price(5),
+            DepegPrice::Swap,
+            DepegWindow::Vwap { blocks: 3 },
+            &[],
+        );
+        let mut big = swap(100, price(1000));
+        big.amount0_as_decimal_num = Decimal::ONE;
+        big.amount1_as_decimal_num = Decimal::MAX / Decimal::from(2) + Decimal::ONE;
+        assert_eq!(rule.check(&block(1, vec![big.clone()])).len(), 1);
+        // block 2 is left out of the window's sum, its depeg stands
+        assert!(rule.check(&block(2, vec![big])).is_empty());
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        blocks: Vec<u64>,
+        alerts: Vec<Alert>,
+    }
+
+    #[async_trait]
+    impl Sink for Recorder {
+        async fn write(&mut self, confirmed_block: &ConfirmedBlock) -> Result<(), anyhow::Error> {
+            self.blocks.push(confirmed_block.block_number);
+            Ok(())
+        }
+
+        async fn write_alert(&mut self, alert: &Alert) -> Result<(), anyhow::Error> {
+            self.alerts.push(alert.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_alerts_are_written_to_the_sinks() {
+        let config = AlertsConfig {
+            depeg_threshold: Some(price(5)),
+            ..Default::default()
+        };
+        let mut sink = AlertingSink::new(Recorder::default(), rules(&config, &[]).unwrap());
+        sink.write(&block(1, vec![swap(100, price(1000))]))
+            .await
+            .unwrap();
+        sink.write(&block(2, vec![swap(100, price(900))]))
+            .await
+            .unwrap();
+
+        let recorder = sink.into_inner();
+        assert_eq!(recorder.blocks, vec![1, 2]);
+        assert!(
+            matches!(
+                &recorder.alerts[..],
+                [Alert::Depeg {
+                    block_number: 2,
+                    ..
+                }]
+            ),
+            "{:?}",
+            recorder.alerts
+        );
+        let json = serde_json::to_value(&recorder.alerts[0]).unwrap();
+        assert_eq!(json["kind"], "depeg");
+        assert_eq!(
+            json["window"],
+            serde_json::json!({"type": "sustained", "blocks": 1})
+        );
+
+        assert!(rules(&AlertsConfig::default(), &[]).unwrap().is_empty());
+    }
+
+    fn swap_by(sender: H160, recipient: H160) -> SwapDetails {
+        SwapDetails {
+            sender,
+            recipient,
+            ..swap(100, price(1000))
+        }
+    }
+
+    #[test]
+    fn test_large_swaps_of_either_token() {
+        let mut rule = LargeSwapRule::new(Decimal::from(1000));
+        let mut usdc_whale = swap(1, price(1000));
+        usdc_whale.amount1_as_decimal_num = Decimal::from(-2000);
+        let alerts = rule.check(&block(
+            1,
+            vec![swap(1000, price(1000)), swap(1001, price(990)), usdc_whale],
+        ));
+
+        let amounts: Vec<(Decimal, Decimal)> = alerts
+            .iter()
+            .map(|alert| match alert {
+                Alert::LargeSwap {
+                    amount0, amount1, ..
+                } => (*amount0, *amount1),
+                alert => panic!("unexpected alert: {:?}", alert),
+            })
+            .collect();
+        assert_eq!(
+            amounts,
+            vec![
+                (Decimal::from(1001), Decimal::new(-99099, 2)),
+                (Decimal::ONE, Decimal::from(-2000))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_watchlist_is_reloaded_once_it_changes() {
+        let dir = std::env::temp_dir().join(format!("watchlist-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("watchlist.txt");
+        let (treasury, bot, other) = (
+            H160::repeat_byte(0x11),
+            H160::repeat_byte(0x22),
+            H160::repeat_byte(0x33),
+        );
+        let write_watchlist = |contents: String, modified: u64| {
+            fs::write(&path, contents).unwrap();
+            let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(modified);
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+        write_watchlist(
+            format!(
+                "# treasuries\n{:?} maker treasury\n\n{:?}  # a bot\n",
+                treasury, bot
+            ),
+            1_000,
+        );
+        let mut rule = WatchlistRule::load(&path).unwrap();
+
+        let alerts = rule.check(&block(
+            1,
+            vec![swap_by(treasury, bot), swap_by(other, other)],
+        ));
+        let watched: Vec<(H160, Option<String>)> = alerts
+            .iter()
+            .map(|alert| match alert {
+                Alert::Watchlist { address, label, .. } => (*address, label.clone()),
+                alert => panic!("unexpected alert: {:?}", alert),
+            })
+            .collect();
+        assert_eq!(
+            watched,
+            vec![(treasury, Some("maker treasury".to_string())), (bot, None)]
+        );
+
+        write_watchlist(format!("{:?}\n", other), 2_000);
+        let alerts = rule.check(&block(2, vec![swap_by(treasury, other)]));
+        assert!(
+            matches!(&alerts[..], [Alert::Watchlist { address, .. }] if *address == other),
+            "{:?}",
+            alerts
+        );
+
+        // a broken file leaves the list as it was
+        write_watchlist("0x1234 too short\n".to_string(), 3_000);
+        assert_eq!(rule.check(&block(3, vec![swap_by(other, bot)])).len(), 1);
+        let err = WatchlistRule::load(&path).err().unwrap();
+        assert!(err.to_string().contains("line 1"), "{}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bursts_of_one_sender() {
+        let (bot, user) = (H160::repeat_byte(0x22), H160::repeat_byte(0x44));
+        let mut rule = BurstRule::new(3, 2);
+        let mut bursts = vec![];
+        let blocks = vec![
+            block(1, vec![swap_by(bot, user), swap_by(user, user)]),
+            block(2, vec![swap_by(bot, user), swap_by(user, user)]),
+            // 3 swaps by the bot in blocks 2 & 3
+            block(3, vec![swap_by(bot, user), swap_by(bot, user)]),
+            // still bursting, no new alert
+            block(4, vec![swap_by(bot, user), swap_by(bot, user)]),
+            block(5, vec![]),
+            block(6, vec![]),
+            block(
+                7,
+                vec![swap_by(bot, user), swap_by(bot, user), swap_by(bot, user)],
+            ),
+        ];
+        for block in &blocks {
+            bursts.extend(rule.check(block).into_iter().map(|alert| match alert {
+                Alert::Burst {
+                    sender,
+                    swaps,
+                    from_block,
+                    block_number,
+                } => (sender, swaps, from_block, block_number),
+                alert => panic!("unexpected alert: {:?}", alert),
+            }));
+        }
+        assert_eq!(bursts, vec![(bot, 3, 2, 3), (bot, 3, 7, 7)]);
+        // senders without swaps in the window are forgotten
+        assert_eq!(rule.senders.keys().collect::<Vec<_>>(), vec![&bot]);
+    }
+}
@@ -0,0 +1,210 @@
+use crate::{
+    blocks_handler::{BlockEvent, ConfirmedBlock, HeadsHandler, Reorg},
+    config::PoolConfig,
+    store::{Store, SwapFilter},
+    swap_details::SwapDirection,
+};
+use async_trait::async_trait;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use log::{error, info};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+};
+use web3::types::{BlockHeader, H160, H256};
+
+/// Most swaps `/swaps` returns at once, narrow the blocks range for the rest
+pub const MAX_SWAPS: usize = 1000;
+
+/// Where the monitor is at, as served by `/status`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Status {
+    pub head: Option<u64>,
+    /// The last confirmed block
+    pub confirmed: Option<u64>,
+    /// Blocks waiting for their confirmation
+    pub tracked: Vec<TrackedBlock>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrackedBlock {
+    pub number: u64,
+    pub hash: H256,
+}
+
+pub type SharedStatus = Arc<Mutex<Status>>;
+
+/// `HeadsHandler` keeping `status` up to date with the heads `inner` handles
+pub struct StatusHeadsHandler<H> {
+    inner: H,
+    status: SharedStatus,
+}
+
+impl<H: HeadsHandler> StatusHeadsHandler<H> {
+    pub fn new(inner: H, status: SharedStatus) -> Self {
+        Self { inner, status }
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<H: HeadsHandler> HeadsHandler for StatusHeadsHandler<H> {
+    async fn handle_head(
+        &mut self,
+        block_header: BlockHeader,
+    ) -> Result<Vec<ConfirmedBlock>, anyhow::Error> {
+        let head = block_header.number.map(|number| number.as_u64());
+        let result = self.inner.handle_head(block_header).await;
+
+        let mut status = self.status.lock().unwrap();
+        status.head = head.or(status.head);
+        if let Ok(confirmed_blocks) = &result {
+            if let Some(last) = confirmed_blocks.last() {
+                status.confirmed = Some(last.block_number);
+            }
+        }
+        status.tracked = self
+            .inner
+            .tracked_window()
+            .into_iter()
+            .map(|(number, hash)| TrackedBlock { number, hash })
+            .collect();
+        result
+    }
+
+    fn take_reorgs(&mut self) -> Vec<Reorg> {
+        self.inner.take_reorgs()
+    }
+
+    fn take_events(&mut self) -> Vec<BlockEvent> {
+        self.inner.take_events()
+    }
+
+    fn tracked_window(&self) -> Vec<(u64, H256)> {
+        self.inner.tracked_window()
+    }
+}
+
+/// Read-only HTTP API over the store & the monitor's status
+#[derive(Clone)]
+pub struct ApiState {
+    store: Arc<Mutex<Store>>,
+    status: SharedStatus,
+    pools: Arc<Vec<PoolConfig>>,
+}
+
+impl ApiState {
+    pub fn new(store: Store, status: SharedStatus, pools: Vec<PoolConfig>) -> Self {
+        Self {
+            store: Arc::new(Mutex::new(store)),
+            status,
+            pools: Arc::new(pools),
+        }
+    }
+
+    /// Serves the API on `listen` in the background, returns the address it's bound to (for port 0)
+    pub fn serve(self, listen: SocketAddr) -> Result<SocketAddr, anyhow::Error> {
+        let listener = TcpListener::bind(listen)
+            .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", listen, e))?;
+        let local_addr = listener.local_addr()?;
+        let app = Router::new()
+            .route("/swaps", get(swaps))
+            .route("/reorgs", get(reorgs))
+            .route("/status", get(status))
+            .route("/pools", get(pools))
+            .with_state(self);
+        let server = axum::Server::from_tcp(listener)?.serve(app.into_make_service());
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("HTTP API failed: {}", err);
+            }
+        });
+        info!("Serving the HTTP API on http://{}", local_addr);
+        Ok(local_addr)
+    }
+
+    /// Runs `query` on a blocking thread, SQLite calls block
+    async fn query<T, F>(&self, query: F) -> Result<T, ApiError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Store) -> Result<T, anyhow::Error> + Send + 'static,
+    {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || query(&store.lock().unwrap()))
+            .await
+            .map_err(anyhow::Error::from)?
+            .map_err(ApiError)
+    }
+}
+
+/// `/swaps` query string, every parameter is optional
+#[derive(Debug, Deserialize)]
+struct SwapsQuery {
+    from_block: Option<u64>,
+    to_block: Option<u64>,
+    /// `DaiToUsdc` or `UsdcToDai`
+    direction: Option<SwapDirection>,
+    min_amount: Option<Decimal>,
+    /// Sender or recipient
+    address: Option<H160>,
+    /// `MAX_SWAPS` at most
+    limit: Option<usize>,
+}
+
+/// Store failures are answered with 500 & `{"error": <message>}`
+struct ApiError(anyhow::Error);
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        Self(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        error!("HTTP API request failed: {:#}", self.0);
+        let body = Json(serde_json::json!({ "error": format!("{:#}", self.0) }));
+        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+    }
+}
+
+async fn swaps(
+    State(state): State<ApiState>,
+    Query(query): Query<SwapsQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let filter = SwapFilter {
+        from_block: query.from_block,
+        to_block: query.to_block,
+        direction: query.direction,
+        min_amount: query.min_amount,
+        address: query.address,
+    };
+    let limit = query.limit.unwrap_or(MAX_SWAPS).min(MAX_SWAPS);
+    let swaps = state
+        .query(move |store| store.query_swaps(&filter, limit))
+        .await?;
+    Ok(Json(swaps))
+}
+
+async fn reorgs(State(state): State<ApiState>) -> Result<impl IntoResponse, ApiError> {
+    Ok(Json(state.query(Store::reorgs).await?))
+}
+
+async fn status(State(state): State<ApiState>) -> impl IntoResponse {
+    Json(state.status.lock().unwrap().clone())
+}
+
+async fn pools(State(state): State<ApiState>) -> impl IntoResponse {
+    Json(state.pools.as_ref().clone())
+}
@@ -1,42 +1,305 @@
-use crate::{events_handler::EventsHandler, web3_client::BlocksFetcher};
+use crate::{
+    checkpoint::Checkpoint, error::MonitorError, events_handler::SwapsFetcher,
+    swap_details::SwapDetails, web3_client::BlocksFetcher,
+};
 use anyhow::bail;
-use log::{debug, info};
-use std::collections::BTreeMap;
-use web3::types::BlockHeader;
+use async_trait::async_trait;
+use futures::{stream, StreamExt, TryStreamExt};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, time::Duration};
+use web3::types::{BlockHeader, H256};
 
 type BlockNumerWithBlockInfo = BTreeMap<u64, BlockHeader>;
 
-pub struct BlocksHandler<T: BlocksFetcher> {
+/// Upper bound on in-flight `eth_getBlockByNumber` requests while re-verifying the tracked window (or catching up)
+const MAX_CONCURRENT_HASH_REQUESTS: usize = 8;
+
+/// What to do when the node can't provide a block (see `MonitorError::is_missing_data`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingDataPolicy {
+    /// Return the error to the caller
+    Fail,
+    /// Log a warning & continue without the missing block
+    Skip,
+    /// Request the block again up to `attempts` times (waiting `delay` in between), then fail.
+    /// Incoming headers can't be requested again, so for those it behaves like `Skip`
+    Retry { attempts: u32, delay: Duration },
+}
+
+impl Default for MissingDataPolicy {
+    fn default() -> Self {
+        MissingDataPolicy::Retry {
+            attempts: 3,
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A block which reached the required confirmations, with the swaps it contains
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfirmedBlock {
+    pub block_number: u64,
+    pub block_hash: H256,
+    /// Unix time the block was mined at, in seconds
+    #[serde(default)]
+    pub timestamp: u64,
+    pub swaps: Vec<SwapDetails>,
+}
+
+/// Same as `confirmed_block` of `tests/common/blocks.rs`, for the unit tests
+#[cfg(test)]
+pub(crate) fn confirmed_block(block_number: u64, hash: u8, swaps: u64) -> ConfirmedBlock {
+    use crate::swap_details::{LogMetadata, SwapDirection};
+    use rust_decimal::Decimal;
+    use web3::types::{H160, U256};
+
+    let block_hash = H256::repeat_byte(hash);
+    let swaps = (0..swaps)
+        .map(|log_index| SwapDetails {
+            metadata: LogMetadata {
+                pool: H160::repeat_byte(0xaa),
+                block_number: Some(block_number),
+                block_hash: Some(block_hash),
+                transaction_hash: Some(H256::repeat_byte(0xbb)),
+                log_index: Some(log_index),
+            },
+            sender: H160::repeat_byte(1),
+            recipient: H160::repeat_byte(2),
+            amount0_raw: U256::from(1_500_000_000_000_000_000_u128),
+            amount0_as_decimal_num: Decimal::new(15, 1),
+            // -1 USDC, two's complement encoded
+            amount1_raw: U256::MAX - U256::from(999_999),
+            amount1_as_decimal_num: Decimal::new(-1, 0),
+            direction: SwapDirection::DaiToUsdc,
+            sqrt_price_x96: U256::zero(),
+            dai_is_token1: false,
+        })
+        .collect();
+    ConfirmedBlock {
+        block_number,
+        block_hash,
+        timestamp: block_number * 12,
+        swaps,
+    }
+}
+
+/// Tracked blocks which were replaced on the canonical chain, as far as the monitor could tell
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reorg {
+    /// The head which revealed the reorg
+    pub detected_at: u64,
+    /// Last block both branches share
+    pub fork_point: u64,
+    /// Number of orphaned blocks
+    pub depth: u64,
+    /// Tracked blocks after the fork point, in order of block numbers
+    pub orphaned_hashes: Vec<H256>,
+    /// Blocks of the new branch after the fork point, up to the head (the ones the node could provide)
+    pub new_hashes: Vec<H256>,
+}
+
+/// Swaps of a block before its confirmation, see `HeadsHandler::take_events`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BlockEvent {
+    /// Swaps of a new block, which a reorg could still drop
+    Provisional(ConfirmedBlock),
+    /// Swaps reported as provisional before, of a block which is no longer on the canonical chain
+    Retracted(ConfirmedBlock),
+}
+
+/// Turns new heads into confirmed blocks, see `monitor_new_heads`
+#[async_trait]
+pub trait HeadsHandler: Send {
+    /// Returns the blocks `block_header` confirmed, in order
+    async fn handle_head(
+        &mut self,
+        block_header: BlockHeader,
+    ) -> Result<Vec<ConfirmedBlock>, anyhow::Error>;
+
+    /// Reorgs detected since the last call, including the one `handle_head` failed with
+    fn take_reorgs(&mut self) -> Vec<Reorg> {
+        vec![]
+    }
+
+    /// Provisional & retracted swaps since the last call, in order. Only reported when the handler was asked to
+    fn take_events(&mut self) -> Vec<BlockEvent> {
+        vec![]
+    }
+
+    /// Numbers & hashes of the blocks waiting for their confirmation, in order
+    fn tracked_window(&self) -> Vec<(u64, H256)> {
+        vec![]
+    }
+}
+
+pub struct BlocksHandler<T: BlocksFetcher, S: SwapsFetcher> {
     block_confirmations: u64,
     blocks_fetcher: T,
+    swaps_fetcher: S,
     previous_blocks: BlockNumerWithBlockInfo,
     starting_block_number: u64,
+    missing_data_policy: MissingDataPolicy,
+    /// Restored from a checkpoint & waiting for the first head, which tells how many blocks were missed
+    resumed: bool,
+    /// Described by `handle_head`, until `take_reorgs`
+    reorgs: Vec<Reorg>,
+    /// See `with_provisional_swaps`
+    provisional_swaps: bool,
+    /// Tracked blocks with their swaps, as reported provisionally
+    provisional: BTreeMap<u64, ConfirmedBlock>,
+    /// Until `take_events`
+    events: Vec<BlockEvent>,
 }
 
-impl<T: BlocksFetcher> BlocksHandler<T> {
-    pub fn new(block_confirmations: u64, blocks_fetcher: T) -> Result<Self, anyhow::Error> {
+impl<T: BlocksFetcher, S: SwapsFetcher> BlocksHandler<T, S> {
+    pub fn new(
+        block_confirmations: u64,
+        blocks_fetcher: T,
+        swaps_fetcher: S,
+    ) -> Result<Self, anyhow::Error> {
         Ok(Self {
             block_confirmations,
             blocks_fetcher,
+            swaps_fetcher,
             previous_blocks: BTreeMap::new(),
             starting_block_number: 0,
+            missing_data_policy: MissingDataPolicy::default(),
+            resumed: false,
+            reorgs: vec![],
+            provisional_swaps: false,
+            provisional: BTreeMap::new(),
+            events: vec![],
         })
     }
 
-    pub async fn handle_block(&mut self, block_header: BlockHeader) -> Result<(), anyhow::Error> {
-        let block_number = block_header.number.unwrap().as_u64();
+    pub fn with_missing_data_policy(mut self, missing_data_policy: MissingDataPolicy) -> Self {
+        self.missing_data_policy = missing_data_policy;
+        self
+    }
+
+    /// Fetches the swaps of every new block right away (a request per head) & reports them as `BlockEvent::Provisional`,
+    /// then as `BlockEvent::Retracted` when a reorg orphans the block. The confirmation reuses them
+    pub fn with_provisional_swaps(mut self) -> Self {
+        self.provisional_swaps = true;
+        self
+    }
+
+    /// Continues from the tracked blocks of a previous run. The blocks mined since then are fetched with the next head
+    pub fn with_checkpoint(mut self, checkpoint: Checkpoint) -> Self {
+        // nothing was tracked yet
+        if checkpoint.starting_block_number == 0 {
+            return self;
+        }
+        self.starting_block_number = checkpoint.starting_block_number;
+        self.previous_blocks = checkpoint
+            .blocks
+            .into_iter()
+            .filter_map(|header| Some((header.number?.as_u64(), header)))
+            .collect();
+        self.resumed = true;
+        self
+    }
+
+    /// The tracked blocks, to be restored with `with_checkpoint`
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            starting_block_number: self.starting_block_number,
+            blocks: self.previous_blocks.values().cloned().collect(),
+        }
+    }
+
+    /// Tracks `block_header` & returns the blocks it confirmed (usually one, none while the window fills up).
+    /// After `with_checkpoint`, the blocks between the restored ones & the first head are tracked first
+    pub async fn handle_block(
+        &mut self,
+        block_header: BlockHeader,
+    ) -> Result<Vec<ConfirmedBlock>, anyhow::Error> {
+        let mut confirmed_blocks = vec![];
+        if std::mem::take(&mut self.resumed) {
+            for missed_header in self.fetch_missed_headers(&block_header).await? {
+                confirmed_blocks.extend(self.track_block(missed_header).await?);
+            }
+        }
+        confirmed_blocks.extend(self.track_block(block_header).await?);
+        Ok(confirmed_blocks)
+    }
+
+    /// Headers of the blocks after the last tracked one, up to the parent of `block_header`
+    async fn fetch_missed_headers(
+        &self,
+        block_header: &BlockHeader,
+    ) -> Result<Vec<BlockHeader>, anyhow::Error> {
+        let Some(number) = block_header.number else {
+            return Ok(vec![]);
+        };
+        // none are tracked after a reorg orphaned all of them
+        let first_missed = self
+            .previous_blocks
+            .keys()
+            .last()
+            .map_or(self.starting_block_number, |last_tracked| last_tracked + 1);
+        let missed = first_missed..number.as_u64();
+        if missed.is_empty() {
+            return Ok(vec![]);
+        }
         info!(
-            "current block: {}, hash: {:?}",
-            block_number,
-            block_header.hash.unwrap()
+            "catching up on blocks: {} - {}",
+            missed.start,
+            missed.end - 1
         );
+        stream::iter(missed)
+            .map(|block_num| self.blocks_fetcher.get_block_header(block_num))
+            .buffered(MAX_CONCURRENT_HASH_REQUESTS)
+            .try_collect()
+            .await
+    }
+
+    async fn track_block(
+        &mut self,
+        block_header: BlockHeader,
+    ) -> Result<Vec<ConfirmedBlock>, anyhow::Error> {
+        let (block_number, block_hash) = match (block_header.number, block_header.hash) {
+            (Some(number), Some(hash)) => (number.as_u64(), hash),
+            (number, _) => {
+                let err = MonitorError::PendingBlock(number.map(|n| n.as_u64()));
+                if self.missing_data_policy == MissingDataPolicy::Fail {
+                    return Err(err.into());
+                }
+                warn!("skipping block: {}", err);
+                return Ok(vec![]);
+            }
+        };
+        info!("current block: {}, hash: {:?}", block_number, block_hash);
 
         // first block (e.g., 21836327)
         if self.starting_block_number == 0 {
             self.starting_block_number = block_number;
 
             self.previous_blocks.insert(block_number, block_header);
-            return Ok(());
+            self.report_provisional(block_number, block_hash).await?;
+            return Ok(vec![]);
+        }
+
+        if block_number < self.starting_block_number {
+            return Err(MonitorError::DeepReorg {
+                block_number,
+                oldest_tracked: self.starting_block_number,
+            }
+            .into());
+        }
+
+        // a competing block for a height we already track (e.g., 2 heads at the same height)
+        if let Some(tracked_block) = self.previous_blocks.get(&block_number) {
+            let tracked_hash = Self::tracked_block_hash(Some(tracked_block))?;
+            if tracked_hash != block_hash {
+                return Err(MonitorError::ReorgDetected {
+                    block_number,
+                    previous_hash: tracked_hash,
+                    new_hash: block_hash,
+                }
+                .into());
+            }
         }
 
         // `?` will cause return early in case of mismatch
@@ -55,37 +318,93 @@ impl<T: BlocksFetcher> BlocksHandler<T> {
         }
 
         self.previous_blocks.insert(block_number, block_header);
+        self.report_provisional(block_number, block_hash).await?;
 
         // cleanup, remove block when we reach N+5 confirmations
-        if diff == self.block_confirmations {
-            let starting_block = self
-                .previous_blocks
-                .get(&self.starting_block_number)
-                .cloned();
-            let starting_block_hash = starting_block.unwrap().hash.unwrap();
-            debug!(
-                "✅ N+5 condition met. Fetching events for block: {} with hash: {:?}",
-                self.starting_block_number, starting_block_hash
-            );
+        // (it's a loop, since a skipped head could make us jump over the exact N+5 block)
+        let mut confirmed_blocks = vec![];
+        while block_number - self.starting_block_number >= self.block_confirmations {
             let target_block = self.starting_block_number;
-            // but first show relevant events
-            let events_handler = EventsHandler::new(self.blocks_fetcher.web3())?;
-            let swap_info = events_handler.handle_events(starting_block_hash).await?;
-            if swap_info.is_empty() {
-                debug!("events not found");
+            if let Some(starting_block) = self.previous_blocks.remove(&target_block) {
+                let starting_block_hash = Self::tracked_block_hash(Some(&starting_block))?;
+                debug!(
+                    "✅ N+5 condition met. Fetching events for block: {} with hash: {:?}",
+                    target_block, starting_block_hash
+                );
+                // but first show relevant events
+                let swap_info = match self.provisional.remove(&target_block) {
+                    Some(provisional) if provisional.block_hash == starting_block_hash => {
+                        provisional.swaps
+                    }
+                    _ => self.swaps_fetcher.fetch_swaps(starting_block_hash).await?,
+                };
+                if swap_info.is_empty() {
+                    debug!("events not found");
+                } else {
+                    debug!("swap info: {:#?}", swap_info);
+                }
+                confirmed_blocks.push(ConfirmedBlock {
+                    block_number: target_block,
+                    block_hash: starting_block_hash,
+                    timestamp: starting_block.timestamp.low_u64(),
+                    swaps: swap_info,
+                });
             } else {
-                info!("swap info: {:#?}", swap_info);
+                warn!("block: {} was skipped, no events to fetch", target_block);
             }
 
-            self.previous_blocks.remove(&target_block);
             debug!("⛔ block: {} untracked", target_block);
             self.starting_block_number += 1;
             debug!("-----------------------");
         }
 
+        Ok(confirmed_blocks)
+    }
+
+    /// Fetches the swaps of the new block, see `with_provisional_swaps`
+    async fn report_provisional(
+        &mut self,
+        block_number: u64,
+        block_hash: H256,
+    ) -> Result<(), anyhow::Error> {
+        let reported = self
+            .provisional
+            .get(&block_number)
+            .is_some_and(|block| block.block_hash == block_hash);
+        // e.g., the same head arriving twice
+        if !self.provisional_swaps || reported {
+            return Ok(());
+        }
+        let timestamp = self
+            .previous_blocks
+            .get(&block_number)
+            .map_or(0, |header| header.timestamp.low_u64());
+        let block = ConfirmedBlock {
+            block_number,
+            block_hash,
+            timestamp,
+            swaps: self.swaps_fetcher.fetch_swaps(block_hash).await?,
+        };
+        self.events.push(BlockEvent::Provisional(block.clone()));
+        self.provisional.insert(block_number, block);
         Ok(())
     }
 
+    /// Retracts the provisional swaps of the blocks `reorg` orphaned
+    fn retract_orphaned(&mut self, reorg: &Reorg) {
+        let orphaned: Vec<u64> = self
+            .provisional
+            .iter()
+            .filter(|(_, block)| reorg.orphaned_hashes.contains(&block.block_hash))
+            .map(|(number, _)| *number)
+            .collect();
+        for number in orphaned {
+            if let Some(block) = self.provisional.remove(&number) {
+                self.events.push(BlockEvent::Retracted(block));
+            }
+        }
+    }
+
     fn match_parent_hash(
         &mut self,
         previous_block_number: u64,
@@ -94,14 +413,14 @@ impl<T: BlocksFetcher> BlocksHandler<T> {
         let may_be_previous_block_header =
             self.previous_blocks.get(&previous_block_number).cloned();
         if let Some(previous_block_header) = may_be_previous_block_header {
-            let previous_block_hash = previous_block_header.hash.unwrap();
+            let previous_block_hash = Self::tracked_block_hash(Some(&previous_block_header))?;
             if block_header.parent_hash != previous_block_hash {
-                // (:?) is needed for H256 type to properly display the full hex representation of the hash
-                bail!(
-                    "parent_hash mismatch. Previous: {:?}, current: {:?}",
-                    previous_block_hash,
-                    block_header.parent_hash
-                );
+                return Err(MonitorError::ParentHashMismatch {
+                    block_number: previous_block_number,
+                    previous: previous_block_hash,
+                    current: block_header.parent_hash,
+                }
+                .into());
             }
             debug!(
                 "√ parent hash matched for previous block: {}",
@@ -123,7 +442,9 @@ impl<T: BlocksFetcher> BlocksHandler<T> {
         (start, end)
     }
 
-    /// Compares the hashes of freshly fetched blocks
+    /// Compares the hashes of freshly fetched blocks.
+    /// All hashes of the range are requested concurrently (so the whole window is verified in a single round trip),
+    /// but compared in order, so the reported reorg is always the lowest mismatching block.
     /// # Arguments
     /// * start - starting block num
     /// * end - ending block num
@@ -133,37 +454,209 @@ impl<T: BlocksFetcher> BlocksHandler<T> {
         end: u64,
     ) -> Result<(), anyhow::Error> {
         debug!("start: {}, end: {}", start, end);
-        for block_num in start..=end {
-            let new_hash = self.blocks_fetcher.get_block_hash(block_num).await?;
-            let stored_block = self.previous_blocks.get(&block_num).cloned().unwrap();
-            let previous_hash = stored_block.hash.unwrap();
+        let this = &*self;
+        // blocks skipped earlier (see `MissingDataPolicy`) have nothing to compare against
+        let tracked_blocks =
+            (start..=end).filter(|block_num| this.previous_blocks.contains_key(block_num));
+        let new_hashes: Vec<(u64, Option<H256>)> = stream::iter(tracked_blocks)
+            .map(|block_num| async move {
+                let new_hash = this.fetch_block_hash(block_num).await?;
+                Ok::<_, anyhow::Error>((block_num, new_hash))
+            })
+            // `buffered` (unlike `buffer_unordered`) keeps the results in the order of block numbers
+            .buffered(MAX_CONCURRENT_HASH_REQUESTS)
+            .try_collect()
+            .await?;
+
+        for (block_num, new_hash) in new_hashes {
+            let Some(new_hash) = new_hash else {
+                warn!("block: {} skipped from hash verification", block_num);
+                continue;
+            };
+            let previous_hash = Self::tracked_block_hash(self.previous_blocks.get(&block_num))?;
             if new_hash != previous_hash {
-                // {:?} will show FULL hash in error message, otherwise, something like `0x69d5…cc0b`
-                bail!(
-                    "Reorg detected at block {}. Previous hash: {:?}, New hash: {:?}",
-                    block_num,
+                return Err(MonitorError::ReorgDetected {
+                    block_number: block_num,
                     previous_hash,
-                    new_hash
-                );
+                    new_hash,
+                }
+                .into());
             }
             debug!("new hash matched for block: {}", block_num);
         }
         Ok(())
     }
+
+    /// Fetches the block hash, applying `missing_data_policy` when the node can't provide it.
+    /// `None` means the block was skipped
+    async fn fetch_block_hash(&self, block_number: u64) -> Result<Option<H256>, anyhow::Error> {
+        let mut attempt = 0;
+        loop {
+            let err = match self.blocks_fetcher.get_block_hash(block_number).await {
+                Ok(hash) => return Ok(Some(hash)),
+                Err(err) => err,
+            };
+            let is_missing_data = err
+                .downcast_ref::<MonitorError>()
+                .is_some_and(MonitorError::is_missing_data);
+            if !is_missing_data {
+                return Err(err);
+            }
+
+            match self.missing_data_policy {
+                MissingDataPolicy::Fail => return Err(err),
+                MissingDataPolicy::Skip => {
+                    warn!("{}", err);
+                    return Ok(None);
+                }
+                MissingDataPolicy::Retry { attempts, delay } => {
+                    if attempt >= attempts {
+                        return Err(err);
+                    }
+                    attempt += 1;
+                    warn!("{}, retrying ({}/{})", err, attempt, attempts);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    /// Compares the tracked window against the canonical chain after `err` (returned for `block_header`).
+    /// `None` for errors which aren't about a reorg
+    pub async fn describe_reorg(
+        &self,
+        block_header: &BlockHeader,
+        err: &MonitorError,
+    ) -> Option<Reorg> {
+        let (head_number, head_hash) = (block_header.number?.as_u64(), block_header.hash?);
+        let lowest_mismatch = match err {
+            MonitorError::ReorgDetected { block_number, .. }
+            | MonitorError::ParentHashMismatch { block_number, .. }
+            | MonitorError::DeepReorg { block_number, .. } => *block_number,
+            _ => return None,
+        };
+        let first = lowest_mismatch.min(self.starting_block_number);
+        let last = self
+            .previous_blocks
+            .keys()
+            .last()
+            .map_or(head_number, |last| (*last).max(head_number));
+        // blocks the node can't provide (anymore) aren't canonical, so the error is as good as a different hash
+        let canonical: BTreeMap<u64, H256> = stream::iter(first..=last)
+            .map(|block_num| async move {
+                let hash = self.blocks_fetcher.get_block_hash(block_num).await.ok();
+                (block_num, hash)
+            })
+            .buffered(MAX_CONCURRENT_HASH_REQUESTS)
+            .filter_map(|(block_num, hash)| async move { Some((block_num, hash?)) })
+            .collect()
+            .await;
+        let is_orphaned = |block_num: &u64, header: &BlockHeader| {
+            canonical.get(block_num).copied() != header.hash
+        };
+
+        let lowest_orphaned = self
+            .previous_blocks
+            .iter()
+            .find(|(block_num, header)| is_orphaned(block_num, header))
+            .map_or(lowest_mismatch, |(block_num, _)| *block_num);
+        let fork_point = lowest_mismatch.min(lowest_orphaned).saturating_sub(1);
+        let orphaned_hashes: Vec<H256> = self
+            .previous_blocks
+            .range(fork_point + 1..)
+            .filter(|(block_num, header)| is_orphaned(block_num, header))
+            .filter_map(|(_, header)| header.hash)
+            .collect();
+        let new_hashes = canonical
+            .range(fork_point + 1..head_number)
+            .map(|(_, hash)| *hash)
+            .chain([head_hash])
+            .collect();
+        Some(Reorg {
+            detected_at: head_number,
+            fork_point,
+            depth: orphaned_hashes.len() as u64,
+            orphaned_hashes,
+            new_hashes,
+        })
+    }
+
+    /// Only blocks with a hash get tracked, so a missing one means the block isn't tracked at all
+    fn tracked_block_hash(block_header: Option<&BlockHeader>) -> Result<H256, anyhow::Error> {
+        match block_header {
+            Some(BlockHeader {
+                hash: Some(hash), ..
+            }) => Ok(*hash),
+            Some(block_header) => {
+                Err(MonitorError::PendingBlock(block_header.number.map(|n| n.as_u64())).into())
+            }
+            None => bail!("Block is not tracked"),
+        }
+    }
+}
+
+#[async_trait]
+impl<T, S> HeadsHandler for BlocksHandler<T, S>
+where
+    T: BlocksFetcher + Send + Sync,
+    S: SwapsFetcher + Send + Sync,
+{
+    async fn handle_head(
+        &mut self,
+        block_header: BlockHeader,
+    ) -> Result<Vec<ConfirmedBlock>, anyhow::Error> {
+        let err = match self.handle_block(block_header.clone()).await {
+            Ok(confirmed_blocks) => return Ok(confirmed_blocks),
+            Err(err) => err,
+        };
+        if let Some(monitor_err) = err.downcast_ref::<MonitorError>() {
+            if let Some(reorg) = self.describe_reorg(&block_header, monitor_err).await {
+                warn!(
+                    "reorg of {} blocks after block: {}",
+                    reorg.depth, reorg.fork_point
+                );
+                self.retract_orphaned(&reorg);
+                // so a checkpoint saved on the way out resumes from the fork point, on the new branch
+                self.previous_blocks
+                    .retain(|block_num, _| *block_num <= reorg.fork_point);
+                self.reorgs.push(reorg);
+            }
+        }
+        Err(err)
+    }
+
+    fn take_reorgs(&mut self) -> Vec<Reorg> {
+        std::mem::take(&mut self.reorgs)
+    }
+
+    fn take_events(&mut self) -> Vec<BlockEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn tracked_window(&self) -> Vec<(u64, H256)> {
+        self.previous_blocks
+            .iter()
+            .filter_map(|(number, header)| Some((*number, header.hash?)))
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
-        setup_web3,
+        cassette::{replay_web3, CassetteTransport},
+        events_handler::{EventsHandler, MockSwapsFetcher},
+        events_source::Web3EventsSource,
         web3_client::{MockBlocksFetcher, Web3BlocksFetcher},
         BLOCK_CONFIRMATIONS,
     };
     use mockall::predicate::eq;
     use std::str::FromStr;
-    use tokio::fs;
-    use web3::types::H256;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use tokio::{fs, sync::Barrier};
+    use web3::transports::WebSocket;
 
     /// Check README.md on how to load fixtures
     async fn load_fixtures() -> Vec<BlockHeader> {
@@ -173,10 +666,17 @@ mod tests {
         serde_json::from_str(&json).expect("Failed to parse blocks")
     }
 
-    async fn get_blocks_handler() -> BlocksHandler<Web3BlocksFetcher> {
-        let web3 = setup_web3().await.unwrap();
+    type ReplayTransport = CassetteTransport<WebSocket>;
+
+    /// Real fetchers, but served from the recorded cassette, so no node is needed
+    async fn get_blocks_handler() -> BlocksHandler<
+        Web3BlocksFetcher<ReplayTransport>,
+        EventsHandler<Web3EventsSource<ReplayTransport>>,
+    > {
+        let web3 = replay_web3("tests/fixtures/cassettes/mainnet.json").unwrap();
+        let events_handler = EventsHandler::new(Web3EventsSource { web3: web3.clone() }).unwrap();
         let blocks_fetcher = Web3BlocksFetcher { web3 };
-        BlocksHandler::new(BLOCK_CONFIRMATIONS, blocks_fetcher).unwrap()
+        BlocksHandler::new(BLOCK_CONFIRMATIONS, blocks_fetcher, events_handler).unwrap()
     }
 
     #[tokio::test]
@@ -288,7 +788,8 @@ mod tests {
                     ),
             );
 
-        let mut blocks_handler = BlocksHandler::new(BLOCK_CONFIRMATIONS, mock_fetcher).unwrap();
+        let mut blocks_handler =
+            BlocksHandler::new(BLOCK_CONFIRMATIONS, mock_fetcher, MockSwapsFetcher::new()).unwrap();
         blocks_handler
             .handle_block(headers[0].clone())
             .await
@@ -338,7 +839,8 @@ mod tests {
             .times(1) // will be called only ONCE for fourth block (21836330)
             .returning(move |_| Box::pin(async move { Ok(second_block_modified_hash) }));
 
-        let mut blocks_handler = BlocksHandler::new(BLOCK_CONFIRMATIONS, mock_fetcher).unwrap();
+        let mut blocks_handler =
+            BlocksHandler::new(BLOCK_CONFIRMATIONS, mock_fetcher, MockSwapsFetcher::new()).unwrap();
         blocks_handler
             .handle_block(headers[0].clone())
             .await
@@ -361,4 +863,198 @@ mod tests {
         );
         assert_eq!(err_string, msg);
     }
+
+    #[tokio::test]
+    async fn test_match_previous_blocks_hashes_fetches_window_concurrently() {
+        let headers = load_fixtures().await;
+        let window = &headers[..4];
+
+        // every request waits until all 4 of them are in flight, so sequential fetching would never finish
+        let barrier = Arc::new(Barrier::new(window.len()));
+        let mut mock_fetcher = MockBlocksFetcher::new();
+        for header in window {
+            let block_number = header.number.unwrap().as_u64();
+            let block_hash = header.hash.unwrap();
+            let barrier = barrier.clone();
+            mock_fetcher
+                .expect_get_block_hash()
+                .with(eq(block_number))
+                .times(1)
+                .returning(move |_| {
+                    let barrier = barrier.clone();
+                    Box::pin(async move {
+                        barrier.wait().await;
+                        Ok(block_hash)
+                    })
+                });
+        }
+
+        let mut blocks_handler =
+            BlocksHandler::new(BLOCK_CONFIRMATIONS, mock_fetcher, MockSwapsFetcher::new()).unwrap();
+        for header in window {
+            blocks_handler
+                .previous_blocks
+                .insert(header.number.unwrap().as_u64(), header.clone());
+        }
+
+        let start = window[0].number.unwrap().as_u64();
+        let end = window[3].number.unwrap().as_u64();
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            blocks_handler.match_previous_blocks_hashes(start, end),
+        )
+        .await
+        .expect("block hashes should be fetched concurrently");
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pending_block_is_handled_according_to_policy() {
+        let headers = load_fixtures().await;
+        let mut pending_header = headers[0].clone();
+        pending_header.hash = None;
+
+        let mut blocks_handler = BlocksHandler::new(
+            BLOCK_CONFIRMATIONS,
+            MockBlocksFetcher::new(),
+            MockSwapsFetcher::new(),
+        )
+        .unwrap()
+        .with_missing_data_policy(MissingDataPolicy::Skip);
+        blocks_handler
+            .handle_block(pending_header.clone())
+            .await
+            .unwrap();
+        assert!(blocks_handler.previous_blocks.is_empty());
+
+        let mut blocks_handler = BlocksHandler::new(
+            BLOCK_CONFIRMATIONS,
+            MockBlocksFetcher::new(),
+            MockSwapsFetcher::new(),
+        )
+        .unwrap()
+        .with_missing_data_policy(MissingDataPolicy::Fail);
+        let err = blocks_handler
+            .handle_block(pending_header)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MonitorError>(),
+            Some(MonitorError::PendingBlock(Some(21836327)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_block_not_found_is_retried() {
+        let headers = load_fixtures().await;
+        let first_block_number = headers[0].number.unwrap().as_u64();
+        let first_block_hash = headers[0].hash.unwrap();
+
+        // node is lagging, so the block shows up only on the 3rd request
+        let calls = Arc::new(AtomicU32::new(0));
+        let mut mock_fetcher = MockBlocksFetcher::new();
+        let counter = calls.clone();
+        mock_fetcher
+            .expect_get_block_hash()
+            .with(eq(first_block_number))
+            .times(3)
+            .returning(move |block_number| {
+                let attempt = counter.fetch_add(1, Ordering::SeqCst);
+                Box::pin(async move {
+                    if attempt < 2 {
+                        Err(MonitorError::BlockNotFound(block_number).into())
+                    } else {
+                        Ok(first_block_hash)
+                    }
+                })
+            });
+
+        let mut blocks_handler =
+            BlocksHandler::new(BLOCK_CONFIRMATIONS, mock_fetcher, MockSwapsFetcher::new())
+                .unwrap()
+                .with_missing_data_policy(MissingDataPolicy::Retry {
+                    attempts: 2,
+                    delay: Duration::ZERO,
+                });
+        for header in headers.iter().take(3) {
+            blocks_handler.handle_block(header.clone()).await.unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_block_not_found_fails_once_retries_are_exhausted() {
+        let headers = load_fixtures().await;
+        let first_block_number = headers[0].number.unwrap().as_u64();
+
+        let mut mock_fetcher = MockBlocksFetcher::new();
+        mock_fetcher
+            .expect_get_block_hash()
+            .with(eq(first_block_number))
+            .times(2) // first attempt + 1 retry
+            .returning(|block_number| {
+                Box::pin(async move { Err(MonitorError::BlockNotFound(block_number).into()) })
+            });
+
+        let mut blocks_handler =
+            BlocksHandler::new(BLOCK_CONFIRMATIONS, mock_fetcher, MockSwapsFetcher::new())
+                .unwrap()
+                .with_missing_data_policy(MissingDataPolicy::Retry {
+                    attempts: 1,
+                    delay: Duration::ZERO,
+                });
+        blocks_handler
+            .handle_block(headers[0].clone())
+            .await
+            .unwrap();
+        blocks_handler
+            .handle_block(headers[1].clone())
+            .await
+            .unwrap();
+
+        let err = blocks_handler
+            .handle_block(headers[2].clone())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Block {} not found", first_block_number)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_swaps_fetched_once_block_is_confirmed() {
+        let headers = load_fixtures().await;
+
+        let mut mock_fetcher = MockBlocksFetcher::new();
+        for header in headers.iter() {
+            let block_hash = header.hash.unwrap();
+            mock_fetcher
+                .expect_get_block_hash()
+                .with(eq(header.number.unwrap().as_u64()))
+                .returning(move |_| Box::pin(async move { Ok(block_hash) }));
+        }
+
+        // 6 blocks are needed to confirm the first one, so only it's swaps are fetched
+        let mut swaps_fetcher = MockSwapsFetcher::new();
+        swaps_fetcher
+            .expect_fetch_swaps()
+            .with(eq(headers[0].hash.unwrap()))
+            .times(1)
+            .returning(|_| Box::pin(async { Ok(vec![]) }));
+
+        let mut blocks_handler =
+            BlocksHandler::new(BLOCK_CONFIRMATIONS, mock_fetcher, swaps_fetcher).unwrap();
+        let mut confirmed_blocks = vec![];
+        for header in headers.iter().take(BLOCK_CONFIRMATIONS as usize + 1) {
+            confirmed_blocks.extend(blocks_handler.handle_block(header.clone()).await.unwrap());
+        }
+        assert_eq!(confirmed_blocks.len(), 1);
+        assert_eq!(confirmed_blocks[0].block_hash, headers[0].hash.unwrap());
+        assert_eq!(confirmed_blocks[0].timestamp, headers[0].timestamp.as_u64());
+        assert_eq!(
+            blocks_handler.starting_block_number,
+            headers[1].number.unwrap().as_u64()
+        );
+    }
 }
@@ -0,0 +1,495 @@
+use crate::{
+    blocks_handler::{ConfirmedBlock, Reorg},
+    sinks::Sink,
+    swap_details::SwapDetails,
+};
+use async_trait::async_trait;
+use log::{debug, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, ErrorKind, Write},
+    path::Path,
+};
+use web3::types::{H160, H256};
+
+/// Length of a candle, `1m`, `5m`, `1h` or `1d` in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Interval {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl Interval {
+    pub const ALL: [Interval; 4] = [
+        Interval::OneMinute,
+        Interval::FiveMinutes,
+        Interval::OneHour,
+        Interval::OneDay,
+    ];
+
+    pub fn seconds(self) -> u64 {
+        match self {
+            Interval::OneMinute => 60,
+            Interval::FiveMinutes => 5 * 60,
+            Interval::OneHour => 60 * 60,
+            Interval::OneDay => 24 * 60 * 60,
+        }
+    }
+
+    /// Start of the candle `timestamp` falls into (UTC aligned)
+    pub fn start_of(self, timestamp: u64) -> u64 {
+        timestamp - timestamp % self.seconds()
+    }
+}
+
+/// Open, high, low & close prices (USDC per DAI) & volume of a pool's swaps in `interval` from `start`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Candle {
+    pub pool: H160,
+    pub interval: Interval,
+    /// Unix time the candle starts at, in seconds
+    pub start: u64,
+    /// Prices are none while no swap had a price (no swaps, e.g., after a reorg dropped them)
+    pub open: Option<Decimal>,
+    pub high: Option<Decimal>,
+    pub low: Option<Decimal>,
+    pub close: Option<Decimal>,
+    /// Token0 swapped, in either direction
+    pub volume0: Decimal,
+    /// Token1 swapped, in either direction
+    pub volume1: Decimal,
+    pub swaps: u64,
+    /// Newest block the candle includes
+    pub last_block: u64,
+}
+
+impl Candle {
+    fn empty(pool: H160, interval: Interval, start: u64) -> Self {
+        Self {
+            pool,
+            interval,
+            start,
+            open: None,
+            high: None,
+            low: None,
+            close: None,
+            volume0: Decimal::ZERO,
+            volume1: Decimal::ZERO,
+            swaps: 0,
+            last_block: 0,
+        }
+    }
+
+    fn key(&self) -> CandleKey {
+        (self.pool, self.interval, self.start)
+    }
+
+    fn add(&mut self, block_number: u64, swap: &SwapDetails) {
+        if let Some(price) = swap.price() {
+            self.open = self.open.or(Some(price));
+            self.high = self.high.max(Some(price));
+            self.low = Some(self.low.map_or(price, |low| low.min(price)));
+            self.close = Some(price);
+        }
+        self.volume0 += swap.amount0_as_decimal_num.abs();
+        self.volume1 += swap.amount1_as_decimal_num.abs();
+        self.swaps += 1;
+        self.last_block = self.last_block.max(block_number);
+    }
+}
+
+type CandleKey = (H160, Interval, u64);
+
+/// Builds candles from confirmed blocks. The blocks of the open candles are kept,
+/// so the candles of blocks a reorg orphaned are recomputed without them
+pub struct CandleAggregator {
+    intervals: Vec<Interval>,
+    /// Blocks with swaps, back to the start of the longest interval's open candle
+    blocks: BTreeMap<u64, ConfirmedBlock>,
+    /// Candles of earlier runs, the swaps they include are gone
+    restored: HashMap<CandleKey, Candle>,
+}
+
+impl CandleAggregator {
+    pub fn new(intervals: Vec<Interval>) -> Self {
+        Self {
+            intervals,
+            blocks: BTreeMap::new(),
+            restored: HashMap::new(),
+        }
+    }
+
+    /// Continues `candle` (e.g., read back from the output of an earlier run), replacing an earlier state of it
+    pub fn restore(&mut self, candle: Candle) {
+        self.restored.insert(candle.key(), candle);
+    }
+
+    /// Adds the swaps of `block`, returns the candles they changed. A block added before changes nothing
+    pub fn add_block(&mut self, block: &ConfirmedBlock) -> Vec<Candle> {
+        self.prune(block.timestamp);
+        if block.swaps.is_empty() || self.blocks.contains_key(&block.block_number) {
+            return vec![];
+        }
+        let keys: BTreeSet<CandleKey> = self
+            .keys(block)
+            .filter(|key| {
+                self.restored
+                    .get(key)
+                    .is_none_or(|restored| restored.last_block < block.block_number)
+            })
+            .collect();
+        self.blocks.insert(block.block_number, block.clone());
+        keys.into_iter().map(|key| self.candle(key)).collect()
+    }
+
+    /// Drops the blocks of `orphaned_hashes`, returns their candles recomputed without them
+    pub fn remove_blocks(&mut self, orphaned_hashes: &[H256]) -> Vec<Candle> {
+        let orphaned: Vec<u64> = self
+            .blocks
+            .values()
+            .filter(|block| orphaned_hashes.contains(&block.block_hash))
+            .map(|block| block.block_number)
+            .collect();
+        let mut keys = BTreeSet::new();
+        for number in orphaned {
+            if let Some(block) = self.blocks.remove(&number) {
+                debug!("recomputing the candles of orphaned block: {}", number);
+                keys.extend(self.keys(&block));
+            }
+        }
+        keys.into_iter().map(|key| self.candle(key)).collect()
+    }
+
+    /// Candles the swaps of `block` fall into
+    fn keys<'a>(&'a self, block: &'a ConfirmedBlock) -> impl Iterator<Item = CandleKey> + 'a {
+        let pools: BTreeSet<H160> = block.swaps.iter().map(|swap| swap.metadata.pool).collect();
+        pools.into_iter().flat_map(move |pool| {
+            self.intervals
+                .iter()
+                .map(move |interval| (pool, *interval, interval.start_of(block.timestamp)))
+        })
+    }
+
+    /// The restored state of the candle, with the kept blocks after it added in chain order
+    fn candle(&self, (pool, interval, start): CandleKey) -> Candle {
+        let mut candle = self
+            .restored
+            .get(&(pool, interval, start))
+            .cloned()
+            .unwrap_or_else(|| Candle::empty(pool, interval, start));
+        let included_up_to = candle.last_block;
+        let blocks = self
+            .blocks
+            .range(included_up_to + 1..)
+            .map(|(_, block)| block);
+        for block in blocks.filter(|block| interval.start_of(block.timestamp) == start) {
+            for swap in block.swaps.iter().filter(|swap| swap.metadata.pool == pool) {
+                candle.add(block.block_number, swap);
+            }
+        }
+        candle
+    }
+
+    /// Forgets what's older than the open candle of the longest interval at `timestamp`
+    fn prune(&mut self, timestamp: u64) {
+        let Some(longest) = self
+            .intervals
+            .iter()
+            .max_by_key(|interval| interval.seconds())
+        else {
+            return;
+        };
+        let cutoff = longest.start_of(timestamp);
+        self.blocks.retain(|_, block| block.timestamp >= cutoff);
+        self.restored
+            .retain(|(_, interval, start), _| start + interval.seconds() > cutoff);
+    }
+}
+
+/// Appends a JSON line to `path` for every candle a confirmed block or a reorg changed,
+/// the last line of a candle (`pool`, `interval` & `start`) is its current state
+pub struct CandleSink {
+    aggregator: CandleAggregator,
+    out: BufWriter<File>,
+}
+
+impl CandleSink {
+    /// Continues the candles already in the file at `path`
+    pub fn file(path: impl AsRef<Path>, intervals: Vec<Interval>) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let mut aggregator = CandleAggregator::new(intervals);
+        match fs::read_to_string(path) {
+            Ok(lines) => {
+                for line in lines.lines().filter(|line| !line.trim().is_empty()) {
+                    match serde_json::from_str(line) {
+                        Ok(candle) => aggregator.restore(candle),
+                        // e.g., a line cut short by a crash
+                        Err(err) => warn!("skipping a candle of {}: {}", path.display(), err),
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => anyhow::bail!("Failed to read {}: {}", path.display(), err),
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("Failed to open {}: {}", path.display(), e))?;
+        Ok(Self {
+            aggregator,
+            out: BufWriter::new(file),
+        })
+    }
+
+    fn write_candles(&mut self, candles: &[Candle]) -> Result<(), anyhow::Error> {
+        for candle in candles {
+            writeln!(self.out, "{}", serde_json::to_string(candle)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for CandleSink {
+    async fn write(&mut self, confirmed_block: &ConfirmedBlock) -> Result<(), anyhow::Error> {
+        let candles = self.aggregator.add_block(confirmed_block);
+        self.write_candles(&candles)
+    }
+
+    async fn write_reorg(&mut self, reorg: &Reorg) -> Result<(), anyhow::Error> {
+        let candles = self.aggregator.remove_blocks(&reorg.orphaned_hashes);
+        self.write_candles(&candles)
+    }
+
+    async fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::swap_details::{LogMetadata, SwapDirection};
+    use web3::types::U256;
+
+    const POOL: H160 = H160::repeat_byte(0xaa);
+    /// 2025-02-13 08:19:00 UTC
+    const MINUTE: u64 = 1739434740;
+
+    /// A swap of `amount0` DAI for `amount0 * price` USDC
+    fn swap(log_index: u64, amount0: i64, price: Decimal) -> SwapDetails {
+        let amount0 = Decimal::from(amount0);
+        SwapDetails {
+            metadata: LogMetadata {
+                pool: POOL,
+                log_index: Some(log_index),
+                ..Default::default()
+            },
+            sender: H160::repeat_byte(1),
+            recipient: H160::repeat_byte(2),
+            amount0_raw: U256::zero(),
+            amount0_as_decimal_num: amount0,
+            amount1_raw: U256::zero(),
+            amount1_as_decimal_num: -amount0 * price,
+            direction: SwapDirection::DaiToUsdc,
+            sqrt_price_x96: U256::zero(),
+            dai_is_token1: false,
+        }
+    }
+
+    fn block(block_number: u64, timestamp: u64, swaps: Vec<SwapDetails>) -> ConfirmedBlock {
+        ConfirmedBlock {
+            block_number,
+            block_hash: H256::from_low_u64_be(block_number),
+            timestamp,
+            swaps,
+        }
+    }
+
+    fn price(cents: i64) -> Decimal {
+        Decimal::new(cents, 2)
+    }
+
+    /// `(open, high, low, close)`
+    fn prices(
+        candle: &Candle,
+    ) -> (
+        Option<Decimal>,
+        Option<Decimal>,
+        Option<Decimal>,
+        Option<Decimal>,
+    ) {
+        (candle.open, candle.high, candle.low, candle.close)
+    }
+
+    #[test]
+    fn test_swaps_are_aggregated_per_interval() {
+        let mut aggregator = CandleAggregator::new(vec![Interval::OneMinute, Interval::OneHour]);
+
+        let candles = aggregator.add_block(&block(
+            1,
+            MINUTE + 5,
+            vec![swap(0, 100, price(100)), swap(1, 50, price(102))],
+        ));
+        assert_eq!(candles.len(), 2);
+        assert_eq!(candles[0].interval, Interval::OneMinute);
+        assert_eq!(candles[0].start, MINUTE);
+        assert_eq!(candles[1].start, MINUTE - 19 * 60);
+
+        aggregator.add_block(&block(2, MINUTE + 17, vec![]));
+        let candles = aggregator.add_block(&block(3, MINUTE + 29, vec![swap(0, 10, price(99))]));
+        let minute = &candles[0];
+        assert_eq!(
+            prices(minute),
+            (
+                Some(price(100)),
+                Some(price(102)),
+                Some(price(99)),
+                Some(price(99))
+            )
+        );
+        assert_eq!(minute.volume0, Decimal::from(160));
+        assert_eq!(minute.volume1, Decimal::new(16090, 2));
+        assert_eq!((minute.swaps, minute.last_block), (3, 3));
+
+        // the next minute, same hour
+        let candles = aggregator.add_block(&block(4, MINUTE + 65, vec![swap(0, 1, price(101))]));
+        assert_eq!(candles[0].start, MINUTE + 60);
+        assert_eq!(prices(&candles[0]).0, Some(price(101)));
+        assert_eq!(candles[1].swaps, 4);
+        assert_eq!(prices(&candles[1]).3, Some(price(101)));
+
+        // a block written again changes nothing
+        assert!(aggregator
+            .add_block(&block(4, MINUTE + 65, vec![swap(0, 1, price(101))]))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_candles_of_orphaned_blocks_are_recomputed() {
+        let mut aggregator = CandleAggregator::new(Interval::ALL.to_vec());
+        aggregator.add_block(&block(1, MINUTE, vec![swap(0, 100, price(100))]));
+        aggregator.add_block(&block(2, MINUTE + 12, vec![swap(0, 100, price(90))]));
+        aggregator.add_block(&block(3, MINUTE + 60, vec![swap(0, 100, price(101))]));
+
+        let candles =
+            aggregator.remove_blocks(&[H256::from_low_u64_be(2), H256::from_low_u64_be(3)]);
+        let candles: HashMap<Interval, Vec<&Candle>> =
+            candles
+                .iter()
+                .fold(HashMap::new(), |mut by_interval, candle| {
+                    by_interval.entry(candle.interval).or_default().push(candle);
+                    by_interval
+                });
+        // the first minute keeps block 1, the second one is left without swaps
+        let minutes = &candles[&Interval::OneMinute];
+        assert_eq!(minutes.len(), 2);
+        assert_eq!(
+            prices(minutes[0]),
+            (
+                Some(price(100)),
+                Some(price(100)),
+                Some(price(100)),
+                Some(price(100))
+            )
+        );
+        assert_eq!((minutes[0].swaps, minutes[0].last_block), (1, 1));
+        assert_eq!(prices(minutes[1]), (None, None, None, None));
+        assert_eq!(minutes[1].swaps, 0);
+        assert_eq!(candles[&Interval::OneDay].len(), 1);
+        assert_eq!(candles[&Interval::OneDay][0].volume0, Decimal::from(100));
+
+        // unknown blocks recompute nothing
+        assert!(aggregator
+            .remove_blocks(&[H256::from_low_u64_be(9)])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_restored_candles_are_continued() {
+        let mut first_run = CandleAggregator::new(vec![Interval::FiveMinutes]);
+        let candles = first_run.add_block(&block(1, MINUTE, vec![swap(0, 100, price(100))]));
+
+        let mut aggregator = CandleAggregator::new(vec![Interval::FiveMinutes]);
+        aggregator.restore(candles[0].clone());
+        // already included
+        assert!(aggregator
+            .add_block(&block(1, MINUTE, vec![swap(0, 100, price(100))]))
+            .is_empty());
+        let candles = aggregator.add_block(&block(2, MINUTE + 12, vec![swap(0, 50, price(98))]));
+        assert_eq!(
+            prices(&candles[0]),
+            (
+                Some(price(100)),
+                Some(price(100)),
+                Some(price(98)),
+                Some(price(98))
+            )
+        );
+        assert_eq!(
+            (candles[0].swaps, candles[0].volume0),
+            (2, Decimal::from(150))
+        );
+
+        // the next day, neither the restored candle nor block 2 are kept
+        aggregator.add_block(&block(3, MINUTE + 24 * 60 * 60, vec![]));
+        assert!(aggregator.restored.is_empty());
+        assert!(aggregator
+            .remove_blocks(&[H256::from_low_u64_be(2)])
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sink_continues_the_candles_of_its_file() {
+        let dir = std::env::temp_dir().join(format!("candles-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("candles.jsonl");
+
+        let mut sink = CandleSink::file(&path, vec![Interval::OneMinute]).unwrap();
+        sink.write(&block(1, MINUTE, vec![swap(0, 100, price(100))]))
+            .await
+            .unwrap();
+        sink.flush().await.unwrap();
+        drop(sink);
+
+        let mut sink = CandleSink::file(&path, vec![Interval::OneMinute]).unwrap();
+        sink.write(&block(2, MINUTE + 12, vec![swap(0, 100, price(102))]))
+            .await
+            .unwrap();
+        let reorg = Reorg {
+            detected_at: 4,
+            fork_point: 1,
+            depth: 1,
+            orphaned_hashes: vec![H256::from_low_u64_be(2)],
+            new_hashes: vec![],
+        };
+        sink.write_reorg(&reorg).await.unwrap();
+        sink.flush().await.unwrap();
+
+        let candles: Vec<Candle> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let swaps: Vec<u64> = candles.iter().map(|candle| candle.swaps).collect();
+        assert_eq!(swaps, vec![1, 2, 1]);
+        assert_eq!(candles[1].close, Some(price(102)));
+        assert_eq!(candles[2].close, Some(price(100)));
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&candles[0]).unwrap()).unwrap();
+        assert_eq!(json["interval"], "1m");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,340 @@
+use anyhow::Context;
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
+use jsonrpc_core as rpc;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use web3::{
+    api::SubscriptionId,
+    error::{Error as Web3Error, TransportError},
+    helpers,
+    transports::WebSocket,
+    DuplexTransport, RequestId, Transport, Web3,
+};
+
+/// A single JSON-RPC request & the node's response to it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Interaction {
+    pub method: String,
+    pub params: Vec<rpc::Value>,
+    pub response: Result<rpc::Value, rpc::Error>,
+}
+
+/// Everything a `CassetteTransport` recorded, as stored in a fixture file
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+    /// Notifications (e.g., `newHeads`) by subscription id, in the order they arrived
+    #[serde(default)]
+    pub notifications: BTreeMap<String, Vec<rpc::Value>>,
+}
+
+impl Cassette {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read cassette {}", path.display()))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("Failed to parse cassette {}", path.display()))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), anyhow::Error> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)
+            .with_context(|| format!("Failed to write cassette {}", path.display()))
+    }
+}
+
+/// `Web3` serving the responses recorded in the cassette at `path` (see `scripts/generate_fixtures.rs`)
+pub fn replay_web3(
+    path: impl AsRef<Path>,
+) -> Result<Web3<CassetteTransport<WebSocket>>, anyhow::Error> {
+    let cassette = Cassette::load(path)?;
+    Ok(Web3::new(CassetteTransport::replay(cassette)))
+}
+
+#[derive(Debug, Default)]
+struct State {
+    cassette: Cassette,
+    /// How many times each recorded request was already replayed
+    replayed: HashMap<(String, String), usize>,
+}
+
+/// Transport wrapper that records every request/response (and subscription notification) of `inner`,
+/// or, without `inner`, replays a recorded `Cassette` with no network access.
+///
+/// When the same request was recorded several times, replay serves the responses in recorded order
+/// and keeps repeating the last one once they are exhausted.
+#[derive(Clone, Debug)]
+pub struct CassetteTransport<T> {
+    inner: Option<T>,
+    state: Arc<Mutex<State>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+impl<T: Transport> CassetteTransport<T> {
+    pub fn record(inner: T) -> Self {
+        Self {
+            inner: Some(inner),
+            state: Arc::default(),
+            next_id: Arc::default(),
+        }
+    }
+
+    pub fn replay(cassette: Cassette) -> Self {
+        Self {
+            inner: None,
+            state: Arc::new(Mutex::new(State {
+                cassette,
+                replayed: HashMap::new(),
+            })),
+            next_id: Arc::default(),
+        }
+    }
+
+    /// Everything recorded (or loaded for replay) so far
+    pub fn cassette(&self) -> Cassette {
+        self.state.lock().unwrap().cassette.clone()
+    }
+
+    fn replay_response(&self, method: &str, params: &[rpc::Value]) -> web3::Result<rpc::Value> {
+        let mut state = self.state.lock().unwrap();
+        let responses: Vec<_> = state
+            .cassette
+            .interactions
+            .iter()
+            .filter(|interaction| interaction.method == method && interaction.params == params)
+            .map(|interaction| interaction.response.clone())
+            .collect();
+        if responses.is_empty() {
+            return Err(Web3Error::Transport(TransportError::Message(format!(
+                "No recorded response for {}({})",
+                method,
+                rpc::Value::from(params.to_vec())
+            ))));
+        }
+
+        let key = (
+            method.to_string(),
+            rpc::Value::from(params.to_vec()).to_string(),
+        );
+        let replayed = state.replayed.entry(key).or_default();
+        let response = responses[(*replayed).min(responses.len() - 1)].clone();
+        *replayed += 1;
+        response.map_err(Web3Error::Rpc)
+    }
+}
+
+fn method_and_params(request: &rpc::Call) -> (String, Vec<rpc::Value>) {
+    match request {
+        rpc::Call::MethodCall(call) => {
+            let params = match &call.params {
+                rpc::Params::Array(params) => params.clone(),
+                rpc::Params::Map(map) => vec![rpc::Value::Object(map.clone())],
+                rpc::Params::None => vec![],
+            };
+            (call.method.clone(), params)
+        }
+        rpc::Call::Notification(notification) => (notification.method.clone(), vec![]),
+        rpc::Call::Invalid { .. } => (String::new(), vec![]),
+    }
+}
+
+impl<T> Transport for CassetteTransport<T>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send + 'static,
+{
+    type Out = BoxFuture<'static, web3::Result<rpc::Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+        match &self.inner {
+            Some(inner) => inner.prepare(method, params),
+            None => {
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                (id, helpers::build_request(id, method, params))
+            }
+        }
+    }
+
+    fn send(&self, id: RequestId, request: rpc::Call) -> Self::Out {
+        let (method, params) = method_and_params(&request);
+        let Some(inner) = &self.inner else {
+            return futures::future::ready(self.replay_response(&method, &params)).boxed();
+        };
+
+        let state = self.state.clone();
+        let response = inner.send(id, request);
+        async move {
+            let response = response.await;
+            let recorded = match &response {
+                Ok(value) => Some(Ok(value.clone())),
+                Err(Web3Error::Rpc(err)) => Some(Err(err.clone())),
+                // not a response of the node, nothing to replay
+                Err(_) => None,
+            };
+            if let Some(recorded) = recorded {
+                state
+                    .lock()
+                    .unwrap()
+                    .cassette
+                    .interactions
+                    .push(Interaction {
+                        method,
+                        params,
+                        response: recorded,
+                    });
+            }
+            response
+        }
+        .boxed()
+    }
+}
+
+impl<T> DuplexTransport for CassetteTransport<T>
+where
+    T: DuplexTransport + Send + Sync + 'static,
+    T::Out: Send + 'static,
+    T::NotificationStream: Send + 'static,
+{
+    type NotificationStream = BoxStream<'static, rpc::Value>;
+
+    fn subscribe(&self, id: SubscriptionId) -> web3::Result<Self::NotificationStream> {
+        let key = self.subscription_key(&id)?;
+        let Some(inner) = &self.inner else {
+            let notifications = self
+                .state
+                .lock()
+                .unwrap()
+                .cassette
+                .notifications
+                .get(&key)
+                .cloned()
+                .unwrap_or_default();
+            return Ok(futures::stream::iter(notifications).boxed());
+        };
+
+        let state = self.state.clone();
+        let stream = inner.subscribe(id)?.inspect(move |notification| {
+            state
+                .lock()
+                .unwrap()
+                .cassette
+                .notifications
+                .entry(key.clone())
+                .or_default()
+                .push(notification.clone());
+        });
+        Ok(stream.boxed())
+    }
+
+    fn unsubscribe(&self, id: SubscriptionId) -> web3::Result<()> {
+        match &self.inner {
+            Some(inner) => inner.unsubscribe(id),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T> CassetteTransport<T> {
+    /// `SubscriptionId` has no accessor for the id, so it's looked up among the recorded `eth_subscribe` responses
+    fn subscription_key(&self, id: &SubscriptionId) -> web3::Result<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .cassette
+            .interactions
+            .iter()
+            .filter(|interaction| interaction.method == "eth_subscribe")
+            .filter_map(|interaction| match &interaction.response {
+                Ok(rpc::Value::String(key)) => Some(key.clone()),
+                _ => None,
+            })
+            .find(|key| SubscriptionId::from(key.clone()) == *id)
+            .ok_or_else(|| Web3Error::Decoder(format!("Unknown subscription {:?}", id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use web3::types::BlockHeader;
+
+    fn header_json(number: u64) -> rpc::Value {
+        let json = std::fs::read_to_string("tests/fixtures/block_headers.json").unwrap();
+        let headers: Vec<rpc::Value> = serde_json::from_str(&json).unwrap();
+        headers
+            .into_iter()
+            .find(|header| header["number"] == json!(format!("0x{:x}", number)))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_replays_responses_in_recorded_order() {
+        let params = vec![json!("0x1"), json!(false)];
+        let cassette = Cassette {
+            interactions: vec![
+                Interaction {
+                    method: "eth_getBlockByNumber".to_string(),
+                    params: params.clone(),
+                    response: Ok(json!("first")),
+                },
+                Interaction {
+                    method: "eth_getBlockByNumber".to_string(),
+                    params: params.clone(),
+                    response: Ok(json!("second")),
+                },
+            ],
+            notifications: BTreeMap::new(),
+        };
+
+        let transport = CassetteTransport::<WebSocket>::replay(cassette);
+        let method = "eth_getBlockByNumber";
+        for expected in ["first", "second", "second"] {
+            let response = transport.execute(method, params.clone()).await.unwrap();
+            assert_eq!(response, json!(expected));
+        }
+
+        let err = transport
+            .execute(method, vec![json!("0x2"), json!(false)])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("No recorded response"));
+    }
+
+    #[tokio::test]
+    async fn test_replays_new_heads_subscription() {
+        let mut notifications = BTreeMap::new();
+        notifications.insert(
+            "0xcd0c3e8af590364c09d0fa6a1210faf5".to_string(),
+            vec![header_json(21836327), header_json(21836328)],
+        );
+        let cassette = Cassette {
+            interactions: vec![Interaction {
+                method: "eth_subscribe".to_string(),
+                params: vec![json!("newHeads")],
+                response: Ok(json!("0xcd0c3e8af590364c09d0fa6a1210faf5")),
+            }],
+            notifications,
+        };
+
+        let web3 = Web3::new(CassetteTransport::<WebSocket>::replay(cassette));
+        let block_stream = web3.eth_subscribe().subscribe_new_heads().await.unwrap();
+        let headers: Vec<BlockHeader> = block_stream.map(|header| header.unwrap()).collect().await;
+        let numbers: Vec<u64> = headers
+            .iter()
+            .map(|header| header.number.unwrap().as_u64())
+            .collect();
+        assert_eq!(numbers, vec![21836327, 21836328]);
+    }
+}
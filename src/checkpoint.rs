@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+use std::{fs, io::ErrorKind, path::Path};
+use web3::types::BlockHeader;
+
+/// The blocks `BlocksHandler` tracks but didn't confirm yet, persisted on shutdown,
+/// so a restarted monitor confirms them (& the blocks mined in between) instead of starting over
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Next block to be confirmed
+    pub starting_block_number: u64,
+    /// Tracked headers, in order of block numbers
+    pub blocks: Vec<BlockHeader>,
+}
+
+impl Checkpoint {
+    /// `None` when nothing was persisted at `path` yet
+    pub fn load(path: impl AsRef<Path>) -> Result<Option<Self>, anyhow::Error> {
+        let path = path.as_ref();
+        let json = match fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => anyhow::bail!("Failed to read {}: {}", path.display(), err),
+        };
+        let checkpoint = serde_json::from_str(&json)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))?;
+        Ok(Some(checkpoint))
+    }
+
+    /// `load`s & removes the checkpoint, so it's resumed from once. A run that stops before saving
+    /// its own checkpoint (e.g., a crash) then starts over instead of replaying the old blocks
+    pub fn take(path: impl AsRef<Path>) -> Result<Option<Self>, anyhow::Error> {
+        let path = path.as_ref();
+        let checkpoint = Self::load(path)?;
+        if checkpoint.is_some() {
+            fs::remove_file(path)
+                .map_err(|e| anyhow::anyhow!("Failed to remove {}: {}", path.display(), e))?;
+        }
+        Ok(checkpoint)
+    }
+
+    /// Writes to a temporary file first, so an interrupted save leaves the previous checkpoint intact
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), anyhow::Error> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", tmp_path.display(), e))?;
+        fs::rename(&tmp_path, path)
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))?;
+        Ok(())
+    }
+
+    /// Highest tracked block
+    pub fn last_block_number(&self) -> Option<u64> {
+        self.blocks
+            .last()
+            .and_then(|header| header.number)
+            .map(|number| number.as_u64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("checkpoint-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("checkpoint.json");
+        assert_eq!(Checkpoint::load(&path).unwrap(), None);
+
+        let json = std::fs::read_to_string("tests/fixtures/block_headers.json").unwrap();
+        let headers: Vec<BlockHeader> = serde_json::from_str(&json).unwrap();
+        let checkpoint = Checkpoint {
+            starting_block_number: 21836327,
+            blocks: headers[..3].to_vec(),
+        };
+        checkpoint.save(&path).unwrap();
+        assert_eq!(Checkpoint::load(&path).unwrap(), Some(checkpoint.clone()));
+        assert_eq!(checkpoint.last_block_number(), Some(21836329));
+        assert!(!path.with_extension("tmp").exists());
+
+        // resumed from once
+        assert_eq!(Checkpoint::take(&path).unwrap(), Some(checkpoint));
+        assert!(!path.exists());
+        assert_eq!(Checkpoint::take(&path).unwrap(), None);
+
+        fs::write(&path, "{").unwrap();
+        let err = Checkpoint::load(&path).unwrap_err();
+        assert!(err.to_string().contains("checkpoint.json"), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
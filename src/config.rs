@@ -0,0 +1,820 @@
+use crate::{
+    alerts::{DepegPrice, DepegWindow},
+    blocks_handler::MissingDataPolicy,
+    candles::Interval,
+    error::MonitorError,
+    l2_handler::BlockState,
+    networks::Network,
+    sinks::OutputFormat,
+    BLOCK_CONFIRMATIONS,
+};
+use log::LevelFilter;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, net::SocketAddr, path::Path, str::FromStr, time::Duration};
+use web3::types::H160;
+
+/// `Decimal` can't scale by more than 28 decimals
+const MAX_TOKEN_DECIMALS: u32 = 28;
+
+/// Monitor settings, see `monitor.example.toml` for every key.
+/// Values come from (in order of precedence): env vars, the config file, defaults.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub endpoints: EndpointsConfig,
+    /// The endpoints are checked to be on this chain
+    #[serde(default = "default_chain_id")]
+    pub chain_id: u64,
+    /// The network's pools (see `Network::pools`) when empty
+    #[serde(default)]
+    pub pools: Vec<PoolConfig>,
+    #[serde(default)]
+    pub confirmations: ConfirmationsConfig,
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+    #[serde(default)]
+    pub alerts: AlertsConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub api: ApiConfig,
+    #[serde(default)]
+    pub feed: FeedConfig,
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    /// Where the tracked blocks are persisted on shutdown & restored from on start
+    #[serde(default = "default_checkpoint")]
+    pub checkpoint: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EndpointsConfig {
+    /// WebSocket endpoint, `WEBSOCKET_ENDPOINT` overrides it
+    pub primary: Option<String>,
+    /// Tried in order when the primary one can't be connected to
+    #[serde(default)]
+    pub fallbacks: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    pub address: H160,
+    pub token0: TokenConfig,
+    pub token1: TokenConfig,
+}
+
+impl PoolConfig {
+    /// Whether token1 is DAI & token0 isn't, e.g., on pools whose USDC address sorts before DAI's
+    pub fn dai_is_token1(&self) -> bool {
+        self.token1.symbol.eq_ignore_ascii_case("DAI")
+            && !self.token0.symbol.eq_ignore_ascii_case("DAI")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    pub symbol: String,
+    pub decimals: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfirmationsConfig {
+    /// Blocks on top of a block before its swaps are reported, the network's default (see `Network::default_confirmations`) when unset
+    pub blocks: Option<u64>,
+    /// The network's default (see `Network::default_confirmation_mode`) when unset
+    pub mode: Option<ConfirmationMode>,
+    #[serde(default)]
+    pub missing_data: MissingDataConfig,
+    /// Used with `missing_data = "retry"`
+    #[serde(default = "default_retry_attempts")]
+    pub retry_attempts: u32,
+    #[serde(default = "default_retry_delay_ms")]
+    pub retry_delay_ms: u64,
+}
+
+impl Default for ConfirmationsConfig {
+    fn default() -> Self {
+        Self {
+            blocks: None,
+            mode: None,
+            missing_data: MissingDataConfig::default(),
+            retry_attempts: default_retry_attempts(),
+            retry_delay_ms: default_retry_delay_ms(),
+        }
+    }
+}
+
+impl ConfirmationsConfig {
+    pub fn missing_data_policy(&self) -> MissingDataPolicy {
+        match self.missing_data {
+            MissingDataConfig::Fail => MissingDataPolicy::Fail,
+            MissingDataConfig::Skip => MissingDataPolicy::Skip,
+            MissingDataConfig::Retry => MissingDataPolicy::Retry {
+                attempts: self.retry_attempts,
+                delay: Duration::from_millis(self.retry_delay_ms),
+            },
+        }
+    }
+}
+
+/// When a block counts as confirmed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfirmationMode {
+    /// With `blocks` blocks on top of it (`BlocksHandler`)
+    Depth,
+    /// Once the node tags it `safe`, i.e., a rollup posted it to L1 (`L2BlocksHandler`)
+    Safe,
+    /// Once the node tags it `finalized` (`L2BlocksHandler`)
+    Finalized,
+}
+
+impl ConfirmationMode {
+    /// State of `L2BlocksHandler` blocks are confirmed at, `None` for `Depth`
+    pub fn block_state(self) -> Option<BlockState> {
+        match self {
+            ConfirmationMode::Depth => None,
+            ConfirmationMode::Safe => Some(BlockState::Safe),
+            ConfirmationMode::Finalized => Some(BlockState::Finalized),
+        }
+    }
+}
+
+/// See `MissingDataPolicy`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingDataConfig {
+    Fail,
+    Skip,
+    #[default]
+    Retry,
+}
+
+/// Where confirmed swaps are written (besides stdout)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum SinkConfig {
+    /// Appends the swaps to a file
+    File {
+        path: String,
+        #[serde(default = "default_file_format")]
+        format: OutputFormat,
+    },
+    /// POSTs swaps, reorgs & provider outages as JSON
+    Webhook {
+        url: String,
+        /// Key of the `X-Monitor-Signature` HMAC, payloads aren't signed without it
+        secret: Option<String>,
+        /// Payloads which couldn't be delivered are appended here & sent again once the endpoint is back
+        #[serde(default = "default_dead_letters")]
+        dead_letters: String,
+        /// Attempts per payload, with exponential backoff in between
+        #[serde(default = "default_webhook_attempts")]
+        max_attempts: u32,
+    },
+    /// `sqlite://<path>` or `postgres://...`
+    Database { url: String },
+    /// Appends OHLCV candles of the swaps as JSON lines, recomputed when a reorg orphans their blocks
+    Candles {
+        path: String,
+        #[serde(default = "default_candle_intervals")]
+        intervals: Vec<Interval>,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertsConfig {
+    /// Swaps above this amount (of either token) are whales
+    pub large_swap_amount: Option<Decimal>,
+    /// Deviation of the price from 1.0 considered a depeg, e.g., `0.005`
+    pub depeg_threshold: Option<Decimal>,
+    /// The price checked for a depeg, `swap` (what swaps paid) by default or `pool` (`sqrtPriceX96` after them)
+    #[serde(default)]
+    pub depeg_price: DepegPrice,
+    /// Blocks in a row the price has to be off for, 1 by default
+    pub depeg_blocks: Option<u64>,
+    /// Instead of `depeg_blocks`, blocks of the volume-weighted price which has to be off
+    pub depeg_vwap_blocks: Option<u64>,
+    /// File of addresses to alert on as a swap's sender or recipient, an address per line with an optional label.
+    /// It's read again once it changes
+    pub watchlist: Option<String>,
+    /// Swaps of one sender within `burst_blocks` blocks which make a burst
+    pub burst_swaps: Option<u64>,
+    pub burst_blocks: Option<u64>,
+}
+
+impl AlertsConfig {
+    pub fn depeg_window(&self) -> DepegWindow {
+        match (self.depeg_vwap_blocks, self.depeg_blocks) {
+            (Some(blocks), _) => DepegWindow::Vwap { blocks },
+            (None, blocks) => DepegWindow::Sustained {
+                blocks: blocks.unwrap_or(1),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Where Prometheus metrics are served (`GET /metrics`), off when unset
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiConfig {
+    /// Where the HTTP API (`/swaps`, `/reorgs`, `/status`, `/pools`) is served, off when unset.
+    /// It reads the swaps & reorgs from the `sqlite://` database sink
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeedConfig {
+    /// Where the WebSocket feed of provisional, confirmed & retracted swaps is served (`/feed`), off when unset.
+    /// Swaps of every new block are then fetched right away, a request per head
+    pub listen: Option<SocketAddr>,
+}
+
+fn default_chain_id() -> u64 {
+    1
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_delay_ms() -> u64 {
+    1000
+}
+
+fn default_file_format() -> OutputFormat {
+    OutputFormat::Json
+}
+
+fn default_dead_letters() -> String {
+    "webhook.dead_letters.jsonl".to_string()
+}
+
+fn default_candle_intervals() -> Vec<Interval> {
+    Interval::ALL.to_vec()
+}
+
+fn default_webhook_attempts() -> u32 {
+    5
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_checkpoint() -> String {
+    "monitor.checkpoint.json".to_string()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            endpoints: EndpointsConfig::default(),
+            chain_id: default_chain_id(),
+            pools: vec![],
+            confirmations: ConfirmationsConfig::default(),
+            sinks: vec![],
+            alerts: AlertsConfig::default(),
+            metrics: MetricsConfig::default(),
+            api: ApiConfig::default(),
+            feed: FeedConfig::default(),
+            log_level: default_log_level(),
+            checkpoint: default_checkpoint(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file at `path` (defaults only without one), applies env var overrides & the network's defaults,
+    /// then validates the result
+    pub fn load(path: Option<&Path>) -> Result<Self, MonitorError> {
+        let config = match path {
+            Some(path) => {
+                let toml = std::fs::read_to_string(path).map_err(|e| {
+                    MonitorError::InvalidConfig(format!("Failed to read {}: {}", path.display(), e))
+                })?;
+                Self::from_toml(&toml).map_err(|e| {
+                    MonitorError::InvalidConfig(format!("{}: {}", path.display(), e))
+                })?
+            }
+            None => Self::default(),
+        };
+
+        let config = config
+            .with_env_overrides(|key| std::env::var(key).ok())?
+            .with_network_defaults();
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Fills in the pools & confirmations left unset from the registry of the `chain_id` network.
+    /// Pools of other chains stay empty (& fail `validate`)
+    pub fn with_network_defaults(mut self) -> Self {
+        let network = self.network();
+        if self.pools.is_empty() {
+            self.pools = network.map(Network::pools).unwrap_or_default();
+        }
+        if self.confirmations.blocks.is_none() {
+            self.confirmations.blocks = Some(self.confirmation_blocks());
+        }
+        if self.confirmations.mode.is_none() {
+            self.confirmations.mode = Some(self.confirmation_mode());
+        }
+        self
+    }
+
+    /// The known network of `chain_id`, if any
+    pub fn network(&self) -> Option<Network> {
+        Network::from_chain_id(self.chain_id)
+    }
+
+    /// See `ConfirmationsConfig::mode`
+    pub fn confirmation_mode(&self) -> ConfirmationMode {
+        self.confirmations.mode.unwrap_or_else(|| {
+            self.network()
+                .map_or(ConfirmationMode::Depth, Network::default_confirmation_mode)
+        })
+    }
+
+    /// Confirmations to wait for, see `ConfirmationsConfig::blocks`
+    pub fn confirmation_blocks(&self) -> u64 {
+        self.confirmations.blocks.unwrap_or_else(|| {
+            self.network()
+                .map_or(BLOCK_CONFIRMATIONS, Network::default_confirmations)
+        })
+    }
+
+    /// Path of the first `sqlite://` database sink, the store the HTTP API reads from
+    pub fn sqlite_path(&self) -> Option<&str> {
+        self.sinks.iter().find_map(|sink| match sink {
+            SinkConfig::Database { url } => url.strip_prefix("sqlite://"),
+            _ => None,
+        })
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    /// Applies `WEBSOCKET_ENDPOINT`, `MONITOR_FALLBACK_ENDPOINTS` (comma separated), `MONITOR_CHAIN_ID`,
+    /// `MONITOR_CONFIRMATIONS`, `MONITOR_LOG_LEVEL`, `MONITOR_METRICS_LISTEN`, `MONITOR_API_LISTEN` & `MONITOR_FEED_LISTEN`,
+    /// as returned by `env`
+    pub fn with_env_overrides(
+        mut self,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, MonitorError> {
+        fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, MonitorError>
+        where
+            T::Err: std::fmt::Display,
+        {
+            value
+                .trim()
+                .parse()
+                .map_err(|e| MonitorError::InvalidConfig(format!("{}={:?}: {}", key, value, e)))
+        }
+
+        if let Some(endpoint) = env("WEBSOCKET_ENDPOINT") {
+            self.endpoints.primary = Some(endpoint);
+        }
+        if let Some(endpoints) = env("MONITOR_FALLBACK_ENDPOINTS") {
+            self.endpoints.fallbacks = endpoints
+                .split(',')
+                .map(str::trim)
+                .filter(|endpoint| !endpoint.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(chain_id) = env("MONITOR_CHAIN_ID") {
+            self.chain_id = parse("MONITOR_CHAIN_ID", &chain_id)?;
+        }
+        if let Some(blocks) = env("MONITOR_CONFIRMATIONS") {
+            self.confirmations.blocks = Some(parse("MONITOR_CONFIRMATIONS", &blocks)?);
+        }
+        if let Some(log_level) = env("MONITOR_LOG_LEVEL") {
+            self.log_level = log_level;
+        }
+        if let Some(listen) = env("MONITOR_METRICS_LISTEN") {
+            self.metrics.listen = Some(parse("MONITOR_METRICS_LISTEN", &listen)?);
+        }
+        if let Some(listen) = env("MONITOR_API_LISTEN") {
+            self.api.listen = Some(parse("MONITOR_API_LISTEN", &listen)?);
+        }
+        if let Some(listen) = env("MONITOR_FEED_LISTEN") {
+            self.feed.listen = Some(parse("MONITOR_FEED_LISTEN", &listen)?);
+        }
+        Ok(self)
+    }
+
+    /// Checks everything that can be checked without connecting anywhere, so mistakes fail at startup
+    pub fn validate(&self) -> Result<(), MonitorError> {
+        let invalid = |msg: String| Err(MonitorError::InvalidConfig(msg));
+
+        let endpoints = self
+            .endpoints
+            .primary
+            .iter()
+            .chain(&self.endpoints.fallbacks);
+        for endpoint in endpoints {
+            if !(endpoint.starts_with("ws://") || endpoint.starts_with("wss://")) {
+                return invalid(format!(
+                    "endpoints: {:?} isn't a WebSocket (ws:// or wss://) URL",
+                    endpoint
+                ));
+            }
+        }
+        if self.chain_id == 0 {
+            return invalid("chain_id: must be greater than 0".to_string());
+        }
+
+        if self.pools.is_empty() {
+            return invalid(format!(
+                "pools: chain {} has no known pools, configure at least one (known networks: {})",
+                self.chain_id,
+                Network::ALL.map(|network| network.to_string()).join(", ")
+            ));
+        }
+        let mut addresses = HashSet::new();
+        for pool in &self.pools {
+            if !addresses.insert(pool.address) {
+                return invalid(format!("pools: {:?} is configured twice", pool.address));
+            }
+            for token in [&pool.token0, &pool.token1] {
+                if token.symbol.trim().is_empty() {
+                    return invalid(format!(
+                        "pools: {:?} has a token without symbol",
+                        pool.address
+                    ));
+                }
+                if token.decimals > MAX_TOKEN_DECIMALS {
+                    return invalid(format!(
+                        "pools: {} of {:?} has {} decimals, at most {} are supported",
+                        token.symbol, pool.address, token.decimals, MAX_TOKEN_DECIMALS
+                    ));
+                }
+            }
+        }
+
+        if self.confirmations.blocks == Some(0) {
+            return invalid("confirmations.blocks: must be at least 1".to_string());
+        }
+
+        for sink in &self.sinks {
+            match sink {
+                SinkConfig::File { path, .. } if path.trim().is_empty() => {
+                    return invalid("sinks: file sink without path".to_string());
+                }
+                SinkConfig::Webhook { url, .. }
+                    if !(url.starts_with("http://") || url.starts_with("https://")) =>
+                {
+                    return invalid(format!("sinks: webhook url {:?} isn't an HTTP(S) URL", url));
+                }
+                SinkConfig::Webhook { dead_letters, .. } if dead_letters.trim().is_empty() => {
+                    return invalid("sinks: webhook dead_letters path can't be empty".to_string());
+                }
+                SinkConfig::Webhook {
+                    max_attempts: 0, ..
+                } => {
+                    return invalid("sinks: webhook max_attempts must be at least 1".to_string());
+                }
+                SinkConfig::Database { url }
+                    if !(url.starts_with("sqlite://") || url.starts_with("postgres://")) =>
+                {
+                    return invalid(format!(
+                        "sinks: database url {:?} must start with sqlite:// or postgres://",
+                        url
+                    ));
+                }
+                SinkConfig::Candles { path, .. } if path.trim().is_empty() => {
+                    return invalid("sinks: candles sink without path".to_string());
+                }
+                SinkConfig::Candles { intervals, .. } if intervals.is_empty() => {
+                    return invalid("sinks: candles sink without intervals".to_string());
+                }
+                _ => {}
+            }
+        }
+
+        if self.api.listen.is_some() && self.sqlite_path().is_none() {
+            return invalid(
+                "api: the HTTP API reads from a database sink with a sqlite:// url, add one"
+                    .to_string(),
+            );
+        }
+
+        if self.alerts.depeg_blocks.is_some() && self.alerts.depeg_vwap_blocks.is_some() {
+            return invalid(
+                "alerts.depeg_vwap_blocks: set either it or alerts.depeg_blocks".to_string(),
+            );
+        }
+        let windows = [
+            ("alerts.depeg_blocks", self.alerts.depeg_blocks),
+            ("alerts.depeg_vwap_blocks", self.alerts.depeg_vwap_blocks),
+        ];
+        for (key, blocks) in windows {
+            if blocks == Some(0) {
+                return invalid(format!("{}: must be at least 1", key));
+            }
+        }
+
+        if self.alerts.burst_swaps.is_some() != self.alerts.burst_blocks.is_some() {
+            return invalid(
+                "alerts.burst_swaps: set both it & alerts.burst_blocks, or neither".to_string(),
+            );
+        }
+        if self.alerts.burst_swaps.is_some_and(|swaps| swaps < 2) {
+            return invalid("alerts.burst_swaps: must be at least 2".to_string());
+        }
+        if self.alerts.burst_blocks == Some(0) {
+            return invalid("alerts.burst_blocks: must be at least 1".to_string());
+        }
+        if self
+            .alerts
+            .watchlist
+            .as_ref()
+            .is_some_and(|path| path.trim().is_empty())
+        {
+            return invalid("alerts.watchlist: path can't be empty".to_string());
+        }
+
+        let thresholds = [
+            ("alerts.large_swap_amount", self.alerts.large_swap_amount),
+            ("alerts.depeg_threshold", self.alerts.depeg_threshold),
+        ];
+        for (key, threshold) in thresholds {
+            if threshold.is_some_and(|threshold| threshold <= Decimal::ZERO) {
+                return invalid(format!("{}: must be greater than 0", key));
+            }
+        }
+
+        if self.checkpoint.trim().is_empty() {
+            return invalid("checkpoint: path can't be empty".to_string());
+        }
+
+        if LevelFilter::from_str(&self.log_level).is_err() {
+            return invalid(format!(
+                "log_level: {:?} isn't one of off, error, warn, info, debug, trace",
+                self.log_level
+            ));
+        }
+        Ok(())
+    }
+
+    /// Primary endpoint first, then the fallbacks
+    pub fn endpoints(&self) -> Result<Vec<&str>, MonitorError> {
+        let primary = self.endpoints.primary.as_deref().ok_or_else(|| {
+            MonitorError::MissingConfig("endpoints.primary (or WEBSOCKET_ENDPOINT)".to_string())
+        })?;
+        Ok(std::iter::once(primary)
+            .chain(self.endpoints.fallbacks.iter().map(String::as_str))
+            .collect())
+    }
+
+    pub fn pool(&self, address: H160) -> Option<&PoolConfig> {
+        self.pools.iter().find(|pool| pool.address == address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    fn invalid_config_message(toml: &str) -> String {
+        let config = Config::from_toml(toml).unwrap().with_network_defaults();
+        match config.validate() {
+            Err(MonitorError::InvalidConfig(msg)) => msg,
+            result => panic!("expected invalid config, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_example_config_is_valid() {
+        let toml = std::fs::read_to_string("monitor.example.toml").unwrap();
+        let config = Config::from_toml(&toml).unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.endpoints().unwrap().len(), 2);
+        assert_eq!(config.pools, Network::Mainnet.pools());
+        assert_eq!(config.sinks.len(), 1);
+        assert_eq!(
+            config.confirmations.missing_data_policy(),
+            MissingDataPolicy::Retry {
+                attempts: 3,
+                delay: Duration::from_secs(1)
+            }
+        );
+    }
+
+    #[test]
+    fn test_defaults_without_file() {
+        let config = Config::from_toml("").unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.chain_id, 1);
+        assert_eq!(config.confirmation_blocks(), BLOCK_CONFIRMATIONS);
+        let config = config.with_network_defaults();
+        assert_eq!(config.confirmations.blocks, Some(BLOCK_CONFIRMATIONS));
+        assert_eq!(config.pools, Network::Mainnet.pools());
+        config.validate().unwrap();
+        assert!(matches!(
+            config.endpoints(),
+            Err(MonitorError::MissingConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let config = Config::from_toml(
+            r#"
+            chain_id = 10
+            log_level = "debug"
+            [endpoints]
+            primary = "wss://from-file"
+            "#,
+        )
+        .unwrap()
+        .with_env_overrides(env(&[
+            ("WEBSOCKET_ENDPOINT", "wss://from-env"),
+            ("MONITOR_FALLBACK_ENDPOINTS", "wss://a, wss://b"),
+            ("MONITOR_CONFIRMATIONS", "12"),
+            ("MONITOR_METRICS_LISTEN", "0.0.0.0:9090"),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            config.endpoints().unwrap(),
+            vec!["wss://from-env", "wss://a", "wss://b"]
+        );
+        assert_eq!(config.confirmations.blocks, Some(12));
+        assert_eq!(
+            config.metrics.listen,
+            Some(SocketAddr::from(([0, 0, 0, 0], 9090)))
+        );
+        // not overridden
+        assert_eq!(config.chain_id, 10);
+        assert_eq!(config.log_level, "debug");
+
+        let err = Config::default()
+            .with_env_overrides(env(&[("MONITOR_CHAIN_ID", "mainnet")]))
+            .unwrap_err();
+        assert!(err.to_string().contains("MONITOR_CHAIN_ID"), "{}", err);
+    }
+
+    #[test]
+    fn test_network_defaults() {
+        let config = Config::from_toml("chain_id = 42161")
+            .unwrap()
+            .with_network_defaults();
+        assert_eq!(config.network(), Some(Network::Arbitrum));
+        assert_eq!(config.pools, Network::Arbitrum.pools());
+        assert_eq!(config.confirmation_blocks(), 20);
+        assert_eq!(config.confirmation_mode(), ConfirmationMode::Safe);
+
+        // configured values win
+        let config = Config::from_toml(
+            r#"
+            chain_id = 8453
+            [confirmations]
+            blocks = 3
+            mode = "depth"
+            [[pools]]
+            address = "0x5777d92f208679db4b9778590fa3cab3ac9e2168"
+            token0 = { symbol = "DAI", decimals = 18 }
+            token1 = { symbol = "USDC", decimals = 6 }
+            "#,
+        )
+        .unwrap()
+        .with_network_defaults();
+        assert_eq!(config.pools.len(), 1);
+        assert_eq!(config.confirmation_blocks(), 3);
+        assert_eq!(config.confirmation_mode(), ConfirmationMode::Depth);
+
+        let config = Config::from_toml("chain_id = 11155111")
+            .unwrap()
+            .with_network_defaults();
+        assert!(config.pools.is_empty());
+        assert_eq!(config.confirmation_blocks(), BLOCK_CONFIRMATIONS);
+    }
+
+    #[test]
+    fn test_parse_errors_point_at_the_key() {
+        let err = Config::from_toml("chain_id = \"one\"").unwrap_err();
+        assert!(err.to_string().contains("chain_id"), "{}", err);
+
+        let err = Config::from_toml("confirmation = 5").unwrap_err();
+        assert!(err.to_string().contains("unknown field"), "{}", err);
+
+        let err = Config::from_toml("[[sinks]]\ntype = \"kafka\"").unwrap_err();
+        assert!(err.to_string().contains("kafka"), "{}", err);
+    }
+
+    #[test]
+    fn test_candles_sink_intervals() {
+        let config =
+            Config::from_toml("[[sinks]]\ntype = \"candles\"\npath = \"candles.jsonl\"").unwrap();
+        assert_eq!(
+            config.sinks,
+            vec![SinkConfig::Candles {
+                path: "candles.jsonl".to_string(),
+                intervals: Interval::ALL.to_vec(),
+            }]
+        );
+
+        let toml =
+            "[[sinks]]\ntype = \"candles\"\npath = \"candles.jsonl\"\nintervals = [\"5m\", \"1d\"]";
+        let config = Config::from_toml(toml).unwrap();
+        assert!(matches!(
+            &config.sinks[0],
+            SinkConfig::Candles { intervals, .. } if intervals == &[Interval::FiveMinutes, Interval::OneDay]
+        ));
+        assert!(Config::from_toml(&toml.replace("5m", "2m")).is_err());
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let msg = invalid_config_message("[endpoints]\nprimary = \"https://mainnet\"");
+        assert!(msg.starts_with("endpoints:"), "{}", msg);
+
+        let msg = invalid_config_message("chain_id = 0");
+        assert!(msg.starts_with("chain_id:"), "{}", msg);
+
+        // e.g., Sepolia
+        let msg = invalid_config_message("chain_id = 11155111");
+        assert!(
+            msg.starts_with("pools: chain 11155111 has no known pools"),
+            "{}",
+            msg
+        );
+
+        let pool = r#"
+            [[pools]]
+            address = "0x5777d92f208679db4b9778590fa3cab3ac9e2168"
+            token0 = { symbol = "DAI", decimals = 18 }
+            token1 = { symbol = "USDC", decimals = 6 }
+        "#;
+        let msg = invalid_config_message(&pool.repeat(2));
+        assert!(msg.contains("configured twice"), "{}", msg);
+
+        let msg = invalid_config_message(&pool.replace("decimals = 6", "decimals = 30"));
+        assert!(msg.contains("30 decimals"), "{}", msg);
+
+        let msg = invalid_config_message("[confirmations]\nblocks = 0");
+        assert!(msg.starts_with("confirmations.blocks:"), "{}", msg);
+
+        let msg = invalid_config_message("[[sinks]]\ntype = \"webhook\"\nurl = \"ftp://hooks\"");
+        assert!(msg.contains("webhook url"), "{}", msg);
+        let msg = invalid_config_message(
+            "[[sinks]]\ntype = \"webhook\"\nurl = \"https://hooks\"\nmax_attempts = 0",
+        );
+        assert!(msg.contains("max_attempts"), "{}", msg);
+
+        let msg = invalid_config_message("[[sinks]]\ntype = \"database\"\nurl = \"mysql://db\"");
+        assert!(msg.contains("database url"), "{}", msg);
+        let msg = invalid_config_message(
+            "[api]\nlisten = \"127.0.0.1:8080\"\n[[sinks]]\ntype = \"database\"\nurl = \"postgres://db\"",
+        );
+        assert!(msg.starts_with("api:"), "{}", msg);
+
+        let msg = invalid_config_message(
+            "[[sinks]]\ntype = \"candles\"\npath = \"candles.jsonl\"\nintervals = []",
+        );
+        assert!(msg.contains("candles sink without intervals"), "{}", msg);
+
+        let msg = invalid_config_message("[alerts]\ndepeg_threshold = -0.01");
+        assert!(msg.starts_with("alerts.depeg_threshold:"), "{}", msg);
+        let msg = invalid_config_message("[alerts]\nburst_swaps = 5");
+        assert!(msg.starts_with("alerts.burst_swaps:"), "{}", msg);
+        let msg = invalid_config_message("[alerts]\nburst_swaps = 1\nburst_blocks = 10");
+        assert!(msg.starts_with("alerts.burst_swaps:"), "{}", msg);
+        let msg = invalid_config_message("[alerts]\nwatchlist = \"\"");
+        assert!(msg.starts_with("alerts.watchlist:"), "{}", msg);
+        let msg = invalid_config_message("[alerts]\ndepeg_blocks = 0");
+        assert!(msg.starts_with("alerts.depeg_blocks:"), "{}", msg);
+        let msg = invalid_config_message("[alerts]\ndepeg_blocks = 3\ndepeg_vwap_blocks = 10");
+        assert!(msg.starts_with("alerts.depeg_vwap_blocks:"), "{}", msg);
+
+        let msg = invalid_config_message("log_level = \"verbose\"");
+        assert!(msg.starts_with("log_level:"), "{}", msg);
+    }
+}
@@ -0,0 +1,68 @@
+use crate::networks::describe_chain;
+use thiserror::Error;
+use web3::types::H256;
+
+/// Errors for missing data, reorgs & config. Callers receive them wrapped in `anyhow::Error`,
+/// so use `err.downcast_ref::<MonitorError>()` to decide whether to retry, skip or give up.
+#[derive(Debug, Error)]
+pub enum MonitorError {
+    /// The node doesn't know the block (yet), e.g., a lagging or pruned node
+    #[error("Block {0} not found")]
+    BlockNotFound(u64),
+
+    /// Pending blocks don't have a hash (and sometimes not even a number)
+    #[error("Pending block {} has no hash", .0.map_or("<unknown>".to_string(), |n| n.to_string()))]
+    PendingBlock(Option<u64>),
+
+    /// The new block's parent isn't the tracked previous block
+    // (:?) is needed for H256 type to properly display the full hex representation of the hash
+    #[error("parent_hash mismatch. Previous: {previous:?}, current: {current:?}")]
+    ParentHashMismatch {
+        block_number: u64,
+        previous: H256,
+        current: H256,
+    },
+
+    /// A tracked block was replaced on the canonical chain
+    #[error("Reorg detected at block {block_number}. Previous hash: {previous_hash:?}, New hash: {new_hash:?}")]
+    ReorgDetected {
+        block_number: u64,
+        previous_hash: H256,
+        new_hash: H256,
+    },
+
+    /// A new block at a height that is already confirmed (& no longer tracked)
+    #[error("Block {block_number} is older than the tracked window (starts at {oldest_tracked}), reorg is deeper than confirmations")]
+    DeepReorg {
+        block_number: u64,
+        oldest_tracked: u64,
+    },
+
+    #[error("Missing config: {0}")]
+    MissingConfig(String),
+
+    /// A config value (from the file or an env var) that can't be used, with the key it came from
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+
+    /// The endpoint serves another network than the configured one, so the pool addresses would match nothing
+    #[error("Endpoint is on chain {}, but chain {} is configured", describe_chain(*.actual), describe_chain(*.expected))]
+    ChainIdMismatch { expected: u64, actual: u64 },
+
+    /// A swap amount beyond what `Decimal` holds (96 bits), e.g., of a token with a weird supply
+    #[error("Amount {0} is out of Decimal range")]
+    AmountOutOfRange(String),
+
+    #[error(transparent)]
+    Transport(#[from] web3::Error),
+}
+
+impl MonitorError {
+    /// Whether the error is about data that isn't available (yet), as opposed to a transport or config failure
+    pub fn is_missing_data(&self) -> bool {
+        matches!(
+            self,
+            MonitorError::BlockNotFound(_) | MonitorError::PendingBlock(_)
+        )
+    }
+}
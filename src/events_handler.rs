@@ -1,60 +1,138 @@
-use crate::swap_details::SwapDetails;
-use anyhow::{anyhow, Context};
+use crate::{
+    config::PoolConfig,
+    error::MonitorError,
+    events_source::EventsSource,
+    swap_details::{LogMetadata, SwapDetails, AMOUNT0_DECIMALS, AMOUNT1_DECIMALS},
+};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use futures::future::try_join_all;
+use log::warn;
+use std::sync::OnceLock;
 use web3::{
-    contract::Contract,
     ethabi,
-    ethabi::{Event, Hash},
-    transports::WebSocket,
+    ethabi::{Contract, Event, Hash},
     types::{Log, H160, H256},
-    Web3,
 };
 
-pub struct EventsHandler {
-    web3: Web3<WebSocket>,
+/// Uniswap V3 DAI/USDC 0.01% pool on mainnet
+pub const DAI_USDC_POOL: &str = "5777d92f208679db4b9778590fa3cab3ac9e2168";
+
+/// Parsed once per process, the ABI is big & never changes
+static SWAP_EVENT: OnceLock<Event> = OnceLock::new();
+
+fn swap_event() -> Result<&'static Event, anyhow::Error> {
+    if let Some(swap_event) = SWAP_EVENT.get() {
+        return Ok(swap_event);
+    }
+
+    let contract = Contract::load(&include_bytes!("contracts/uniswap_pool_abi.json")[..])
+        .map_err(|e| anyhow!("Failed to load contract ABI: {}", e))?;
+    let swap_event = contract
+        .events_by_name("Swap")?
+        .first()
+        .ok_or_else(|| anyhow!("Swap event not found in ABI"))?
+        .clone();
+
+    Ok(SWAP_EVENT.get_or_init(|| swap_event))
+}
+
+#[async_trait]
+#[cfg_attr(test, mockall::automock)]
+pub trait SwapsFetcher {
+    async fn fetch_swaps(&self, block_hash: H256) -> Result<Vec<SwapDetails>, anyhow::Error>;
+}
+
+/// Swaps of several pools (e.g., one `EventsHandler` per pool), in the order of the fetchers
+#[async_trait]
+impl<S: SwapsFetcher + Send + Sync> SwapsFetcher for Vec<S> {
+    async fn fetch_swaps(&self, block_hash: H256) -> Result<Vec<SwapDetails>, anyhow::Error> {
+        let swaps =
+            try_join_all(self.iter().map(|fetcher| fetcher.fetch_swaps(block_hash))).await?;
+        Ok(swaps.into_iter().flatten().collect())
+    }
+}
+
+pub struct EventsHandler<S: EventsSource> {
+    events_source: S,
     contract_address: H160,
     swap_event: Event,
     swap_event_signature: Hash,
+    /// of the pool's token0 & token1
+    decimals: (u32, u32),
+    /// Whether the pool's token1 is DAI, see `SwapDetails::dai_is_token1`
+    dai_is_token1: bool,
 }
 
-impl EventsHandler {
-    pub fn new(web3: Web3<WebSocket>) -> Result<Self, anyhow::Error> {
+impl<S: EventsSource> EventsHandler<S> {
+    /// Handles the swaps of the DAI/USDC pool, see `with_pool` for others
+    pub fn new(events_source: S) -> Result<Self, anyhow::Error> {
         let contract_address = H160::from_slice(
-            &hex::decode("5777d92f208679db4b9778590fa3cab3ac9e2168")
-                .map_err(|e| anyhow!("Failed to decode hex: {}", e))?[..],
+            &hex::decode(DAI_USDC_POOL).map_err(|e| anyhow!("Failed to decode hex: {}", e))?[..],
         );
 
-        let contract = Contract::from_json(
-            web3.eth(),
-            contract_address,
-            include_bytes!("contracts/uniswap_pool_abi.json"),
-        )
-        .map_err(|e| anyhow!("Failed to create contract: {}", e))?;
-
-        let swap_event = contract
-            .abi()
-            .events_by_name("Swap")?
-            .first()
-            .unwrap()
-            .clone();
+        let swap_event = swap_event()?.clone();
         let swap_event_signature = swap_event.signature();
 
         Ok(Self {
-            web3,
+            events_source,
             contract_address,
             swap_event,
             swap_event_signature,
+            decimals: (AMOUNT0_DECIMALS, AMOUNT1_DECIMALS),
+            dai_is_token1: false,
         })
     }
 
+    pub fn with_pool(mut self, pool: H160) -> Self {
+        self.contract_address = pool;
+        self
+    }
+
+    /// Handles the swaps of a configured pool
+    pub fn for_pool(events_source: S, pool: &PoolConfig) -> Result<Self, anyhow::Error> {
+        Ok(Self::new(events_source)?
+            .with_pool(pool.address)
+            .with_decimals(pool.token0.decimals, pool.token1.decimals)
+            .with_dai_as_token1(pool.dai_is_token1()))
+    }
+
+    /// Decimals of the pool's token0 & token1, DAI's & USDC's by default
+    pub fn with_decimals(mut self, decimals0: u32, decimals1: u32) -> Self {
+        self.decimals = (decimals0, decimals1);
+        self
+    }
+
+    /// Whether DAI is the pool's token1 rather than token0, for the direction & price of its swaps
+    pub fn with_dai_as_token1(mut self, dai_is_token1: bool) -> Self {
+        self.dai_is_token1 = dai_is_token1;
+        self
+    }
+
+    pub fn pool(&self) -> H160 {
+        self.contract_address
+    }
+
+    /// Whether `log` is a Swap event of the pool, e.g., to pick swaps out of a transaction receipt
+    pub fn is_swap_log(&self, log: &Log) -> bool {
+        log.address == self.contract_address
+            && log.topics.first() == Some(&self.swap_event_signature)
+    }
+
     pub async fn fetch_swap_logs(&self, block_hash: H256) -> Result<Vec<Log>, anyhow::Error> {
-        let filter = web3::types::FilterBuilder::default()
-            .block_hash(block_hash)
-            .address(vec![self.contract_address])
-            .topics(Some(vec![self.swap_event_signature]), None, None, None)
-            .build();
+        self.events_source
+            .logs_by_block_hash(self.contract_address, self.swap_event_signature, block_hash)
+            .await
+    }
 
-        let swap_logs = self.web3.eth().logs(filter).await?;
-        Ok(swap_logs)
+    pub async fn fetch_swap_logs_in_range(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<Log>, anyhow::Error> {
+        self.events_source
+            .logs_by_block_range(self.contract_address, self.swap_event_signature, from, to)
+            .await
     }
 
     pub fn parse_logs(&self, raw_logs: Vec<Log>) -> Result<Vec<ethabi::Log>, anyhow::Error> {
@@ -74,28 +152,78 @@ impl EventsHandler {
         &self,
         parsed_logs: Vec<ethabi::Log>,
     ) -> Result<Vec<SwapDetails>, anyhow::Error> {
-        let mut swap_details = vec![];
-        for parsed_log in parsed_logs {
-            swap_details.push(SwapDetails::from_parsed_log(parsed_log)?);
-        }
+        parsed_logs
+            .into_iter()
+            .map(|parsed_log| self.to_swap(parsed_log))
+            .collect()
+    }
 
-        Ok(swap_details)
+    fn to_swap(&self, parsed_log: ethabi::Log) -> Result<SwapDetails, anyhow::Error> {
+        let (decimals0, decimals1) = self.decimals;
+        SwapDetails::from_parsed_log_of_pool(parsed_log, decimals0, decimals1, self.dai_is_token1)
     }
 
     pub async fn handle_events(&self, block_hash: H256) -> Result<Vec<SwapDetails>, anyhow::Error> {
-        let mut handled_events = vec![];
-
         let raw_logs = self.fetch_swap_logs(block_hash).await?;
+        self.handle_logs(raw_logs).await
+    }
+
+    /// Same as `handle_events`, but for all blocks in `from..=to` (e.g., for backfilling)
+    pub async fn handle_events_in_range(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<SwapDetails>, anyhow::Error> {
+        let raw_logs = self.fetch_swap_logs_in_range(from, to).await?;
+        self.handle_logs(raw_logs).await
+    }
+
+    /// Decodes raw Swap logs (e.g., from `fetch_swap_logs`), keeping where each one was emitted.
+    /// A swap whose amounts don't fit into `Decimal` is skipped with a warning, rather than failing the block
+    pub async fn handle_logs(&self, raw_logs: Vec<Log>) -> Result<Vec<SwapDetails>, anyhow::Error> {
+        let mut handled_events = vec![];
         if raw_logs.is_empty() {
             return Ok(handled_events);
         }
 
+        let metadata: Vec<LogMetadata> = raw_logs.iter().map(LogMetadata::from).collect();
         let parsed_logs = self.parse_logs(raw_logs)?;
-        handled_events = self
-            .to_swap_details(parsed_logs)
-            .await
-            .context("Could not convert to swap info")?;
+        for (parsed_log, metadata) in parsed_logs.into_iter().zip(metadata) {
+            let mut swap = match self.to_swap(parsed_log) {
+                Ok(swap) => swap,
+                Err(err) if is_out_of_range(&err) => {
+                    warn!(
+                        "skipping swap of tx {} log {}: {}",
+                        metadata
+                            .transaction_hash
+                            .map_or("-".to_string(), |hash| format!("{:?}", hash)),
+                        metadata
+                            .log_index
+                            .map_or("-".to_string(), |index| index.to_string()),
+                        err
+                    );
+                    continue;
+                }
+                Err(err) => return Err(err.context("Could not convert to swap info")),
+            };
+            swap.metadata = metadata;
+            handled_events.push(swap);
+        }
 
         Ok(handled_events)
     }
 }
+
+fn is_out_of_range(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<MonitorError>(),
+        Some(MonitorError::AmountOutOfRange(_))
+    )
+}
+
+#[async_trait]
+impl<S: EventsSource + Send + Sync> SwapsFetcher for EventsHandler<S> {
+    async fn fetch_swaps(&self, block_hash: H256) -> Result<Vec<SwapDetails>, anyhow::Error> {
+        self.handle_events(block_hash).await
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::Context;
+use async_trait::async_trait;
+use std::path::Path;
+use web3::{
+    transports::WebSocket,
+    types::{BlockNumber, FilterBuilder, Log, H160, H256},
+    Transport, Web3,
+};
+
+/// Where raw logs of a contract come from
+#[async_trait]
+pub trait EventsSource {
+    /// Logs of `address` with `topic` (event signature) emitted in the block with `block_hash`
+    async fn logs_by_block_hash(
+        &self,
+        address: H160,
+        topic: H256,
+        block_hash: H256,
+    ) -> Result<Vec<Log>, anyhow::Error>;
+
+    /// Logs of `address` with `topic` (event signature) emitted in blocks `from..=to`
+    async fn logs_by_block_range(
+        &self,
+        address: H160,
+        topic: H256,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<Log>, anyhow::Error>;
+}
+
+#[derive(Clone)]
+pub struct Web3EventsSource<T: Transport = WebSocket> {
+    pub web3: Web3<T>,
+}
+
+#[async_trait]
+impl<T> EventsSource for Web3EventsSource<T>
+where
+    T: Transport + Send + Sync,
+    T::Out: Send,
+{
+    async fn logs_by_block_hash(
+        &self,
+        address: H160,
+        topic: H256,
+        block_hash: H256,
+    ) -> Result<Vec<Log>, anyhow::Error> {
+        let filter = FilterBuilder::default()
+            .block_hash(block_hash)
+            .address(vec![address])
+            .topics(Some(vec![topic]), None, None, None)
+            .build();
+
+        let logs = self.web3.eth().logs(filter).await?;
+        Ok(logs)
+    }
+
+    async fn logs_by_block_range(
+        &self,
+        address: H160,
+        topic: H256,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<Log>, anyhow::Error> {
+        let filter = FilterBuilder::default()
+            .from_block(BlockNumber::Number(from.into()))
+            .to_block(BlockNumber::Number(to.into()))
+            .address(vec![address])
+            .topics(Some(vec![topic]), None, None, None)
+            .build();
+
+        let logs = self.web3.eth().logs(filter).await?;
+        Ok(logs)
+    }
+}
+
+/// Serves logs recorded in `<fixtures dir>/<block>/logs.json` files, so decoding can be tested offline
+#[derive(Clone, Debug, Default)]
+pub struct FixtureEventsSource {
+    logs: Vec<Log>,
+}
+
+impl FixtureEventsSource {
+    /// Loads `logs.json` of every block directory inside `fixtures_dir` (e.g., `tests/fixtures`)
+    pub fn load(fixtures_dir: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let fixtures_dir = fixtures_dir.as_ref();
+        let mut logs = vec![];
+        for entry in std::fs::read_dir(fixtures_dir)
+            .with_context(|| format!("Failed to read fixtures dir {}", fixtures_dir.display()))?
+        {
+            let logs_file = entry?.path().join("logs.json");
+            if !logs_file.is_file() {
+                continue;
+            }
+
+            let json = std::fs::read_to_string(&logs_file)
+                .with_context(|| format!("Failed to read {}", logs_file.display()))?;
+            let block_logs: Vec<Log> = serde_json::from_str(&json)
+                .with_context(|| format!("Failed to parse {}", logs_file.display()))?;
+            logs.extend(block_logs);
+        }
+        // `read_dir` order is platform dependent, a node returns logs in chain order
+        logs.sort_by_key(|log| (log.block_number, log.log_index));
+
+        Ok(Self { logs })
+    }
+
+    fn matching_logs(
+        &self,
+        address: H160,
+        topic: H256,
+        in_block: impl Fn(&Log) -> bool,
+    ) -> Vec<Log> {
+        self.logs
+            .iter()
+            .filter(|log| log.address == address && log.topics.first() == Some(&topic))
+            .filter(|log| in_block(log))
+            .cloned()
+            .collect()
+    }
+}
+
+#[async_trait]
+impl EventsSource for FixtureEventsSource {
+    async fn logs_by_block_hash(
+        &self,
+        address: H160,
+        topic: H256,
+        block_hash: H256,
+    ) -> Result<Vec<Log>, anyhow::Error> {
+        Ok(self.matching_logs(address, topic, |log| log.block_hash == Some(block_hash)))
+    }
+
+    async fn logs_by_block_range(
+        &self,
+        address: H160,
+        topic: H256,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<Log>, anyhow::Error> {
+        Ok(self.matching_logs(address, topic, |log| {
+            log.block_number
+                .is_some_and(|number| (from..=to).contains(&number.as_u64()))
+        }))
+    }
+}
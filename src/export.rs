@@ -0,0 +1,480 @@
+use crate::{
+    swap_details::{SwapDetails, SwapDirection},
+    web3_client::BlocksFetcher,
+};
+use anyhow::anyhow;
+use futures::{stream, StreamExt, TryStreamExt};
+use parquet::{
+    basic::Compression,
+    column::writer::ColumnWriter,
+    data_type::ByteArray,
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    io::BufWriter,
+    path::Path,
+    sync::Arc,
+};
+use web3::types::{H160, H256};
+
+/// Upper bound on in-flight header requests
+const MAX_CONCURRENT_HEADER_REQUESTS: usize = 8;
+
+/// Columns of a Parquet export, in `SwapRow` order. Decimal amounts & the price are doubles so they load
+/// as numbers, the raw amounts are the exact values
+const PARQUET_SCHEMA: &str = "
+message swap {
+    REQUIRED INT64 block_number (INTEGER(64, false));
+    REQUIRED INT64 timestamp (TIMESTAMP(MILLIS, true));
+    REQUIRED BYTE_ARRAY transaction_hash (STRING);
+    REQUIRED INT64 log_index (INTEGER(64, false));
+    REQUIRED BYTE_ARRAY pool (STRING);
+    REQUIRED BYTE_ARRAY sender (STRING);
+    REQUIRED BYTE_ARRAY recipient (STRING);
+    REQUIRED BYTE_ARRAY amount0_raw (STRING);
+    REQUIRED BYTE_ARRAY amount1_raw (STRING);
+    REQUIRED DOUBLE amount0;
+    REQUIRED DOUBLE amount1;
+    OPTIONAL DOUBLE price;
+    REQUIRED BYTE_ARRAY direction (STRING);
+}
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// A header & a line per swap
+    Csv,
+    /// A row group per batch of blocks, Snappy compressed
+    Parquet,
+}
+
+impl ExportFormat {
+    /// Format of a `.csv` or `.parquet` file
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "csv" => Some(Self::Csv),
+            "parquet" => Some(Self::Parquet),
+            _ => None,
+        }
+    }
+}
+
+/// A swap as exported, with the timestamp of its block
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SwapRow {
+    pub block_number: u64,
+    /// Unix time of the block, in seconds
+    pub timestamp: u64,
+    pub transaction_hash: H256,
+    pub log_index: u64,
+    pub pool: H160,
+    pub sender: H160,
+    pub recipient: H160,
+    /// Signed amounts as emitted by the pool
+    pub amount0_raw: String,
+    pub amount1_raw: String,
+    pub amount0: Decimal,
+    pub amount1: Decimal,
+    /// USDC per DAI, none when no DAI was swapped
+    pub price: Option<Decimal>,
+    pub direction: SwapDirection,
+}
+
+impl SwapRow {
+    pub fn new(swap: &SwapDetails, timestamp: u64) -> Result<Self, anyhow::Error> {
+        let metadata = &swap.metadata;
+        Ok(Self {
+            block_number: metadata
+                .block_number
+                .ok_or_else(|| anyhow!("Swap without a block number: {:?}", metadata))?,
+            timestamp,
+            transaction_hash: metadata
+                .transaction_hash
+                .ok_or_else(|| anyhow!("Swap without a transaction hash: {:?}", metadata))?,
+            log_index: metadata.log_index.unwrap_or_default(),
+            pool: metadata.pool,
+            sender: swap.sender,
+            recipient: swap.recipient,
+            amount0_raw: SwapDetails::format_amount(swap.amount0_raw),
+            amount1_raw: SwapDetails::format_amount(swap.amount1_raw),
+            amount0: swap.amount0_as_decimal_num,
+            amount1: swap.amount1_as_decimal_num,
+            price: swap.price(),
+            direction: swap.direction,
+        })
+    }
+}
+
+/// Rows of `swaps`, timestamped with the headers of their blocks
+pub async fn swap_rows<B: BlocksFetcher>(
+    blocks_fetcher: &B,
+    swaps: &[SwapDetails],
+) -> Result<Vec<SwapRow>, anyhow::Error> {
+    let block_numbers: BTreeSet<u64> = swaps
+        .iter()
+        .filter_map(|swap| swap.metadata.block_number)
+        .collect();
+    let timestamps: HashMap<u64, u64> = stream::iter(block_numbers)
+        .map(|block_number| async move {
+            let header = blocks_fetcher.get_block_header(block_number).await?;
+            Ok::<_, anyhow::Error>((block_number, header.timestamp.as_u64()))
+        })
+        .buffered(MAX_CONCURRENT_HEADER_REQUESTS)
+        .try_collect()
+        .await?;
+
+    swaps
+        .iter()
+        .map(|swap| {
+            let timestamp = swap
+                .metadata
+                .block_number
+                .and_then(|block_number| timestamps.get(&block_number))
+                .copied()
+                .unwrap_or_default();
+            SwapRow::new(swap, timestamp)
+        })
+        .collect()
+}
+
+/// First block of `from..=to` mined at or after `timestamp`, none when they're all older
+pub async fn first_block_at_or_after<B: BlocksFetcher>(
+    blocks_fetcher: &B,
+    timestamp: u64,
+    from: u64,
+    to: u64,
+) -> Result<Option<u64>, anyhow::Error> {
+    first_block_where(blocks_fetcher, from, to, |mined_at| mined_at >= timestamp).await
+}
+
+/// Last block of `from..=to` mined at or before `timestamp`, none when they're all newer
+pub async fn last_block_at_or_before<B: BlocksFetcher>(
+    blocks_fetcher: &B,
+    timestamp: u64,
+    from: u64,
+    to: u64,
+) -> Result<Option<u64>, anyhow::Error> {
+    let newer =
+        first_block_where(blocks_fetcher, from, to, |mined_at| mined_at > timestamp).await?;
+    Ok(match newer {
+        None => Some(to),
+        Some(newer) if newer == from => None,
+        Some(newer) => Some(newer - 1),
+    })
+}
+
+/// Binary search for the first block of `from..=to` whose timestamp satisfies `predicate`,
+/// timestamps only grow along the chain
+async fn first_block_where<B: BlocksFetcher>(
+    blocks_fetcher: &B,
+    from: u64,
+    to: u64,
+    predicate: impl Fn(u64) -> bool,
+) -> Result<Option<u64>, anyhow::Error> {
+    let (mut low, mut high) = (from, to.saturating_add(1));
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let header = blocks_fetcher.get_block_header(middle).await?;
+        if predicate(header.timestamp.as_u64()) {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    Ok((low <= to).then_some(low))
+}
+
+/// Writes swap rows to a CSV or Parquet file, `finish` has to be called for a complete file
+pub enum ExportWriter {
+    Csv(csv::Writer<BufWriter<File>>),
+    Parquet(SerializedFileWriter<File>),
+}
+
+impl ExportWriter {
+    pub fn create(path: &Path, format: ExportFormat) -> Result<Self, anyhow::Error> {
+        let file = File::create(path)
+            .map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e))?;
+        Ok(match format {
+            ExportFormat::Csv => Self::Csv(csv::Writer::from_writer(BufWriter::new(file))),
+            ExportFormat::Parquet => {
+                let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                Self::Parquet(SerializedFileWriter::new(
+                    file,
+                    schema,
+                    Arc::new(properties),
+                )?)
+            }
+        })
+    }
+
+    pub fn write(&mut self, rows: &[SwapRow]) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Csv(writer) => {
+                for row in rows {
+                    writer.serialize(row)?;
+                }
+            }
+            // a row group per call, an empty one is of no use
+            Self::Parquet(writer) if !rows.is_empty() => write_row_group(writer, rows)?,
+            Self::Parquet(_) => {}
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Csv(mut writer) => writer.flush()?,
+            Self::Parquet(writer) => {
+                writer.close()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_row_group(
+    writer: &mut SerializedFileWriter<File>,
+    rows: &[SwapRow],
+) -> Result<(), anyhow::Error> {
+    let to_f64 = |amount: Decimal| amount.to_f64().unwrap_or(f64::NAN);
+    let strings = |column: fn(&SwapRow) -> String| -> Vec<ByteArray> {
+        rows.iter().map(|row| column(row).as_str().into()).collect()
+    };
+
+    let mut row_group = writer.next_row_group()?;
+    let mut column_index = 0;
+    while let Some(mut column) = row_group.next_column()? {
+        match (column_index, column.untyped()) {
+            (0, ColumnWriter::Int64ColumnWriter(writer)) => {
+                let values: Vec<i64> = rows.iter().map(|row| row.block_number as i64).collect();
+                writer.write_batch(&values, None, None)?
+            }
+            (1, ColumnWriter::Int64ColumnWriter(writer)) => {
+                let values: Vec<i64> = rows.iter().map(|row| row.timestamp as i64 * 1000).collect();
+                writer.write_batch(&values, None, None)?
+            }
+            (2, ColumnWriter::ByteArrayColumnWriter(writer)) => writer.write_batch(
+                &strings(|row| format!("{:?}", row.transaction_hash)),
+                None,
+                None,
+            )?,
+            (3, ColumnWriter::Int64ColumnWriter(writer)) => {
+                let values: Vec<i64> = rows.iter().map(|row| row.log_index as i64).collect();
+                writer.write_batch(&values, None, None)?
+            }
+            (4, ColumnWriter::ByteArrayColumnWriter(writer)) => {
+                writer.write_batch(&strings(|row| format!("{:?}", row.pool)), None, None)?
+            }
+            (5, ColumnWriter::ByteArrayColumnWriter(writer)) => {
+                writer.write_batch(&strings(|row| format!("{:?}", row.sender)), None, None)?
+            }
+            (6, ColumnWriter::ByteArrayColumnWriter(writer)) => {
+                writer.write_batch(&strings(|row| format!("{:?}", row.recipient)), None, None)?
+            }
+            (7, ColumnWriter::ByteArrayColumnWriter(writer)) => {
+                writer.write_batch(&strings(|row| row.amount0_raw.clone()), None, None)?
+            }
+            (8, ColumnWriter::ByteArrayColumnWriter(writer)) => {
+                writer.write_batch(&strings(|row| row.amount1_raw.clone()), None, None)?
+            }
+            (9, ColumnWriter::DoubleColumnWriter(writer)) => {
+                let values: Vec<f64> = rows.iter().map(|row| to_f64(row.amount0)).collect();
+                writer.write_batch(&values, None, None)?
+            }
+            (10, ColumnWriter::DoubleColumnWriter(writer)) => {
+                let values: Vec<f64> = rows.iter().map(|row| to_f64(row.amount1)).collect();
+                writer.write_batch(&values, None, None)?
+            }
+            (11, ColumnWriter::DoubleColumnWriter(writer)) => {
+                // nulls are left out of the values, a definition level of 0 marks them
+                let values: Vec<f64> = rows
+                    .iter()
+                    .filter_map(|row| row.price)
+                    .map(to_f64)
+                    .collect();
+                let definition_levels: Vec<i16> =
+                    rows.iter().map(|row| row.price.is_some() as i16).collect();
+                writer.write_batch(&values, Some(&definition_levels), None)?
+            }
+            (12, ColumnWriter::ByteArrayColumnWriter(writer)) => {
+                writer.write_batch(&strings(|row| format!("{:?}", row.direction)), None, None)?
+            }
+            (index, _) => return Err(anyhow!("Unexpected Parquet column: {}", index)),
+        };
+        column.close()?;
+        column_index += 1;
+    }
+    row_group.close()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::MonitorError, swap_details::LogMetadata, web3_client::MockBlocksFetcher};
+    use parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        record::{Field, Row, RowAccessor},
+    };
+    use web3::types::{BlockHeader, U256};
+
+    /// Blocks 21836327 - 21836337, mined 12s apart from 1739434787
+    async fn load_fixtures() -> Vec<BlockHeader> {
+        let json = tokio::fs::read_to_string("tests/fixtures/block_headers.json")
+            .await
+            .expect("Failed to read fixtures file");
+        serde_json::from_str(&json).expect("Failed to parse blocks")
+    }
+
+    fn mock_fetcher(headers: Vec<BlockHeader>) -> MockBlocksFetcher {
+        let mut mock_fetcher = MockBlocksFetcher::new();
+        mock_fetcher
+            .expect_get_block_header()
+            .returning(move |block_number| {
+                let header = headers
+                    .iter()
+                    .find(|header| header.number.unwrap().as_u64() == block_number)
+                    .cloned()
+                    .ok_or(MonitorError::BlockNotFound(block_number));
+                Box::pin(async move { Ok(header?) })
+            });
+        mock_fetcher
+    }
+
+    /// 2000 DAI sold for 1999.5 USDC in block 21836328
+    fn swap(log_index: u64) -> SwapDetails {
+        SwapDetails {
+            metadata: LogMetadata {
+                pool: H160::repeat_byte(0xaa),
+                block_number: Some(21836328),
+                block_hash: Some(H256::repeat_byte(1)),
+                transaction_hash: Some(H256::repeat_byte(2)),
+                log_index: Some(log_index),
+            },
+            sender: H160::repeat_byte(3),
+            recipient: H160::repeat_byte(4),
+            amount0_raw: U256::from(2000) * U256::exp10(18),
+            amount0_as_decimal_num: Decimal::new(2000, 0),
+            amount1_raw: U256::MAX - U256::from(1_999_500_000) + 1,
+            amount1_as_decimal_num: Decimal::new(-19995, 1),
+            direction: SwapDirection::DaiToUsdc,
+            sqrt_price_x96: U256::zero(),
+            dai_is_token1: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_times_are_mapped_to_the_blocks_mined_then() {
+        let fetcher = mock_fetcher(load_fixtures().await);
+        let (from, to) = (21836327, 21836337);
+
+        let first = |time| first_block_at_or_after(&fetcher, time, from, to);
+        assert_eq!(first(0).await.unwrap(), Some(from));
+        assert_eq!(first(1739434799).await.unwrap(), Some(21836328));
+        assert_eq!(first(1739434800).await.unwrap(), Some(21836329));
+        assert_eq!(first(1739434788 + 120).await.unwrap(), None);
+
+        let last = |time| last_block_at_or_before(&fetcher, time, from, to);
+        assert_eq!(last(1739434786).await.unwrap(), None);
+        assert_eq!(last(1739434799).await.unwrap(), Some(21836328));
+        assert_eq!(last(1739434810).await.unwrap(), Some(21836328));
+        assert_eq!(last(u64::MAX).await.unwrap(), Some(to));
+    }
+
+    #[tokio::test]
+    async fn test_rows_are_timestamped_with_their_block() {
+        let mut no_token0 = swap(1);
+        no_token0.amount0_as_decimal_num = Decimal::ZERO;
+        let rows = swap_rows(&mock_fetcher(load_fixtures().await), &[swap(0), no_token0])
+            .await
+            .unwrap();
+
+        assert_eq!(rows[0].timestamp, 1739434799);
+        assert_eq!(rows[0].amount0_raw, "2000000000000000000000");
+        assert_eq!(rows[0].amount1_raw, "-1999500000");
+        assert_eq!(rows[0].price, Some(Decimal::new(99975, 5)));
+        assert_eq!(rows[1].price, None);
+    }
+
+    #[test]
+    fn test_format_is_inferred_from_the_extension() {
+        assert_eq!(
+            ExportFormat::from_path(Path::new("swaps.CSV")),
+            Some(ExportFormat::Csv)
+        );
+        assert_eq!(
+            ExportFormat::from_path(Path::new("out/swaps.parquet")),
+            Some(ExportFormat::Parquet)
+        );
+        assert_eq!(ExportFormat::from_path(Path::new("swaps.json")), None);
+        assert_eq!(ExportFormat::from_path(Path::new("swaps")), None);
+    }
+
+    #[test]
+    fn test_rows_are_written_to_csv_and_parquet() {
+        let dir = std::env::temp_dir().join(format!("export-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut no_token0 = SwapRow::new(&swap(1), 1739434799).unwrap();
+        no_token0.price = None;
+        let rows = [SwapRow::new(&swap(0), 1739434799).unwrap(), no_token0];
+
+        let csv_path = dir.join("swaps.csv");
+        let mut writer = ExportWriter::create(&csv_path, ExportFormat::Csv).unwrap();
+        writer.write(&rows[..1]).unwrap();
+        writer.write(&rows[1..]).unwrap();
+        writer.finish().unwrap();
+        let csv = std::fs::read_to_string(&csv_path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "block_number,timestamp,transaction_hash,log_index,pool,sender,recipient,\
+             amount0_raw,amount1_raw,amount0,amount1,price,direction"
+        );
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with(&format!(
+            "21836328,1739434799,{:?},0,",
+            H256::repeat_byte(2)
+        )));
+        assert!(lines[1]
+            .ends_with(",2000000000000000000000,-1999500000,2000,-1999.5,0.99975,DaiToUsdc"));
+        assert!(lines[2].ends_with(",2000,-1999.5,,DaiToUsdc"));
+
+        let parquet_path = dir.join("swaps.parquet");
+        let mut writer = ExportWriter::create(&parquet_path, ExportFormat::Parquet).unwrap();
+        writer.write(&rows[..1]).unwrap();
+        writer.write(&[]).unwrap();
+        writer.write(&rows[1..]).unwrap();
+        writer.finish().unwrap();
+        let reader = SerializedFileReader::new(File::open(&parquet_path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        let exported: Vec<Row> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(exported.len(), 2);
+        let row = &exported[0];
+        assert_eq!(row.get_ulong(0).unwrap(), 21836328);
+        assert_eq!(row.get_timestamp_millis(1).unwrap(), 1739434799000);
+        let transaction_hash = format!("{:?}", H256::repeat_byte(2));
+        assert_eq!(row.get_string(2).unwrap(), &transaction_hash);
+        assert_eq!(row.get_string(8).unwrap(), "-1999500000");
+        assert_eq!(row.get_double(10).unwrap(), -1999.5);
+        assert_eq!(row.get_double(11).unwrap(), 0.99975);
+        assert_eq!(row.get_string(12).unwrap(), "DaiToUsdc");
+        assert_eq!(exported[1].get_ulong(3).unwrap(), 1);
+        let (_, price) = exported[1].get_column_iter().nth(11).unwrap();
+        assert_eq!(price, &Field::Null);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
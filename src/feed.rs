@@ -0,0 +1,340 @@
+use crate::{
+    blocks_handler::{BlockEvent, ConfirmedBlock},
+    sinks::Sink,
+    swap_details::SwapDetails,
+};
+use async_trait::async_trait;
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use web3::types::{H160, H256};
+
+/// Confirmed swaps kept for clients resuming from a cursor, older ones are dropped
+const HISTORY_SIZE: usize = 10_000;
+/// Messages a client can fall behind before it's disconnected (it can resume from its cursor)
+const CLIENT_BUFFER: usize = 1024;
+
+/// Where a swap is on the chain, what clients resume from. The same across restarts of the monitor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Cursor {
+    pub block_number: u64,
+    pub log_index: u64,
+}
+
+/// A swap as pushed to clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwapUpdate {
+    pub cursor: Cursor,
+    pub block_hash: H256,
+    pub swap: SwapDetails,
+}
+
+/// Sent to clients as JSON, with the variant in `type`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ServerMessage {
+    /// Swap of a block which isn't confirmed yet. Not resumed from, a resuming client gets the pending ones again
+    Provisional(SwapUpdate),
+    /// Swap of a confirmed block, its cursor is what the client resumes from
+    Confirmed(SwapUpdate),
+    /// Swap reported as provisional before, of a block a reorg dropped
+    Retracted(SwapUpdate),
+    Error {
+        message: String,
+    },
+}
+
+impl ServerMessage {
+    fn update(&self) -> Option<&SwapUpdate> {
+        match self {
+            ServerMessage::Provisional(update)
+            | ServerMessage::Confirmed(update)
+            | ServerMessage::Retracted(update) => Some(update),
+            ServerMessage::Error { .. } => None,
+        }
+    }
+}
+
+/// Sent by clients as JSON, with the variant in `type`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientMessage {
+    /// Swaps of `pools` & swaps `addresses` sent or received (everything when both are empty), replacing the previous subscription.
+    /// With a `cursor` (of the last confirmed swap the client processed), the confirmed swaps after it are sent first,
+    /// then the provisional ones pending, to replace the ones the client has
+    Subscribe {
+        #[serde(default)]
+        pools: Vec<H160>,
+        #[serde(default)]
+        addresses: Vec<H160>,
+        cursor: Option<Cursor>,
+    },
+}
+
+struct Subscription {
+    pools: Vec<H160>,
+    addresses: Vec<H160>,
+    receiver: broadcast::Receiver<Arc<ServerMessage>>,
+}
+
+impl Subscription {
+    fn matches(&self, message: &ServerMessage) -> bool {
+        let Some(SwapUpdate { swap, .. }) = message.update() else {
+            return true;
+        };
+        (self.pools.is_empty() && self.addresses.is_empty())
+            || self.pools.contains(&swap.metadata.pool)
+            || self.addresses.contains(&swap.sender)
+            || self.addresses.contains(&swap.recipient)
+    }
+}
+
+#[derive(Default)]
+struct History {
+    confirmed: VecDeque<Arc<ServerMessage>>,
+    /// Provisional swaps of the blocks which are neither confirmed nor retracted yet
+    pending: Vec<Arc<ServerMessage>>,
+    /// Highest cursor of the dropped confirmed swaps, clients resuming from before it missed some
+    dropped_up_to: Option<Cursor>,
+    /// First block confirmed since the monitor started, the ones before were confirmed by an earlier run
+    first_block: Option<u64>,
+}
+
+impl History {
+    fn keep(&mut self, message: &Arc<ServerMessage>) {
+        match message.as_ref() {
+            ServerMessage::Provisional(_) => self.pending.push(message.clone()),
+            ServerMessage::Retracted(retracted) => self.pending.retain(|pending| {
+                pending
+                    .update()
+                    .is_some_and(|update| update.block_hash != retracted.block_hash)
+            }),
+            ServerMessage::Confirmed(confirmed) => {
+                self.pending.retain(|pending| {
+                    pending.update().is_some_and(|update| {
+                        update.cursor.block_number > confirmed.cursor.block_number
+                    })
+                });
+                self.first_block
+                    .get_or_insert(confirmed.cursor.block_number);
+                self.confirmed.push_back(message.clone());
+                if self.confirmed.len() > HISTORY_SIZE {
+                    let dropped = self.confirmed.pop_front();
+                    if let Some(update) = dropped.as_deref().and_then(ServerMessage::update) {
+                        self.dropped_up_to = self.dropped_up_to.max(Some(update.cursor));
+                    }
+                }
+            }
+            ServerMessage::Error { .. } => {}
+        }
+    }
+}
+
+/// Pushes provisional, confirmed & retracted swaps to WebSocket clients, as a sink of the monitor
+#[derive(Clone)]
+pub struct Feed {
+    history: Arc<Mutex<History>>,
+    sender: broadcast::Sender<Arc<ServerMessage>>,
+}
+
+impl Default for Feed {
+    fn default() -> Self {
+        Self {
+            history: Arc::default(),
+            sender: broadcast::channel(CLIENT_BUFFER).0,
+        }
+    }
+}
+
+impl Feed {
+    /// Serves the feed on `ws://<listen>/feed` in the background, returns the address it's bound to (for port 0)
+    pub fn serve(&self, listen: SocketAddr) -> Result<SocketAddr, anyhow::Error> {
+        let listener = TcpListener::bind(listen)
+            .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", listen, e))?;
+        let local_addr = listener.local_addr()?;
+        let app = Router::new()
+            .route("/feed", get(upgrade))
+            .with_state(self.clone());
+        let server = axum::Server::from_tcp(listener)?.serve(app.into_make_service());
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("Swaps feed failed: {}", err);
+            }
+        });
+        info!("Serving the swaps feed on ws://{}/feed", local_addr);
+        Ok(local_addr)
+    }
+
+    fn publish(&self, block: &ConfirmedBlock, to_message: fn(SwapUpdate) -> ServerMessage) {
+        let mut history = self.history.lock().unwrap();
+        for swap in &block.swaps {
+            let message = Arc::new(to_message(SwapUpdate {
+                cursor: Cursor {
+                    block_number: block.block_number,
+                    log_index: swap.metadata.log_index.unwrap_or_default(),
+                },
+                block_hash: block.block_hash,
+                swap: swap.clone(),
+            }));
+            history.keep(&message);
+            // no clients is fine
+            let _ = self.sender.send(message);
+        }
+    }
+
+    /// Confirmed swaps after `cursor`, the pending provisional ones & a receiver of the messages published from now on,
+    /// without a gap in between
+    fn subscribe(
+        &self,
+        cursor: Option<Cursor>,
+    ) -> (
+        Vec<Arc<ServerMessage>>,
+        broadcast::Receiver<Arc<ServerMessage>>,
+    ) {
+        let history = self.history.lock().unwrap();
+        let mut replay = vec![];
+        if let Some(cursor) = cursor {
+            if history
+                .dropped_up_to
+                .is_some_and(|dropped| dropped > cursor)
+            {
+                replay.push(Arc::new(ServerMessage::Error {
+                    message: format!(
+                        "swaps after block {} log {} are no longer kept, some were missed",
+                        cursor.block_number, cursor.log_index
+                    ),
+                }));
+            } else if let Some(first_block) = history
+                .first_block
+                .filter(|first_block| cursor.block_number.saturating_add(1) < *first_block)
+            {
+                replay.push(Arc::new(ServerMessage::Error {
+                    message: format!(
+                        "swaps before block {} were confirmed before a restart, some may have been missed",
+                        first_block
+                    ),
+                }));
+            }
+            replay.extend(
+                history
+                    .confirmed
+                    .iter()
+                    .chain(&history.pending)
+                    .filter(|message| {
+                        message
+                            .update()
+                            .is_some_and(|update| update.cursor > cursor)
+                    })
+                    .cloned(),
+            );
+        }
+        (replay, self.sender.subscribe())
+    }
+}
+
+#[async_trait]
+impl Sink for Feed {
+    async fn write(&mut self, confirmed_block: &ConfirmedBlock) -> Result<(), anyhow::Error> {
+        self.publish(confirmed_block, ServerMessage::Confirmed);
+        Ok(())
+    }
+
+    async fn write_event(&mut self, event: &BlockEvent) -> Result<(), anyhow::Error> {
+        match event {
+            BlockEvent::Provisional(block) => self.publish(block, ServerMessage::Provisional),
+            BlockEvent::Retracted(block) => self.publish(block, ServerMessage::Retracted),
+        }
+        Ok(())
+    }
+}
+
+async fn upgrade(ws: WebSocketUpgrade, State(feed): State<Feed>) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| serve_client(socket, feed))
+}
+
+async fn serve_client(mut socket: WebSocket, feed: Feed) {
+    let mut subscription: Option<Subscription> = None;
+    loop {
+        tokio::select! {
+            incoming = socket.recv() => {
+                let text = match incoming {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // pings are answered by the socket itself
+                    Some(Ok(_)) => continue,
+                };
+                let (pools, addresses, cursor) = match serde_json::from_str(&text) {
+                    Ok(ClientMessage::Subscribe { pools, addresses, cursor }) => {
+                        (pools, addresses, cursor)
+                    }
+                    Err(err) => {
+                        let message = format!("Invalid message: {}", err);
+                        if send(&mut socket, &ServerMessage::Error { message }).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                let (replay, receiver) = feed.subscribe(cursor);
+                let new_subscription = Subscription { pools, addresses, receiver };
+                for message in replay.iter().filter(|message| new_subscription.matches(message)) {
+                    if send(&mut socket, message).await.is_err() {
+                        return;
+                    }
+                }
+                subscription = Some(new_subscription);
+            }
+            received = next_message(&mut subscription) => {
+                let message = match received {
+                    Ok(message) => message,
+                    Err(RecvError::Lagged(missed)) => {
+                        let message = format!(
+                            "Fell behind by {} messages, reconnect & resume from your cursor",
+                            missed
+                        );
+                        let _ = send(&mut socket, &ServerMessage::Error { message }).await;
+                        break;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let matches = subscription
+                    .as_ref()
+                    .is_some_and(|subscription| subscription.matches(&message));
+                if matches && send(&mut socket, &message).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    debug!("feed client disconnected");
+}
+
+/// The next published message, never resolves before the client subscribes
+async fn next_message(
+    subscription: &mut Option<Subscription>,
+) -> Result<Arc<ServerMessage>, RecvError> {
+    match subscription {
+        Some(subscription) => subscription.receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &ServerMessage) -> Result<(), anyhow::Error> {
+    let json = serde_json::to_string(message)?;
+    socket.send(Message::Text(json)).await?;
+    Ok(())
+}
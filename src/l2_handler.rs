@@ -0,0 +1,644 @@
+use crate::{
+    blocks_handler::{BlockEvent, ConfirmedBlock, HeadsHandler, Reorg},
+    error::MonitorError,
+    events_handler::SwapsFetcher,
+    swap_details::SwapDetails,
+    web3_client::BlocksFetcher,
+};
+use async_trait::async_trait;
+use futures::{stream, StreamExt, TryStreamExt};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+use web3::types::{BlockHeader, BlockNumber, H256};
+
+/// Upper bound on in-flight `eth_getBlockByNumber` requests while catching up on blocks that weren't announced
+const MAX_CONCURRENT_HEADER_REQUESTS: usize = 8;
+
+/// The `safe` & `finalized` tags move with L1 (minutes), rather than with every (sub-second) L2 head
+const DEFAULT_TAG_REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How final a rollup block is, in the terms of the node's block tags
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockState {
+    /// Sequenced (`latest`), but not posted to L1 yet, so the sequencer can still drop it
+    Unsafe,
+    /// Posted to L1 (`safe`), dropped only by an L1 reorg
+    Safe,
+    /// Posted to a finalized L1 block (`finalized`)
+    Finalized,
+}
+
+impl BlockState {
+    /// `eth_getBlockByNumber` tag of the newest block in this state
+    pub fn tag(self) -> BlockNumber {
+        match self {
+            BlockState::Unsafe => BlockNumber::Latest,
+            BlockState::Safe => BlockNumber::Safe,
+            BlockState::Finalized => BlockNumber::Finalized,
+        }
+    }
+}
+
+/// What happened to a block (& its swaps) on the rollup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum L2Event {
+    /// The block reached `state`, e.g., provisional swaps for `Unsafe`
+    Advanced {
+        state: BlockState,
+        block: ConfirmedBlock,
+    },
+    /// The sequencer dropped the (unsafe) block, its swaps never happened
+    Retracted { block: ConfirmedBlock },
+}
+
+struct TrackedBlock {
+    hash: H256,
+    timestamp: u64,
+    state: BlockState,
+    swaps: Vec<SwapDetails>,
+}
+
+/// Follows the heads of a rollup (Arbitrum, Optimism, Base, ..), where blocks are final once their batch is on L1,
+/// not after some number of blocks. Blocks are tracked from the sequencer's head until the node tags them `finalized`.
+/// A new head which doesn't extend the tracked chain retracts the unsafe blocks after the fork point.
+pub struct L2BlocksHandler<T: BlocksFetcher, S: SwapsFetcher> {
+    blocks_fetcher: T,
+    swaps_fetcher: S,
+    /// Blocks reaching this state are the confirmed ones
+    confirm_at: BlockState,
+    tracked: BTreeMap<u64, TrackedBlock>,
+    tag_refresh_interval: Duration,
+    last_tag_refresh: Option<Instant>,
+    /// Retractions & replaced safe blocks, until `take_reorgs`
+    reorgs: Vec<Reorg>,
+    /// Unsafe & retracted blocks, until `take_events`
+    events: Vec<BlockEvent>,
+}
+
+impl<T: BlocksFetcher, S: SwapsFetcher> L2BlocksHandler<T, S> {
+    pub fn new(blocks_fetcher: T, swaps_fetcher: S, confirm_at: BlockState) -> Self {
+        Self {
+            blocks_fetcher,
+            swaps_fetcher,
+            confirm_at,
+            tracked: BTreeMap::new(),
+            tag_refresh_interval: DEFAULT_TAG_REFRESH_INTERVAL,
+            last_tag_refresh: None,
+            reorgs: vec![],
+            events: vec![],
+        }
+    }
+
+    /// How often the `safe` & `finalized` tags are requested (at most once per head)
+    pub fn with_tag_refresh_interval(mut self, tag_refresh_interval: Duration) -> Self {
+        self.tag_refresh_interval = tag_refresh_interval;
+        self
+    }
+
+    /// Tracks `block_header` (& the blocks before it which weren't announced) & moves tracked blocks along the tags.
+    /// Returns what happened, in order
+    pub async fn handle_block(
+        &mut self,
+        block_header: BlockHeader,
+    ) -> Result<Vec<L2Event>, anyhow::Error> {
+        let mut events = vec![];
+        let (Some(number), Some(hash)) = (block_header.number, block_header.hash) else {
+            let err = MonitorError::PendingBlock(block_header.number.map(|n| n.as_u64()));
+            warn!("skipping block: {}", err);
+            return Ok(events);
+        };
+        let number = number.as_u64();
+        info!("current block: {}, hash: {:?}", number, hash);
+
+        let already_tracked = self
+            .tracked
+            .get(&number)
+            .is_some_and(|tracked| tracked.hash == hash);
+        if !already_tracked {
+            self.track(block_header, &mut events).await?;
+        }
+        self.refresh_tags(&mut events).await?;
+        Ok(events)
+    }
+
+    async fn track(
+        &mut self,
+        block_header: BlockHeader,
+        events: &mut Vec<L2Event>,
+    ) -> Result<(), anyhow::Error> {
+        let mut queue = VecDeque::from([block_header]);
+        while let Some(header) = queue.pop_front() {
+            let (number, hash) = Self::number_and_hash(&header)?;
+            self.retract_conflicting(number, hash, header.parent_hash, events)
+                .await?;
+
+            // blocks the node didn't announce, or the ones of the new branch before `header`
+            let last_tracked = self.tracked.keys().last().copied();
+            if let Some(last_tracked) = last_tracked.filter(|last| number > last + 1) {
+                let missed = self.fetch_headers(last_tracked + 1, number - 1).await?;
+                queue.push_front(header);
+                for missed_header in missed.into_iter().rev() {
+                    queue.push_front(missed_header);
+                }
+                continue;
+            }
+
+            let swaps = self.swaps_fetcher.fetch_swaps(hash).await?;
+            self.tracked.insert(
+                number,
+                TrackedBlock {
+                    hash,
+                    timestamp: header.timestamp.low_u64(),
+                    state: BlockState::Unsafe,
+                    swaps,
+                },
+            );
+            events.push(L2Event::Advanced {
+                state: BlockState::Unsafe,
+                block: self.block(number),
+            });
+        }
+        Ok(())
+    }
+
+    /// Retracts the tracked blocks a block `number` (child of `parent_hash`) replaces, i.e., the ones after the fork point
+    async fn retract_conflicting(
+        &mut self,
+        number: u64,
+        hash: H256,
+        parent_hash: H256,
+        events: &mut Vec<L2Event>,
+    ) -> Result<(), anyhow::Error> {
+        let extends_parent = self
+            .tracked
+            .get(&(number - 1))
+            .is_none_or(|parent| parent.hash == parent_hash);
+        if extends_parent && self.tracked.range(number..).next().is_none() {
+            return Ok(());
+        }
+
+        // walk back until a tracked block is still canonical
+        let mut fork_point = number - 1;
+        let mut new_hashes = vec![hash];
+        loop {
+            let Some(tracked) = self.tracked.get(&fork_point) else {
+                let oldest_tracked = self.tracked.keys().next().copied().unwrap_or(number);
+                if fork_point < oldest_tracked {
+                    return Err(MonitorError::DeepReorg {
+                        block_number: number,
+                        oldest_tracked,
+                    }
+                    .into());
+                }
+                // above the tracked blocks, so it's on the new branch only
+                fork_point -= 1;
+                continue;
+            };
+            let canonical_hash = if fork_point == number - 1 {
+                parent_hash
+            } else {
+                self.blocks_fetcher.get_block_hash(fork_point).await?
+            };
+            if canonical_hash == tracked.hash {
+                break;
+            }
+            new_hashes.push(canonical_hash);
+            if tracked.state != BlockState::Unsafe {
+                return Err(MonitorError::ReorgDetected {
+                    block_number: fork_point,
+                    previous_hash: tracked.hash,
+                    new_hash: canonical_hash,
+                }
+                .into());
+            }
+            fork_point -= 1;
+        }
+
+        // a block on the same parent skips the walk above, yet it can replace safe blocks too
+        if let Some((&replaced, previous_hash)) = self
+            .tracked
+            .range(fork_point + 1..)
+            .find(|(_, tracked)| tracked.state != BlockState::Unsafe)
+            .map(|(number, tracked)| (number, tracked.hash))
+        {
+            let new_hash = if replaced == number {
+                hash
+            } else {
+                self.blocks_fetcher.get_block_hash(replaced).await?
+            };
+            return Err(MonitorError::ReorgDetected {
+                block_number: replaced,
+                previous_hash,
+                new_hash,
+            }
+            .into());
+        }
+
+        let retracted: Vec<u64> = self
+            .tracked
+            .range(fork_point + 1..)
+            .map(|(number, _)| *number)
+            .collect();
+        new_hashes.reverse();
+        let orphaned_hashes: Vec<H256> = retracted
+            .iter()
+            .map(|number| self.tracked[number].hash)
+            .collect();
+        self.reorgs.push(Reorg {
+            detected_at: number,
+            fork_point,
+            depth: orphaned_hashes.len() as u64,
+            orphaned_hashes,
+            new_hashes,
+        });
+        // newest first, undoing the blocks in reverse
+        for retracted_number in retracted.into_iter().rev() {
+            let block = self.block(retracted_number);
+            self.tracked.remove(&retracted_number);
+            warn!(
+                "↩️ block: {} ({:?}) retracted by the sequencer, {} swaps dropped",
+                retracted_number,
+                block.block_hash,
+                block.swaps.len()
+            );
+            events.push(L2Event::Retracted { block });
+        }
+        Ok(())
+    }
+
+    /// Moves the tracked blocks up to the `safe` & `finalized` tags along, then forgets the finalized ones
+    async fn refresh_tags(&mut self, events: &mut Vec<L2Event>) -> Result<(), anyhow::Error> {
+        if self
+            .last_tag_refresh
+            .is_some_and(|refreshed| refreshed.elapsed() < self.tag_refresh_interval)
+        {
+            return Ok(());
+        }
+        self.last_tag_refresh = Some(Instant::now());
+
+        for state in [BlockState::Safe, BlockState::Finalized] {
+            let Some(tagged) = self
+                .blocks_fetcher
+                .get_block_header_by_tag(state.tag())
+                .await?
+            else {
+                continue;
+            };
+            let (tagged_number, tagged_hash) = Self::number_and_hash(&tagged)?;
+            if let Some(tracked) = self.tracked.get(&tagged_number) {
+                if tracked.hash != tagged_hash {
+                    return Err(MonitorError::ReorgDetected {
+                        block_number: tagged_number,
+                        previous_hash: tracked.hash,
+                        new_hash: tagged_hash,
+                    }
+                    .into());
+                }
+            }
+
+            let advanced: Vec<u64> = self
+                .tracked
+                .range(..=tagged_number)
+                .filter(|(_, tracked)| tracked.state < state)
+                .map(|(number, _)| *number)
+                .collect();
+            for number in advanced {
+                if let Some(tracked) = self.tracked.get_mut(&number) {
+                    tracked.state = state;
+                }
+                debug!("block: {} is {:?}", number, state);
+                events.push(L2Event::Advanced {
+                    state,
+                    block: self.block(number),
+                });
+            }
+
+            if state == BlockState::Finalized {
+                // the newest block is kept either way, the next head has to link to it
+                let keep_from = self
+                    .tracked
+                    .keys()
+                    .last()
+                    .map_or(tagged_number, |last| tagged_number.min(*last));
+                self.tracked = self.tracked.split_off(&keep_from);
+            }
+        }
+        Ok(())
+    }
+
+    async fn fetch_headers(&self, from: u64, to: u64) -> Result<Vec<BlockHeader>, anyhow::Error> {
+        info!("catching up on blocks: {} - {}", from, to);
+        stream::iter(from..=to)
+            .map(|block_num| self.blocks_fetcher.get_block_header(block_num))
+            .buffered(MAX_CONCURRENT_HEADER_REQUESTS)
+            .try_collect()
+            .await
+    }
+
+    fn block(&self, number: u64) -> ConfirmedBlock {
+        let tracked = &self.tracked[&number];
+        ConfirmedBlock {
+            block_number: number,
+            block_hash: tracked.hash,
+            timestamp: tracked.timestamp,
+            swaps: tracked.swaps.clone(),
+        }
+    }
+
+    fn number_and_hash(header: &BlockHeader) -> Result<(u64, H256), MonitorError> {
+        match (header.number, header.hash) {
+            (Some(number), Some(hash)) => Ok((number.as_u64(), hash)),
+            (number, _) => Err(MonitorError::PendingBlock(number.map(|n| n.as_u64()))),
+        }
+    }
+}
+
+/// The blocks reaching `confirm_at` are the confirmed ones, the other events are logged
+#[async_trait]
+impl<T, S> HeadsHandler for L2BlocksHandler<T, S>
+where
+    T: BlocksFetcher + Send + Sync,
+    S: SwapsFetcher + Send + Sync,
+{
+    async fn handle_head(
+        &mut self,
+        block_header: BlockHeader,
+    ) -> Result<Vec<ConfirmedBlock>, anyhow::Error> {
+        let confirm_at = self.confirm_at;
+        let head_number = block_header.number.map(|n| n.as_u64());
+        let events = match self.handle_block(block_header).await {
+            Ok(events) => events,
+            Err(err) => {
+                if let Some(MonitorError::ReorgDetected {
+                    block_number,
+                    new_hash,
+                    ..
+                }) = err.downcast_ref::<MonitorError>()
+                {
+                    // a safe (or finalized) block was replaced, the blocks after it are unknown as of now
+                    let orphaned_hashes: Vec<H256> = self
+                        .tracked
+                        .range(block_number..)
+                        .map(|(_, tracked)| tracked.hash)
+                        .collect();
+                    self.reorgs.push(Reorg {
+                        detected_at: head_number.unwrap_or(*block_number),
+                        fork_point: block_number - 1,
+                        depth: orphaned_hashes.len() as u64,
+                        orphaned_hashes,
+                        new_hashes: vec![*new_hash],
+                    });
+                }
+                return Err(err);
+            }
+        };
+        let mut confirmed_blocks = vec![];
+        for event in events {
+            match event {
+                L2Event::Advanced { state, block } if state == confirm_at => {
+                    confirmed_blocks.push(block)
+                }
+                // swaps the sequencer could still drop
+                L2Event::Advanced {
+                    state: BlockState::Unsafe,
+                    block,
+                } => {
+                    if !block.swaps.is_empty() {
+                        info!(
+                            "block: {} is unsafe, with {} swaps",
+                            block.block_number,
+                            block.swaps.len()
+                        );
+                    }
+                    self.events.push(BlockEvent::Provisional(block));
+                }
+                L2Event::Advanced { state, block } if !block.swaps.is_empty() => {
+                    info!(
+                        "block: {} is {:?}, with {} swaps",
+                        block.block_number,
+                        state,
+                        block.swaps.len()
+                    );
+                }
+                L2Event::Advanced { .. } => {}
+                L2Event::Retracted { block } => self.events.push(BlockEvent::Retracted(block)),
+            }
+        }
+        Ok(confirmed_blocks)
+    }
+
+    fn take_reorgs(&mut self) -> Vec<Reorg> {
+        std::mem::take(&mut self.reorgs)
+    }
+
+    fn take_events(&mut self) -> Vec<BlockEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn tracked_window(&self) -> Vec<(u64, H256)> {
+        self.tracked
+            .iter()
+            .map(|(number, tracked)| (*number, tracked.hash))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{events_handler::MockSwapsFetcher, web3_client::MockBlocksFetcher};
+    use web3::types::U64;
+
+    fn header(number: u64, hash: u64, parent_hash: u64) -> BlockHeader {
+        let json = serde_json::json!({
+            "hash": H256::from_low_u64_be(hash),
+            "parentHash": H256::from_low_u64_be(parent_hash),
+            "number": U64::from(number),
+            "sha3Uncles": H256::zero(),
+            "miner": "0x0000000000000000000000000000000000000000",
+            "stateRoot": H256::zero(),
+            "transactionsRoot": H256::zero(),
+            "receiptsRoot": H256::zero(),
+            "gasUsed": "0x0",
+            "gasLimit": "0x0",
+            "extraData": "0x",
+            "logsBloom": format!("0x{}", "0".repeat(512)),
+            "timestamp": "0x0",
+            "difficulty": "0x0",
+        });
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn no_swaps() -> MockSwapsFetcher {
+        let mut swaps_fetcher = MockSwapsFetcher::new();
+        swaps_fetcher
+            .expect_fetch_swaps()
+            .returning(|_| Box::pin(async { Ok(vec![]) }));
+        swaps_fetcher
+    }
+
+    fn without_tags(blocks_fetcher: &mut MockBlocksFetcher) {
+        blocks_fetcher
+            .expect_get_block_header_by_tag()
+            .returning(|_| Box::pin(async { Ok(None) }));
+    }
+
+    fn summary(events: &[L2Event]) -> Vec<(&'static str, u64)> {
+        events
+            .iter()
+            .map(|event| match event {
+                L2Event::Advanced { state, block } => {
+                    let state = match state {
+                        BlockState::Unsafe => "unsafe",
+                        BlockState::Safe => "safe",
+                        BlockState::Finalized => "finalized",
+                    };
+                    (state, block.block_number)
+                }
+                L2Event::Retracted { block } => ("retracted", block.block_number),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_sequencer_reorg_retracts_unsafe_blocks() {
+        let mut blocks_fetcher = MockBlocksFetcher::new();
+        without_tags(&mut blocks_fetcher);
+        let mut handler = L2BlocksHandler::new(blocks_fetcher, no_swaps(), BlockState::Safe)
+            .with_tag_refresh_interval(Duration::ZERO);
+
+        for number in 10..=13 {
+            handler
+                .handle_block(header(number, number, number - 1))
+                .await
+                .unwrap();
+        }
+
+        // 12' on top of 11, announced after 13
+        let events = handler.handle_block(header(12, 1012, 11)).await.unwrap();
+        assert_eq!(
+            summary(&events),
+            vec![("retracted", 13), ("retracted", 12), ("unsafe", 12)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocks_advance_along_the_tags() {
+        let mut blocks_fetcher = MockBlocksFetcher::new();
+        without_tags(&mut blocks_fetcher);
+        // 11 & 12 weren't announced
+        blocks_fetcher
+            .expect_get_block_header()
+            .returning(|number| Box::pin(async move { Ok(header(number, number, number - 1)) }));
+        let mut handler = L2BlocksHandler::new(blocks_fetcher, no_swaps(), BlockState::Safe)
+            .with_tag_refresh_interval(Duration::ZERO);
+
+        handler.handle_block(header(10, 10, 9)).await.unwrap();
+        let confirmed = handler.handle_head(header(13, 13, 12)).await.unwrap();
+        // nothing is safe yet
+        assert!(confirmed.is_empty());
+
+        let mut blocks_fetcher = MockBlocksFetcher::new();
+        blocks_fetcher
+            .expect_get_block_header_by_tag()
+            .returning(|tag| {
+                let tagged = match tag {
+                    BlockNumber::Safe => header(12, 12, 11),
+                    _ => header(11, 11, 10),
+                };
+                Box::pin(async move { Ok(Some(tagged)) })
+            });
+        handler.blocks_fetcher = blocks_fetcher;
+        let events = handler.handle_block(header(14, 14, 13)).await.unwrap();
+        assert_eq!(
+            summary(&events),
+            vec![
+                ("unsafe", 14),
+                ("safe", 10),
+                ("safe", 11),
+                ("safe", 12),
+                ("finalized", 10),
+                ("finalized", 11),
+            ]
+        );
+        // finalized blocks are forgotten, except the newest one
+        assert_eq!(
+            handler.tracked.keys().copied().collect::<Vec<_>>(),
+            vec![11, 12, 13, 14]
+        );
+
+        // a finalized block can't be retracted
+        let err = handler.handle_block(header(12, 1012, 1011)).await;
+        assert!(err.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_safe_block_replaced_is_an_error() {
+        let mut blocks_fetcher = MockBlocksFetcher::new();
+        blocks_fetcher
+            .expect_get_block_header_by_tag()
+            .returning(|tag| {
+                let tagged = (tag == BlockNumber::Safe).then(|| header(10, 1010, 9));
+                Box::pin(async move { Ok(tagged) })
+            });
+        let mut handler = L2BlocksHandler::new(blocks_fetcher, no_swaps(), BlockState::Safe)
+            .with_tag_refresh_interval(Duration::ZERO);
+
+        let err = handler.handle_block(header(10, 10, 9)).await.unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<MonitorError>(),
+                Some(MonitorError::ReorgDetected {
+                    block_number: 10,
+                    ..
+                })
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_competing_block_at_a_safe_height_is_an_error() {
+        let mut blocks_fetcher = MockBlocksFetcher::new();
+        blocks_fetcher
+            .expect_get_block_header_by_tag()
+            .returning(|tag| {
+                let tagged = (tag == BlockNumber::Safe).then(|| header(12, 12, 11));
+                Box::pin(async move { Ok(tagged) })
+            });
+        let mut handler = L2BlocksHandler::new(blocks_fetcher, no_swaps(), BlockState::Safe)
+            .with_tag_refresh_interval(Duration::ZERO);
+        for number in 11..=14 {
+            handler
+                .handle_block(header(number, number, number - 1))
+                .await
+                .unwrap();
+        }
+        assert_eq!(handler.tracked[&12].state, BlockState::Safe);
+
+        // 12' on the same parent as the safe 12
+        let err = handler
+            .handle_block(header(12, 1012, 11))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<MonitorError>(),
+                Some(MonitorError::ReorgDetected {
+                    block_number: 12,
+                    ..
+                })
+            ),
+            "{}",
+            err
+        );
+        assert_eq!(handler.tracked[&12].hash, H256::from_low_u64_be(12));
+    }
+}
@@ -1,18 +1,206 @@
+pub mod alerts;
+pub mod api;
 pub mod blocks_handler;
+pub mod candles;
+pub mod cassette;
+pub mod checkpoint;
+pub mod config;
+pub mod error;
 pub mod events_handler;
+pub mod events_source;
+pub mod export;
+pub mod feed;
+pub mod l2_handler;
+pub mod metrics;
+pub mod networks;
+pub mod postgres;
+pub mod range_verifier;
+pub mod sinks;
+pub mod store;
 pub mod swap_details;
 pub mod web3_client;
+pub mod webhook;
 
-use dotenv::dotenv;
-use std::env;
-use web3::{error::Error as Web3Error, transports::WebSocket, Web3};
+use crate::{
+    blocks_handler::HeadsHandler,
+    config::Config,
+    error::MonitorError,
+    sinks::{ProviderOutage, Sink},
+};
+use futures::StreamExt;
+use log::{info, warn};
+use std::future::Future;
+use web3::{transports::WebSocket, DuplexTransport, Transport, Web3};
 
 pub const BLOCK_CONFIRMATIONS: u64 = 5;
 
-pub async fn setup_web3() -> Result<Web3<WebSocket>, Web3Error> {
-    dotenv().ok();
+/// Connects to the first endpoint of `config` (primary, then fallbacks) that accepts the connection.
+/// An endpoint on another chain than `config.chain_id` is a misconfiguration, so it fails right away
+pub async fn setup_web3(config: &Config) -> Result<Web3<WebSocket>, MonitorError> {
+    let mut last_err = None;
+    for endpoint in config.endpoints()? {
+        match connect(endpoint).await {
+            Ok(web3) => {
+                verify_chain_id(&web3, config.chain_id).await?;
+                return Ok(web3);
+            }
+            Err(err) => {
+                warn!("Failed to connect to an endpoint: {}", err);
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.expect("at least the primary endpoint is tried"))
+}
 
-    let ws_endpoint = env::var("WEBSOCKET_ENDPOINT").expect("Couldn't load WEBSOCKET_ENDPOINT");
-    let transport = WebSocket::new(ws_endpoint.as_str()).await?;
+pub async fn connect(ws_endpoint: &str) -> Result<Web3<WebSocket>, MonitorError> {
+    let transport = WebSocket::new(ws_endpoint).await?;
     Ok(Web3::new(transport))
 }
+
+/// Checks `eth_chainId` of the node against the `expected` one
+pub async fn verify_chain_id<T: Transport>(
+    web3: &Web3<T>,
+    expected: u64,
+) -> Result<(), MonitorError> {
+    let actual = web3.eth().chain_id().await?.as_u64();
+    if actual != expected {
+        return Err(MonitorError::ChainIdMismatch { expected, actual });
+    }
+    info!("Connected to chain {}", networks::describe_chain(actual));
+    Ok(())
+}
+
+/// Subscribes to new heads & feeds them to `heads_handler` until `shutdown` resolves, the subscription ends or a block fails.
+/// Every confirmed block (& every reorg, provisional or retracted block the handler reports) is written to `sink`, an error from it stops the monitor.
+/// The subscription ending or failing, like a failed request to the node, is reported to `sink` as a `ProviderOutage`.
+/// A head being handled when `shutdown` resolves is finished (& its confirmed blocks written) first,
+/// then `sink` is flushed, so e.g. `BlocksHandler::checkpoint()` picks up right after the last written block.
+pub async fn monitor_new_heads<T, H, K>(
+    web3: &Web3<T>,
+    heads_handler: &mut H,
+    sink: &mut K,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), anyhow::Error>
+where
+    T: DuplexTransport,
+    T::NotificationStream: Unpin,
+    H: HeadsHandler + ?Sized,
+    K: Sink + ?Sized,
+{
+    let mut last_head = None;
+    let mut block_stream = match web3.eth_subscribe().subscribe_new_heads().await {
+        Ok(block_stream) => block_stream,
+        Err(err) => {
+            report_outage(sink, last_head, err.to_string()).await?;
+            return Err(err.into());
+        }
+    };
+    tokio::pin!(shutdown);
+    loop {
+        let next = tokio::select! {
+            _ = &mut shutdown => None,
+            next = block_stream.next() => Some(next),
+        };
+        let block_header = match next {
+            Some(Some(Ok(block_header))) => block_header,
+            Some(Some(Err(err))) => {
+                report_outage(sink, last_head, err.to_string()).await?;
+                break;
+            }
+            Some(None) => {
+                report_outage(
+                    sink,
+                    last_head,
+                    "subscription to new heads ended".to_string(),
+                )
+                .await?;
+                break;
+            }
+            None => {
+                info!("Shutting down, no more heads are handled");
+                // the node may be gone already, there's nothing to recover from
+                if let Err(err) = block_stream.unsubscribe().await {
+                    warn!("Failed to unsubscribe from new heads: {}", err);
+                }
+                break;
+            }
+        };
+        let head_number = block_header.number.map(|number| number.as_u64());
+        let result = heads_handler.handle_head(block_header).await;
+        for event in heads_handler.take_events() {
+            sink.write_event(&event).await?;
+        }
+        for reorg in heads_handler.take_reorgs() {
+            sink.write_reorg(&reorg).await?;
+        }
+        let confirmed_blocks = match result {
+            Ok(confirmed_blocks) => confirmed_blocks,
+            Err(err) => {
+                if is_provider_error(&err) {
+                    report_outage(sink, last_head, format!("{:#}", err)).await?;
+                }
+                // the reorg (or outage) is recorded, even though the monitor stops
+                sink.flush().await?;
+                return Err(anyhow::Error::msg(err));
+            }
+        };
+        last_head = head_number.or(last_head);
+        for confirmed_block in &confirmed_blocks {
+            sink.write(confirmed_block).await?;
+        }
+    }
+
+    sink.flush().await
+}
+
+async fn report_outage<K: Sink + ?Sized>(
+    sink: &mut K,
+    last_head: Option<u64>,
+    error: String,
+) -> Result<(), anyhow::Error> {
+    warn!("Provider outage (last head: {:?}): {}", last_head, error);
+    sink.write_outage(&ProviderOutage { last_head, error })
+        .await
+}
+
+/// Whether `err` came from the node (or the connection to it), rather than from the data it served
+pub fn is_provider_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.is::<web3::Error>()
+            || matches!(
+                cause.downcast_ref::<MonitorError>(),
+                Some(MonitorError::Transport(_))
+            )
+    })
+}
+
+/// Resolves on the first SIGINT (Ctrl-C) or SIGTERM
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", err);
+            std::future::pending::<()>().await
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                warn!("Failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
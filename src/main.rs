@@ -1,24 +1,616 @@
-use futures::StreamExt;
+use anyhow::{anyhow, bail};
+use clap::{Args, Parser, Subcommand};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use uniswap_dai_usd_monitor::alerts::{self, AlertingSink};
+use uniswap_dai_usd_monitor::api::{ApiState, SharedStatus, StatusHeadsHandler};
 use uniswap_dai_usd_monitor::blocks_handler::BlocksHandler;
-use uniswap_dai_usd_monitor::web3_client::Web3BlocksFetcher;
-use uniswap_dai_usd_monitor::{setup_web3, BLOCK_CONFIRMATIONS};
+use uniswap_dai_usd_monitor::checkpoint::Checkpoint;
+use uniswap_dai_usd_monitor::config::Config;
+use uniswap_dai_usd_monitor::error::MonitorError;
+use uniswap_dai_usd_monitor::events_handler::EventsHandler;
+use uniswap_dai_usd_monitor::events_source::Web3EventsSource;
+use uniswap_dai_usd_monitor::export::{
+    first_block_at_or_after, last_block_at_or_before, swap_rows, ExportFormat, ExportWriter,
+};
+use uniswap_dai_usd_monitor::feed::Feed;
+use uniswap_dai_usd_monitor::l2_handler::L2BlocksHandler;
+use uniswap_dai_usd_monitor::metrics::{MeteredHeadsHandler, MeteredTransport, Metrics};
+use uniswap_dai_usd_monitor::range_verifier::verify_range;
+use uniswap_dai_usd_monitor::sinks::{
+    build_sinks, write_swap, OutputFormat, ProviderOutage, Sink, WriterSink,
+};
+use uniswap_dai_usd_monitor::store::Store;
+use uniswap_dai_usd_monitor::swap_details::SwapDetails;
+use uniswap_dai_usd_monitor::web3_client::{BlocksFetcher, Web3BlocksFetcher};
+use uniswap_dai_usd_monitor::{monitor_new_heads, setup_web3, shutdown_signal};
+use web3::transports::WebSocket;
+use web3::types::{H160, H256};
+use web3::Web3;
+
+/// Loaded when it exists & `--config` isn't given
+const DEFAULT_CONFIG_PATH: &str = "monitor.toml";
+
+/// Monitors swaps of Uniswap V3 pools (DAI/USDC by default), reporting them once their block is confirmed
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Cli {
+    /// Config file (see `monitor.example.toml`), `monitor.toml` is used when it exists
+    #[arg(long, global = true, env = "MONITOR_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// WebSocket endpoint of the node, instead of the configured ones
+    #[arg(long, global = true)]
+    endpoint: Option<String>,
+
+    /// Addresses of configured pools to watch, all of them by default
+    #[arg(long = "pool", global = true, value_delimiter = ',')]
+    pools: Vec<H160>,
+
+    /// Blocks on top of a block before its swaps are reported, instead of the configured ones
+    #[arg(long, global = true)]
+    confirmations: Option<u64>,
+
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Instead of the configured one (`RUST_LOG` takes precedence), logs go to stderr & swaps to stdout
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Follows new heads & reports swaps once their block is confirmed
+    Monitor,
+    /// Reports swaps of already confirmed blocks
+    Backfill(BatchedRangeArgs),
+    /// Checks that the parent hashes of a historic range of blocks link up
+    VerifyRange(RangeArgs),
+    /// Reports swaps of a single transaction
+    DecodeTx {
+        /// Transaction hash
+        hash: H256,
+    },
+    /// Writes swaps of already confirmed blocks to a CSV or Parquet file (other files get them in `--format`)
+    Export(ExportArgs),
+}
+
+#[derive(Debug, Args)]
+struct RangeArgs {
+    /// First block of the range
+    #[arg(long)]
+    from: u64,
+
+    /// Last block of the range (inclusive), the latest confirmed block by default
+    #[arg(long)]
+    to: Option<u64>,
+}
+
+#[derive(Debug, Args)]
+struct BatchedRangeArgs {
+    #[command(flatten)]
+    range: RangeArgs,
+
+    /// Blocks per `eth_getLogs` request, providers limit the range of a single request
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    batch_size: u64,
+}
+
+#[derive(Debug, Args)]
+struct ExportArgs {
+    /// First block of the range
+    #[arg(
+        long,
+        required_unless_present = "from_time",
+        conflicts_with = "from_time"
+    )]
+    from: Option<u64>,
+
+    /// Last block of the range (inclusive), the latest confirmed block by default
+    #[arg(long, conflicts_with = "to_time")]
+    to: Option<u64>,
+
+    /// Starts at the first block mined at or after this unix time (seconds), instead of `--from`
+    #[arg(long)]
+    from_time: Option<u64>,
+
+    /// Ends at the last block mined at or before this unix time (seconds), instead of `--to`
+    #[arg(long)]
+    to_time: Option<u64>,
+
+    /// Blocks per `eth_getLogs` request, providers limit the range of a single request
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    batch_size: u64,
+
+    #[arg(long)]
+    output: PathBuf,
+
+    /// Inferred from the `--output` extension (`.csv` / `.parquet`) by default
+    #[arg(long, value_enum)]
+    file_format: Option<ExportFormat>,
+}
+
+/// Requests to the node are timed for the metrics, whether or not they're served
+type Node = Web3<MeteredTransport<WebSocket>>;
+type Handlers = Vec<EventsHandler<Web3EventsSource<MeteredTransport<WebSocket>>>>;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
-    log::info!("🚀 App launched. Fetching blocks...");
+    // `.env` has to be loaded before the config, so env vars can come from it
+    dotenv::dotenv().ok();
+    let cli = Cli::parse();
+    let config = load_config(&cli)?;
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&config.log_level))
+        .init();
+
+    let metrics = Metrics::new(&config.pools)?;
+    let web3 = match setup_web3(&config).await {
+        Ok(web3) => Web3::new(MeteredTransport::new(
+            web3.transport().clone(),
+            metrics.clone(),
+        )),
+        Err(err) => {
+            if matches!(cli.command, Command::Monitor) && matches!(err, MonitorError::Transport(_))
+            {
+                report_outage(&config, &err).await;
+            }
+            return Err(err.into());
+        }
+    };
+    let handlers = events_handlers(&web3, &config)?;
+
+    match &cli.command {
+        Command::Monitor => monitor(&cli, &config, web3, handlers, metrics).await,
+        Command::Backfill(args) => {
+            let mut stdout = io::stdout().lock();
+            backfill(&cli, &config, &web3, &handlers, args, &mut stdout).await
+        }
+        Command::VerifyRange(args) => verify(&cli, &config, web3, args).await,
+        Command::DecodeTx { hash } => decode_tx(&cli, &config, &web3, &handlers, *hash).await,
+        Command::Export(args) => export(&cli, &config, &web3, &handlers, args).await,
+    }
+}
+
+/// The config file (& env vars), with the flags applied on top
+fn load_config(cli: &Cli) -> Result<Config, MonitorError> {
+    let default_path = Path::new(DEFAULT_CONFIG_PATH);
+    let path = match &cli.config {
+        Some(path) => Some(path.as_path()),
+        None => default_path.exists().then_some(default_path),
+    };
+    let mut config = Config::load(path)?;
+
+    if let Some(endpoint) = &cli.endpoint {
+        config.endpoints.primary = Some(endpoint.clone());
+        config.endpoints.fallbacks.clear();
+    }
+    if let Some(confirmations) = cli.confirmations {
+        config.confirmations.blocks = Some(confirmations);
+    }
+    if let Some(log_level) = &cli.log_level {
+        config.log_level = log_level.clone();
+    }
+    if !cli.pools.is_empty() {
+        // token decimals come from the config, so only configured pools can be picked
+        let pools = cli
+            .pools
+            .iter()
+            .map(|address| {
+                config.pool(*address).cloned().ok_or_else(|| {
+                    MonitorError::InvalidConfig(format!(
+                        "--pool {:?} isn't configured, add it (with its tokens) to the config file",
+                        address
+                    ))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        config.pools = pools;
+    }
 
-    let web3 = setup_web3().await?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// One handler per configured pool
+fn events_handlers(web3: &Node, config: &Config) -> Result<Handlers, anyhow::Error> {
+    config
+        .pools
+        .iter()
+        .map(|pool| EventsHandler::for_pool(Web3EventsSource { web3: web3.clone() }, pool))
+        .collect()
+}
+
+/// None of the endpoints could be connected to, the sinks are told before the monitor exits
+async fn report_outage(config: &Config, err: &MonitorError) {
+    let outage = ProviderOutage {
+        last_head: None,
+        error: err.to_string(),
+    };
+    let result = async {
+        let mut sinks = build_sinks(&config.sinks, config.chain_id).await?;
+        sinks.write_outage(&outage).await?;
+        sinks.flush().await
+    };
+    if let Err(sink_err) = result.await {
+        log::warn!("Failed to report the outage to the sinks: {}", sink_err);
+    }
+}
+
+async fn monitor(
+    cli: &Cli,
+    config: &Config,
+    web3: Node,
+    handlers: Handlers,
+    metrics: Metrics,
+) -> Result<(), anyhow::Error> {
+    if let Some(listen) = config.metrics.listen {
+        metrics.serve(listen)?;
+    }
+    log::info!("🚀 App launched. Fetching blocks...");
     let web3_blocks_fetcher = Web3BlocksFetcher { web3: web3.clone() };
-    let mut blocks_handler = BlocksHandler::new(BLOCK_CONFIRMATIONS, web3_blocks_fetcher)?;
+    let mut sinks: Vec<Box<dyn Sink>> = vec![Box::new(WriterSink::new(io::stdout(), cli.format))];
+    sinks.extend(build_sinks(&config.sinks, config.chain_id).await?);
+
+    let status = SharedStatus::default();
+    if let Some(listen) = config.api.listen {
+        // `validate` checked there's one, & the sink created it
+        let path = config.sqlite_path().expect("the API needs a sqlite sink");
+        ApiState::new(Store::open(path)?, status.clone(), config.pools.clone()).serve(listen)?;
+    }
+    if let Some(listen) = config.feed.listen {
+        let feed = Feed::default();
+        feed.serve(listen)?;
+        sinks.push(Box::new(feed));
+    }
+    let mut sinks = AlertingSink::new(sinks, alerts::rules(&config.alerts, &config.pools)?);
 
-    let mut block_stream = web3.eth_subscribe().subscribe_new_heads().await?;
-    while let Some(Ok(block_header)) = block_stream.next().await {
-        blocks_handler
-            .handle_block(block_header)
-            .await
-            .map_err(anyhow::Error::msg)?;
+    if let Some(confirm_at) = config.confirmation_mode().block_state() {
+        log::info!("Swaps are reported once their block is {:?}", confirm_at);
+        // tracked blocks aren't checkpointed, a restart picks up from the new head (see `backfill` for the gap)
+        let l2_handler = L2BlocksHandler::new(web3_blocks_fetcher, handlers, confirm_at);
+        let mut l2_handler =
+            MeteredHeadsHandler::new(StatusHeadsHandler::new(l2_handler, status), metrics);
+        return monitor_new_heads(&web3, &mut l2_handler, &mut sinks, shutdown_signal()).await;
     }
 
+    let mut blocks_handler =
+        BlocksHandler::new(config.confirmation_blocks(), web3_blocks_fetcher, handlers)?
+            .with_missing_data_policy(config.confirmations.missing_data_policy());
+    if config.feed.listen.is_some() {
+        blocks_handler = blocks_handler.with_provisional_swaps();
+    }
+    if let Some(checkpoint) = Checkpoint::take(&config.checkpoint)? {
+        log::info!(
+            "Resuming from {} (blocks {} - {})",
+            config.checkpoint,
+            checkpoint.starting_block_number,
+            checkpoint.last_block_number().unwrap_or_default()
+        );
+        blocks_handler = blocks_handler.with_checkpoint(checkpoint);
+    }
+
+    let mut observed_handler =
+        MeteredHeadsHandler::new(StatusHeadsHandler::new(blocks_handler, status), metrics);
+    let result =
+        monitor_new_heads(&web3, &mut observed_handler, &mut sinks, shutdown_signal()).await;
+    let blocks_handler = observed_handler.into_inner().into_inner();
+
+    // after an error too, the blocks written by then aren't repeated by a restart
+    let saved = blocks_handler.checkpoint().save(&config.checkpoint);
+    result?;
+    saved?;
+    log::info!("✅ Saved the tracked blocks to {}", config.checkpoint);
+    Ok(())
+}
+
+async fn backfill(
+    cli: &Cli,
+    config: &Config,
+    web3: &Node,
+    handlers: &Handlers,
+    args: &BatchedRangeArgs,
+    out: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    let (from, to) = confirmed_range(config, web3, &args.range).await?;
+    for (batch_from, batch_to) in batches(from, to, args.batch_size) {
+        for swap in fetch_swaps(handlers, batch_from, batch_to).await? {
+            write_swap(out, cli.format, &swap)?;
+        }
+    }
+    Ok(())
+}
+
+async fn export(
+    cli: &Cli,
+    config: &Config,
+    web3: &Node,
+    handlers: &Handlers,
+    args: &ExportArgs,
+) -> Result<(), anyhow::Error> {
+    let range = export_range(config, web3, args).await?;
+    let Some(format) = args
+        .file_format
+        .or_else(|| ExportFormat::from_path(&args.output))
+    else {
+        let mut file = BufWriter::new(File::create(&args.output)?);
+        let batched = BatchedRangeArgs {
+            range,
+            batch_size: args.batch_size,
+        };
+        backfill(cli, config, web3, handlers, &batched, &mut file).await?;
+        file.flush()?;
+        log::info!("✅ Exported swaps to {}", args.output.display());
+        return Ok(());
+    };
+
+    let (from, to) = confirmed_range(config, web3, &range).await?;
+    let blocks_fetcher = Web3BlocksFetcher { web3: web3.clone() };
+    let mut writer = ExportWriter::create(&args.output, format)?;
+    for (batch_from, batch_to) in batches(from, to, args.batch_size) {
+        let swaps = fetch_swaps(handlers, batch_from, batch_to).await?;
+        writer.write(&swap_rows(&blocks_fetcher, &swaps).await?)?;
+    }
+    writer.finish()?;
+    log::info!(
+        "✅ Exported swaps of blocks {} - {} to {}",
+        from,
+        to,
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// Blocks range of the export, with times mapped to the blocks mined then
+async fn export_range(
+    config: &Config,
+    web3: &Node,
+    args: &ExportArgs,
+) -> Result<RangeArgs, anyhow::Error> {
+    if args.from_time.is_none() && args.to_time.is_none() {
+        return Ok(RangeArgs {
+            from: args.from.unwrap_or_default(),
+            to: args.to,
+        });
+    }
+    let latest_confirmed = latest_confirmed(config, web3).await?;
+    let blocks_fetcher = Web3BlocksFetcher { web3: web3.clone() };
+    let from = match args.from_time {
+        Some(time) => first_block_at_or_after(&blocks_fetcher, time, 0, latest_confirmed)
+            .await?
+            .ok_or_else(|| anyhow!("No confirmed block was mined at or after {}", time))?,
+        None => args.from.unwrap_or_default(),
+    };
+    let to = match args.to_time {
+        Some(time) => Some(
+            last_block_at_or_before(&blocks_fetcher, time, 0, latest_confirmed)
+                .await?
+                .ok_or_else(|| anyhow!("No block was mined at or before {}", time))?,
+        ),
+        None => args.to,
+    };
+    log::info!("Exporting blocks {} - {:?}", from, to);
+    Ok(RangeArgs { from, to })
+}
+
+/// `from..=to` in ranges of `batch_size` blocks
+fn batches(from: u64, to: u64, batch_size: u64) -> impl Iterator<Item = (u64, u64)> {
+    std::iter::successors(Some(from), move |batch_from| {
+        batch_from.checked_add(batch_size)
+    })
+    .take_while(move |batch_from| *batch_from <= to)
+    .map(move |batch_from| {
+        (
+            batch_from,
+            to.min(batch_from.saturating_add(batch_size - 1)),
+        )
+    })
+}
+
+/// Swaps of the `handlers` pools in blocks `from..=to`, in chain order
+async fn fetch_swaps(
+    handlers: &Handlers,
+    from: u64,
+    to: u64,
+) -> Result<Vec<SwapDetails>, anyhow::Error> {
+    log::info!("Fetching swaps of blocks: {} - {}", from, to);
+    let mut swaps = vec![];
+    for events_handler in handlers {
+        swaps.extend(events_handler.handle_events_in_range(from, to).await?);
+    }
+    // chain order across pools
+    swaps.sort_by_key(|swap| (swap.metadata.block_number, swap.metadata.log_index));
+    Ok(swaps)
+}
+
+async fn verify(
+    cli: &Cli,
+    config: &Config,
+    web3: Node,
+    args: &RangeArgs,
+) -> Result<(), anyhow::Error> {
+    let (from, to) = confirmed_range(config, &web3, args).await?;
+    let breaks = verify_range(&Web3BlocksFetcher { web3 }, from, to).await?;
+
+    let mut stdout = io::stdout().lock();
+    match cli.format {
+        OutputFormat::Text => {
+            for err in &breaks {
+                writeln!(stdout, "{}", err)?;
+            }
+        }
+        OutputFormat::Json => {
+            let breaks: Vec<String> = breaks.iter().map(ToString::to_string).collect();
+            let report = serde_json::json!({ "from": from, "to": to, "breaks": breaks });
+            writeln!(stdout, "{}", report)?;
+        }
+    }
+
+    if !breaks.is_empty() {
+        bail!("{} broken links in blocks {} - {}", breaks.len(), from, to);
+    }
+    log::info!("✅ Blocks {} - {} are linked", from, to);
+    Ok(())
+}
+
+async fn decode_tx(
+    cli: &Cli,
+    config: &Config,
+    web3: &Node,
+    handlers: &Handlers,
+    hash: H256,
+) -> Result<(), anyhow::Error> {
+    let receipt = web3
+        .eth()
+        .transaction_receipt(hash)
+        .await?
+        .ok_or_else(|| anyhow!("Transaction {:?} not found (or still pending)", hash))?;
+
+    if let Some(block_number) = receipt.block_number {
+        if block_number.as_u64() > latest_confirmed(config, web3).await? {
+            log::warn!("Transaction isn't confirmed yet, its swaps could still be reorged");
+        }
+    }
+
+    let mut stdout = io::stdout().lock();
+    for events_handler in handlers {
+        let swap_logs = receipt
+            .logs
+            .iter()
+            .filter(|log| events_handler.is_swap_log(log))
+            .cloned()
+            .collect();
+        for swap in events_handler.handle_logs(swap_logs).await? {
+            write_swap(&mut stdout, cli.format, &swap)?;
+        }
+    }
     Ok(())
 }
+
+/// `args` range, limited to blocks with enough confirmations
+async fn confirmed_range(
+    config: &Config,
+    web3: &Node,
+    args: &RangeArgs,
+) -> Result<(u64, u64), anyhow::Error> {
+    let latest_confirmed = latest_confirmed(config, web3).await?;
+    let to = match args.to {
+        Some(to) if to > latest_confirmed => {
+            log::warn!(
+                "Blocks after {} aren't confirmed yet, the range ends there",
+                latest_confirmed
+            );
+            latest_confirmed
+        }
+        Some(to) => to,
+        None => latest_confirmed,
+    };
+    if args.from > to {
+        bail!("Empty blocks range: {} - {}", args.from, to);
+    }
+    Ok((args.from, to))
+}
+
+/// Newest block with enough confirmations, or tagged `safe` / `finalized` (see `ConfirmationMode`)
+async fn latest_confirmed(config: &Config, web3: &Node) -> Result<u64, anyhow::Error> {
+    let Some(confirm_at) = config.confirmation_mode().block_state() else {
+        let latest = web3.eth().block_number().await?.as_u64();
+        return Ok(latest.saturating_sub(config.confirmation_blocks()));
+    };
+    let blocks_fetcher = Web3BlocksFetcher { web3: web3.clone() };
+    let tagged = blocks_fetcher
+        .get_block_header_by_tag(confirm_at.tag())
+        .await?
+        .ok_or_else(|| anyhow!("The node has no {:?} block yet", confirm_at))?;
+    Ok(tagged.number.map_or(0, |number| number.as_u64()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_parses_subcommands() {
+        let cli = Cli::try_parse_from([
+            "monitor",
+            "backfill",
+            "--from",
+            "21836327",
+            "--to",
+            "21836337",
+            "--pool",
+            "0x5777d92f208679db4b9778590fa3cab3ac9e2168",
+            "--format",
+            "json",
+            "--confirmations",
+            "12",
+        ])
+        .unwrap();
+        assert_eq!(cli.confirmations, Some(12));
+        assert_eq!(cli.format, OutputFormat::Json);
+        assert_eq!(cli.pools.len(), 1);
+        match cli.command {
+            Command::Backfill(args) => {
+                assert_eq!(args.range.from, 21836327);
+                assert_eq!(args.range.to, Some(21836337));
+                assert_eq!(args.batch_size, 1000);
+            }
+            command => panic!("unexpected command: {:?}", command),
+        }
+
+        let cli = Cli::try_parse_from(["monitor", "monitor"]).unwrap();
+        assert_eq!(cli.confirmations, None);
+        assert!(cli.pools.is_empty());
+
+        assert!(Cli::try_parse_from(["monitor", "decode-tx", "0x1234"]).is_err());
+        assert!(Cli::try_parse_from(["monitor", "export", "--from", "1"]).is_err());
+        let cli = Cli::try_parse_from([
+            "monitor",
+            "export",
+            "--from-time",
+            "1739434787",
+            "--to",
+            "21836337",
+            "--output",
+            "swaps.parquet",
+        ])
+        .unwrap();
+        match cli.command {
+            Command::Export(args) => {
+                assert_eq!((args.from, args.from_time), (None, Some(1739434787)));
+                assert_eq!((args.to, args.to_time), (Some(21836337), None));
+                assert_eq!(args.file_format, None);
+            }
+            command => panic!("unexpected command: {:?}", command),
+        }
+        let export = |range: &[&'static str]| {
+            let args = ["monitor", "export", "--output", "swaps.csv"];
+            Cli::try_parse_from(args.iter().chain(range))
+        };
+        assert!(export(&[]).is_err());
+        assert!(export(&["--from", "1", "--from-time", "1739434787"]).is_err());
+        assert!(export(&["--from", "1", "--to", "2", "--to-time", "1739434787"]).is_err());
+        assert!(export(&["--from", "1", "--file-format", "parquet"]).is_ok());
+        assert!(
+            Cli::try_parse_from(["monitor", "backfill", "--from", "1", "--batch-size", "0"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_batches_cover_the_range() {
+        assert_eq!(
+            batches(1, 2500, 1000).collect::<Vec<_>>(),
+            vec![(1, 1000), (1001, 2000), (2001, 2500)]
+        );
+        assert_eq!(batches(5, 5, 1000).collect::<Vec<_>>(), vec![(5, 5)]);
+        assert_eq!(
+            batches(u64::MAX - 1, u64::MAX, 1).collect::<Vec<_>>(),
+            vec![(u64::MAX - 1, u64::MAX - 1), (u64::MAX, u64::MAX)]
+        );
+    }
+}
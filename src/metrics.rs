@@ -0,0 +1,468 @@
+use crate::{
+    blocks_handler::{BlockEvent, ConfirmedBlock, HeadsHandler, Reorg},
+    config::PoolConfig,
+    error::MonitorError,
+    swap_details::SwapDirection,
+};
+use async_trait::async_trait;
+use axum::{extract::State, http::header, routing::get, Router};
+use futures::{future::BoxFuture, FutureExt};
+use jsonrpc_core as rpc;
+use log::info;
+use prometheus::{
+    core::Collector, CounterVec, Encoder, Gauge, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use rust_decimal::prelude::ToPrimitive;
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use web3::{
+    api::SubscriptionId,
+    types::{BlockHeader, H160, H256},
+    DuplexTransport, RequestId, Transport,
+};
+
+/// Metrics of the monitor, in a registry of their own. Clones share the metrics
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    head: IntGauge,
+    last_confirmed: IntGauge,
+    tracked_blocks: IntGauge,
+    reorgs: IntCounterVec,
+    parent_hash_mismatches: IntCounter,
+    rpc_duration: HistogramVec,
+    rpc_errors: IntCounterVec,
+    swaps: IntCounterVec,
+    volume: CounterVec,
+    seconds_since_last_head: Gauge,
+    /// When the last head arrived, the start of the monitor until the first one
+    last_head_at: Arc<Mutex<Instant>>,
+    /// Symbols of token0 & token1 by pool, for the volume labels
+    pool_tokens: Arc<HashMap<H160, (String, String)>>,
+}
+
+impl Metrics {
+    pub fn new(pools: &[PoolConfig]) -> Result<Self, anyhow::Error> {
+        let registry = Registry::new();
+        let metrics = Self {
+            head: IntGauge::new("monitor_head_block", "Number of the last head")?,
+            last_confirmed: IntGauge::new(
+                "monitor_last_confirmed_block",
+                "Number of the last confirmed block",
+            )?,
+            tracked_blocks: IntGauge::new(
+                "monitor_tracked_blocks",
+                "Blocks waiting for their confirmation",
+            )?,
+            reorgs: IntCounterVec::new(
+                Opts::new("monitor_reorgs_total", "Reorgs detected, by depth"),
+                &["depth"],
+            )?,
+            parent_hash_mismatches: IntCounter::new(
+                "monitor_parent_hash_mismatches_total",
+                "Heads whose parent isn't the tracked previous block",
+            )?,
+            rpc_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "monitor_rpc_duration_seconds",
+                    "Latency of requests to the node, by method",
+                ),
+                &["method"],
+            )?,
+            rpc_errors: IntCounterVec::new(
+                Opts::new(
+                    "monitor_rpc_errors_total",
+                    "Failed requests to the node, by method",
+                ),
+                &["method"],
+            )?,
+            swaps: IntCounterVec::new(
+                Opts::new("monitor_swaps_total", "Confirmed swaps, by direction"),
+                &["direction"],
+            )?,
+            volume: CounterVec::new(
+                Opts::new(
+                    "monitor_volume_total",
+                    "Amounts swapped in confirmed swaps, by token",
+                ),
+                &["token"],
+            )?,
+            seconds_since_last_head: Gauge::new(
+                "monitor_seconds_since_last_head",
+                "Seconds since the last head arrived (since the start before the first one)",
+            )?,
+            last_head_at: Arc::new(Mutex::new(Instant::now())),
+            pool_tokens: Arc::new(
+                pools
+                    .iter()
+                    .map(|pool| {
+                        (
+                            pool.address,
+                            (pool.token0.symbol.clone(), pool.token1.symbol.clone()),
+                        )
+                    })
+                    .collect(),
+            ),
+            registry,
+        };
+        let collectors: [Box<dyn Collector>; 10] = [
+            Box::new(metrics.head.clone()),
+            Box::new(metrics.last_confirmed.clone()),
+            Box::new(metrics.tracked_blocks.clone()),
+            Box::new(metrics.reorgs.clone()),
+            Box::new(metrics.parent_hash_mismatches.clone()),
+            Box::new(metrics.rpc_duration.clone()),
+            Box::new(metrics.rpc_errors.clone()),
+            Box::new(metrics.swaps.clone()),
+            Box::new(metrics.volume.clone()),
+            Box::new(metrics.seconds_since_last_head.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector)?;
+        }
+        Ok(metrics)
+    }
+
+    pub fn observe_head(&self, block_number: u64) {
+        self.head.set(block_number as i64);
+        *self.last_head_at.lock().unwrap() = Instant::now();
+    }
+
+    pub fn observe_confirmed(&self, confirmed_block: &ConfirmedBlock) {
+        self.last_confirmed.set(confirmed_block.block_number as i64);
+        for swap in &confirmed_block.swaps {
+            let direction = match swap.direction {
+                SwapDirection::DaiToUsdc => "dai_to_usdc",
+                SwapDirection::UsdcToDai => "usdc_to_dai",
+            };
+            self.swaps.with_label_values(&[direction]).inc();
+
+            let (token0, token1) = self
+                .pool_tokens
+                .get(&swap.metadata.pool)
+                .map_or(("token0", "token1"), |(token0, token1)| {
+                    (token0.as_str(), token1.as_str())
+                });
+            for (token, amount) in [
+                (token0, swap.amount0_as_decimal_num),
+                (token1, swap.amount1_as_decimal_num),
+            ] {
+                let amount = amount.abs().to_f64().unwrap_or_default();
+                self.volume.with_label_values(&[token]).inc_by(amount);
+            }
+        }
+    }
+
+    pub fn observe_reorg(&self, reorg: &Reorg) {
+        self.reorgs
+            .with_label_values(&[&reorg.depth.to_string()])
+            .inc();
+    }
+
+    /// Counts the parent hash mismatches among the errors of handling a head
+    pub fn observe_error(&self, err: &anyhow::Error) {
+        if let Some(MonitorError::ParentHashMismatch { .. }) = err.downcast_ref() {
+            self.parent_hash_mismatches.inc();
+        }
+    }
+
+    pub fn observe_tracked_blocks(&self, tracked_blocks: usize) {
+        self.tracked_blocks.set(tracked_blocks as i64);
+    }
+
+    pub fn observe_rpc(&self, method: &str, seconds: f64, failed: bool) {
+        self.rpc_duration
+            .with_label_values(&[method])
+            .observe(seconds);
+        if failed {
+            self.rpc_errors.with_label_values(&[method]).inc();
+        }
+    }
+
+    /// All metrics, in the Prometheus text format
+    pub fn render(&self) -> String {
+        let since_last_head = self.last_head_at.lock().unwrap().elapsed();
+        self.seconds_since_last_head
+            .set(since_last_head.as_secs_f64());
+
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics are encoded into memory");
+        String::from_utf8(buffer).expect("the text format is UTF-8")
+    }
+
+    /// Serves `GET /metrics` on `listen` in the background, returns the address it's bound to (for port 0)
+    pub fn serve(&self, listen: SocketAddr) -> Result<SocketAddr, anyhow::Error> {
+        let listener = TcpListener::bind(listen)
+            .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", listen, e))?;
+        let local_addr = listener.local_addr()?;
+        let app = Router::new()
+            .route("/metrics", get(render_metrics))
+            .with_state(self.clone());
+        let server = axum::Server::from_tcp(listener)?.serve(app.into_make_service());
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                log::error!("Metrics server failed: {}", err);
+            }
+        });
+        info!("Serving metrics on http://{}/metrics", local_addr);
+        Ok(local_addr)
+    }
+}
+
+async fn render_metrics(State(metrics): State<Metrics>) -> impl axum::response::IntoResponse {
+    (
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        metrics.render(),
+    )
+}
+
+/// `HeadsHandler` recording the heads, confirmed blocks, reorgs & errors of `inner`
+pub struct MeteredHeadsHandler<H> {
+    inner: H,
+    metrics: Metrics,
+}
+
+impl<H: HeadsHandler> MeteredHeadsHandler<H> {
+    pub fn new(inner: H, metrics: Metrics) -> Self {
+        Self { inner, metrics }
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<H: HeadsHandler> HeadsHandler for MeteredHeadsHandler<H> {
+    async fn handle_head(
+        &mut self,
+        block_header: BlockHeader,
+    ) -> Result<Vec<ConfirmedBlock>, anyhow::Error> {
+        if let Some(number) = block_header.number {
+            self.metrics.observe_head(number.as_u64());
+        }
+        let result = self.inner.handle_head(block_header).await;
+        match &result {
+            Ok(confirmed_blocks) => confirmed_blocks
+                .iter()
+                .for_each(|confirmed_block| self.metrics.observe_confirmed(confirmed_block)),
+            Err(err) => self.metrics.observe_error(err),
+        }
+        self.metrics
+            .observe_tracked_blocks(self.inner.tracked_window().len());
+        result
+    }
+
+    fn take_reorgs(&mut self) -> Vec<Reorg> {
+        let reorgs = self.inner.take_reorgs();
+        reorgs
+            .iter()
+            .for_each(|reorg| self.metrics.observe_reorg(reorg));
+        reorgs
+    }
+
+    fn take_events(&mut self) -> Vec<BlockEvent> {
+        self.inner.take_events()
+    }
+
+    fn tracked_window(&self) -> Vec<(u64, H256)> {
+        self.inner.tracked_window()
+    }
+}
+
+/// Transport wrapper timing every request of `inner`, by method (like `CassetteTransport` records them)
+#[derive(Clone)]
+pub struct MeteredTransport<T> {
+    inner: T,
+    metrics: Metrics,
+}
+
+impl<T> MeteredTransport<T> {
+    pub fn new(inner: T, metrics: Metrics) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl<T> std::fmt::Debug for MeteredTransport<T>
+where
+    T: std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MeteredTransport")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<T> Transport for MeteredTransport<T>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send + 'static,
+{
+    type Out = BoxFuture<'static, web3::Result<rpc::Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+        self.inner.prepare(method, params)
+    }
+
+    fn send(&self, id: RequestId, request: rpc::Call) -> Self::Out {
+        let method = match &request {
+            rpc::Call::MethodCall(call) => call.method.clone(),
+            rpc::Call::Notification(notification) => notification.method.clone(),
+            rpc::Call::Invalid { .. } => "invalid".to_string(),
+        };
+        let metrics = self.metrics.clone();
+        let started_at = Instant::now();
+        let response = self.inner.send(id, request);
+        async move {
+            let response = response.await;
+            metrics.observe_rpc(
+                &method,
+                started_at.elapsed().as_secs_f64(),
+                response.is_err(),
+            );
+            response
+        }
+        .boxed()
+    }
+}
+
+impl<T> DuplexTransport for MeteredTransport<T>
+where
+    T: DuplexTransport + Send + Sync + 'static,
+    T::Out: Send + 'static,
+{
+    type NotificationStream = T::NotificationStream;
+
+    fn subscribe(&self, id: SubscriptionId) -> web3::Result<Self::NotificationStream> {
+        self.inner.subscribe(id)
+    }
+
+    fn unsubscribe(&self, id: SubscriptionId) -> web3::Result<()> {
+        self.inner.unsubscribe(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::swap_details::{LogMetadata, SwapDetails};
+    use rust_decimal::Decimal;
+    use web3::types::U256;
+
+    fn pool() -> PoolConfig {
+        crate::config::Config::default()
+            .with_network_defaults()
+            .pools
+            .remove(0)
+    }
+
+    fn swap(
+        pool: H160,
+        direction: SwapDirection,
+        amount0: Decimal,
+        amount1: Decimal,
+    ) -> SwapDetails {
+        SwapDetails {
+            metadata: LogMetadata {
+                pool,
+                ..Default::default()
+            },
+            sender: H160::zero(),
+            recipient: H160::zero(),
+            amount0_raw: U256::zero(),
+            amount0_as_decimal_num: amount0,
+            amount1_raw: U256::zero(),
+            amount1_as_decimal_num: amount1,
+            direction,
+            sqrt_price_x96: U256::zero(),
+            dai_is_token1: false,
+        }
+    }
+
+    #[test]
+    fn test_confirmed_swaps_are_counted_by_direction_and_token() {
+        let pools = vec![pool()];
+        let pool = &pools[0];
+        let metrics = Metrics::new(&pools).unwrap();
+        metrics.observe_head(110);
+        metrics.observe_confirmed(&ConfirmedBlock {
+            block_number: 105,
+            block_hash: H256::zero(),
+            timestamp: 1260,
+            swaps: vec![
+                swap(
+                    pool.address,
+                    SwapDirection::DaiToUsdc,
+                    Decimal::new(15, 1),
+                    Decimal::new(-149, 2),
+                ),
+                swap(
+                    pool.address,
+                    SwapDirection::UsdcToDai,
+                    Decimal::new(-2, 0),
+                    Decimal::new(2, 0),
+                ),
+            ],
+        });
+        metrics.observe_reorg(&Reorg {
+            detected_at: 110,
+            fork_point: 107,
+            depth: 2,
+            orphaned_hashes: vec![],
+            new_hashes: vec![],
+        });
+        metrics.observe_error(
+            &MonitorError::ParentHashMismatch {
+                block_number: 110,
+                previous: H256::zero(),
+                current: H256::zero(),
+            }
+            .into(),
+        );
+        metrics.observe_rpc("eth_getBlockByNumber", 0.1, true);
+
+        let rendered = metrics.render();
+        for line in [
+            "monitor_head_block 110",
+            "monitor_last_confirmed_block 105",
+            "monitor_swaps_total{direction=\"dai_to_usdc\"} 1",
+            "monitor_swaps_total{direction=\"usdc_to_dai\"} 1",
+            "monitor_volume_total{token=\"DAI\"} 3.5",
+            "monitor_volume_total{token=\"USDC\"} 3.49",
+            "monitor_reorgs_total{depth=\"2\"} 1",
+            "monitor_parent_hash_mismatches_total 1",
+            "monitor_rpc_errors_total{method=\"eth_getBlockByNumber\"} 1",
+            "monitor_rpc_duration_seconds_count{method=\"eth_getBlockByNumber\"} 1",
+        ] {
+            assert!(rendered.contains(line), "{} isn't in:\n{}", line, rendered);
+        }
+        assert!(rendered.contains("monitor_seconds_since_last_head"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_are_served() {
+        let metrics = Metrics::new(&[]).unwrap();
+        let addr = metrics
+            .serve(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .unwrap();
+        metrics.observe_head(42);
+
+        let response = reqwest::get(format!("http://{}/metrics", addr))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("monitor_head_block 42"));
+    }
+}
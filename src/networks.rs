@@ -0,0 +1,262 @@
+use crate::{
+    config::{ConfirmationMode, PoolConfig, TokenConfig},
+    BLOCK_CONFIRMATIONS,
+};
+use std::{fmt, str::FromStr};
+use web3::{
+    ethabi::{encode, Token as AbiToken},
+    signing::keccak256,
+    types::{H160, H256},
+};
+
+/// `UniswapV3Factory` of Ethereum mainnet, Arbitrum, Optimism & Polygon
+const FACTORY: &str = "0x1F98431c8aD98523631AE4a59f267346ea31F984";
+/// `UniswapV3Factory` of Base
+const BASE_FACTORY: &str = "0x33128a8fC17869897dcE68Ed026d694621f6FDfD";
+/// `keccak256` of the `UniswapV3Pool` creation code, the same on every network
+const POOL_INIT_CODE_HASH: &str =
+    "0xe34f199b19b2b4f47f68442619d555527d244f78a3297ea89325f843f87b8b54";
+/// 0.01%, the tier stablecoin pairs trade in
+const STABLE_FEE_TIER: u32 = 100;
+
+/// Networks with a known DAI/USDC pool, picked by `chain_id` in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Network {
+    Mainnet,
+    Arbitrum,
+    Optimism,
+    Base,
+    Polygon,
+}
+
+/// An ERC-20 token of the registry
+struct Token {
+    symbol: &'static str,
+    address: &'static str,
+    decimals: u32,
+}
+
+const fn token(symbol: &'static str, address: &'static str, decimals: u32) -> Token {
+    Token {
+        symbol,
+        address,
+        decimals,
+    }
+}
+
+impl Network {
+    pub const ALL: [Network; 5] = [
+        Network::Mainnet,
+        Network::Arbitrum,
+        Network::Optimism,
+        Network::Base,
+        Network::Polygon,
+    ];
+
+    pub fn chain_id(self) -> u64 {
+        match self {
+            Network::Mainnet => 1,
+            Network::Arbitrum => 42161,
+            Network::Optimism => 10,
+            Network::Base => 8453,
+            Network::Polygon => 137,
+        }
+    }
+
+    pub fn from_chain_id(chain_id: u64) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|network| network.chain_id() == chain_id)
+    }
+
+    /// Blocks on top of a block before its swaps are reported, unless configured otherwise.
+    /// Chains with faster blocks need more of them for the same safety (& there are more of them to re-verify)
+    pub fn default_confirmations(self) -> u64 {
+        match self {
+            Network::Mainnet => BLOCK_CONFIRMATIONS,
+            // ~0.25s blocks
+            Network::Arbitrum => 20,
+            // ~2s blocks, the sequencer rarely reorgs
+            Network::Optimism | Network::Base => 10,
+            // ~2s blocks, reorgs of a few dozen blocks used to happen
+            Network::Polygon => 32,
+        }
+    }
+
+    /// Rollups tag the blocks they posted to L1, which makes them final rather than some number of blocks
+    pub fn default_confirmation_mode(self) -> ConfirmationMode {
+        match self {
+            Network::Arbitrum | Network::Optimism | Network::Base => ConfirmationMode::Safe,
+            Network::Mainnet | Network::Polygon => ConfirmationMode::Depth,
+        }
+    }
+
+    /// DAI & USDC of the network. The bridged USDC (`USDC.e`) is listed too where it still has DAI liquidity
+    fn stablecoins(self) -> (Token, Vec<Token>) {
+        match self {
+            Network::Mainnet => (
+                token("DAI", "0x6B175474E89094C44Da98b954EedeAC495271d0F", 18),
+                vec![token(
+                    "USDC",
+                    "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48",
+                    6,
+                )],
+            ),
+            Network::Arbitrum => (
+                token("DAI", "0xDA10009cBd5D07dd0CeCc66161FC93D7c9000da1", 18),
+                vec![
+                    token("USDC", "0xaf88d065e77c8cC2239327C5EDb3A432268e5831", 6),
+                    token("USDC.e", "0xFF970A61A04b1cA14834A43f5dE4533eBDDB5CC8", 6),
+                ],
+            ),
+            Network::Optimism => (
+                token("DAI", "0xDA10009cBd5D07dd0CeCc66161FC93D7c9000da1", 18),
+                vec![
+                    token("USDC", "0x0b2C639c533813f4Aa9D7837CAf62653d097Ff85", 6),
+                    token("USDC.e", "0x7F5c764cBc14f9669B88837ca1490cCa17c31607", 6),
+                ],
+            ),
+            Network::Base => (
+                token("DAI", "0x50c5725949A6F0c72E6C4a641F24049A917DB0Cb", 18),
+                vec![token(
+                    "USDC",
+                    "0x833589fCD6eDb6E08f4c7C32D4f71b54bdA02913",
+                    6,
+                )],
+            ),
+            Network::Polygon => (
+                token("DAI", "0x8f3Cf7ad23Cd3CaDbD9735AFf958023239c6A063", 18),
+                vec![
+                    token("USDC", "0x3c499c542cEF5E3811e1192ce70d8cC03d5c3359", 6),
+                    token("USDC.e", "0x2791Bca1f2de4661ED88A30C99A7a9449Aa84174", 6),
+                ],
+            ),
+        }
+    }
+
+    fn factory(self) -> H160 {
+        let factory = match self {
+            Network::Base => BASE_FACTORY,
+            _ => FACTORY,
+        };
+        parse_address(factory)
+    }
+
+    /// The 0.01% DAI/USDC pools of the network, the default `pools` of the config.
+    /// Tokens are sorted by address like in the pool, so on some networks token0 is USDC
+    /// (see `PoolConfig::dai_is_token1`)
+    pub fn pools(self) -> Vec<PoolConfig> {
+        let (dai, usdcs) = self.stablecoins();
+        usdcs
+            .iter()
+            .map(|usdc| {
+                let (token0, token1) = if parse_address(dai.address) < parse_address(usdc.address) {
+                    (&dai, usdc)
+                } else {
+                    (usdc, &dai)
+                };
+                PoolConfig {
+                    address: pool_address(
+                        self.factory(),
+                        parse_address(token0.address),
+                        parse_address(token1.address),
+                        STABLE_FEE_TIER,
+                    ),
+                    token0: token_config(token0),
+                    token1: token_config(token1),
+                }
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Network::Mainnet => "mainnet",
+            Network::Arbitrum => "arbitrum",
+            Network::Optimism => "optimism",
+            Network::Base => "base",
+            Network::Polygon => "polygon",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Name of a known network, the chain ID otherwise, e.g., for error messages
+pub fn describe_chain(chain_id: u64) -> String {
+    match Network::from_chain_id(chain_id) {
+        Some(network) => format!("{} ({})", chain_id, network),
+        None => chain_id.to_string(),
+    }
+}
+
+/// Address the factory deploys the pool of `token0` & `token1` (sorted) with `fee` at (`CREATE2`)
+pub fn pool_address(factory: H160, token0: H160, token1: H160, fee: u32) -> H160 {
+    let salt = keccak256(&encode(&[
+        AbiToken::Address(token0),
+        AbiToken::Address(token1),
+        AbiToken::Uint(fee.into()),
+    ]));
+    let init_code_hash = H256::from_str(POOL_INIT_CODE_HASH).expect("valid init code hash");
+
+    let mut preimage = Vec::with_capacity(85);
+    preimage.push(0xff);
+    preimage.extend_from_slice(factory.as_bytes());
+    preimage.extend_from_slice(&salt);
+    preimage.extend_from_slice(init_code_hash.as_bytes());
+    H160::from_slice(&keccak256(&preimage)[12..])
+}
+
+fn parse_address(address: &str) -> H160 {
+    H160::from_str(address).expect("valid registry address")
+}
+
+fn token_config(token: &Token) -> TokenConfig {
+    TokenConfig {
+        symbol: token.symbol.to_string(),
+        decimals: token.decimals,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events_handler::DAI_USDC_POOL;
+
+    #[test]
+    fn test_registry() {
+        // derived, rather than copied, so it has to match the pool the monitor was built for
+        let mainnet = Network::Mainnet.pools();
+        assert_eq!(mainnet.len(), 1);
+        assert_eq!(mainnet[0].address, parse_address(DAI_USDC_POOL));
+        assert_eq!(mainnet[0].token0.symbol, "DAI");
+
+        for network in Network::ALL {
+            assert_eq!(Network::from_chain_id(network.chain_id()), Some(network));
+            for pool in network.pools() {
+                let symbols = [pool.token0.symbol.as_str(), pool.token1.symbol.as_str()];
+                assert!(symbols.contains(&"DAI"), "{}: {:?}", network, symbols);
+            }
+        }
+        // USDC's address sorts before DAI's on these
+        assert!(!mainnet[0].dai_is_token1());
+        assert!(Network::Arbitrum.pools()[0].dai_is_token1());
+        assert!(!Network::Arbitrum.pools()[1].dai_is_token1());
+        assert!(Network::Optimism
+            .pools()
+            .iter()
+            .all(PoolConfig::dai_is_token1));
+        assert!(Network::Polygon
+            .pools()
+            .iter()
+            .all(PoolConfig::dai_is_token1));
+        // the DAI/USDC.e pool, as listed by the Arbitrum explorers
+        assert_eq!(
+            Network::Arbitrum.pools()[1].address,
+            parse_address("0xf0428617433652c9dc6d1093a42adfbf30d29f74")
+        );
+        assert_eq!(Network::from_chain_id(11155111), None);
+        assert_eq!(describe_chain(8453), "8453 (base)");
+    }
+}
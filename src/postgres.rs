@@ -0,0 +1,204 @@
+use crate::{
+    blocks_handler::{ConfirmedBlock, Reorg},
+    sinks::Sink,
+};
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use log::{debug, warn};
+use tokio_postgres::{Client, NoTls};
+use web3::types::H256;
+
+/// Schema changes, applied in order & recorded in `monitor_migrations`.
+/// A migration is never changed once released, only new ones are appended
+const MIGRATIONS: &[&str] = &[CREATE_SWAPS_AND_REORGS];
+
+const CREATE_SWAPS_AND_REORGS: &str = "CREATE TABLE swaps (
+        chain_id BIGINT NOT NULL,
+        block_hash TEXT NOT NULL,
+        log_index BIGINT NOT NULL,
+        block_number BIGINT NOT NULL,
+        transaction_hash TEXT,
+        pool TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        amount0_raw TEXT NOT NULL,
+        amount0 NUMERIC NOT NULL,
+        amount1_raw TEXT NOT NULL,
+        amount1 NUMERIC NOT NULL,
+        direction TEXT NOT NULL,
+        canonical BOOLEAN NOT NULL DEFAULT TRUE,
+        PRIMARY KEY (chain_id, block_hash, log_index)
+    );
+    CREATE INDEX swaps_block_number ON swaps (chain_id, block_number);
+    CREATE TABLE reorgs (
+        id BIGSERIAL PRIMARY KEY,
+        chain_id BIGINT NOT NULL,
+        detected_at BIGINT NOT NULL,
+        fork_point BIGINT NOT NULL,
+        depth BIGINT NOT NULL,
+        orphaned_hashes TEXT[] NOT NULL,
+        new_hashes TEXT[] NOT NULL,
+        recorded_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        UNIQUE (chain_id, fork_point, orphaned_hashes)
+    );";
+
+/// Writes confirmed swaps into Postgres, a transaction per block. Rows are keyed by `(chain_id, block_hash, log_index)`,
+/// so several monitors can share the tables & writing a block again is a no-op.
+/// Swaps of blocks which turn out to be orphaned are kept, but marked `canonical = FALSE`
+pub struct PostgresSink {
+    client: Client,
+    chain_id: i64,
+}
+
+impl PostgresSink {
+    /// Connects to `url` (`postgres://...`, without TLS) & migrates the schema
+    pub async fn connect(url: &str, chain_id: u64) -> Result<Self, anyhow::Error> {
+        let (client, connection) = tokio_postgres::connect(url, NoTls)
+            .await
+            .map_err(|e| anyhow!("Failed to connect to the database: {}", e))?;
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                warn!("Database connection failed: {}", err);
+            }
+        });
+        let mut sink = Self {
+            client,
+            chain_id: bigint(chain_id)?,
+        };
+        sink.migrate().await?;
+        Ok(sink)
+    }
+
+    async fn migrate(&mut self) -> Result<(), anyhow::Error> {
+        self.client
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS monitor_migrations (
+                    version INTEGER PRIMARY KEY,
+                    applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                )",
+            )
+            .await?;
+        let tx = self.client.transaction().await?;
+        // monitors of other chains may share the database & start at the same time
+        tx.batch_execute("LOCK TABLE monitor_migrations IN EXCLUSIVE MODE")
+            .await?;
+        let applied: i32 = tx
+            .query_one(
+                "SELECT COALESCE(MAX(version), 0) FROM monitor_migrations",
+                &[],
+            )
+            .await?
+            .get(0);
+        let applied = applied as usize;
+        if applied > MIGRATIONS.len() {
+            bail!(
+                "Database schema version {} is newer than this build supports ({})",
+                applied,
+                MIGRATIONS.len()
+            );
+        }
+        for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+            tx.batch_execute(migration).await?;
+            tx.execute(
+                "INSERT INTO monitor_migrations (version) VALUES ($1)",
+                &[&(version as i32 + 1)],
+            )
+            .await?;
+            debug!("database migrated to version {}", version + 1);
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for PostgresSink {
+    async fn write(&mut self, confirmed_block: &ConfirmedBlock) -> Result<(), anyhow::Error> {
+        let block_hash = format!("{:?}", confirmed_block.block_hash);
+        let block_number = bigint(confirmed_block.block_number)?;
+        let tx = self.client.transaction().await?;
+        // the confirmed block replaces any other block at its height, e.g., one confirmed before a deep reorg
+        tx.execute(
+            "UPDATE swaps SET canonical = FALSE
+            WHERE chain_id = $1 AND block_number = $2 AND block_hash <> $3 AND canonical",
+            &[&self.chain_id, &block_number, &block_hash],
+        )
+        .await?;
+        let insert = tx
+            .prepare(
+                "INSERT INTO swaps (chain_id, block_hash, log_index, block_number, transaction_hash, pool,
+                    sender, recipient, amount0_raw, amount0, amount1_raw, amount1, direction)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10::TEXT::NUMERIC, $11, $12::TEXT::NUMERIC, $13)
+                ON CONFLICT (chain_id, block_hash, log_index) DO UPDATE SET canonical = TRUE",
+            )
+            .await?;
+        for swap in &confirmed_block.swaps {
+            let metadata = &swap.metadata;
+            let log_index = metadata.log_index.ok_or_else(|| {
+                anyhow!(
+                    "Swap of block {} has no log index",
+                    confirmed_block.block_number
+                )
+            })?;
+            tx.execute(
+                &insert,
+                &[
+                    &self.chain_id,
+                    &block_hash,
+                    &bigint(log_index)?,
+                    &block_number,
+                    &metadata.transaction_hash.map(|hash| format!("{:?}", hash)),
+                    &format!("{:?}", metadata.pool),
+                    &format!("{:?}", swap.sender),
+                    &format!("{:?}", swap.recipient),
+                    &swap.amount0_raw.to_string(),
+                    &swap.amount0_as_decimal_num.to_string(),
+                    &swap.amount1_raw.to_string(),
+                    &swap.amount1_as_decimal_num.to_string(),
+                    &format!("{:?}", swap.direction),
+                ],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn write_reorg(&mut self, reorg: &Reorg) -> Result<(), anyhow::Error> {
+        let hashes = |hashes: &[H256]| -> Vec<String> {
+            hashes.iter().map(|hash| format!("{:?}", hash)).collect()
+        };
+        let orphaned_hashes = hashes(&reorg.orphaned_hashes);
+        let tx = self.client.transaction().await?;
+        let orphaned_swaps = tx
+            .execute(
+                "UPDATE swaps SET canonical = FALSE WHERE chain_id = $1 AND block_hash = ANY($2)",
+                &[&self.chain_id, &orphaned_hashes],
+            )
+            .await?;
+        tx.execute(
+            "INSERT INTO reorgs (chain_id, detected_at, fork_point, depth, orphaned_hashes, new_hashes)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT DO NOTHING",
+            &[
+                &self.chain_id,
+                &bigint(reorg.detected_at)?,
+                &bigint(reorg.fork_point)?,
+                &bigint(reorg.depth)?,
+                &orphaned_hashes,
+                &hashes(&reorg.new_hashes),
+            ],
+        )
+        .await?;
+        tx.commit().await?;
+        if orphaned_swaps > 0 {
+            warn!("{} stored swaps marked non-canonical", orphaned_swaps);
+        }
+        Ok(())
+    }
+}
+
+/// Postgres has no unsigned integers
+fn bigint(value: u64) -> Result<i64, anyhow::Error> {
+    i64::try_from(value).map_err(|_| anyhow!("{} doesn't fit into BIGINT", value))
+}
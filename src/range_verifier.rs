@@ -0,0 +1,130 @@
+use crate::{error::MonitorError, web3_client::BlocksFetcher};
+use futures::{stream, StreamExt, TryStreamExt};
+use log::debug;
+use web3::types::BlockHeader;
+
+/// Upper bound on in-flight header requests
+const MAX_CONCURRENT_HEADER_REQUESTS: usize = 8;
+
+/// Checks the hash linkage of the canonical blocks `from..=to`: every block's `parent_hash` must be the hash
+/// of the block before it. Returns every break found (a node serving a mix of forks, or a reorg while
+/// the range was fetched), an empty result means the range is consistent.
+pub async fn verify_range<B: BlocksFetcher>(
+    blocks_fetcher: &B,
+    from: u64,
+    to: u64,
+) -> Result<Vec<MonitorError>, anyhow::Error> {
+    // compared as they arrive, so only the previous header is held whatever the size of the range
+    let mut headers = stream::iter(from..=to)
+        .map(|block_number| blocks_fetcher.get_block_header(block_number))
+        .buffered(MAX_CONCURRENT_HEADER_REQUESTS);
+    let mut breaks = vec![];
+    let mut previous: Option<BlockHeader> = None;
+    while let Some(current) = headers.try_next().await? {
+        if let Some(linkage_break) = previous
+            .as_ref()
+            .and_then(|previous| find_linkage_break(previous, &current))
+        {
+            breaks.push(linkage_break);
+        }
+        previous = Some(current);
+    }
+
+    Ok(breaks)
+}
+
+/// `ParentHashMismatch` when `current` isn't the child of `previous`
+pub fn find_linkage_break(previous: &BlockHeader, current: &BlockHeader) -> Option<MonitorError> {
+    let previous_hash = previous.hash?;
+    if current.parent_hash == previous_hash {
+        return None;
+    }
+    debug!("parent hash mismatch at block: {:?}", current.number);
+    Some(MonitorError::ParentHashMismatch {
+        block_number: previous.number.map_or(0, |number| number.as_u64()),
+        previous: previous_hash,
+        current: current.parent_hash,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web3_client::MockBlocksFetcher;
+    use web3::types::H256;
+
+    async fn load_fixtures() -> Vec<BlockHeader> {
+        let json = tokio::fs::read_to_string("tests/fixtures/block_headers.json")
+            .await
+            .expect("Failed to read fixtures file");
+        serde_json::from_str(&json).expect("Failed to parse blocks")
+    }
+
+    fn mock_fetcher(headers: Vec<BlockHeader>) -> MockBlocksFetcher {
+        let mut mock_fetcher = MockBlocksFetcher::new();
+        mock_fetcher
+            .expect_get_block_header()
+            .returning(move |block_number| {
+                let header = headers
+                    .iter()
+                    .find(|header| header.number.unwrap().as_u64() == block_number)
+                    .cloned()
+                    .ok_or(MonitorError::BlockNotFound(block_number));
+                Box::pin(async move { Ok(header?) })
+            });
+        mock_fetcher
+    }
+
+    #[tokio::test]
+    async fn test_verify_range_of_fixtures_is_linked() {
+        let headers = load_fixtures().await;
+        let from = headers[0].number.unwrap().as_u64();
+        let to = headers.last().unwrap().number.unwrap().as_u64();
+
+        let breaks = verify_range(&mock_fetcher(headers), from, to)
+            .await
+            .unwrap();
+        assert!(breaks.is_empty(), "{:?}", breaks);
+    }
+
+    #[tokio::test]
+    async fn test_verify_range_reports_every_break() {
+        let mut headers = load_fixtures().await;
+        let from = headers[0].number.unwrap().as_u64();
+        headers[2].parent_hash = H256::repeat_byte(0xaa);
+        headers[5].parent_hash = H256::repeat_byte(0xbb);
+
+        let breaks = verify_range(&mock_fetcher(headers.clone()), from, from + 6)
+            .await
+            .unwrap();
+        let broken: Vec<(u64, H256)> = breaks
+            .iter()
+            .map(|err| match err {
+                MonitorError::ParentHashMismatch {
+                    block_number,
+                    current,
+                    ..
+                } => (*block_number, *current),
+                err => panic!("unexpected error: {}", err),
+            })
+            .collect();
+        assert_eq!(
+            broken,
+            vec![
+                (from + 1, H256::repeat_byte(0xaa)),
+                (from + 4, H256::repeat_byte(0xbb))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_range_fails_for_missing_block() {
+        let headers = load_fixtures().await;
+        let to = headers.last().unwrap().number.unwrap().as_u64();
+
+        let err = verify_range(&mock_fetcher(headers), to, to + 1)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), format!("Block {} not found", to + 1));
+    }
+}
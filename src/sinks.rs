@@ -0,0 +1,304 @@
+use crate::{
+    alerts::Alert,
+    blocks_handler::{BlockEvent, ConfirmedBlock, Reorg},
+    candles::CandleSink,
+    config::SinkConfig,
+    error::MonitorError,
+    postgres::PostgresSink,
+    store::Store,
+    swap_details::SwapDetails,
+    webhook::{WebhookSink, DEFAULT_RETRY_DELAY},
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// How swaps are written by `WriterSink` (& the CLI)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// A line per swap
+    Text,
+    /// A JSON object per line & swap
+    Json,
+}
+
+/// The node stopped serving the monitor, e.g., the subscription ended or a request failed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProviderOutage {
+    /// Number of the last head received, `None` when there was none
+    pub last_head: Option<u64>,
+    pub error: String,
+}
+
+/// Destination of confirmed blocks
+#[async_trait]
+pub trait Sink: Send {
+    async fn write(&mut self, confirmed_block: &ConfirmedBlock) -> Result<(), anyhow::Error>;
+
+    /// Records a reorg, ignored by sinks which only deal with swaps
+    async fn write_reorg(&mut self, _reorg: &Reorg) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    /// Records a provider outage, ignored by sinks which only deal with swaps
+    async fn write_outage(&mut self, _outage: &ProviderOutage) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    /// Records provisional or retracted swaps, ignored by sinks which only deal with confirmed ones
+    async fn write_event(&mut self, _event: &BlockEvent) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    /// Records an alert a rule raised, ignored by sinks which only deal with swaps
+    async fn write_alert(&mut self, _alert: &Alert) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    /// Makes everything written so far durable, e.g., before shutting down
+    async fn flush(&mut self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// Every sink gets every block, in order
+#[async_trait]
+impl Sink for Vec<Box<dyn Sink>> {
+    async fn write(&mut self, confirmed_block: &ConfirmedBlock) -> Result<(), anyhow::Error> {
+        for sink in self.iter_mut() {
+            sink.write(confirmed_block).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_reorg(&mut self, reorg: &Reorg) -> Result<(), anyhow::Error> {
+        for sink in self.iter_mut() {
+            sink.write_reorg(reorg).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_outage(&mut self, outage: &ProviderOutage) -> Result<(), anyhow::Error> {
+        for sink in self.iter_mut() {
+            sink.write_outage(outage).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_event(&mut self, event: &BlockEvent) -> Result<(), anyhow::Error> {
+        for sink in self.iter_mut() {
+            sink.write_event(event).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_alert(&mut self, alert: &Alert) -> Result<(), anyhow::Error> {
+        for sink in self.iter_mut() {
+            sink.write_alert(alert).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), anyhow::Error> {
+        for sink in self.iter_mut() {
+            sink.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the swaps of confirmed blocks (blocks without swaps write nothing) to `out`, e.g., stdout or a file
+pub struct WriterSink<W: Write + Send> {
+    out: W,
+    format: OutputFormat,
+}
+
+impl<W: Write + Send> WriterSink<W> {
+    pub fn new(out: W, format: OutputFormat) -> Self {
+        Self { out, format }
+    }
+}
+
+impl WriterSink<BufWriter<File>> {
+    /// Appends to the file at `path`, so a restarted monitor keeps what was written before
+    pub fn file(path: impl AsRef<Path>, format: OutputFormat) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("Failed to open {}: {}", path.display(), e))?;
+        Ok(Self::new(BufWriter::new(file), format))
+    }
+}
+
+#[async_trait]
+impl<W: Write + Send> Sink for WriterSink<W> {
+    async fn write(&mut self, confirmed_block: &ConfirmedBlock) -> Result<(), anyhow::Error> {
+        for swap in &confirmed_block.swaps {
+            write_swap(&mut self.out, self.format, swap)?;
+        }
+        Ok(())
+    }
+
+    async fn write_alert(&mut self, alert: &Alert) -> Result<(), anyhow::Error> {
+        match self.format {
+            OutputFormat::Text => writeln!(self.out, "alert: {}", alert)?,
+            OutputFormat::Json => writeln!(self.out, "{}", serde_json::to_string(alert)?)?,
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+pub fn write_swap(
+    out: &mut impl Write,
+    format: OutputFormat,
+    swap: &SwapDetails,
+) -> Result<(), anyhow::Error> {
+    match format {
+        OutputFormat::Text => {
+            let metadata = &swap.metadata;
+            writeln!(
+                out,
+                "block {} tx {} log {}: {:?} amount0 {} amount1 {} sender {:?} recipient {:?}",
+                metadata
+                    .block_number
+                    .map_or("-".to_string(), |n| n.to_string()),
+                metadata
+                    .transaction_hash
+                    .map_or("-".to_string(), |h| format!("{:?}", h)),
+                metadata
+                    .log_index
+                    .map_or("-".to_string(), |i| i.to_string()),
+                swap.direction,
+                swap.amount0_as_decimal_num,
+                swap.amount1_as_decimal_num,
+                swap.sender,
+                swap.recipient,
+            )?;
+        }
+        OutputFormat::Json => writeln!(out, "{}", serde_json::to_string(swap)?)?,
+    }
+    Ok(())
+}
+
+/// Sinks of the config, failing for the ones this build can't write to yet.
+/// Rows of shared databases are keyed by `chain_id`
+pub async fn build_sinks(
+    configs: &[SinkConfig],
+    chain_id: u64,
+) -> Result<Vec<Box<dyn Sink>>, anyhow::Error> {
+    let mut sinks: Vec<Box<dyn Sink>> = vec![];
+    for config in configs {
+        match config {
+            SinkConfig::File { path, format } => {
+                sinks.push(Box::new(WriterSink::file(path, *format)?));
+            }
+            SinkConfig::Webhook {
+                url,
+                secret,
+                dead_letters,
+                max_attempts,
+            } => {
+                let webhook = WebhookSink::new(url, secret.clone(), chain_id, dead_letters)?
+                    .with_retry(*max_attempts, DEFAULT_RETRY_DELAY);
+                sinks.push(Box::new(webhook));
+            }
+            SinkConfig::Candles { path, intervals } => {
+                sinks.push(Box::new(CandleSink::file(path, intervals.clone())?));
+            }
+            SinkConfig::Database { url } if url.starts_with("postgres://") => {
+                sinks.push(Box::new(PostgresSink::connect(url, chain_id).await?));
+            }
+            SinkConfig::Database { url } => match url.strip_prefix("sqlite://") {
+                Some(path) => sinks.push(Box::new(Store::open(path)?)),
+                None => {
+                    return Err(MonitorError::InvalidConfig(format!(
+                        "sinks: database {:?} isn't supported yet",
+                        url
+                    ))
+                    .into())
+                }
+            },
+        }
+    }
+    Ok(sinks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks_handler::confirmed_block;
+    use rust_decimal::Decimal;
+    use web3::types::H160;
+
+    #[tokio::test]
+    async fn test_writer_sink_writes_a_line_per_swap() {
+        let mut sink = WriterSink::new(vec![], OutputFormat::Json);
+        sink.write(&confirmed_block(21836327, 1, 2)).await.unwrap();
+        sink.write(&confirmed_block(21836328, 2, 0)).await.unwrap();
+
+        let output = String::from_utf8(sink.out).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["block_number"], 21836327);
+        assert_eq!(lines[1]["log_index"], 1);
+        assert_eq!(lines[0]["amount0_as_decimal_num"], "1.5");
+        assert_eq!(lines[0]["direction"], "DaiToUsdc");
+
+        let mut sink = WriterSink::new(vec![], OutputFormat::Text);
+        sink.write(&confirmed_block(21836327, 1, 1)).await.unwrap();
+        let output = String::from_utf8(sink.out).unwrap();
+        assert!(
+            output.starts_with("block 21836327 tx 0xbbbb")
+                && output.contains("log 0: DaiToUsdc amount0 1.5 amount1 -1 "),
+            "{}",
+            output
+        );
+    }
+
+    #[tokio::test]
+    async fn test_writer_sink_writes_alerts() {
+        let alert = Alert::DepegRecovered {
+            pool: H160::repeat_byte(0xaa),
+            block_number: 21836327,
+            price: Decimal::ONE,
+        };
+        let mut sink = WriterSink::new(vec![], OutputFormat::Json);
+        sink.write_alert(&alert).await.unwrap();
+        let line: serde_json::Value = serde_json::from_slice(&sink.out).unwrap();
+        assert_eq!(line["kind"], "depeg_recovered");
+        assert_eq!(line["block_number"], 21836327);
+
+        let mut sink = WriterSink::new(vec![], OutputFormat::Text);
+        sink.write_alert(&alert).await.unwrap();
+        let output = String::from_utf8(sink.out).unwrap();
+        assert!(
+            output.starts_with("alert: pool 0xaaaa") && output.contains("block 21836327: price 1"),
+            "{}",
+            output
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_sinks_are_rejected() {
+        let database = SinkConfig::Database {
+            url: "mysql://localhost/swaps".to_string(),
+        };
+        let err = build_sinks(&[database], 1).await.err().unwrap();
+        assert!(err.to_string().contains("isn't supported"), "{}", err);
+    }
+}
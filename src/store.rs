@@ -0,0 +1,408 @@
+use crate::{
+    blocks_handler::{ConfirmedBlock, Reorg},
+    sinks::Sink,
+    swap_details::{LogMetadata, SwapDetails, SwapDirection},
+};
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use log::debug;
+use rusqlite::{params, Connection, Row};
+use rust_decimal::Decimal;
+use std::{path::Path, str::FromStr, time::Duration};
+use web3::types::{H160, H256, U256};
+
+/// Schema changes, applied in order. `PRAGMA user_version` is the number of the ones applied already,
+/// so a migration is never changed once released, only new ones are appended
+const MIGRATIONS: &[&str] = &[CREATE_SWAPS_AND_REORGS];
+
+const CREATE_SWAPS_AND_REORGS: &str = "CREATE TABLE swaps (
+        block_hash TEXT NOT NULL,
+        log_index INTEGER NOT NULL,
+        block_number INTEGER NOT NULL,
+        transaction_hash TEXT,
+        pool TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        amount0_raw TEXT NOT NULL,
+        amount0 TEXT NOT NULL,
+        amount1_raw TEXT NOT NULL,
+        amount1 TEXT NOT NULL,
+        direction TEXT NOT NULL,
+        PRIMARY KEY (block_hash, log_index)
+    );
+    CREATE INDEX swaps_block_number ON swaps (block_number);
+    CREATE TABLE reorgs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        detected_at INTEGER NOT NULL,
+        fork_point INTEGER NOT NULL,
+        depth INTEGER NOT NULL,
+        orphaned_hashes TEXT NOT NULL,
+        new_hashes TEXT NOT NULL,
+        recorded_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        UNIQUE (fork_point, orphaned_hashes)
+    );";
+
+/// How long a statement waits for another connection's write (e.g., the HTTP API reading while the monitor writes)
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which swaps `Store::query_swaps` returns, unset criteria match every swap
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SwapFilter {
+    pub from_block: Option<u64>,
+    /// Inclusive
+    pub to_block: Option<u64>,
+    pub direction: Option<SwapDirection>,
+    /// Swaps of at least this amount of either token
+    pub min_amount: Option<Decimal>,
+    /// Sender or recipient
+    pub address: Option<H160>,
+}
+
+/// Confirmed swaps & detected reorgs in an embedded SQLite database.
+/// Writes are idempotent, so blocks written again after a restart don't duplicate swaps
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    /// Opens (or creates) the database at `path` & migrates it to the current schema
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let conn = Connection::open(path)
+            .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+        Self::with_connection(conn)
+    }
+
+    pub fn open_in_memory() -> Result<Self, anyhow::Error> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self, anyhow::Error> {
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        let mut store = Self { conn };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    fn migrate(&mut self) -> Result<(), anyhow::Error> {
+        let applied: usize = self
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if applied > MIGRATIONS.len() {
+            bail!(
+                "Database schema version {} is newer than this build supports ({})",
+                applied,
+                MIGRATIONS.len()
+            );
+        }
+        for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+            let tx = self.conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", version + 1)?;
+            tx.commit()?;
+            debug!("database migrated to version {}", version + 1);
+        }
+        Ok(())
+    }
+
+    /// Inserts the swaps of `confirmed_block` in a single transaction, returns how many weren't stored yet
+    pub fn insert_block(
+        &mut self,
+        confirmed_block: &ConfirmedBlock,
+    ) -> Result<usize, anyhow::Error> {
+        let tx = self.conn.transaction()?;
+        let mut inserted = 0;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT OR IGNORE INTO swaps (block_hash, log_index, block_number, transaction_hash, pool,
+                    sender, recipient, amount0_raw, amount0, amount1_raw, amount1, direction)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            )?;
+            for swap in &confirmed_block.swaps {
+                let metadata = &swap.metadata;
+                let Some(log_index) = metadata.log_index else {
+                    bail!(
+                        "Swap of block {} has no log index",
+                        confirmed_block.block_number
+                    );
+                };
+                inserted += insert.execute(params![
+                    format!("{:?}", confirmed_block.block_hash),
+                    log_index,
+                    confirmed_block.block_number,
+                    metadata.transaction_hash.map(|hash| format!("{:?}", hash)),
+                    format!("{:?}", metadata.pool),
+                    format!("{:?}", swap.sender),
+                    format!("{:?}", swap.recipient),
+                    swap.amount0_raw.to_string(),
+                    swap.amount0_as_decimal_num.to_string(),
+                    swap.amount1_raw.to_string(),
+                    swap.amount1_as_decimal_num.to_string(),
+                    format!("{:?}", swap.direction),
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    /// Returns whether the reorg wasn't stored yet
+    pub fn insert_reorg(&mut self, reorg: &Reorg) -> Result<bool, anyhow::Error> {
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO reorgs (detected_at, fork_point, depth, orphaned_hashes, new_hashes)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                reorg.detected_at,
+                reorg.fork_point,
+                reorg.depth,
+                serde_json::to_string(&reorg.orphaned_hashes)?,
+                serde_json::to_string(&reorg.new_hashes)?,
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// Swaps of blocks `from_block` to `to_block` (inclusive), in order of blocks & logs
+    pub fn swaps(&self, from_block: u64, to_block: u64) -> Result<Vec<SwapDetails>, anyhow::Error> {
+        let filter = SwapFilter {
+            from_block: Some(from_block),
+            to_block: Some(to_block),
+            ..Default::default()
+        };
+        self.query_swaps(&filter, usize::MAX)
+    }
+
+    /// The first `limit` swaps matching `filter`, in order of blocks & logs
+    pub fn query_swaps(
+        &self,
+        filter: &SwapFilter,
+        limit: usize,
+    ) -> Result<Vec<SwapDetails>, anyhow::Error> {
+        let mut select = self.conn.prepare_cached(
+            "SELECT block_hash, log_index, block_number, transaction_hash, pool, sender, recipient,
+                amount0_raw, amount0, amount1_raw, amount1, direction
+            FROM swaps
+            WHERE block_number BETWEEN ?1 AND ?2
+                AND (?3 IS NULL OR direction = ?3)
+                AND (?4 IS NULL OR sender = ?4 OR recipient = ?4)
+            ORDER BY block_number, log_index",
+        )?;
+        // SQLite integers are signed
+        let to_block = filter
+            .to_block
+            .map_or(i64::MAX, |to| to.min(i64::MAX as u64) as i64);
+        let rows = select.query_map(
+            params![
+                filter.from_block.unwrap_or_default().min(i64::MAX as u64) as i64,
+                to_block,
+                filter.direction.map(|direction| format!("{:?}", direction)),
+                filter.address.map(|address| format!("{:?}", address)),
+            ],
+            |row| Ok(read_swap(row)),
+        )?;
+        // amounts are stored as text, so they're compared once parsed
+        let mut swaps = vec![];
+        for row in rows {
+            let swap = row??;
+            let large_enough = filter.min_amount.is_none_or(|min_amount| {
+                swap.amount0_as_decimal_num.abs() >= min_amount
+                    || swap.amount1_as_decimal_num.abs() >= min_amount
+            });
+            if large_enough {
+                swaps.push(swap);
+            }
+            if swaps.len() >= limit {
+                break;
+            }
+        }
+        Ok(swaps)
+    }
+
+    /// Recorded reorgs, oldest first
+    pub fn reorgs(&self) -> Result<Vec<Reorg>, anyhow::Error> {
+        let mut select = self.conn.prepare_cached(
+            "SELECT detected_at, fork_point, depth, orphaned_hashes, new_hashes FROM reorgs ORDER BY id",
+        )?;
+        let rows = select.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        })?;
+        rows.map(|row| {
+            let (detected_at, fork_point, depth, orphaned_hashes, new_hashes) = row?;
+            Ok(Reorg {
+                detected_at,
+                fork_point,
+                depth,
+                orphaned_hashes: serde_json::from_str(&orphaned_hashes)?,
+                new_hashes: serde_json::from_str(&new_hashes)?,
+            })
+        })
+        .collect()
+    }
+}
+
+fn read_swap(row: &Row) -> Result<SwapDetails, anyhow::Error> {
+    let transaction_hash: Option<String> = row.get(3)?;
+    let direction: String = row.get(11)?;
+    let direction = match direction.as_str() {
+        "DaiToUsdc" => SwapDirection::DaiToUsdc,
+        "UsdcToDai" => SwapDirection::UsdcToDai,
+        _ => bail!("Unknown swap direction {:?}", direction),
+    };
+    let amount0_as_decimal_num: Decimal = row.get::<_, String>(8)?.parse()?;
+    Ok(SwapDetails {
+        metadata: LogMetadata {
+            pool: H160::from_str(&row.get::<_, String>(4)?)?,
+            block_number: Some(row.get(2)?),
+            block_hash: Some(H256::from_str(&row.get::<_, String>(0)?)?),
+            transaction_hash: transaction_hash
+                .map(|hash| H256::from_str(&hash))
+                .transpose()?,
+            log_index: Some(row.get(1)?),
+        },
+        sender: H160::from_str(&row.get::<_, String>(5)?)?,
+        recipient: H160::from_str(&row.get::<_, String>(6)?)?,
+        amount0_raw: U256::from_dec_str(&row.get::<_, String>(7)?)?,
+        amount0_as_decimal_num,
+        amount1_raw: U256::from_dec_str(&row.get::<_, String>(9)?)?,
+        amount1_as_decimal_num: row.get::<_, String>(10)?.parse()?,
+        direction,
+        // not stored, but DAI is token0 when it moved the way amount0 did
+        dai_is_token1: (direction == SwapDirection::DaiToUsdc)
+            != (amount0_as_decimal_num > Decimal::ZERO),
+        sqrt_price_x96: U256::zero(),
+    })
+}
+
+/// Every write is committed right away, so there's nothing to flush
+#[async_trait]
+impl Sink for Store {
+    async fn write(&mut self, confirmed_block: &ConfirmedBlock) -> Result<(), anyhow::Error> {
+        let inserted = self.insert_block(confirmed_block)?;
+        debug!(
+            "block: {} stored, {} new swaps",
+            confirmed_block.block_number, inserted
+        );
+        Ok(())
+    }
+
+    async fn write_reorg(&mut self, reorg: &Reorg) -> Result<(), anyhow::Error> {
+        self.insert_reorg(reorg)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks_handler::confirmed_block;
+
+    #[test]
+    fn test_writes_are_idempotent() {
+        let mut store = Store::open_in_memory().unwrap();
+        assert_eq!(store.insert_block(&confirmed_block(100, 1, 2)).unwrap(), 2);
+        assert_eq!(store.insert_block(&confirmed_block(100, 1, 3)).unwrap(), 1);
+        // the same height on another branch is another block
+        assert_eq!(store.insert_block(&confirmed_block(100, 2, 1)).unwrap(), 1);
+        assert_eq!(store.insert_block(&confirmed_block(101, 3, 0)).unwrap(), 0);
+
+        let swaps = store.swaps(100, 101).unwrap();
+        assert_eq!(swaps.len(), 4);
+        let expected = &confirmed_block(100, 1, 1).swaps[0];
+        let stored = &swaps[0];
+        assert_eq!(stored.metadata, expected.metadata);
+        assert_eq!(stored.amount0_raw, expected.amount0_raw);
+        assert_eq!(stored.amount1_raw, expected.amount1_raw);
+        assert_eq!(stored.amount1_as_decimal_num, Decimal::new(-1, 0));
+        assert_eq!(stored.direction, SwapDirection::DaiToUsdc);
+        assert!(store.swaps(101, 200).unwrap().is_empty());
+
+        let reorg = Reorg {
+            detected_at: 105,
+            fork_point: 99,
+            depth: 1,
+            orphaned_hashes: vec![H256::repeat_byte(1)],
+            new_hashes: vec![H256::repeat_byte(2), H256::repeat_byte(3)],
+        };
+        assert!(store.insert_reorg(&reorg).unwrap());
+        assert!(!store.insert_reorg(&reorg).unwrap());
+        assert_eq!(store.reorgs().unwrap(), vec![reorg]);
+    }
+
+    #[test]
+    fn test_swaps_are_filtered() {
+        let mut store = Store::open_in_memory().unwrap();
+        let mut block = confirmed_block(100, 1, 3);
+        block.swaps[1].direction = SwapDirection::UsdcToDai;
+        block.swaps[1].recipient = H160::repeat_byte(3);
+        block.swaps[2].amount0_as_decimal_num = Decimal::new(-5000, 0);
+        store.insert_block(&block).unwrap();
+        store.insert_block(&confirmed_block(101, 2, 1)).unwrap();
+
+        let log_indexes = |filter: SwapFilter, limit: usize| -> Vec<(u64, u64)> {
+            store
+                .query_swaps(&filter, limit)
+                .unwrap()
+                .iter()
+                .map(|swap| {
+                    (
+                        swap.metadata.block_number.unwrap(),
+                        swap.metadata.log_index.unwrap(),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(
+            log_indexes(SwapFilter::default(), 2),
+            vec![(100, 0), (100, 1)]
+        );
+        let filter = SwapFilter {
+            direction: Some(SwapDirection::UsdcToDai),
+            ..Default::default()
+        };
+        assert_eq!(log_indexes(filter, 10), vec![(100, 1)]);
+        let filter = SwapFilter {
+            address: Some(H160::repeat_byte(3)),
+            ..Default::default()
+        };
+        assert_eq!(log_indexes(filter, 10), vec![(100, 1)]);
+        let filter = SwapFilter {
+            min_amount: Some(Decimal::new(1000, 0)),
+            ..Default::default()
+        };
+        assert_eq!(log_indexes(filter, 10), vec![(100, 2)]);
+        let filter = SwapFilter {
+            from_block: Some(101),
+            to_block: Some(u64::MAX),
+            ..Default::default()
+        };
+        assert_eq!(log_indexes(filter, 10), vec![(101, 0)]);
+    }
+
+    #[test]
+    fn test_migrations_are_applied_once() {
+        let dir = std::env::temp_dir().join(format!("store-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("swaps.db");
+
+        let mut store = Store::open(&path).unwrap();
+        store.insert_block(&confirmed_block(100, 1, 1)).unwrap();
+        drop(store);
+        let store = Store::open(&path).unwrap();
+        assert_eq!(store.swaps(100, 100).unwrap().len(), 1);
+
+        store
+            .conn
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        drop(store);
+        let err = Store::open(&path).err().unwrap();
+        assert!(err.to_string().contains("newer"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,21 +1,47 @@
+use crate::error::MonitorError;
 use anyhow::anyhow;
 use log::debug;
 use rust_decimal::Decimal;
-use std::str::FromStr;
+use serde::{Deserialize, Serialize};
 use web3::{
     ethabi,
     ethabi::Int,
-    types::{Address, U256},
+    types::{Address, Log, H256, U256},
 };
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SwapDirection {
     DaiToUsdc,
     UsdcToDai,
 }
 
-#[derive(Debug)]
+/// Where a swap was emitted. A parsed log doesn't carry it, so it's empty until filled from the raw log
+/// (see `EventsHandler::handle_logs`)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogMetadata {
+    pub pool: Address,
+    pub block_number: Option<u64>,
+    pub block_hash: Option<H256>,
+    pub transaction_hash: Option<H256>,
+    pub log_index: Option<u64>,
+}
+
+impl From<&Log> for LogMetadata {
+    fn from(log: &Log) -> Self {
+        Self {
+            pool: log.address,
+            block_number: log.block_number.map(|number| number.as_u64()),
+            block_hash: log.block_hash,
+            transaction_hash: log.transaction_hash,
+            log_index: log.log_index.and_then(|index| u64::try_from(index).ok()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SwapDetails {
+    #[serde(flatten)]
+    pub metadata: LogMetadata,
     pub sender: Address,
     pub recipient: Address,
     pub amount0_raw: Int,
@@ -26,43 +52,112 @@ pub struct SwapDetails {
     /// The negative indicates the amount output to the `receiver` address.
     /// e.g., 1000 `amount0`/DAI and -50 `amount1`/USDC indicates a swap direction of DAI -> USDC
     pub direction: SwapDirection,
+    /// Whether the pool's token1 is DAI (& token0 USDC), as on pools whose USDC address sorts before DAI's
+    #[serde(default)]
+    pub dai_is_token1: bool,
+    /// Square root of the pool's price (raw token1 per raw token0) after the swap, as a Q64.96 number.
+    /// Zero when unknown, e.g., for swaps read back from a store
+    #[serde(default)]
+    pub sqrt_price_x96: U256,
 }
 
+/// DAI has 18 decimals
+pub const AMOUNT0_DECIMALS: u32 = 18;
+/// USDC has 6 decimals
+pub const AMOUNT1_DECIMALS: u32 = 6;
+
 impl SwapDetails {
     /// So while U256 can't store negative numbers directly, the contract uses two's complement encoding to
     /// represent negative values within the positive number space. The function detects this encoding and
-    /// converts it back to a proper negative number string representation.
-    fn format_amount(amount: U256) -> String {
+    /// returns whether the number is negative, along with it's absolute value.
+    fn decode_signed(amount: U256) -> (bool, U256) {
         let max = U256::max_value();
         // is it negative ?
         if amount > max / 2 {
             // handle negative numbers in two's complement
             // convert from two's complement to get positive value
-            let positive_amount = max - amount + U256::from(1);
+            (true, max - amount + U256::from(1))
+        } else {
+            (false, amount)
+        }
+    }
+
+    /// Signed string representation of a two's complement encoded amount
+    pub(crate) fn format_amount(amount: U256) -> String {
+        match Self::decode_signed(amount) {
             // add negative sign
-            format!("-{}", positive_amount)
+            (true, positive_amount) => format!("-{}", positive_amount),
+            (false, amount) => amount.to_string(),
+        }
+    }
+
+    /// Converts a raw (two's complement encoded) token amount to a decimal one, e.g., `1500000` with 6 decimals
+    /// is `1.5`. Scaling is exact, amounts that don't fit into `Decimal` (96 bit) are an error rather than a rounded value.
+    fn to_decimal(amount: U256, decimals: u32) -> Result<Decimal, anyhow::Error> {
+        let (negative, positive_amount) = Self::decode_signed(amount);
+        let out_of_range = || MonitorError::AmountOutOfRange(Self::format_amount(amount));
+
+        if positive_amount > U256::from(u128::MAX) {
+            return Err(out_of_range().into());
+        }
+        let mantissa = i128::try_from(positive_amount.as_u128()).map_err(|_| out_of_range())?;
+        let mantissa = if negative { -mantissa } else { mantissa };
+        Ok(Decimal::try_from_i128_with_scale(mantissa, decimals).map_err(|_| out_of_range())?)
+    }
+
+    /// DAI & USDC amounts of the swap, token0's & token1's unless `dai_is_token1`
+    pub fn dai_and_usdc_amounts(&self) -> (Decimal, Decimal) {
+        if self.dai_is_token1 {
+            (self.amount1_as_decimal_num, self.amount0_as_decimal_num)
         } else {
-            amount.to_string()
+            (self.amount0_as_decimal_num, self.amount1_as_decimal_num)
         }
     }
 
-    /// Converts raw amount from contract to decimal DAI amount
-    fn format_amount0(amount0: U256) -> Decimal {
-        let amount_str = Self::format_amount(amount0);
-        let dai_amount = Decimal::from_str(&amount_str).unwrap_or(Decimal::ZERO);
-        let divisor = Decimal::from_str("1000000000000000000").unwrap(); // 1e18
-        dai_amount / divisor
+    /// USDC paid per DAI, whichever token of the pool DAI is. None when no DAI was swapped
+    pub fn price(&self) -> Option<Decimal> {
+        let (dai, usdc) = self.dai_and_usdc_amounts();
+        usdc.abs()
+            .checked_div(dai.abs())
+            .map(|price| price.normalize())
     }
 
-    /// Converts raw amount from contract to decimal USDC amount
-    fn format_amount1(amount1: U256) -> Decimal {
-        let amount_str = Self::format_amount(amount1);
-        let usdc_amount = Decimal::from_str(&amount_str).unwrap_or(Decimal::ZERO);
-        let divisor = Decimal::from_str("1000000").unwrap(); // 1e6 for USDC
-        usdc_amount / divisor
+    /// The pool's price after the swap in USDC per DAI (from `sqrt_price_x96`), none when unknown.
+    /// `decimals0` & `decimals1` are the pool's token0's & token1's. It's a float underneath, precise to about 15 digits
+    pub fn pool_price(&self, decimals0: u32, decimals1: u32) -> Option<Decimal> {
+        if self.sqrt_price_x96.is_zero() {
+            return None;
+        }
+        let sqrt_price = self
+            .sqrt_price_x96
+            .0
+            .iter()
+            .rev()
+            .fold(0.0, |value, word| value * 2f64.powi(64) + *word as f64)
+            / 2f64.powi(96);
+        // token1 per token0
+        let price = sqrt_price * sqrt_price * 10f64.powi(decimals0 as i32 - decimals1 as i32);
+        let price = if self.dai_is_token1 {
+            1.0 / price
+        } else {
+            price
+        };
+        Decimal::from_f64_retain(price).map(|price| price.round_sf(15).unwrap_or(price).normalize())
     }
 
+    /// Swap of the DAI/USDC pool
     pub fn from_parsed_log(parsed_log: ethabi::Log) -> Result<SwapDetails, anyhow::Error> {
+        Self::from_parsed_log_of_pool(parsed_log, AMOUNT0_DECIMALS, AMOUNT1_DECIMALS, false)
+    }
+
+    /// Swap of a pool whose tokens have `decimals0` & `decimals1` decimals, with DAI as token1 if `dai_is_token1`.
+    /// `direction` is named after DAI/USDC, for pools of other tokens `DaiToUsdc` means token0 -> token1
+    pub fn from_parsed_log_of_pool(
+        parsed_log: ethabi::Log,
+        decimals0: u32,
+        decimals1: u32,
+        dai_is_token1: bool,
+    ) -> Result<SwapDetails, anyhow::Error> {
         debug!("parsed log: {:#?}", parsed_log);
 
         let amount0 = Self::extract_param_by_name(&parsed_log, "amount0")?
@@ -73,10 +168,17 @@ impl SwapDetails {
             .into_int()
             .ok_or(anyhow!("Invalid type: expected Int"))?;
 
-        let amount0_decimal = Self::format_amount0(amount0);
-        let amount1_decimal = Self::format_amount1(amount1);
+        let amount0_decimal = Self::to_decimal(amount0, decimals0)?;
+        let amount1_decimal = Self::to_decimal(amount1, decimals1)?;
+        // USDC paid out means DAI was paid in
+        let usdc_decimal = if dai_is_token1 {
+            amount0_decimal
+        } else {
+            amount1_decimal
+        };
 
         let swap_details = SwapDetails {
+            metadata: LogMetadata::default(),
             sender: Self::extract_param_by_name(&parsed_log, "sender")? // Result -> Token
                 .into_address()
                 // convert Option<Address> to Result<Address, & then ? will unwrap it's value
@@ -89,11 +191,15 @@ impl SwapDetails {
             amount0_as_decimal_num: amount0_decimal,
             amount1_raw: amount1,
             amount1_as_decimal_num: amount1_decimal,
-            direction: if amount1_decimal < Decimal::ZERO {
+            direction: if usdc_decimal < Decimal::ZERO {
                 SwapDirection::DaiToUsdc
             } else {
                 SwapDirection::UsdcToDai
             },
+            dai_is_token1,
+            sqrt_price_x96: Self::extract_param_by_name(&parsed_log, "sqrtPriceX96")?
+                .into_uint()
+                .ok_or(anyhow!("Invalid type: expected Uint"))?,
         };
 
         Ok(swap_details)
@@ -114,3 +220,209 @@ impl SwapDetails {
         Ok(token)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::str::FromStr;
+    use web3::ethabi::{LogParam, Token};
+
+    /// 2^255, i.e., the absolute value of `I256::MIN`
+    fn i256_min_abs() -> U256 {
+        U256::one() << 255
+    }
+
+    /// Two's complement encoding, the way the contract emits `int256`
+    fn encode(value: i128) -> U256 {
+        let abs = U256::from(value.unsigned_abs());
+        if value < 0 {
+            abs.overflowing_neg().0
+        } else {
+            abs
+        }
+    }
+
+    /// `value` with the decimal point put `decimals` digits from the right, e.g., (-15, 1) -> "-1.5"
+    fn with_decimal_point(value: i128, decimals: u32) -> String {
+        let digits = format!(
+            "{:0>width$}",
+            value.unsigned_abs(),
+            width = decimals as usize + 1
+        );
+        let (int, frac) = digits.split_at(digits.len() - decimals as usize);
+        let sign = if value < 0 { "-" } else { "" };
+        if frac.is_empty() {
+            format!("{}{}", sign, int)
+        } else {
+            format!("{}{}.{}", sign, int, frac)
+        }
+    }
+
+    fn any_u256() -> impl Strategy<Value = U256> {
+        any::<[u8; 32]>().prop_map(|bytes| U256::from_big_endian(&bytes))
+    }
+
+    /// Absolute values of negative `int256`s, i.e., `1..=2^255`
+    fn negative_int256_abs() -> impl Strategy<Value = U256> {
+        any_u256().prop_map(|value| (value & (i256_min_abs() - 1)) + 1)
+    }
+
+    /// Values whose absolute value fits into `Decimal`'s 96 bit mantissa
+    fn decimal_range_i128() -> impl Strategy<Value = i128> {
+        let max = (1_i128 << 96) - 1;
+        -max..=max
+    }
+
+    fn swap_log(amount0: U256, amount1: U256) -> ethabi::Log {
+        let param = |name: &str, value: Token| LogParam {
+            name: name.to_string(),
+            value,
+        };
+        ethabi::Log {
+            params: vec![
+                param("sender", Token::Address(Address::repeat_byte(0xaa))),
+                param("recipient", Token::Address(Address::repeat_byte(0xbb))),
+                param("amount0", Token::Int(amount0)),
+                param("amount1", Token::Int(amount1)),
+                param("sqrtPriceX96", Token::Uint(U256::one() << 96)),
+                param("liquidity", Token::Uint(U256::zero())),
+                param("tick", Token::Int(U256::zero())),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_pool_price_of_sqrt_price() {
+        let mut swap = SwapDetails::from_parsed_log(swap_log(U256::zero(), U256::zero())).unwrap();
+        // 2^96, a raw price of 1
+        assert_eq!(swap.sqrt_price_x96, U256::one() << 96);
+        assert_eq!(swap.pool_price(6, 6), Some(Decimal::ONE));
+        // DAI/USDC at 1.0001 USDC per DAI, i.e., sqrt(1.0001e-12) * 2^96
+        swap.sqrt_price_x96 = U256::from_dec_str("79232123823359799118286").unwrap();
+        assert_eq!(
+            swap.pool_price(AMOUNT0_DECIMALS, AMOUNT1_DECIMALS)
+                .map(|price| price.round_dp(9)),
+            Some(Decimal::from_str("1.0001").unwrap())
+        );
+        swap.sqrt_price_x96 = U256::zero();
+        assert_eq!(swap.pool_price(AMOUNT0_DECIMALS, AMOUNT1_DECIMALS), None);
+    }
+
+    #[test]
+    fn test_swap_of_a_usdc_token0_pool() {
+        // 1000 USDC (token0) out for 1000.5 DAI (token1) in, e.g., on the Optimism pools
+        let log = swap_log(
+            encode(-1_000_000_000),
+            encode(1_000_500_000_000_000_000_000),
+        );
+        let mut swap = SwapDetails::from_parsed_log_of_pool(log, 6, 18, true).unwrap();
+        assert_eq!(swap.direction, SwapDirection::DaiToUsdc);
+        assert_eq!(
+            swap.dai_and_usdc_amounts(),
+            (Decimal::from_str("1000.5").unwrap(), Decimal::from(-1000))
+        );
+        // still USDC per DAI
+        assert_eq!(
+            swap.price().map(|price| price.round_dp(6)),
+            Some(Decimal::from_str("0.99950").unwrap())
+        );
+        // 1 DAI per USDC, a raw price of 10^12 token1 per token0
+        swap.sqrt_price_x96 = (U256::one() << 96) * U256::exp10(6);
+        assert_eq!(swap.pool_price(6, 18), Some(Decimal::ONE));
+
+        let log = swap_log(encode(1_000_000_000), encode(-999_500_000_000_000_000_000));
+        let swap = SwapDetails::from_parsed_log_of_pool(log, 6, 18, true).unwrap();
+        assert_eq!(swap.direction, SwapDirection::UsdcToDai);
+        assert_eq!(
+            swap.price().map(|price| price.round_dp(6)),
+            Some(Decimal::from_str("1.000500").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_int256_edge_cases() {
+        assert_eq!(SwapDetails::format_amount(U256::zero()), "0");
+        assert_eq!(SwapDetails::format_amount(U256::MAX), "-1");
+        // I256::MAX
+        assert_eq!(
+            SwapDetails::format_amount(i256_min_abs() - 1),
+            "57896044618658097711785492504343953926634992332820282019728792003956564819967"
+        );
+        // I256::MIN
+        assert_eq!(
+            SwapDetails::format_amount(i256_min_abs()),
+            "-57896044618658097711785492504343953926634992332820282019728792003956564819968"
+        );
+
+        // way beyond `Decimal`, so they must not end up as some (e.g., zero) amount
+        assert!(SwapDetails::to_decimal(i256_min_abs(), AMOUNT0_DECIMALS).is_err());
+        assert!(SwapDetails::to_decimal(i256_min_abs() - 1, AMOUNT0_DECIMALS).is_err());
+        assert_eq!(
+            SwapDetails::to_decimal(U256::MAX, AMOUNT1_DECIMALS).unwrap(),
+            Decimal::from_str("-0.000001").unwrap()
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_negative_amounts_round_trip(abs in negative_int256_abs()) {
+            let encoded = abs.overflowing_neg().0;
+            prop_assert_eq!(SwapDetails::decode_signed(encoded), (true, abs));
+            prop_assert_eq!(SwapDetails::format_amount(encoded), format!("-{}", abs));
+        }
+
+        #[test]
+        fn prop_positive_amounts_round_trip(amount in any_u256()) {
+            let amount = amount & (i256_min_abs() - 1);
+            prop_assert_eq!(SwapDetails::decode_signed(amount), (false, amount));
+            prop_assert_eq!(SwapDetails::format_amount(amount), amount.to_string());
+        }
+
+        #[test]
+        fn prop_sign_follows_top_bit(amount in any_u256()) {
+            let formatted = SwapDetails::format_amount(amount);
+            prop_assert_eq!(formatted.starts_with('-'), amount.bit(255));
+        }
+
+        #[test]
+        fn prop_scaling_is_exact(value in decimal_range_i128(), decimals in 0_u32..=28) {
+            let decimal = SwapDetails::to_decimal(encode(value), decimals).unwrap();
+            prop_assert_eq!(decimal.mantissa(), value);
+            prop_assert_eq!(decimal.scale(), decimals);
+            prop_assert_eq!(
+                decimal,
+                Decimal::from_str(&with_decimal_point(value, decimals)).unwrap()
+            );
+        }
+
+        #[test]
+        fn prop_amounts_beyond_decimal_range_are_errors(
+            abs in negative_int256_abs(),
+            negative in any::<bool>(),
+            decimals in 0_u32..=28,
+        ) {
+            let abs = abs | (U256::one() << 96);
+            let amount = if negative { abs.overflowing_neg().0 } else { abs.min(i256_min_abs() - 1) };
+            prop_assert!(SwapDetails::to_decimal(amount, decimals).is_err());
+        }
+
+        #[test]
+        fn prop_direction_follows_amount1_sign(
+            amount0 in decimal_range_i128(),
+            amount1 in decimal_range_i128(),
+        ) {
+            let swap_details =
+                SwapDetails::from_parsed_log(swap_log(encode(amount0), encode(amount1))).unwrap();
+
+            prop_assert_eq!(swap_details.amount0_as_decimal_num.mantissa(), amount0);
+            prop_assert_eq!(swap_details.amount1_as_decimal_num.mantissa(), amount1);
+            let expected = if amount1 < 0 {
+                SwapDirection::DaiToUsdc
+            } else {
+                SwapDirection::UsdcToDai
+            };
+            prop_assert_eq!(swap_details.direction, expected);
+        }
+    }
+}
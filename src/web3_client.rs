@@ -1,25 +1,40 @@
+use crate::error::MonitorError;
 use anyhow::Context;
 use async_trait::async_trait;
+use serde_json::json;
 use web3::{
     transports::WebSocket,
-    types::{BlockId, H256},
-    Web3,
+    types::{BlockHeader, BlockId, BlockNumber, H256},
+    Transport, Web3,
 };
 
 #[async_trait]
 #[cfg_attr(test, mockall::automock)]
 pub trait BlocksFetcher {
     async fn get_block_hash(&self, block_number: u64) -> Result<H256, anyhow::Error>;
-    fn web3(&self) -> Web3<WebSocket>;
+
+    /// Header of the canonical block at `block_number`, e.g., to check parent hashes of a historic range
+    async fn get_block_header(&self, block_number: u64) -> Result<BlockHeader, anyhow::Error>;
+
+    /// Header of the block the node currently tags with `tag`, e.g., `BlockNumber::Safe` on a rollup.
+    /// `None` when the node has no such block (yet)
+    async fn get_block_header_by_tag(
+        &self,
+        tag: BlockNumber,
+    ) -> Result<Option<BlockHeader>, anyhow::Error>;
 }
 
 #[derive(Clone)]
-pub struct Web3BlocksFetcher {
-    pub web3: Web3<WebSocket>,
+pub struct Web3BlocksFetcher<T: Transport = WebSocket> {
+    pub web3: Web3<T>,
 }
 
 #[async_trait]
-impl BlocksFetcher for Web3BlocksFetcher {
+impl<T> BlocksFetcher for Web3BlocksFetcher<T>
+where
+    T: Transport + Send + Sync,
+    T::Out: Send,
+{
     async fn get_block_hash(&self, block_number: u64) -> Result<H256, anyhow::Error> {
         let block_id = BlockId::Number(block_number.into());
         let block = self
@@ -28,12 +43,52 @@ impl BlocksFetcher for Web3BlocksFetcher {
             .block(block_id)
             .await
             .context("Failed to fetch block")?;
-        let block = block.unwrap();
-        let hash = block.hash.unwrap();
+        let block = block.ok_or(MonitorError::BlockNotFound(block_number))?;
+        let hash = block
+            .hash
+            .ok_or(MonitorError::PendingBlock(Some(block_number)))?;
         Ok(hash)
     }
 
-    fn web3(&self) -> Web3<WebSocket> {
-        self.web3.clone()
+    async fn get_block_header(&self, block_number: u64) -> Result<BlockHeader, anyhow::Error> {
+        let header = self
+            .fetch_header(BlockNumber::Number(block_number.into()))
+            .await?
+            .ok_or(MonitorError::BlockNotFound(block_number))?;
+        if header.hash.is_none() {
+            return Err(MonitorError::PendingBlock(Some(block_number)).into());
+        }
+        Ok(header)
+    }
+
+    async fn get_block_header_by_tag(
+        &self,
+        tag: BlockNumber,
+    ) -> Result<Option<BlockHeader>, anyhow::Error> {
+        let header = self.fetch_header(tag).await?;
+        if let Some(header) = &header {
+            if header.hash.is_none() {
+                return Err(MonitorError::PendingBlock(header.number.map(|n| n.as_u64())).into());
+            }
+        }
+        Ok(header)
+    }
+}
+
+impl<T: Transport> Web3BlocksFetcher<T> {
+    async fn fetch_header(&self, block: BlockNumber) -> Result<Option<BlockHeader>, anyhow::Error> {
+        // `eth().block(..)` would deserialize the whole block, a header is all we need
+        let params = vec![json!(block), json!(false)];
+        let response = self
+            .web3
+            .transport()
+            .execute("eth_getBlockByNumber", params)
+            .await
+            .context("Failed to fetch block")?;
+        if response.is_null() {
+            return Ok(None);
+        }
+        let header = serde_json::from_value(response).context("Failed to parse block header")?;
+        Ok(Some(header))
     }
 }
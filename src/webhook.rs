@@ -0,0 +1,415 @@
+use crate::{
+    alerts::Alert,
+    blocks_handler::{ConfirmedBlock, Reorg},
+    sinks::{ProviderOutage, Sink},
+};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use hmac::{Hmac, KeyInit, Mac};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot,
+};
+
+/// `sha256=<hex HMAC-SHA256 of the body>`, keyed by the configured secret
+pub const SIGNATURE_HEADER: &str = "X-Monitor-Signature";
+/// `swaps`, `reorg`, `provider_outage` or `alert`, the `event` field of the body
+pub const EVENT_HEADER: &str = "X-Monitor-Event";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay after the first failed attempt, doubled after every further one
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Payloads waiting for the delivery task, further ones are dead-lettered right away
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// A payload which couldn't be delivered, a JSON object per line of the dead letter file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub event: String,
+    pub body: Value,
+    /// Why the last attempt failed
+    pub error: String,
+    /// Unix timestamp (seconds)
+    pub failed_at: u64,
+}
+
+impl DeadLetter {
+    fn new(event: &str, body: Value, error: String) -> Self {
+        Self {
+            event: event.to_string(),
+            body,
+            error,
+            failed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+        }
+    }
+}
+
+/// Why a delivery attempt failed
+struct Failure {
+    /// Timeouts, connection errors, 5xx & 429, rather than a request the endpoint refuses
+    retryable: bool,
+    error: String,
+}
+
+enum Message {
+    Payload {
+        event: String,
+        body: Value,
+    },
+    /// Answered once the payloads queued before it are delivered or dead-lettered
+    Flush(oneshot::Sender<()>),
+}
+
+/// POSTs confirmed swaps (blocks without swaps are skipped), reorgs, provider outages & alerts as JSON.
+/// Payloads are queued for a background task, so a slow or unreachable endpoint doesn't hold up the heads.
+/// Failed deliveries are retried with exponential backoff, then appended to the dead letter file,
+/// which is sent again (in order) after the next successful delivery. The endpoint being down never stops the monitor
+pub struct WebhookSink {
+    chain_id: u64,
+    dead_letters: Arc<Mutex<DeadLetters>>,
+    queue_capacity: usize,
+    /// Settings of the delivery task until the first payload starts it
+    delivery: Option<Delivery>,
+    queue: Option<mpsc::Sender<Message>>,
+}
+
+impl WebhookSink {
+    pub fn new(
+        url: &str,
+        secret: Option<String>,
+        chain_id: u64,
+        dead_letters: impl AsRef<Path>,
+    ) -> Result<Self, anyhow::Error> {
+        let dead_letters = DeadLetters::open(dead_letters.as_ref())?;
+        if dead_letters.pending > 0 {
+            info!(
+                "{} undelivered webhook payloads in {}",
+                dead_letters.pending,
+                dead_letters.path.display()
+            );
+        }
+        let dead_letters = Arc::new(Mutex::new(dead_letters));
+        Ok(Self {
+            chain_id,
+            dead_letters: dead_letters.clone(),
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            delivery: Some(Delivery {
+                client: reqwest::Client::builder()
+                    .timeout(REQUEST_TIMEOUT)
+                    .build()?,
+                url: url.to_string(),
+                secret,
+                dead_letters,
+                max_attempts: 5,
+                retry_delay: DEFAULT_RETRY_DELAY,
+            }),
+            queue: None,
+        })
+    }
+
+    /// Attempts per payload & the delay after the first one, doubled after every further attempt
+    pub fn with_retry(mut self, max_attempts: u32, retry_delay: Duration) -> Self {
+        if let Some(delivery) = &mut self.delivery {
+            delivery.max_attempts = max_attempts.max(1);
+            delivery.retry_delay = retry_delay;
+        }
+        self
+    }
+
+    /// Payloads waiting for delivery, beyond them they're dead-lettered without an attempt
+    pub fn with_queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = queue_capacity.max(1);
+        self
+    }
+
+    /// The queue of the delivery task, started on first use
+    fn queue(&mut self) -> &mpsc::Sender<Message> {
+        let (delivery, capacity) = (&mut self.delivery, self.queue_capacity);
+        self.queue.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel(capacity);
+            let delivery = delivery.take().expect("the delivery task starts once");
+            tokio::spawn(delivery.run(receiver));
+            sender
+        })
+    }
+
+    /// Queues the payload, or dead-letters it when the queue is full
+    fn send(&mut self, event: &str, mut body: Value) -> Result<(), anyhow::Error> {
+        body["event"] = json!(event);
+        body["chain_id"] = json!(self.chain_id);
+        let message = Message::Payload {
+            event: event.to_string(),
+            body,
+        };
+        let (error, message) = match self.queue().try_send(message) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(message)) => ("delivery queue is full", message),
+            Err(TrySendError::Closed(message)) => ("delivery task stopped", message),
+        };
+        let Message::Payload { event, body } = message else {
+            unreachable!("a payload was sent");
+        };
+        warn!(
+            "webhook {} payload not queued, keeping it in the dead letter file: {}",
+            event, error
+        );
+        lock(&self.dead_letters).append(DeadLetter::new(&event, body, error.to_string()))
+    }
+}
+
+/// Delivers the queued payloads, one at a time in order
+struct Delivery {
+    client: reqwest::Client,
+    url: String,
+    secret: Option<String>,
+    dead_letters: Arc<Mutex<DeadLetters>>,
+    max_attempts: u32,
+    retry_delay: Duration,
+}
+
+impl Delivery {
+    async fn run(self, mut queue: mpsc::Receiver<Message>) {
+        while let Some(message) = queue.recv().await {
+            match message {
+                Message::Payload { event, body } => {
+                    if let Err(err) = self.send(&event, body).await {
+                        warn!("webhook {} payload lost: {}", event, err);
+                    }
+                }
+                Message::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+
+    async fn send(&self, event: &str, body: Value) -> Result<(), anyhow::Error> {
+        match self.deliver(event, &body).await {
+            Ok(()) => self.redeliver_dead_letters().await,
+            Err(error) => {
+                warn!(
+                    "webhook {} payload not delivered, keeping it in the dead letter file: {}",
+                    event, error
+                );
+                lock(&self.dead_letters).append(DeadLetter::new(event, body, error))
+            }
+        }
+    }
+
+    /// POSTs `body` until it's accepted or `max_attempts` are used up
+    async fn deliver(&self, event: &str, body: &Value) -> Result<(), String> {
+        let body = serde_json::to_vec(body).map_err(|e| e.to_string())?;
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+        loop {
+            let failure = match self.post(event, &body).await {
+                Ok(()) => return Ok(()),
+                Err(failure) => failure,
+            };
+            if !failure.retryable || attempt >= self.max_attempts {
+                return Err(failure.error);
+            }
+            warn!(
+                "webhook {} payload failed, retrying ({}/{}): {}",
+                event, attempt, self.max_attempts, failure.error
+            );
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+            attempt += 1;
+        }
+    }
+
+    async fn post(&self, event: &str, body: &[u8]) -> Result<(), Failure> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event)
+            .body(body.to_vec());
+        if let Some(secret) = &self.secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, body));
+        }
+        let response = request.send().await.map_err(|e| Failure {
+            retryable: true,
+            error: e.to_string(),
+        })?;
+        let status = response.status();
+        if status.is_success() {
+            debug!("webhook {} payload delivered", event);
+            return Ok(());
+        }
+        Err(Failure {
+            retryable: status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS,
+            error: format!("{} responded {}", self.url, status),
+        })
+    }
+
+    /// Sends the dead letters once each, oldest first, & keeps the ones from the first retryable failure on.
+    /// The ones the endpoint refuses are dropped, they'd never get through & would hold up the rest
+    async fn redeliver_dead_letters(&self) -> Result<(), anyhow::Error> {
+        let dead_letters = {
+            let dead_letters = lock(&self.dead_letters);
+            if dead_letters.pending == 0 {
+                return Ok(());
+            }
+            read_dead_letters(&dead_letters.path)?
+        };
+        let (mut delivered, mut rejected) = (0, 0);
+        for dead_letter in &dead_letters {
+            let body = serde_json::to_vec(&dead_letter.body)?;
+            match self.post(&dead_letter.event, &body).await {
+                Ok(()) => delivered += 1,
+                Err(failure) if failure.retryable => {
+                    warn!("webhook dead letter not delivered: {}", failure.error);
+                    break;
+                }
+                Err(failure) => {
+                    warn!(
+                        "webhook {} dead letter dropped, it's refused: {} ({})",
+                        dead_letter.event,
+                        failure.error,
+                        serde_json::to_string(&dead_letter.body)?
+                    );
+                    rejected += 1;
+                }
+            }
+        }
+        info!(
+            "{} of {} webhook dead letters delivered, {} dropped",
+            delivered,
+            dead_letters.len(),
+            rejected
+        );
+        lock(&self.dead_letters).remove_first(delivered + rejected)
+    }
+}
+
+/// The dead letter file, appended to by the sink (when its queue is full) & the delivery task
+struct DeadLetters {
+    path: PathBuf,
+    /// Lines in the file, so it's only read when there's something to send
+    pending: usize,
+}
+
+impl DeadLetters {
+    fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            pending: read_dead_letters(path)?.len(),
+        })
+    }
+
+    fn append(&mut self, dead_letter: DeadLetter) -> Result<(), anyhow::Error> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| anyhow!("Failed to open {}: {}", self.path.display(), e))?;
+        writeln!(file, "{}", serde_json::to_string(&dead_letter)?)?;
+        file.sync_data()?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Drops the oldest `count` dead letters (the ones appended since they were read are kept),
+    /// replacing the file through a temporary one like `Checkpoint::save`
+    fn remove_first(&mut self, count: usize) -> Result<(), anyhow::Error> {
+        if count == 0 {
+            return Ok(());
+        }
+        let mut lines = String::new();
+        let dead_letters = read_dead_letters(&self.path)?;
+        for dead_letter in dead_letters.iter().skip(count) {
+            lines.push_str(&serde_json::to_string(dead_letter)?);
+            lines.push('\n');
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, lines)
+            .map_err(|e| anyhow!("Failed to write {}: {}", tmp_path.display(), e))?;
+        fs::rename(&tmp_path, &self.path)
+            .map_err(|e| anyhow!("Failed to write {}: {}", self.path.display(), e))?;
+        self.pending = dead_letters.len().saturating_sub(count);
+        Ok(())
+    }
+}
+
+/// Never held across an `.await`
+fn lock(dead_letters: &Mutex<DeadLetters>) -> std::sync::MutexGuard<'_, DeadLetters> {
+    dead_letters
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// `SIGNATURE_HEADER` value of `body`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Dead letters at `path`, none when the file doesn't exist
+pub fn read_dead_letters(path: &Path) -> Result<Vec<DeadLetter>, anyhow::Error> {
+    let lines = match fs::read_to_string(path) {
+        Ok(lines) => lines,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => anyhow::bail!("Failed to read {}: {}", path.display(), err),
+    };
+    lines
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str(line)
+                .map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e))
+        })
+        .collect()
+}
+
+#[async_trait]
+impl Sink for WebhookSink {
+    async fn write(&mut self, confirmed_block: &ConfirmedBlock) -> Result<(), anyhow::Error> {
+        if confirmed_block.swaps.is_empty() {
+            return Ok(());
+        }
+        self.send("swaps", serde_json::to_value(confirmed_block)?)
+    }
+
+    async fn write_reorg(&mut self, reorg: &Reorg) -> Result<(), anyhow::Error> {
+        self.send("reorg", serde_json::to_value(reorg)?)
+    }
+
+    async fn write_outage(&mut self, outage: &ProviderOutage) -> Result<(), anyhow::Error> {
+        self.send("provider_outage", serde_json::to_value(outage)?)
+    }
+
+    async fn write_alert(&mut self, alert: &Alert) -> Result<(), anyhow::Error> {
+        self.send("alert", serde_json::to_value(alert)?)
+    }
+
+    /// Waits for the queued payloads to be delivered (or dead-lettered)
+    async fn flush(&mut self) -> Result<(), anyhow::Error> {
+        let Some(queue) = &self.queue else {
+            return Ok(());
+        };
+        let (done, flushed) = oneshot::channel();
+        if queue.send(Message::Flush(done)).await.is_ok() {
+            // the task only stops (dropping `done`) when the queue is closed
+            let _ = flushed.await;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+use rust_decimal::Decimal;
+use uniswap_dai_usd_monitor::blocks_handler::ConfirmedBlock;
+use uniswap_dai_usd_monitor::swap_details::{LogMetadata, SwapDetails, SwapDirection};
+use web3::types::{H160, H256, U256};
+
+/// Block `block_number` (its hash is `hash` repeated, mined at `block_number * 12`) with `swaps` swaps of
+/// 1.5 DAI for 1 USDC in pool `0xaa..`, log indexes from 0
+pub fn confirmed_block(block_number: u64, hash: u8, swaps: u64) -> ConfirmedBlock {
+    let block_hash = H256::repeat_byte(hash);
+    let swaps = (0..swaps)
+        .map(|log_index| SwapDetails {
+            metadata: LogMetadata {
+                pool: H160::repeat_byte(0xaa),
+                block_number: Some(block_number),
+                block_hash: Some(block_hash),
+                transaction_hash: Some(H256::repeat_byte(0xbb)),
+                log_index: Some(log_index),
+            },
+            sender: H160::repeat_byte(1),
+            recipient: H160::repeat_byte(2),
+            amount0_raw: U256::from(1_500_000_000_000_000_000_u128),
+            amount0_as_decimal_num: Decimal::new(15, 1),
+            // -1 USDC, two's complement encoded
+            amount1_raw: U256::MAX - U256::from(999_999),
+            amount1_as_decimal_num: Decimal::new(-1, 0),
+            direction: SwapDirection::DaiToUsdc,
+            sqrt_price_x96: U256::zero(),
+            dai_is_token1: false,
+        })
+        .collect();
+    ConfirmedBlock {
+        block_number,
+        block_hash,
+        timestamp: block_number * 12,
+        swaps,
+    }
+}
@@ -0,0 +1,208 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use web3::{
+    signing::keccak256,
+    types::{BlockHeader, Log, H160, H256, U256},
+};
+
+/// DAI/USDC pool, same as `EventsHandler`
+pub const POOL_ADDRESS: &str = "5777d92f208679db4b9778590fa3cab3ac9e2168";
+/// `Swap(address,address,int256,int256,uint160,uint128,int24)`
+pub const SWAP_TOPIC: &str = "c42079f94a6350d7e6235f29174924f928cc2ac818eb64fed8004e115fbcca67";
+
+const BLOCK_TIME: u64 = 12;
+const FIRST_TIMESTAMP: u64 = 1_739_500_000;
+
+/// Scripted block tree. Blocks are linked by parent hash, the branch ending at `head` is the canonical chain
+pub struct Chain {
+    headers: HashMap<H256, BlockHeader>,
+    head: H256,
+    logs: Vec<Log>,
+    /// makes sibling blocks (same parent & number) get different hashes
+    salt: u64,
+    /// canonical blocks tagged `safe` & `finalized`, as a rollup node would (none by default)
+    pub safe: Option<u64>,
+    pub finalized: Option<u64>,
+}
+
+impl Chain {
+    /// Chain with a single block `first_number`, e.g., where the monitor starts
+    pub fn new(first_number: u64) -> Self {
+        let mut chain = Self {
+            headers: HashMap::new(),
+            head: H256::zero(),
+            logs: vec![],
+            salt: 0,
+            safe: None,
+            finalized: None,
+        };
+        let first = chain.new_header(H256::zero(), first_number);
+        chain.head = first.hash.unwrap();
+        chain.headers.insert(chain.head, first);
+        chain
+    }
+
+    pub fn head(&self) -> &BlockHeader {
+        &self.headers[&self.head]
+    }
+
+    pub fn header(&self, hash: H256) -> Option<&BlockHeader> {
+        self.headers.get(&hash)
+    }
+
+    /// Appends `count` blocks to the head, returns them in order
+    pub fn extend(&mut self, count: u64) -> Vec<BlockHeader> {
+        self.extend_from(self.head, count)
+    }
+
+    /// Appends `count` blocks on top of `parent` (which doesn't have to be the head) & makes the last one the head
+    pub fn extend_from(&mut self, parent: H256, count: u64) -> Vec<BlockHeader> {
+        let mut parent = self.headers[&parent].clone();
+        let mut new_headers = vec![];
+        for _ in 0..count {
+            let number = parent.number.unwrap().as_u64() + 1;
+            let header = self.new_header(parent.hash.unwrap(), number);
+            self.headers.insert(header.hash.unwrap(), header.clone());
+            new_headers.push(header.clone());
+            parent = header;
+        }
+        self.head = parent.hash.unwrap();
+        new_headers
+    }
+
+    /// Canonical block at `number`
+    pub fn canonical(&self, number: u64) -> Option<&BlockHeader> {
+        let mut header = self.head();
+        loop {
+            let header_number = header.number.unwrap().as_u64();
+            if header_number == number {
+                return Some(header);
+            }
+            if header_number < number {
+                return None;
+            }
+            header = self.headers.get(&header.parent_hash)?;
+        }
+    }
+
+    /// Canonical block of an `eth_getBlockByNumber` tag: `latest`, `safe` or `finalized`
+    pub fn tagged(&self, tag: &str) -> Option<&BlockHeader> {
+        match tag {
+            "latest" => Some(self.head()),
+            "safe" => self.canonical(self.safe?),
+            "finalized" => self.canonical(self.finalized?),
+            _ => None,
+        }
+    }
+
+    /// Adds a swap log of the DAI/USDC pool to the block with `block_hash`
+    pub fn add_swap(&mut self, block_hash: H256, amount0: i128, amount1: i128) -> Log {
+        let header = self.headers[&block_hash].clone();
+        let log_index = self
+            .logs
+            .iter()
+            .filter(|log| log.block_hash == Some(block_hash))
+            .count();
+        let word = |value: i128| -> String {
+            let value = if value < 0 {
+                U256::MAX - U256::from(value.unsigned_abs()) + 1
+            } else {
+                U256::from(value as u128)
+            };
+            format!("{:064x}", value)
+        };
+        let address_topic = |byte: u8| format!("0x{:064x}", byte);
+
+        let log: Log = serde_json::from_value(json!({
+            "address": format!("0x{}", POOL_ADDRESS),
+            "topics": [format!("0x{}", SWAP_TOPIC), address_topic(0xaa), address_topic(0xbb)],
+            "data": format!("0x{}{}{}{}{}", word(amount0), word(amount1), word(0), word(0), word(0)),
+            "blockHash": block_hash,
+            "blockNumber": header.number.unwrap(),
+            "transactionHash": H256::from_low_u64_be(self.logs.len() as u64 + 1),
+            "transactionIndex": "0x0",
+            "logIndex": format!("0x{:x}", log_index),
+            "removed": false,
+        }))
+        .unwrap();
+        self.logs.push(log.clone());
+        log
+    }
+
+    /// Logs matching an `eth_getLogs` filter, ranges are resolved against the canonical chain
+    pub fn logs(&self, filter: &Value) -> Vec<Log> {
+        let address = filter["address"]
+            .as_str()
+            .map(|a| a.parse::<H160>().unwrap());
+        let topic = match &filter["topics"][0] {
+            Value::String(topic) => Some(topic.parse::<H256>().unwrap()),
+            _ => None,
+        };
+        let block_hash = filter["blockHash"]
+            .as_str()
+            .map(|h| h.parse::<H256>().unwrap());
+        let block_number = |key: &str| {
+            filter[key]
+                .as_str()
+                .map(|n| u64::from_str_radix(n.trim_start_matches("0x"), 16).unwrap())
+        };
+        let (from, to) = (block_number("fromBlock"), block_number("toBlock"));
+
+        self.logs
+            .iter()
+            .filter(|log| address.is_none_or(|address| log.address == address))
+            .filter(|log| topic.is_none_or(|topic| log.topics.first() == Some(&topic)))
+            .filter(|log| {
+                let log_block = log.block_hash.unwrap();
+                match block_hash {
+                    Some(block_hash) => log_block == block_hash,
+                    None => {
+                        let number = log.block_number.unwrap().as_u64();
+                        from.is_none_or(|from| number >= from)
+                            && to.is_none_or(|to| number <= to)
+                            && self.canonical(number).and_then(|h| h.hash) == Some(log_block)
+                    }
+                }
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn new_header(&mut self, parent_hash: H256, number: u64) -> BlockHeader {
+        self.salt += 1;
+        let mut preimage = parent_hash.as_bytes().to_vec();
+        preimage.extend_from_slice(&number.to_be_bytes());
+        preimage.extend_from_slice(&self.salt.to_be_bytes());
+        let hash = H256::from(keccak256(&preimage));
+        let timestamp = FIRST_TIMESTAMP + BLOCK_TIME * number;
+
+        serde_json::from_value(json!({
+            "hash": hash,
+            "parentHash": parent_hash,
+            "sha3Uncles": H256::zero(),
+            "miner": H160::zero(),
+            "stateRoot": H256::zero(),
+            "transactionsRoot": H256::zero(),
+            "receiptsRoot": H256::zero(),
+            "number": format!("0x{:x}", number),
+            "gasUsed": "0x0",
+            "gasLimit": "0x1c9c380",
+            "baseFeePerGas": "0x3b9aca00",
+            "extraData": "0x",
+            "logsBloom": format!("0x{}", "0".repeat(512)),
+            "timestamp": format!("0x{:x}", timestamp),
+            "difficulty": "0x0",
+            "mixHash": H256::zero(),
+            "nonce": "0x0000000000000000",
+        }))
+        .unwrap()
+    }
+}
+
+/// Full block as returned by `eth_getBlockByNumber(_, false)` / `eth_getBlockByHash(_, false)`
+pub fn block_json(header: &BlockHeader) -> Value {
+    let mut block = serde_json::to_value(header).unwrap();
+    block["uncles"] = json!([]);
+    block["transactions"] = json!([]);
+    block
+}
@@ -0,0 +1,136 @@
+use super::chain::Chain;
+use async_trait::async_trait;
+use serde_json::json;
+use std::sync::{Arc, Mutex, MutexGuard};
+use uniswap_dai_usd_monitor::error::MonitorError;
+use uniswap_dai_usd_monitor::events_source::EventsSource;
+use uniswap_dai_usd_monitor::web3_client::BlocksFetcher;
+use web3::types::{BlockHeader, BlockNumber, Log, H160, H256};
+
+/// Drives a `Chain` through reorg scenarios & answers `BlocksFetcher` / `EventsSource` requests
+/// from its current canonical chain, the way a node would.
+/// Clones share the same chain, so one can be handed to `BlocksHandler` & the other kept for scripting.
+#[derive(Clone)]
+pub struct ChainSimulator {
+    chain: Arc<Mutex<Chain>>,
+    /// blocks whose logs were requested, i.e., the ones `BlocksHandler` confirmed
+    confirmed: Arc<Mutex<Vec<H256>>>,
+}
+
+impl ChainSimulator {
+    /// Starts with a single canonical block `first_number`
+    pub fn new(first_number: u64) -> Self {
+        Self {
+            chain: Arc::new(Mutex::new(Chain::new(first_number))),
+            confirmed: Arc::default(),
+        }
+    }
+
+    pub fn chain(&self) -> MutexGuard<'_, Chain> {
+        self.chain.lock().unwrap()
+    }
+
+    pub fn head(&self) -> BlockHeader {
+        self.chain().head().clone()
+    }
+
+    /// Extends the canonical chain by `count` blocks
+    pub fn advance(&self, count: u64) -> Vec<BlockHeader> {
+        self.chain().extend(count)
+    }
+
+    /// Replaces the canonical blocks from `height` on with a `len` blocks long sibling branch,
+    /// e.g., `fork_at(10, 3)` makes `10' <- 11' <- 12'` canonical
+    pub fn fork_at(&self, height: u64, len: u64) -> Vec<BlockHeader> {
+        let mut chain = self.chain();
+        let parent = chain
+            .canonical(height - 1)
+            .and_then(|header| header.hash)
+            .expect("fork point must be canonical");
+        chain.extend_from(parent, len)
+    }
+
+    /// A second block at the head's height (same parent), which becomes the head
+    pub fn competing_head(&self) -> BlockHeader {
+        let height = self.head().number.unwrap().as_u64();
+        self.fork_at(height, 1).remove(0)
+    }
+
+    pub fn canonical_hash(&self, number: u64) -> H256 {
+        self.chain().canonical(number).unwrap().hash.unwrap()
+    }
+
+    /// Tags the canonical block `number` as `safe`, as if its batch was posted to L1
+    pub fn set_safe(&self, number: u64) {
+        self.chain().safe = Some(number);
+    }
+
+    /// Tags the canonical block `number` as `finalized`
+    pub fn set_finalized(&self, number: u64) {
+        self.chain().finalized = Some(number);
+    }
+
+    /// Hashes of the blocks `BlocksHandler` confirmed so far, in order
+    pub fn confirmed(&self) -> Vec<H256> {
+        self.confirmed.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl BlocksFetcher for ChainSimulator {
+    async fn get_block_hash(&self, block_number: u64) -> Result<H256, anyhow::Error> {
+        let hash = self
+            .chain()
+            .canonical(block_number)
+            .and_then(|header| header.hash)
+            .ok_or(MonitorError::BlockNotFound(block_number))?;
+        Ok(hash)
+    }
+
+    async fn get_block_header(&self, block_number: u64) -> Result<BlockHeader, anyhow::Error> {
+        let header = self
+            .chain()
+            .canonical(block_number)
+            .cloned()
+            .ok_or(MonitorError::BlockNotFound(block_number))?;
+        Ok(header)
+    }
+
+    async fn get_block_header_by_tag(
+        &self,
+        tag: BlockNumber,
+    ) -> Result<Option<BlockHeader>, anyhow::Error> {
+        let tag = serde_json::to_value(tag)?;
+        Ok(self.chain().tagged(tag.as_str().unwrap()).cloned())
+    }
+}
+
+#[async_trait]
+impl EventsSource for ChainSimulator {
+    async fn logs_by_block_hash(
+        &self,
+        address: H160,
+        topic: H256,
+        block_hash: H256,
+    ) -> Result<Vec<Log>, anyhow::Error> {
+        self.confirmed.lock().unwrap().push(block_hash);
+        let filter = json!({"address": address, "topics": [topic], "blockHash": block_hash});
+        Ok(self.chain().logs(&filter))
+    }
+
+    async fn logs_by_block_range(
+        &self,
+        address: H160,
+        topic: H256,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<Log>, anyhow::Error> {
+        let filter = json!({
+            "address": address,
+            "topics": [topic],
+            "fromBlock": format!("0x{:x}", from),
+            "toBlock": format!("0x{:x}", to),
+        });
+        Ok(self.chain().logs(&filter))
+    }
+}
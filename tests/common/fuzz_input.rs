@@ -0,0 +1,83 @@
+//! Input format of the `decode_swap_log` fuzz target (`fuzz/`), shared with `tests/fuzz_regressions_test.rs`
+//! so every crashing input found by the fuzzer can be replayed on stable by `cargo test`.
+//!
+//! Layout: 1 header byte, `n` 32 bytes topics, the rest is the log data.
+//! The header's low 3 bits are `n` (up to 7, the Swap event has 3), its high bit replaces the first topic
+//! with the Swap signature, otherwise almost every input would be rejected by the signature check.
+use futures::executor::block_on;
+use std::sync::OnceLock;
+use uniswap_dai_usd_monitor::{
+    events_handler::EventsHandler, events_source::FixtureEventsSource, swap_details::SwapDetails,
+};
+use web3::types::{Bytes, Log, H256};
+
+const SWAP_SIGNATURE_FLAG: u8 = 0x80;
+const TOPICS_MASK: u8 = 0x07;
+
+fn swap_signature() -> H256 {
+    "c42079f94a6350d7e6235f29174924f928cc2ac818eb64fed8004e115fbcca67"
+        .parse()
+        .unwrap()
+}
+
+/// Raw log as a node could return it, missing topic bytes are zeros
+pub fn log_from_bytes(bytes: &[u8]) -> Log {
+    let (header, rest) = bytes.split_first().unwrap_or((&0, &[]));
+    let topics_count = usize::from(header & TOPICS_MASK);
+    let topics_len = (topics_count * 32).min(rest.len());
+    let (topic_bytes, data) = rest.split_at(topics_len);
+
+    let mut topics: Vec<H256> = (0..topics_count)
+        .map(|i| {
+            let mut topic = [0u8; 32];
+            let chunk = topic_bytes.get(i * 32..).unwrap_or_default();
+            let len = chunk.len().min(32);
+            topic[..len].copy_from_slice(&chunk[..len]);
+            H256(topic)
+        })
+        .collect();
+    if header & SWAP_SIGNATURE_FLAG != 0 {
+        match topics.first_mut() {
+            Some(topic) => *topic = swap_signature(),
+            None => topics.push(swap_signature()),
+        }
+    }
+
+    Log {
+        address: Default::default(),
+        topics,
+        data: Bytes(data.to_vec()),
+        block_hash: None,
+        block_number: None,
+        transaction_hash: None,
+        transaction_index: None,
+        log_index: None,
+        transaction_log_index: None,
+        log_type: None,
+        removed: None,
+    }
+}
+
+/// Inverse of `log_from_bytes`, used to turn recorded logs into seeds
+pub fn bytes_from_log(log: &Log) -> Vec<u8> {
+    let mut bytes = vec![log.topics.len() as u8 & TOPICS_MASK];
+    for topic in &log.topics {
+        bytes.extend_from_slice(topic.as_bytes());
+    }
+    bytes.extend_from_slice(&log.data.0);
+    bytes
+}
+
+/// The decode & conversion path of the monitor: `EventsHandler::parse_logs`, then `SwapDetails::from_parsed_log`
+pub fn decode(bytes: &[u8]) -> Result<Vec<SwapDetails>, anyhow::Error> {
+    static EVENTS_HANDLER: OnceLock<EventsHandler<FixtureEventsSource>> = OnceLock::new();
+    let events_handler = match EVENTS_HANDLER.get() {
+        Some(events_handler) => events_handler,
+        None => EVENTS_HANDLER.get_or_init(|| {
+            EventsHandler::new(FixtureEventsSource::default()).expect("Swap event ABI")
+        }),
+    };
+
+    let parsed_logs = events_handler.parse_logs(vec![log_from_bytes(bytes)])?;
+    block_on(events_handler.to_swap_details(parsed_logs))
+}
@@ -0,0 +1,213 @@
+use super::chain::{block_json, Chain};
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+    task::JoinHandle,
+};
+use tokio_tungstenite::{accept_async, tungstenite::Message};
+use web3::types::{BlockHeader, H256};
+
+const SUBSCRIPTION_ID: &str = "0x9ce59a13059e417087c02d3236a0b1cc";
+
+/// Requests received so far, as `(method, params)`
+type Requests = Arc<Mutex<Vec<(String, Vec<Value>)>>>;
+
+#[derive(Clone, Debug)]
+enum Broadcast {
+    NewHead(Box<BlockHeader>),
+    Shutdown,
+}
+
+/// In-process Ethereum JSON-RPC node (WebSocket only), serving a scripted `Chain`.
+/// Supports `eth_subscribe newHeads`, `eth_getBlockByNumber`, `eth_getBlockByHash`, `eth_getLogs` & `eth_chainId`
+pub struct MockNode {
+    url: String,
+    chain: Arc<Mutex<Chain>>,
+    requests: Requests,
+    broadcast: broadcast::Sender<Broadcast>,
+    server: JoinHandle<()>,
+}
+
+impl MockNode {
+    pub async fn start(chain: Chain) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let chain = Arc::new(Mutex::new(chain));
+        let requests = Arc::new(Mutex::new(vec![]));
+        let (broadcast, _) = broadcast::channel(1024);
+
+        let server = {
+            let (chain, requests, broadcast) = (chain.clone(), requests.clone(), broadcast.clone());
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let connection = Connection {
+                        chain: chain.clone(),
+                        requests: requests.clone(),
+                        broadcast: broadcast.subscribe(),
+                    };
+                    tokio::spawn(connection.serve(stream));
+                }
+            })
+        };
+
+        Self {
+            url,
+            chain,
+            requests,
+            broadcast,
+            server,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// For scripting the chain while the node is running
+    pub fn chain(&self) -> MutexGuard<'_, Chain> {
+        self.chain.lock().unwrap()
+    }
+
+    /// Sends a `newHeads` notification to all subscribers
+    pub fn announce(&self, header: &BlockHeader) {
+        let _ = self
+            .broadcast
+            .send(Broadcast::NewHead(Box::new(header.clone())));
+    }
+
+    /// Extends the canonical chain by `count` blocks & announces each of them
+    pub fn mine(&self, count: u64) -> Vec<BlockHeader> {
+        let headers = self.chain().extend(count);
+        for header in &headers {
+            self.announce(header);
+        }
+        headers
+    }
+
+    /// Params of every request received for `method`, in order
+    pub fn requests(&self, method: &str) -> Vec<Vec<Value>> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(m, _)| m == method)
+            .map(|(_, params)| params.clone())
+            .collect()
+    }
+
+    /// `eth_getLogs` requests for the block with `block_hash`
+    pub fn logs_requests_for(&self, block_hash: H256) -> usize {
+        self.requests("eth_getLogs")
+            .iter()
+            .filter(|params| params[0]["blockHash"] == json!(block_hash))
+            .count()
+    }
+
+    /// Waits (up to 5 secs) until `condition` holds
+    pub async fn wait_until(&self, condition: impl Fn(&Self) -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !condition(self) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("mock node: condition not met in time");
+    }
+
+    /// Closes all connections, which ends the `newHeads` subscription of clients
+    pub fn shutdown(&self) {
+        let _ = self.broadcast.send(Broadcast::Shutdown);
+        self.server.abort();
+    }
+}
+
+impl Drop for MockNode {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+struct Connection {
+    chain: Arc<Mutex<Chain>>,
+    requests: Requests,
+    broadcast: broadcast::Receiver<Broadcast>,
+}
+
+impl Connection {
+    async fn serve(mut self, stream: TcpStream) {
+        let Ok(ws) = accept_async(stream).await else {
+            return;
+        };
+        let (mut sink, mut stream) = ws.split();
+        let mut subscribed = false;
+
+        loop {
+            tokio::select! {
+                message = stream.next() => {
+                    let Some(Ok(Message::Text(text))) = message else {
+                        break;
+                    };
+                    let request: Value = serde_json::from_str(&text).unwrap();
+                    let method = request["method"].as_str().unwrap_or_default().to_string();
+                    let params = request["params"].as_array().cloned().unwrap_or_default();
+                    self.requests.lock().unwrap().push((method.clone(), params.clone()));
+
+                    subscribed |= method == "eth_subscribe";
+                    let response = json!({
+                        "jsonrpc": "2.0",
+                        "id": request["id"],
+                        "result": self.handle(&method, &params),
+                    });
+                    if sink.send(Message::text(response.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+                broadcast = self.broadcast.recv() => {
+                    let header = match broadcast {
+                        Ok(Broadcast::NewHead(header)) if subscribed => header,
+                        Ok(Broadcast::NewHead(_)) => continue,
+                        _ => break,
+                    };
+                    let notification = json!({
+                        "jsonrpc": "2.0",
+                        "method": "eth_subscription",
+                        "params": {"subscription": SUBSCRIPTION_ID, "result": header},
+                    });
+                    if sink.send(Message::text(notification.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = sink.close().await;
+    }
+
+    fn handle(&self, method: &str, params: &[Value]) -> Value {
+        let chain = self.chain.lock().unwrap();
+        match method {
+            "eth_subscribe" => json!(SUBSCRIPTION_ID),
+            "eth_unsubscribe" => json!(true),
+            "eth_chainId" => json!("0x1"),
+            "eth_getBlockByNumber" => {
+                let block = params[0].as_str().unwrap();
+                let header = match block.strip_prefix("0x") {
+                    Some(number) => chain.canonical(u64::from_str_radix(number, 16).unwrap()),
+                    None => chain.tagged(block),
+                };
+                header.map_or(Value::Null, block_json)
+            }
+            "eth_getBlockByHash" => {
+                let hash: H256 = params[0].as_str().unwrap().parse().unwrap();
+                chain.header(hash).map_or(Value::Null, block_json)
+            }
+            "eth_getLogs" => json!(chain.logs(&params[0])),
+            _ => Value::Null,
+        }
+    }
+}
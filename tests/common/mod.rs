@@ -0,0 +1,8 @@
+#![allow(dead_code)] // not every test binary uses every helper
+
+pub mod blocks;
+pub mod chain;
+pub mod chain_simulator;
+pub mod fuzz_input;
+pub mod mock_node;
+pub mod webhook_receiver;
@@ -0,0 +1,137 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+/// A request as received, header names are lowercase
+#[derive(Debug, Clone)]
+pub struct ReceivedRequest {
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl ReceivedRequest {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Statuses of the next responses, 200 once they're used up
+    statuses: VecDeque<u16>,
+    /// Requests answered with 2xx
+    accepted: Vec<ReceivedRequest>,
+    attempts: usize,
+}
+
+/// In-process HTTP endpoint for webhooks, responding with scripted statuses (a connection per request)
+pub struct WebhookReceiver {
+    url: String,
+    state: Arc<Mutex<State>>,
+    server: JoinHandle<()>,
+}
+
+impl WebhookReceiver {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+        let server = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(stream, state.clone()));
+                }
+            })
+        };
+        Self { url, state, server }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The next requests are answered with `statuses`, in order
+    pub fn respond_with(&self, statuses: &[u16]) {
+        self.state.lock().unwrap().statuses.extend(statuses);
+    }
+
+    pub fn accepted(&self) -> Vec<ReceivedRequest> {
+        self.state.lock().unwrap().accepted.clone()
+    }
+
+    /// Requests received, including the ones answered with an error
+    pub fn attempts(&self) -> usize {
+        self.state.lock().unwrap().attempts
+    }
+}
+
+impl Drop for WebhookReceiver {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+async fn serve(mut stream: TcpStream, state: Arc<Mutex<State>>) {
+    let Some(request) = read_request(&mut stream).await else {
+        return;
+    };
+    let status = {
+        let mut state = state.lock().unwrap();
+        state.attempts += 1;
+        let status = state.statuses.pop_front().unwrap_or(200);
+        if (200..300).contains(&status) {
+            state.accepted.push(request);
+        }
+        status
+    };
+    let response = format!(
+        "HTTP/1.1 {} Scripted\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        status
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+async fn read_request(stream: &mut TcpStream) -> Option<ReceivedRequest> {
+    let mut buffer = vec![];
+    let mut chunk = [0; 4096];
+    let header_end = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut chunk))
+            .await
+            .ok()?
+            .ok()?;
+        if read == 0 {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let headers: HashMap<String, String> = head
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+    let content_length: usize = headers.get("content-length")?.parse().ok()?;
+    let mut body = buffer[header_end + 4..].to_vec();
+    while body.len() < content_length {
+        let read = stream.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+    Some(ReceivedRequest { headers, body })
+}
@@ -1,8 +1,9 @@
 use rust_decimal::Decimal;
 use std::str::FromStr;
 use tokio::fs;
+use uniswap_dai_usd_monitor::cassette::replay_web3;
 use uniswap_dai_usd_monitor::events_handler::EventsHandler;
-use uniswap_dai_usd_monitor::setup_web3;
+use uniswap_dai_usd_monitor::events_source::{FixtureEventsSource, Web3EventsSource};
 use uniswap_dai_usd_monitor::swap_details::SwapDirection;
 use web3::ethabi::Address;
 use web3::types::U256;
@@ -15,6 +16,12 @@ async fn load_fixtures() -> Vec<BlockHeader> {
     serde_json::from_str(&json).expect("Failed to parse blocks")
 }
 
+/// Logs recorded in `tests/fixtures/<block>/logs.json`, so no node is needed
+fn get_events_handler() -> EventsHandler<FixtureEventsSource> {
+    let events_source = FixtureEventsSource::load("tests/fixtures").unwrap();
+    EventsHandler::new(events_source).unwrap()
+}
+
 fn extract_param_by_name(parsed_log: &ethabi::Log, name: &str) -> Option<Token> {
     let token = parsed_log
         .params
@@ -30,8 +37,7 @@ async fn test_handle_events_21836327() {
     let block_number = headers[0].number.unwrap();
     assert_eq!(21836327_u64, block_number.as_u64());
 
-    let web3 = setup_web3().await.unwrap();
-    let events_handler = EventsHandler::new(web3).unwrap();
+    let events_handler = get_events_handler();
 
     // fetch_swap_logs
     let block_hash = headers[0].hash.unwrap();
@@ -135,8 +141,7 @@ async fn test_handle_events_21904546() {
     let block_number = headers[0].number.unwrap();
     assert_eq!(21904546_u64, block_number.as_u64());
 
-    let web3 = setup_web3().await.unwrap();
-    let events_handler = EventsHandler::new(web3).unwrap();
+    let events_handler = get_events_handler();
 
     // fetch_swap_logs
     let block_hash = headers[0].hash.unwrap();
@@ -233,3 +238,77 @@ async fn test_handle_events_21904546() {
     // the negative indicates the amount output to the `receiver` address
     assert_eq!(swap_details.direction, SwapDirection::UsdcToDai);
 }
+
+#[tokio::test]
+async fn test_handle_events_in_range() {
+    let events_handler = get_events_handler();
+
+    let swap_info = events_handler
+        .handle_events_in_range(21836327, 21904546)
+        .await
+        .unwrap();
+    assert_eq!(swap_info.len(), 2);
+    assert_eq!(swap_info[0].direction, SwapDirection::DaiToUsdc);
+    assert_eq!(swap_info[1].direction, SwapDirection::UsdcToDai);
+    // swaps keep where they were emitted
+    assert_eq!(swap_info[0].metadata.block_number, Some(21836327));
+    assert_eq!(swap_info[1].metadata.block_number, Some(21904546));
+    assert_eq!(swap_info[0].metadata.pool, events_handler.pool());
+
+    // no swaps recorded for the blocks following 21836327
+    let swap_info = events_handler
+        .handle_events_in_range(21836328, 21836337)
+        .await
+        .unwrap();
+    assert!(swap_info.is_empty());
+}
+
+#[tokio::test]
+async fn test_handle_events_replayed_from_cassette() {
+    // goes through the actual `eth_getLogs` requests, answered by the recorded cassette
+    let web3 = replay_web3("tests/fixtures/cassettes/mainnet.json").unwrap();
+    let events_handler = EventsHandler::new(Web3EventsSource { web3 }).unwrap();
+
+    let headers = load_fixtures().await;
+    let swap_info = events_handler
+        .handle_events(headers[0].hash.unwrap())
+        .await
+        .unwrap();
+    assert_eq!(swap_info.len(), 1);
+    assert_eq!(
+        swap_info[0].amount1_as_decimal_num,
+        Decimal::from_str("-3435.377405").unwrap()
+    );
+    assert_eq!(swap_info[0].direction, SwapDirection::DaiToUsdc);
+}
+
+#[tokio::test]
+async fn test_swap_beyond_decimal_range_is_skipped() {
+    let headers = load_fixtures().await;
+    let events_handler = get_events_handler();
+    let raw_logs = events_handler
+        .fetch_swap_logs(headers[0].hash.unwrap())
+        .await
+        .unwrap();
+
+    // the same swap, with 2^200 as amount0 (the first word of the data) & a log index of its own
+    let mut beyond_range = raw_logs[0].clone();
+    let mut data = beyond_range.data.0.clone();
+    (U256::one() << 200).to_big_endian(&mut data[..32]);
+    beyond_range.data = data.into();
+    beyond_range.log_index = Some(U256::from(7));
+
+    let swaps = events_handler
+        .handle_logs(vec![beyond_range, raw_logs[0].clone()])
+        .await
+        .unwrap();
+    assert_eq!(swaps.len(), 1);
+    assert_eq!(
+        swaps[0].amount1_as_decimal_num,
+        Decimal::from_str("-3435.377405").unwrap()
+    );
+    assert_eq!(
+        swaps[0].metadata.log_index,
+        raw_logs[0].log_index.map(|i| i.as_u64())
+    );
+}
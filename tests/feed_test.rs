@@ -0,0 +1,198 @@
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use std::{net::SocketAddr, time::Duration};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use uniswap_dai_usd_monitor::blocks_handler::{BlockEvent, ConfirmedBlock};
+use uniswap_dai_usd_monitor::feed::{Cursor, Feed, ServerMessage};
+use uniswap_dai_usd_monitor::sinks::Sink;
+use uniswap_dai_usd_monitor::swap_details::{LogMetadata, SwapDetails, SwapDirection};
+use web3::types::{H160, H256, U256};
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+const POOL: H160 = H160::repeat_byte(0xaa);
+const OTHER_POOL: H160 = H160::repeat_byte(0xbb);
+const WHALE: H160 = H160::repeat_byte(9);
+
+/// A swap of `POOL` (log 0) & one of `OTHER_POOL` by `WHALE` (log 1)
+fn block(block_number: u64) -> ConfirmedBlock {
+    let block_hash = H256::repeat_byte(block_number as u8);
+    let swaps = [(POOL, H160::repeat_byte(1)), (OTHER_POOL, WHALE)]
+        .into_iter()
+        .enumerate()
+        .map(|(log_index, (pool, sender))| SwapDetails {
+            metadata: LogMetadata {
+                pool,
+                block_number: Some(block_number),
+                block_hash: Some(block_hash),
+                transaction_hash: Some(H256::repeat_byte(0xcc)),
+                log_index: Some(log_index as u64),
+            },
+            sender,
+            recipient: H160::repeat_byte(2),
+            amount0_raw: U256::zero(),
+            amount0_as_decimal_num: 1.into(),
+            amount1_raw: U256::zero(),
+            amount1_as_decimal_num: (-1).into(),
+            direction: SwapDirection::DaiToUsdc,
+            sqrt_price_x96: U256::zero(),
+            dai_is_token1: false,
+        })
+        .collect();
+    ConfirmedBlock {
+        block_number,
+        block_hash,
+        timestamp: block_number * 12,
+        swaps,
+    }
+}
+
+async fn connect(addr: SocketAddr, subscription: serde_json::Value) -> Client {
+    let (mut client, _) = connect_async(format!("ws://{}/feed", addr)).await.unwrap();
+    client
+        .send(Message::text(subscription.to_string()))
+        .await
+        .unwrap();
+    client
+}
+
+/// Connects with a subscription nothing is replayed for, once it's in place
+async fn connect_live(addr: SocketAddr, subscription: serde_json::Value) -> Client {
+    let mut client = connect(addr, subscription).await;
+    // answered after the subscription is handled
+    client
+        .send(Message::text(r#"{"type": "ping"}"#))
+        .await
+        .unwrap();
+    assert!(matches!(
+        next(&mut client).await,
+        ServerMessage::Error { message } if message.contains("Invalid message")
+    ));
+    client
+}
+
+async fn next(client: &mut Client) -> ServerMessage {
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("no message from the feed")
+            .unwrap()
+            .unwrap();
+        if let Message::Text(text) = message {
+            return serde_json::from_str(text.as_str()).unwrap();
+        }
+    }
+}
+
+/// `(type, block number, log index)` of a swap message
+async fn next_swap(client: &mut Client) -> (&'static str, u64, u64) {
+    let (kind, update) = match next(client).await {
+        ServerMessage::Provisional(update) => ("provisional", update),
+        ServerMessage::Confirmed(update) => ("confirmed", update),
+        ServerMessage::Retracted(update) => ("retracted", update),
+        ServerMessage::Error { message } => panic!("unexpected error: {}", message),
+    };
+    (kind, update.cursor.block_number, update.cursor.log_index)
+}
+
+fn cursor(block_number: u64, log_index: u64) -> Cursor {
+    Cursor {
+        block_number,
+        log_index,
+    }
+}
+
+#[tokio::test]
+async fn test_subscribers_get_their_swaps_and_resume_from_a_cursor() {
+    let mut feed = Feed::default();
+    let addr = feed.serve(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+
+    let mut by_pool = connect_live(addr, json!({"type": "subscribe", "pools": [POOL]})).await;
+    let mut by_address =
+        connect_live(addr, json!({"type": "subscribe", "addresses": [WHALE]})).await;
+    feed.write_event(&BlockEvent::Provisional(block(100)))
+        .await
+        .unwrap();
+    feed.write(&block(100)).await.unwrap();
+
+    assert_eq!(next_swap(&mut by_pool).await, ("provisional", 100, 0));
+    assert_eq!(next_swap(&mut by_pool).await, ("confirmed", 100, 0));
+    assert_eq!(next_swap(&mut by_address).await, ("provisional", 100, 1));
+    assert_eq!(next_swap(&mut by_address).await, ("confirmed", 100, 1));
+
+    // a reconnecting client picks up after the last confirmed swap it processed,
+    // then gets the provisional swaps still pending, whether it got them before or not
+    drop(by_pool);
+    feed.write_event(&BlockEvent::Provisional(block(101)))
+        .await
+        .unwrap();
+    feed.write(&block(101)).await.unwrap();
+    feed.write_event(&BlockEvent::Provisional(block(102)))
+        .await
+        .unwrap();
+    let mut by_pool = connect(
+        addr,
+        json!({"type": "subscribe", "pools": [POOL], "cursor": cursor(100, 0)}),
+    )
+    .await;
+    assert_eq!(next_swap(&mut by_pool).await, ("confirmed", 101, 0));
+    assert_eq!(next_swap(&mut by_pool).await, ("provisional", 102, 0));
+    feed.write_event(&BlockEvent::Retracted(block(102)))
+        .await
+        .unwrap();
+    assert_eq!(next_swap(&mut by_pool).await, ("retracted", 102, 0));
+    assert_eq!(next_swap(&mut by_address).await, ("provisional", 101, 1));
+    assert_eq!(next_swap(&mut by_address).await, ("confirmed", 101, 1));
+    assert_eq!(next_swap(&mut by_address).await, ("provisional", 102, 1));
+    assert_eq!(next_swap(&mut by_address).await, ("retracted", 102, 1));
+
+    // retracted swaps aren't pending anymore
+    let mut resumed = connect_live(
+        addr,
+        json!({"type": "subscribe", "pools": [POOL], "cursor": cursor(101, 0)}),
+    )
+    .await;
+    feed.write_event(&BlockEvent::Provisional(block(103)))
+        .await
+        .unwrap();
+    assert_eq!(next_swap(&mut resumed).await, ("provisional", 103, 0));
+    assert_eq!(next_swap(&mut by_pool).await, ("provisional", 103, 0));
+
+    by_pool
+        .send(Message::text(r#"{"type": "unsubscribe"}"#))
+        .await
+        .unwrap();
+    assert!(matches!(
+        next(&mut by_pool).await,
+        ServerMessage::Error { message } if message.contains("Invalid message")
+    ));
+}
+
+#[tokio::test]
+async fn test_cursors_of_swaps_confirmed_before_a_restart() {
+    // the monitor resumes from its checkpoint at block 100, the feed has nothing of the earlier run
+    let mut feed = Feed::default();
+    let addr = feed.serve(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    feed.write(&block(100)).await.unwrap();
+
+    // right before the first block confirmed since is fine
+    let mut resumed = connect(addr, json!({"type": "subscribe", "cursor": cursor(99, 1)})).await;
+    assert_eq!(next_swap(&mut resumed).await, ("confirmed", 100, 0));
+    assert_eq!(next_swap(&mut resumed).await, ("confirmed", 100, 1));
+
+    let mut behind = connect(addr, json!({"type": "subscribe", "cursor": cursor(97, 1)})).await;
+    assert!(matches!(
+        next(&mut behind).await,
+        ServerMessage::Error { message } if message.contains("some may have been missed")
+    ));
+    // what's kept is sent anyway
+    assert_eq!(next_swap(&mut behind).await, ("confirmed", 100, 0));
+
+    let max = cursor(u64::MAX, u64::MAX);
+    let mut ahead = connect_live(addr, json!({"type": "subscribe", "cursor": max})).await;
+    feed.write_event(&BlockEvent::Provisional(block(101)))
+        .await
+        .unwrap();
+    assert_eq!(next_swap(&mut ahead).await, ("provisional", 101, 0));
+}
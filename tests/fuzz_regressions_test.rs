@@ -0,0 +1,72 @@
+mod common;
+
+use common::fuzz_input;
+use std::path::Path;
+use uniswap_dai_usd_monitor::events_source::FixtureEventsSource;
+use web3::types::H256;
+
+fn corpus(dir: &str) -> Vec<(String, Vec<u8>)> {
+    let mut inputs: Vec<_> = std::fs::read_dir(Path::new("fuzz").join(dir))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_file())
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            (name, std::fs::read(&path).unwrap())
+        })
+        .collect();
+    inputs.sort();
+    inputs
+}
+
+/// Hand-written edge cases (malformed topics & data, extreme amounts) & the crashes the fuzzer finds, once fixed.
+/// They must keep failing gracefully
+#[test]
+fn test_regressions_dont_panic() {
+    let inputs = corpus("regressions/decode_swap_log");
+    assert!(!inputs.is_empty());
+    for (name, bytes) in inputs {
+        let result = std::panic::catch_unwind(|| fuzz_input::decode(&bytes));
+        assert!(result.is_ok(), "{} panicked", name);
+    }
+}
+
+/// Seeds are real swaps, they must decode
+#[test]
+fn test_seeds_decode() {
+    let inputs = corpus("seeds/decode_swap_log");
+    assert!(!inputs.is_empty());
+    for (name, bytes) in inputs {
+        let swaps = fuzz_input::decode(&bytes).unwrap_or_else(|e| panic!("{}: {:?}", name, e));
+        assert_eq!(swaps.len(), 1, "{}", name);
+    }
+}
+
+/// Seeds are generated from the recorded logs, regenerate them with `FUZZ_WRITE_SEEDS=1 cargo test`
+#[tokio::test]
+async fn test_seeds_match_fixtures() {
+    let fixtures = FixtureEventsSource::load("tests/fixtures").unwrap();
+    let logs = fixtures_logs(&fixtures).await;
+    assert!(!logs.is_empty());
+
+    for (i, log) in logs.iter().enumerate() {
+        let name = format!("{}-{}", log.block_number.unwrap(), i);
+        let path = Path::new("fuzz/seeds/decode_swap_log").join(name);
+        let bytes = fuzz_input::bytes_from_log(log);
+        if std::env::var_os("FUZZ_WRITE_SEEDS").is_some() {
+            std::fs::write(&path, &bytes).unwrap();
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), bytes, "{}", path.display());
+        assert_eq!(fuzz_input::log_from_bytes(&bytes).topics, log.topics);
+    }
+}
+
+async fn fixtures_logs(fixtures: &FixtureEventsSource) -> Vec<web3::types::Log> {
+    use uniswap_dai_usd_monitor::events_source::EventsSource;
+    let pool = common::chain::POOL_ADDRESS.parse().unwrap();
+    let topic: H256 = common::chain::SWAP_TOPIC.parse().unwrap();
+    fixtures
+        .logs_by_block_range(pool, topic, 0, u64::MAX)
+        .await
+        .unwrap()
+}
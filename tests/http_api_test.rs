@@ -0,0 +1,161 @@
+mod common;
+
+use async_trait::async_trait;
+use common::{blocks::confirmed_block, chain::Chain};
+use rust_decimal::Decimal;
+use serde_json::Value;
+use std::net::SocketAddr;
+use uniswap_dai_usd_monitor::api::{ApiState, SharedStatus, StatusHeadsHandler};
+use uniswap_dai_usd_monitor::blocks_handler::{ConfirmedBlock, HeadsHandler, Reorg};
+use uniswap_dai_usd_monitor::config::Config;
+use uniswap_dai_usd_monitor::store::Store;
+use uniswap_dai_usd_monitor::swap_details::SwapDirection;
+use web3::types::{BlockHeader, H160, H256};
+
+/// A block of `(direction, sender, DAI amount)` swaps
+fn block_of(block_number: u64, swaps: &[(SwapDirection, H160, i64)]) -> ConfirmedBlock {
+    let mut block = confirmed_block(block_number, block_number as u8, swaps.len() as u64);
+    for (swap, (direction, sender, amount0)) in block.swaps.iter_mut().zip(swaps) {
+        swap.direction = *direction;
+        swap.sender = *sender;
+        swap.amount0_as_decimal_num = Decimal::new(*amount0, 0);
+        swap.amount1_as_decimal_num = Decimal::new(-amount0, 0);
+    }
+    block
+}
+
+/// Confirms the block `confirmations` before every head & tracks the ones in between
+struct FakeHandler {
+    confirmations: u64,
+    tracked: Vec<(u64, H256)>,
+}
+
+#[async_trait]
+impl HeadsHandler for FakeHandler {
+    async fn handle_head(
+        &mut self,
+        block_header: BlockHeader,
+    ) -> Result<Vec<ConfirmedBlock>, anyhow::Error> {
+        let head = block_header.number.unwrap().as_u64();
+        self.tracked = (head - self.confirmations + 1..=head)
+            .map(|number| (number, H256::repeat_byte(number as u8)))
+            .collect();
+        Ok(vec![block_of(head - self.confirmations, &[])])
+    }
+
+    fn tracked_window(&self) -> Vec<(u64, H256)> {
+        self.tracked.clone()
+    }
+}
+
+async fn get(addr: SocketAddr, path: &str) -> (u16, Value) {
+    let response = reqwest::get(format!("http://{}{}", addr, path))
+        .await
+        .unwrap();
+    let status = response.status().as_u16();
+    let body = response.text().await.unwrap();
+    (
+        status,
+        serde_json::from_str(&body).unwrap_or(Value::String(body)),
+    )
+}
+
+fn block_and_log(swaps: &Value) -> Vec<(u64, u64)> {
+    swaps
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|swap| {
+            (
+                swap["block_number"].as_u64().unwrap(),
+                swap["log_index"].as_u64().unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_swaps_reorgs_status_and_pools_are_served() {
+    let dir = std::env::temp_dir().join(format!("http-api-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("swaps.db");
+    let _ = std::fs::remove_file(&path);
+
+    // the monitor's sink & the API have connections of their own
+    let mut sink = Store::open(&path).unwrap();
+    let whale = H160::repeat_byte(9);
+    sink.insert_block(&block_of(
+        100,
+        &[
+            (SwapDirection::DaiToUsdc, H160::repeat_byte(1), 10),
+            (SwapDirection::UsdcToDai, whale, 5_000_000),
+        ],
+    ))
+    .unwrap();
+    sink.insert_block(&block_of(
+        101,
+        &[(SwapDirection::UsdcToDai, H160::repeat_byte(1), 20)],
+    ))
+    .unwrap();
+    let reorg = Reorg {
+        detected_at: 106,
+        fork_point: 101,
+        depth: 1,
+        orphaned_hashes: vec![H256::repeat_byte(0xee)],
+        new_hashes: vec![H256::repeat_byte(102)],
+    };
+    sink.insert_reorg(&reorg).unwrap();
+
+    let status = SharedStatus::default();
+    let pools = Config::default().with_network_defaults().pools;
+    let addr = ApiState::new(Store::open(&path).unwrap(), status.clone(), pools.clone())
+        .serve(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .unwrap();
+
+    let (code, swaps) = get(addr, "/swaps").await;
+    assert_eq!(code, 200);
+    assert_eq!(block_and_log(&swaps), vec![(100, 0), (100, 1), (101, 0)]);
+    assert_eq!(swaps[0]["direction"], "DaiToUsdc");
+    let (_, swaps) = get(addr, "/swaps?from_block=101&to_block=200").await;
+    assert_eq!(block_and_log(&swaps), vec![(101, 0)]);
+    let (_, swaps) = get(addr, "/swaps?direction=UsdcToDai&limit=1").await;
+    assert_eq!(block_and_log(&swaps), vec![(100, 1)]);
+    let (_, swaps) = get(addr, "/swaps?min_amount=1000000").await;
+    assert_eq!(block_and_log(&swaps), vec![(100, 1)]);
+    let (_, swaps) = get(addr, &format!("/swaps?address={:?}", whale)).await;
+    assert_eq!(block_and_log(&swaps), vec![(100, 1)]);
+    let (code, _) = get(addr, "/swaps?direction=sideways").await;
+    assert_eq!(code, 400);
+
+    let (code, reorgs) = get(addr, "/reorgs").await;
+    assert_eq!(code, 200);
+    assert_eq!(
+        serde_json::from_value::<Vec<Reorg>>(reorgs).unwrap(),
+        vec![reorg]
+    );
+
+    let (_, pools_served) = get(addr, "/pools").await;
+    assert_eq!(pools_served, serde_json::to_value(&pools).unwrap());
+
+    let (_, initial) = get(addr, "/status").await;
+    assert_eq!(initial["head"], Value::Null);
+    let mut handler = StatusHeadsHandler::new(
+        FakeHandler {
+            confirmations: 2,
+            tracked: vec![],
+        },
+        status,
+    );
+    let head = Chain::new(107).head().clone();
+    handler.handle_head(head).await.unwrap();
+    let (_, status) = get(addr, "/status").await;
+    assert_eq!(status["head"], 107);
+    assert_eq!(status["confirmed"], 105);
+    assert_eq!(status["tracked"][0]["number"], 106);
+    assert_eq!(
+        status["tracked"][1]["hash"],
+        format!("{:?}", H256::repeat_byte(107))
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
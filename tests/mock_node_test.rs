@@ -0,0 +1,265 @@
+mod common;
+
+use common::{chain::Chain, mock_node::MockNode};
+use std::future::{pending, Future};
+use tokio::sync::oneshot;
+use uniswap_dai_usd_monitor::blocks_handler::{BlocksHandler, ConfirmedBlock};
+use uniswap_dai_usd_monitor::checkpoint::Checkpoint;
+use uniswap_dai_usd_monitor::error::MonitorError;
+use uniswap_dai_usd_monitor::events_handler::EventsHandler;
+use uniswap_dai_usd_monitor::events_source::Web3EventsSource;
+use uniswap_dai_usd_monitor::sinks::{ProviderOutage, Sink};
+use uniswap_dai_usd_monitor::web3_client::Web3BlocksFetcher;
+use uniswap_dai_usd_monitor::{monitor_new_heads, verify_chain_id, BLOCK_CONFIRMATIONS};
+use web3::{transports::WebSocket, Web3};
+
+#[derive(Default)]
+struct CollectingSink(Vec<ConfirmedBlock>, Vec<ProviderOutage>);
+
+#[async_trait::async_trait]
+impl Sink for CollectingSink {
+    async fn write(&mut self, confirmed_block: &ConfirmedBlock) -> Result<(), anyhow::Error> {
+        self.0.push(confirmed_block.clone());
+        Ok(())
+    }
+
+    async fn write_outage(&mut self, outage: &ProviderOutage) -> Result<(), anyhow::Error> {
+        self.1.push(outage.clone());
+        Ok(())
+    }
+}
+
+type MonitorRun = (Vec<ConfirmedBlock>, Checkpoint, Vec<ProviderOutage>);
+
+/// Same wiring as `main`, but against the mock node.
+/// Resolves to the confirmed blocks, the tracked ones & the reported outages once the node shuts down or `shutdown` resolves,
+/// or to the error & the tracked blocks (which `main` saves too)
+async fn spawn_monitor(
+    node: &MockNode,
+    checkpoint: Option<Checkpoint>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> tokio::task::JoinHandle<Result<MonitorRun, (anyhow::Error, Checkpoint)>> {
+    let web3 = Web3::new(WebSocket::new(node.url()).await.unwrap());
+    tokio::spawn(async move {
+        let blocks_fetcher = Web3BlocksFetcher { web3: web3.clone() };
+        let events_handler = EventsHandler::new(Web3EventsSource { web3: web3.clone() }).unwrap();
+        let mut blocks_handler =
+            BlocksHandler::new(BLOCK_CONFIRMATIONS, blocks_fetcher, events_handler).unwrap();
+        if let Some(checkpoint) = checkpoint {
+            blocks_handler = blocks_handler.with_checkpoint(checkpoint);
+        }
+        let mut sink = CollectingSink::default();
+        let result = monitor_new_heads(&web3, &mut blocks_handler, &mut sink, shutdown).await;
+        let checkpoint = blocks_handler.checkpoint();
+        match result {
+            Ok(()) => Ok((sink.0, checkpoint, sink.1)),
+            Err(err) => Err((err, checkpoint)),
+        }
+    })
+}
+
+#[tokio::test]
+async fn test_monitor_confirms_blocks_end_to_end() {
+    let node = MockNode::start(Chain::new(21836327)).await;
+    let first_block = node.chain().head().clone();
+    let first_block_hash = first_block.hash.unwrap();
+    node.chain()
+        .add_swap(first_block_hash, 1_000_000_000_000_000_000, -999_000);
+
+    let monitor = spawn_monitor(&node, None, pending()).await;
+    node.wait_until(|node| !node.requests("eth_subscribe").is_empty())
+        .await;
+
+    node.announce(&first_block);
+    let mined = node.mine(BLOCK_CONFIRMATIONS + 1);
+
+    // N+5 reached twice: for the first block & the one after it
+    let second_block_hash = mined[0].hash.unwrap();
+    node.wait_until(|node| node.logs_requests_for(second_block_hash) == 1)
+        .await;
+    assert_eq!(node.logs_requests_for(first_block_hash), 1);
+    // tracked blocks were re-verified by number along the way
+    assert!(!node.requests("eth_getBlockByNumber").is_empty());
+
+    node.shutdown();
+    let (confirmed_blocks, _, outages) = monitor.await.unwrap().unwrap();
+    let confirmed_hashes: Vec<_> = confirmed_blocks
+        .iter()
+        .map(|block| block.block_hash)
+        .collect();
+    assert_eq!(confirmed_hashes, vec![first_block_hash, second_block_hash]);
+    assert_eq!(confirmed_blocks[0].swaps.len(), 1);
+    assert_eq!(
+        confirmed_blocks[0].swaps[0].metadata.block_hash,
+        Some(first_block_hash)
+    );
+    assert!(confirmed_blocks[1].swaps.is_empty());
+    // the node going away ends the subscription
+    assert_eq!(outages.len(), 1);
+    assert_eq!(
+        outages[0].last_head,
+        mined.last().unwrap().number.map(|n| n.as_u64())
+    );
+}
+
+#[tokio::test]
+async fn test_monitor_stops_on_reorg() {
+    let node = MockNode::start(Chain::new(100)).await;
+    let first_block = node.chain().head().clone();
+
+    let monitor = spawn_monitor(&node, None, pending()).await;
+    node.wait_until(|node| !node.requests("eth_subscribe").is_empty())
+        .await;
+
+    node.announce(&first_block);
+    node.mine(2);
+
+    // sibling of the latest block, with a new child on top of it
+    let parent = node.chain().canonical(101).unwrap().hash.unwrap();
+    let fork = node.chain().extend_from(parent, 2);
+    node.announce(&fork[1]);
+
+    let (err, _) = monitor.await.unwrap().unwrap_err();
+    assert!(
+        err.to_string().starts_with("parent_hash mismatch"),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn test_restart_after_a_reorg_resumes_on_the_new_branch() {
+    let node = MockNode::start(Chain::new(100)).await;
+    let first_block = node.chain().head().clone();
+
+    let monitor = spawn_monitor(&node, None, pending()).await;
+    node.wait_until(|node| !node.requests("eth_subscribe").is_empty())
+        .await;
+    node.announce(&first_block);
+    node.mine(2);
+    let orphaned_102 = node.chain().canonical(102).unwrap().hash.unwrap();
+    let parent = node.chain().canonical(101).unwrap().hash.unwrap();
+    let fork = node.chain().extend_from(parent, 2);
+    node.announce(&fork[1]);
+
+    // the orphaned 102 isn't kept for the next run
+    let (_, checkpoint) = monitor.await.unwrap().unwrap_err();
+    assert_eq!(checkpoint.starting_block_number, 100);
+    assert_eq!(checkpoint.last_block_number(), Some(101));
+    assert!(checkpoint
+        .blocks
+        .iter()
+        .all(|header| header.hash != Some(orphaned_102)));
+
+    let monitor = spawn_monitor(&node, Some(checkpoint), pending()).await;
+    node.wait_until(|node| node.requests("eth_subscribe").len() == 2)
+        .await;
+    node.mine(BLOCK_CONFIRMATIONS + 1);
+    node.wait_until(|node| {
+        node.logs_requests_for(node.chain().canonical(104).unwrap().hash.unwrap()) == 1
+    })
+    .await;
+    node.shutdown();
+    let (confirmed, _, _) = monitor.await.unwrap().unwrap();
+
+    let confirmed: Vec<_> = confirmed
+        .iter()
+        .map(|block| (block.block_number, block.block_hash))
+        .collect();
+    let canonical: Vec<_> = (100..=104)
+        .map(|number| {
+            (
+                number,
+                node.chain().canonical(number).unwrap().hash.unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(confirmed, canonical);
+    assert_eq!(confirmed[2].1, fork[0].hash.unwrap());
+}
+
+#[tokio::test]
+async fn test_restart_from_checkpoint_after_shutdown_loses_nothing() {
+    let node = MockNode::start(Chain::new(100)).await;
+    let first_block = node.chain().head().clone();
+
+    let (shutdown, shutdown_rx) = oneshot::channel();
+    let monitor = spawn_monitor(&node, None, async {
+        let _ = shutdown_rx.await;
+    })
+    .await;
+    node.wait_until(|node| !node.requests("eth_subscribe").is_empty())
+        .await;
+
+    node.announce(&first_block);
+    node.mine(BLOCK_CONFIRMATIONS);
+    let first_block_hash = first_block.hash.unwrap();
+    node.wait_until(|node| node.logs_requests_for(first_block_hash) == 1)
+        .await;
+
+    shutdown.send(()).unwrap();
+    let (confirmed_before, checkpoint, outages) = monitor.await.unwrap().unwrap();
+    // a shutdown isn't an outage
+    assert!(outages.is_empty());
+    assert!(!node.requests("eth_unsubscribe").is_empty());
+    assert_eq!(checkpoint.starting_block_number, 101);
+    assert_eq!(checkpoint.last_block_number(), Some(105));
+
+    // mined while the monitor was down, with a swap the restarted one has to report
+    let missed = node.chain().extend(4);
+    let missed_hash = missed[0].hash.unwrap();
+    node.chain()
+        .add_swap(missed_hash, 1_000_000_000_000_000_000, -999_000);
+
+    let checkpoint = serde_json::from_str(&serde_json::to_string(&checkpoint).unwrap()).unwrap();
+    let monitor = spawn_monitor(&node, Some(checkpoint), pending()).await;
+    node.wait_until(|node| node.requests("eth_subscribe").len() == 2)
+        .await;
+    let head = node.mine(2).remove(1);
+
+    // 111 is the head, so everything up to 106 got confirmed: once each & in order
+    node.wait_until(|node| {
+        node.logs_requests_for(node.chain().canonical(106).unwrap().hash.unwrap()) == 1
+    })
+    .await;
+    node.shutdown();
+    let (confirmed_after, _, _) = monitor.await.unwrap().unwrap();
+
+    assert_eq!(head.number.unwrap().as_u64(), 111);
+    let confirmed: Vec<_> = confirmed_before
+        .iter()
+        .chain(&confirmed_after)
+        .map(|block| block.block_number)
+        .collect();
+    assert_eq!(confirmed, (100..=106).collect::<Vec<_>>());
+    let swaps: Vec<_> = confirmed_after
+        .iter()
+        .flat_map(|block| &block.swaps)
+        .collect();
+    assert_eq!(swaps.len(), 1);
+    assert_eq!(swaps[0].metadata.block_hash, Some(missed_hash));
+}
+
+#[tokio::test]
+async fn test_chain_id_is_verified() {
+    let node = MockNode::start(Chain::new(100)).await;
+    let web3 = Web3::new(WebSocket::new(node.url()).await.unwrap());
+
+    verify_chain_id(&web3, 1).await.unwrap();
+    let err = verify_chain_id(&web3, 42161).await.unwrap_err();
+    assert!(
+        matches!(
+            err,
+            MonitorError::ChainIdMismatch {
+                expected: 42161,
+                actual: 1
+            }
+        ),
+        "{}",
+        err
+    );
+    assert_eq!(
+        err.to_string(),
+        "Endpoint is on chain 1 (mainnet), but chain 42161 (arbitrum) is configured"
+    );
+}
@@ -0,0 +1,141 @@
+//! Runs against the database at `POSTGRES_URL` (e.g., `postgres://postgres@localhost/monitor_test`), so the tests are
+//! ignored by default: `POSTGRES_URL=... cargo test --test postgres_sink_test -- --ignored`.
+//! Each test writes with a chain ID of its own, so they don't see each other's rows
+
+mod common;
+
+use common::blocks::confirmed_block;
+use tokio_postgres::{Client, NoTls};
+use uniswap_dai_usd_monitor::blocks_handler::Reorg;
+use uniswap_dai_usd_monitor::postgres::PostgresSink;
+use uniswap_dai_usd_monitor::sinks::Sink;
+use web3::types::{H256, U256};
+
+fn postgres_url() -> String {
+    std::env::var("POSTGRES_URL")
+        .expect("POSTGRES_URL has to be set for the ignored Postgres tests")
+}
+
+/// A chain ID no other test (or run) writes with, its leftovers are deleted
+async fn fresh_chain_id(url: &str, test: u64) -> (u64, Client) {
+    let chain_id = 1_000_000 + u64::from(std::process::id()) * 10 + test;
+    // creates the tables, if this is the first test on the database
+    PostgresSink::connect(url, chain_id).await.unwrap();
+    let (client, connection) = tokio_postgres::connect(url, NoTls).await.unwrap();
+    tokio::spawn(connection);
+    for table in ["swaps", "reorgs"] {
+        client
+            .execute(
+                &format!("DELETE FROM {} WHERE chain_id = $1", table),
+                &[&(chain_id as i64)],
+            )
+            .await
+            .unwrap();
+    }
+    (chain_id, client)
+}
+
+/// `(block_number, block_hash, log_index, canonical)` of the chain's swaps
+async fn swap_rows(client: &Client, chain_id: u64) -> Vec<(i64, String, i64, bool)> {
+    client
+        .query(
+            "SELECT block_number, block_hash, log_index, canonical FROM swaps
+            WHERE chain_id = $1 ORDER BY block_number, block_hash, log_index",
+            &[&(chain_id as i64)],
+        )
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2), row.get(3)))
+        .collect()
+}
+
+fn hash(byte: u8) -> String {
+    format!("{:?}", H256::repeat_byte(byte))
+}
+
+#[tokio::test]
+#[ignore = "needs a database at POSTGRES_URL"]
+async fn test_writes_are_idempotent_per_chain() {
+    let url = postgres_url();
+    let (chain_id, client) = fresh_chain_id(&url, 1).await;
+
+    let mut sink = PostgresSink::connect(&url, chain_id).await.unwrap();
+    sink.write(&confirmed_block(100, 1, 2)).await.unwrap();
+    sink.write(&confirmed_block(100, 1, 2)).await.unwrap();
+    sink.write(&confirmed_block(101, 2, 0)).await.unwrap();
+    // the same block, as seen by a monitor of another chain
+    let mut other_chain = PostgresSink::connect(&url, chain_id + 5).await.unwrap();
+    other_chain
+        .write(&confirmed_block(100, 1, 1))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        swap_rows(&client, chain_id).await,
+        vec![(100, hash(1), 0, true), (100, hash(1), 1, true)]
+    );
+    let amounts = client
+        .query_one(
+            "SELECT amount0::TEXT, amount1::TEXT, amount1_raw FROM swaps WHERE chain_id = $1 AND log_index = 0",
+            &[&(chain_id as i64)],
+        )
+        .await
+        .unwrap();
+    assert_eq!(amounts.get::<_, String>(0), "1.5");
+    assert_eq!(amounts.get::<_, String>(1), "-1");
+    assert_eq!(
+        amounts.get::<_, String>(2),
+        (U256::MAX - U256::from(999_999)).to_string()
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs a database at POSTGRES_URL"]
+async fn test_orphaned_swaps_are_marked_non_canonical() {
+    let url = postgres_url();
+    let (chain_id, client) = fresh_chain_id(&url, 2).await;
+
+    let mut sink = PostgresSink::connect(&url, chain_id).await.unwrap();
+    sink.write(&confirmed_block(100, 1, 1)).await.unwrap();
+    sink.write(&confirmed_block(101, 2, 1)).await.unwrap();
+
+    // a reorg deeper than the confirmations orphaned the confirmed 101
+    let reorg = Reorg {
+        detected_at: 106,
+        fork_point: 100,
+        depth: 1,
+        orphaned_hashes: vec![H256::repeat_byte(2)],
+        new_hashes: vec![H256::repeat_byte(3)],
+    };
+    sink.write_reorg(&reorg).await.unwrap();
+    sink.write_reorg(&reorg).await.unwrap();
+    assert_eq!(
+        swap_rows(&client, chain_id).await,
+        vec![(100, hash(1), 0, true), (101, hash(2), 0, false)]
+    );
+
+    // confirming another block at a height replaces the stored one, even without a reorg record
+    sink.write(&confirmed_block(100, 4, 1)).await.unwrap();
+    assert_eq!(
+        swap_rows(&client, chain_id).await,
+        vec![
+            (100, hash(1), 0, false),
+            (100, hash(4), 0, true),
+            (101, hash(2), 0, false)
+        ]
+    );
+
+    let reorgs = client
+        .query(
+            "SELECT fork_point, depth, orphaned_hashes, new_hashes FROM reorgs WHERE chain_id = $1",
+            &[&(chain_id as i64)],
+        )
+        .await
+        .unwrap();
+    assert_eq!(reorgs.len(), 1);
+    assert_eq!(reorgs[0].get::<_, i64>(0), 100);
+    assert_eq!(reorgs[0].get::<_, i64>(1), 1);
+    assert_eq!(reorgs[0].get::<_, Vec<String>>(2), vec![hash(2)]);
+    assert_eq!(reorgs[0].get::<_, Vec<String>>(3), vec![hash(3)]);
+}
@@ -0,0 +1,405 @@
+mod common;
+
+use common::chain_simulator::ChainSimulator;
+use std::time::Duration;
+use uniswap_dai_usd_monitor::blocks_handler::{BlockEvent, BlocksHandler, HeadsHandler, Reorg};
+use uniswap_dai_usd_monitor::error::MonitorError;
+use uniswap_dai_usd_monitor::events_handler::EventsHandler;
+use uniswap_dai_usd_monitor::l2_handler::{BlockState, L2BlocksHandler, L2Event};
+use uniswap_dai_usd_monitor::sinks::Sink;
+use uniswap_dai_usd_monitor::store::Store;
+use uniswap_dai_usd_monitor::BLOCK_CONFIRMATIONS;
+use web3::types::{BlockHeader, H256};
+
+type SimulatedBlocksHandler = BlocksHandler<ChainSimulator, EventsHandler<ChainSimulator>>;
+
+fn get_blocks_handler(simulator: &ChainSimulator) -> SimulatedBlocksHandler {
+    let events_handler = EventsHandler::new(simulator.clone()).unwrap();
+    BlocksHandler::new(BLOCK_CONFIRMATIONS, simulator.clone(), events_handler).unwrap()
+}
+
+/// Feeds the simulator's first block & `count` more canonical blocks, the way `newHeads` would
+async fn feed_canonical(
+    simulator: &ChainSimulator,
+    blocks_handler: &mut SimulatedBlocksHandler,
+    count: u64,
+) {
+    let first = simulator.head();
+    blocks_handler.handle_block(first).await.unwrap();
+    for header in simulator.advance(count) {
+        blocks_handler.handle_block(header).await.unwrap();
+    }
+}
+
+fn monitor_error(err: anyhow::Error) -> MonitorError {
+    err.downcast::<MonitorError>()
+        .expect("expected a MonitorError")
+}
+
+fn hash(header: &BlockHeader) -> H256 {
+    header.hash.unwrap()
+}
+
+#[tokio::test]
+async fn test_linear_chain_confirms_every_block_once() {
+    let simulator = ChainSimulator::new(1);
+    let swap_block = simulator.head().hash.unwrap();
+    simulator
+        .chain()
+        .add_swap(swap_block, 1_000_000_000_000_000_000, -999_000);
+
+    let mut blocks_handler = get_blocks_handler(&simulator);
+    feed_canonical(&simulator, &mut blocks_handler, 20).await;
+
+    // head is 21, so 1..=16 reached N+5
+    let expected: Vec<H256> = (1..=16).map(|n| simulator.canonical_hash(n)).collect();
+    assert_eq!(simulator.confirmed(), expected);
+}
+
+#[tokio::test]
+async fn test_switch_to_sibling_branch_at_height_10_announcing_its_tip() {
+    let simulator = ChainSimulator::new(1);
+    let mut blocks_handler = get_blocks_handler(&simulator);
+    feed_canonical(&simulator, &mut blocks_handler, 11).await;
+    let orphaned_12 = simulator.canonical_hash(12);
+
+    // 10 <- 11 <- 12 replaced by 10' <- 11' <- 12', the node only announces 12'
+    let branch = simulator.fork_at(10, 3);
+    let err = blocks_handler
+        .handle_block(branch[2].clone())
+        .await
+        .unwrap_err();
+
+    match monitor_error(err) {
+        MonitorError::ReorgDetected {
+            block_number,
+            previous_hash,
+            new_hash,
+        } => {
+            assert_eq!(block_number, 12);
+            assert_eq!(previous_hash, orphaned_12);
+            assert_eq!(new_hash, hash(&branch[2]));
+        }
+        err => panic!("unexpected error: {}", err),
+    }
+    // nothing from the fork point on was confirmed
+    assert_eq!(simulator.confirmed().len(), 7);
+}
+
+#[tokio::test]
+async fn test_longer_sibling_branch_at_height_10_announcing_its_tip() {
+    let simulator = ChainSimulator::new(1);
+    let mut blocks_handler = get_blocks_handler(&simulator);
+    feed_canonical(&simulator, &mut blocks_handler, 11).await;
+    let orphaned_12 = simulator.canonical_hash(12);
+
+    // 10' <- 11' <- 12' <- 13', so 13' is on top of a block (12') that was never seen
+    let branch = simulator.fork_at(10, 4);
+    let err = blocks_handler
+        .handle_block(branch[3].clone())
+        .await
+        .unwrap_err();
+
+    match monitor_error(err) {
+        MonitorError::ParentHashMismatch {
+            block_number,
+            previous,
+            current,
+        } => {
+            assert_eq!(block_number, 12);
+            assert_eq!(previous, orphaned_12);
+            assert_eq!(current, hash(&branch[2]));
+        }
+        err => panic!("unexpected error: {}", err),
+    }
+}
+
+#[tokio::test]
+async fn test_reorg_is_described_and_stored() {
+    let simulator = ChainSimulator::new(1);
+    let mut blocks_handler = get_blocks_handler(&simulator);
+    feed_canonical(&simulator, &mut blocks_handler, 11).await;
+    let orphaned: Vec<H256> = (10..=12).map(|n| simulator.canonical_hash(n)).collect();
+
+    // 13' reveals the reorg by its parent, the tracked 10 & 11 turn out to be orphaned too
+    let branch = simulator.fork_at(10, 4);
+    blocks_handler
+        .handle_head(branch[3].clone())
+        .await
+        .unwrap_err();
+    let reorgs = blocks_handler.take_reorgs();
+    let expected = Reorg {
+        detected_at: 13,
+        fork_point: 9,
+        depth: 3,
+        orphaned_hashes: orphaned,
+        new_hashes: branch.iter().map(hash).collect(),
+    };
+    assert_eq!(reorgs, vec![expected.clone()]);
+    assert!(blocks_handler.take_reorgs().is_empty());
+
+    let mut store = Store::open_in_memory().unwrap();
+    store.write_reorg(&reorgs[0]).await.unwrap();
+    store.write_reorg(&reorgs[0]).await.unwrap();
+    assert_eq!(store.reorgs().unwrap(), vec![expected]);
+}
+
+#[tokio::test]
+async fn test_provisional_swaps_are_retracted_by_a_reorg() {
+    let simulator = ChainSimulator::new(1);
+    let mut blocks_handler = get_blocks_handler(&simulator).with_provisional_swaps();
+    feed_canonical(&simulator, &mut blocks_handler, 9).await;
+    let head = simulator.advance(1).remove(0);
+    simulator
+        .chain()
+        .add_swap(hash(&head), 1_000_000_000_000_000_000, -999_000);
+    blocks_handler.handle_head(head).await.unwrap();
+
+    let events = blocks_handler.take_events();
+    let provisional: Vec<(u64, usize)> = events
+        .iter()
+        .map(|event| match event {
+            BlockEvent::Provisional(block) => (block.block_number, block.swaps.len()),
+            event => panic!("unexpected event: {:?}", event),
+        })
+        .collect();
+    let expected: Vec<(u64, usize)> = (1..=11).map(|n| (n, usize::from(n == 11))).collect();
+    assert_eq!(provisional, expected);
+    // confirmations reuse the swaps fetched for the provisional report
+    let fetched: Vec<H256> = (1..=11).map(|n| simulator.canonical_hash(n)).collect();
+    assert_eq!(simulator.confirmed(), fetched);
+
+    // 10 & 11 are orphaned, 6 - 9 (unconfirmed as well) are not
+    let branch = simulator.fork_at(10, 4);
+    blocks_handler
+        .handle_head(branch[3].clone())
+        .await
+        .unwrap_err();
+    let retracted: Vec<(H256, usize)> = blocks_handler
+        .take_events()
+        .iter()
+        .map(|event| match event {
+            BlockEvent::Retracted(block) => (block.block_hash, block.swaps.len()),
+            event => panic!("unexpected event: {:?}", event),
+        })
+        .collect();
+    assert_eq!(
+        retracted,
+        vec![(fetched[9], 0), (fetched[10], 1)],
+        "retracted blocks 10 & 11"
+    );
+}
+
+#[tokio::test]
+async fn test_switch_to_sibling_branch_at_height_10_announcing_every_block() {
+    let simulator = ChainSimulator::new(1);
+    let mut blocks_handler = get_blocks_handler(&simulator);
+    feed_canonical(&simulator, &mut blocks_handler, 11).await;
+    let orphaned_10 = simulator.canonical_hash(10);
+
+    let branch = simulator.fork_at(10, 3);
+    let err = blocks_handler
+        .handle_block(branch[0].clone())
+        .await
+        .unwrap_err();
+
+    match monitor_error(err) {
+        MonitorError::ReorgDetected {
+            block_number,
+            previous_hash,
+            new_hash,
+        } => {
+            assert_eq!(block_number, 10);
+            assert_eq!(previous_hash, orphaned_10);
+            assert_eq!(new_hash, hash(&branch[0]));
+        }
+        err => panic!("unexpected error: {}", err),
+    }
+}
+
+#[tokio::test]
+async fn test_two_competing_heads() {
+    let simulator = ChainSimulator::new(1);
+    let mut blocks_handler = get_blocks_handler(&simulator);
+    feed_canonical(&simulator, &mut blocks_handler, 4).await;
+    let first_head = simulator.head();
+
+    let competing_head = simulator.competing_head();
+    assert_eq!(competing_head.number, first_head.number);
+    assert_eq!(competing_head.parent_hash, first_head.parent_hash);
+
+    let err = blocks_handler
+        .handle_block(competing_head.clone())
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        monitor_error(err),
+        MonitorError::ReorgDetected { block_number: 5, previous_hash, new_hash }
+            if previous_hash == hash(&first_head) && new_hash == hash(&competing_head)
+    ));
+}
+
+#[tokio::test]
+async fn test_reorg_across_skipped_head_is_found_by_window_verification() {
+    let simulator = ChainSimulator::new(1);
+    let mut blocks_handler = get_blocks_handler(&simulator);
+    feed_canonical(&simulator, &mut blocks_handler, 5).await;
+    let orphaned_3 = simulator.canonical_hash(3);
+
+    // 3'..8', the node announces 8' only, so its parent (7') was never tracked
+    let branch = simulator.fork_at(3, 6);
+    let err = blocks_handler
+        .handle_block(branch[5].clone())
+        .await
+        .unwrap_err();
+
+    match monitor_error(err) {
+        MonitorError::ReorgDetected {
+            block_number,
+            previous_hash,
+            new_hash,
+        } => {
+            assert_eq!(block_number, 3);
+            assert_eq!(previous_hash, orphaned_3);
+            assert_eq!(new_hash, hash(&branch[0]));
+        }
+        err => panic!("unexpected error: {}", err),
+    }
+}
+
+#[tokio::test]
+async fn test_reorg_deeper_than_confirmations() {
+    let simulator = ChainSimulator::new(1);
+    let mut blocks_handler = get_blocks_handler(&simulator);
+    feed_canonical(&simulator, &mut blocks_handler, 19).await;
+    // head is 20, so 16..=20 are tracked & 12 is long confirmed
+    let confirmed_12 = simulator.canonical_hash(12);
+    assert!(simulator.confirmed().contains(&confirmed_12));
+
+    let branch = simulator.fork_at(12, 10);
+    let err = blocks_handler
+        .handle_block(branch[0].clone())
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        monitor_error(err),
+        MonitorError::DeepReorg {
+            block_number: 12,
+            oldest_tracked: 16
+        }
+    ));
+}
+
+#[tokio::test]
+async fn test_reorg_deeper_than_confirmations_is_reported_at_oldest_tracked_block() {
+    let simulator = ChainSimulator::new(1);
+    let mut blocks_handler = get_blocks_handler(&simulator);
+    feed_canonical(&simulator, &mut blocks_handler, 19).await;
+    let orphaned_16 = simulator.canonical_hash(16);
+
+    // 12'..22', only the tip is announced. The fork point (11) is below the tracked window,
+    // so the lowest mismatch the handler can see is its oldest tracked block
+    let branch = simulator.fork_at(12, 11);
+    let err = blocks_handler
+        .handle_block(branch[10].clone())
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        monitor_error(err),
+        MonitorError::ReorgDetected { block_number: 16, previous_hash, new_hash }
+            if previous_hash == orphaned_16 && new_hash == hash(&branch[4])
+    ));
+}
+
+/// `(state or "retracted", block number, swaps)` of each event
+fn l2_summary(events: &[L2Event]) -> Vec<(String, u64, usize)> {
+    events
+        .iter()
+        .map(|event| match event {
+            L2Event::Advanced { state, block } => (
+                format!("{:?}", state).to_lowercase(),
+                block.block_number,
+                block.swaps.len(),
+            ),
+            L2Event::Retracted { block } => (
+                "retracted".to_string(),
+                block.block_number,
+                block.swaps.len(),
+            ),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_l2_sequencer_reorg_retracts_provisional_swaps() {
+    let simulator = ChainSimulator::new(1);
+    let events_handler = EventsHandler::new(simulator.clone()).unwrap();
+    let mut l2_handler = L2BlocksHandler::new(simulator.clone(), events_handler, BlockState::Safe)
+        .with_tag_refresh_interval(Duration::ZERO);
+    l2_handler.handle_block(simulator.head()).await.unwrap();
+
+    // only the sequencer's latest head gets announced, 2..=4 are caught up on
+    let blocks = simulator.advance(4);
+    simulator
+        .chain()
+        .add_swap(hash(&blocks[2]), 1_000_000_000_000_000_000, -999_000);
+    let events = l2_handler.handle_block(blocks[3].clone()).await.unwrap();
+    let expected: Vec<(String, u64, usize)> = [(2, 0), (3, 0), (4, 1), (5, 0)]
+        .map(|(number, swaps)| ("unsafe".to_string(), number, swaps))
+        .to_vec();
+    assert_eq!(l2_summary(&events), expected);
+
+    // the sequencer drops 4 (& its swap) & 5 for 4' <- 5'
+    let branch = simulator.fork_at(4, 2);
+    let events = l2_handler.handle_block(branch[1].clone()).await.unwrap();
+    assert_eq!(
+        l2_summary(&events),
+        vec![
+            ("retracted".to_string(), 5, 0),
+            ("retracted".to_string(), 4, 1),
+            ("unsafe".to_string(), 4, 0),
+            ("unsafe".to_string(), 5, 0),
+        ]
+    );
+    assert_eq!(
+        l2_handler.take_reorgs(),
+        vec![Reorg {
+            detected_at: 5,
+            fork_point: 3,
+            depth: 2,
+            orphaned_hashes: vec![hash(&blocks[2]), hash(&blocks[3])],
+            new_hashes: vec![hash(&branch[0]), hash(&branch[1])],
+        }]
+    );
+
+    // 1..=3 posted to L1
+    simulator.set_safe(3);
+    let events = l2_handler
+        .handle_block(simulator.advance(1).remove(0))
+        .await
+        .unwrap();
+    assert_eq!(
+        l2_summary(&events),
+        vec![
+            ("unsafe".to_string(), 6, 0),
+            ("safe".to_string(), 1, 0),
+            ("safe".to_string(), 2, 0),
+            ("safe".to_string(), 3, 0),
+        ]
+    );
+
+    // as a `HeadsHandler`, the unsafe blocks are provisional & the safe ones confirmed
+    simulator.set_safe(4);
+    let confirmed = l2_handler
+        .handle_head(simulator.advance(1).remove(0))
+        .await
+        .unwrap();
+    assert_eq!(confirmed[0].block_hash, hash(&branch[0]));
+    assert_eq!(confirmed[0].timestamp, branch[0].timestamp.as_u64());
+    let events = l2_handler.take_events();
+    assert!(
+        matches!(&events[..], [BlockEvent::Provisional(block)] if block.block_number == 7),
+        "{:?}",
+        events
+    );
+}
@@ -0,0 +1,203 @@
+mod common;
+
+use common::{blocks::confirmed_block, webhook_receiver::WebhookReceiver};
+use std::{path::PathBuf, time::Duration};
+use uniswap_dai_usd_monitor::blocks_handler::Reorg;
+use uniswap_dai_usd_monitor::sinks::{ProviderOutage, Sink};
+use uniswap_dai_usd_monitor::webhook::{
+    read_dead_letters, sign, WebhookSink, EVENT_HEADER, SIGNATURE_HEADER,
+};
+use web3::types::H256;
+
+const SECRET: &str = "hook secret";
+
+fn dead_letters_path(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("webhook-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{}.jsonl", test));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn webhook(receiver: &WebhookReceiver, dead_letters: &PathBuf) -> WebhookSink {
+    WebhookSink::new(receiver.url(), Some(SECRET.to_string()), 1, dead_letters)
+        .unwrap()
+        .with_retry(3, Duration::from_millis(10))
+}
+
+#[tokio::test]
+async fn test_payloads_are_signed() {
+    let receiver = WebhookReceiver::start().await;
+    let dead_letters = dead_letters_path("signed");
+    let mut sink = webhook(&receiver, &dead_letters);
+
+    sink.write(&confirmed_block(100, 100, 2)).await.unwrap();
+    // nothing to tell about a block without swaps
+    sink.write(&confirmed_block(101, 101, 0)).await.unwrap();
+    let reorg = Reorg {
+        detected_at: 106,
+        fork_point: 100,
+        depth: 1,
+        orphaned_hashes: vec![H256::repeat_byte(101)],
+        new_hashes: vec![H256::repeat_byte(0xee)],
+    };
+    sink.write_reorg(&reorg).await.unwrap();
+    sink.write_outage(&ProviderOutage {
+        last_head: Some(106),
+        error: "subscription to new heads ended".to_string(),
+    })
+    .await
+    .unwrap();
+    sink.flush().await.unwrap();
+
+    let accepted = receiver.accepted();
+    let events: Vec<&str> = accepted
+        .iter()
+        .map(|request| request.headers[&EVENT_HEADER.to_lowercase()].as_str())
+        .collect();
+    assert_eq!(events, vec!["swaps", "reorg", "provider_outage"]);
+    for request in &accepted {
+        assert_eq!(
+            request.headers[&SIGNATURE_HEADER.to_lowercase()],
+            sign(SECRET, &request.body)
+        );
+        assert_eq!(request.headers["content-type"], "application/json");
+    }
+
+    let swaps = accepted[0].json();
+    assert_eq!(swaps["event"], "swaps");
+    assert_eq!(swaps["chain_id"], 1);
+    assert_eq!(swaps["block_number"], 100);
+    assert_eq!(swaps["swaps"].as_array().unwrap().len(), 2);
+    assert_eq!(swaps["swaps"][1]["log_index"], 1);
+    assert_eq!(accepted[1].json()["fork_point"], 100);
+    assert_eq!(accepted[2].json()["last_head"], 106);
+    assert!(read_dead_letters(&dead_letters).unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_failed_deliveries_are_retried_then_dead_lettered() {
+    let receiver = WebhookReceiver::start().await;
+    let dead_letters = dead_letters_path("retried");
+    let mut sink = webhook(&receiver, &dead_letters);
+
+    // a hiccup is retried
+    receiver.respond_with(&[503, 429]);
+    sink.write(&confirmed_block(100, 100, 1)).await.unwrap();
+    sink.flush().await.unwrap();
+    assert_eq!(receiver.attempts(), 3);
+    assert_eq!(receiver.accepted().len(), 1);
+
+    // an outage outlasting the attempts, then a payload the endpoint refuses (not retried)
+    receiver.respond_with(&[500, 500, 500, 400]);
+    sink.write(&confirmed_block(101, 101, 1)).await.unwrap();
+    sink.write(&confirmed_block(102, 102, 1)).await.unwrap();
+    sink.flush().await.unwrap();
+    assert_eq!(receiver.attempts(), 7);
+    let stored = read_dead_letters(&dead_letters).unwrap();
+    let stored_blocks: Vec<_> = stored
+        .iter()
+        .map(|dead_letter| dead_letter.body["block_number"].clone())
+        .collect();
+    assert_eq!(stored_blocks, vec![101, 102]);
+    assert!(stored[1].error.contains("400"), "{}", stored[1].error);
+
+    // a restarted monitor picks them up & sends them after the next delivery, oldest first
+    drop(sink);
+    let mut sink = webhook(&receiver, &dead_letters);
+    sink.write(&confirmed_block(103, 103, 1)).await.unwrap();
+    sink.flush().await.unwrap();
+    let delivered: Vec<_> = receiver
+        .accepted()
+        .iter()
+        .map(|request| request.json()["block_number"].clone())
+        .collect();
+    assert_eq!(delivered, vec![100, 103, 101, 102]);
+    assert!(read_dead_letters(&dead_letters).unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_unreachable_endpoint_does_not_fail_the_sink() {
+    let dead_letters = dead_letters_path("unreachable");
+    // nothing listens on the port of a dropped receiver
+    let url = WebhookReceiver::start().await.url().to_string();
+    let mut sink = WebhookSink::new(&url, None, 1, &dead_letters)
+        .unwrap()
+        .with_retry(2, Duration::from_millis(10));
+
+    sink.write(&confirmed_block(100, 100, 1)).await.unwrap();
+    sink.flush().await.unwrap();
+    let stored = read_dead_letters(&dead_letters).unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].event, "swaps");
+}
+
+#[tokio::test]
+async fn test_writes_do_not_wait_for_the_endpoint() {
+    let receiver = WebhookReceiver::start().await;
+    let dead_letters = dead_letters_path("queued");
+    // retries taking a minute, like a real outage would
+    let mut sink = WebhookSink::new(receiver.url(), None, 1, &dead_letters)
+        .unwrap()
+        .with_retry(2, Duration::from_secs(60))
+        .with_queue_capacity(1);
+    receiver.respond_with(&[503]);
+
+    let written = tokio::time::timeout(Duration::from_secs(5), async {
+        for block_number in 100..104 {
+            sink.write(&confirmed_block(block_number, block_number as u8, 1))
+                .await
+                .unwrap();
+            // lets the delivery task take the payload off the queue
+            tokio::task::yield_now().await;
+        }
+    })
+    .await;
+    assert!(written.is_ok(), "the writes waited for the retries");
+    // the task retries 100 & 101 waits in the queue, the queue was full for the others
+    let stored = read_dead_letters(&dead_letters).unwrap();
+    let stored_blocks: Vec<_> = stored
+        .iter()
+        .map(|dead_letter| dead_letter.body["block_number"].clone())
+        .collect();
+    assert_eq!(stored_blocks, vec![102, 103]);
+    assert!(stored[0].error.contains("queue"), "{}", stored[0].error);
+}
+
+#[tokio::test]
+async fn test_refused_dead_letters_do_not_hold_up_the_others() {
+    let receiver = WebhookReceiver::start().await;
+    let dead_letters = dead_letters_path("refused");
+    let mut sink = webhook(&receiver, &dead_letters);
+
+    receiver.respond_with(&[400, 400, 400]);
+    for block_number in 100..103 {
+        sink.write(&confirmed_block(block_number, block_number as u8, 1))
+            .await
+            .unwrap();
+    }
+    sink.flush().await.unwrap();
+    assert_eq!(read_dead_letters(&dead_letters).unwrap().len(), 3);
+
+    // 100 is refused again & dropped, the endpoint is down for 101, so it's kept with 102 (not tried)
+    receiver.respond_with(&[200, 400, 503]);
+    sink.write(&confirmed_block(103, 103, 1)).await.unwrap();
+    sink.flush().await.unwrap();
+    let stored_blocks: Vec<_> = read_dead_letters(&dead_letters)
+        .unwrap()
+        .iter()
+        .map(|dead_letter| dead_letter.body["block_number"].clone())
+        .collect();
+    assert_eq!(stored_blocks, vec![101, 102]);
+
+    // once it's back, they're delivered after the next payload
+    sink.write(&confirmed_block(104, 104, 1)).await.unwrap();
+    sink.flush().await.unwrap();
+    let delivered: Vec<_> = receiver
+        .accepted()
+        .iter()
+        .map(|request| request.json()["block_number"].clone())
+        .collect();
+    assert_eq!(delivered, vec![103, 104, 101, 102]);
+    assert!(read_dead_letters(&dead_letters).unwrap().is_empty());
+}